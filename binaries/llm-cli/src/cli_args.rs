@@ -7,9 +7,9 @@ use std::{
 use clap::{Parser, ValueEnum};
 use color_eyre::eyre::{self, WrapErr};
 use llm::{
-    ggml_format, samplers::build_sampler, ElementType, InferenceParameters, InferenceSessionConfig,
-    InvalidTokenBias, LoadProgress, Model, ModelKVMemoryType, ModelParameters, RoPEOverrides,
-    TokenBias, TokenId, TokenizerSource,
+    ggml_format, samplers::build_sampler, BeamSearchParameters, ContextOverflowPolicy, ElementType,
+    FileTypeFormat, InferenceParameters, InferenceSessionConfig, InvalidTokenBias, LoadProgress,
+    Model, ModelKVMemoryType, ModelParameters, RoPEOverrides, TokenBias, TokenId, TokenizerSource,
 };
 use rand::SeedableRng;
 
@@ -46,8 +46,25 @@ pub enum Args {
     /// have an extended conversation.
     Chat(Box<Chat>),
 
-    /// Quantize a GGML model to 4-bit.
+    #[command()]
+    /// Record how strongly the weights of a model are activated over calibration text,
+    /// for use with `quantize --imatrix`.
+    Imatrix(Box<Imatrix>),
+
+    /// Quantize a GGML model, re-quantize a quantized model to another type, or expand
+    /// it back to F16 or F32.
     Quantize(Box<Quantize>),
+
+    /// Merge LoRA adapters into a GGML model, and save the result as a new model.
+    MergeLora(Box<MergeLora>),
+
+    /// Convert a Hugging Face checkpoint (`config.json`, `tokenizer.json` and `*.safetensors`)
+    /// to a GGML model.
+    Convert(Box<Convert>),
+
+    /// Upgrade a model in a legacy container (GGML, GGMF or an older GGJT) to GGJT v3,
+    /// so that it can be loaded with mmap. The tensor data is copied as it is.
+    Upgrade(Box<Upgrade>),
 }
 
 #[derive(Parser, Debug)]
@@ -95,6 +112,35 @@ pub struct Infer {
     /// things.
     #[arg(long, default_value_t = false)]
     pub stats: bool,
+
+    /// Decode with beam search, extending this many hypotheses at each step, instead of
+    /// sampling. The sampling options are ignored.
+    #[arg(long, default_value = None)]
+    pub beams: Option<usize>,
+
+    /// With `--beams`, the power of its length that the log-probability of a hypothesis is
+    /// divided by to score it. Values above 0 favour longer hypotheses.
+    #[arg(long, default_value_t = 1.0)]
+    pub length_penalty: f32,
+
+    /// With `--beams`, stop as soon as as many hypotheses as there are beams have ended.
+    #[arg(long, default_value_t = false)]
+    pub early_stopping: bool,
+
+    /// With `--beams`, the number of hypotheses to output, best first.
+    #[arg(long, default_value_t = 1)]
+    pub n_best: usize,
+}
+impl Infer {
+    pub fn beam_search_parameters(&self) -> Option<BeamSearchParameters> {
+        Some(BeamSearchParameters {
+            beam_width: self.beams?,
+            length_penalty: self.length_penalty,
+            early_stopping: self.early_stopping,
+            n_best: self.n_best,
+            maximum_token_count: self.generate.num_predict,
+        })
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -112,6 +158,25 @@ pub struct Perplexity {
     pub prompt: Prompt,
 }
 
+#[derive(Parser, Debug)]
+pub struct Imatrix {
+    #[command(flatten)]
+    pub model_load: ModelLoad,
+
+    #[command(flatten)]
+    pub prompt_file: PromptFile,
+
+    #[command(flatten)]
+    pub generate: Generate,
+
+    #[command(flatten)]
+    pub prompt: Prompt,
+
+    /// The path to save the importance matrix to
+    #[arg(long, short = 'o')]
+    pub output: PathBuf,
+}
+
 #[derive(Parser, Debug)]
 pub struct Info {
     #[command(flatten)]
@@ -324,6 +389,12 @@ pub struct Generate {
     #[arg(long, default_value_t = false)]
     pub ignore_eos: bool,
 
+    /// When the context window is full, discard the oldest half of the tokens after the first
+    /// KEEP tokens to make room, instead of stopping. Only supported by architectures with
+    /// rotary or ALiBi position encodings.
+    #[arg(long, value_name = "KEEP", default_value = None)]
+    pub context_shift: Option<usize>,
+
     /// Whether to use GPU acceleration when available
     #[arg(long, default_value_t = false)]
     pub use_gpu: bool,
@@ -361,6 +432,13 @@ impl Generate {
             memory_v_type: mem_typ,
             n_batch: self.batch_size,
             n_threads: self.num_threads(),
+            context_overflow: match self.context_shift {
+                Some(n_keep) => ContextOverflowPolicy::Shift {
+                    n_keep,
+                    n_discard: None,
+                },
+                None => ContextOverflowPolicy::Error,
+            },
         }
     }
 
@@ -433,6 +511,19 @@ pub struct ModelArchitecture {
     #[arg(long, short = 'a')]
     pub model_architecture: Option<llm::ModelArchitecture>,
 }
+impl ModelArchitecture {
+    /// Returns the specified architecture, or guesses it from the model at `model_path`.
+    pub fn resolve(&self, model_path: &Path) -> eyre::Result<llm::ModelArchitecture> {
+        if let Some(architecture) = self.model_architecture {
+            return Ok(architecture);
+        }
+
+        let architecture = llm::guess_model_architecture(model_path)
+            .wrap_err("failed to guess the model architecture")?;
+        log::info!("Guessed model architecture: {architecture}");
+        Ok(architecture)
+    }
+}
 
 #[derive(Parser, Debug)]
 pub struct ModelAndTokenizer {
@@ -498,10 +589,16 @@ pub struct ModelLoad {
     #[arg(long)]
     pub no_mmap: bool,
 
-    /// LoRA adapter to use for the model
+    /// LoRA adapters to use for the model: GGLA files, or directories containing
+    /// a PEFT `adapter_config.json` and `adapter_model.safetensors`
     #[arg(long, num_args(0..))]
     pub lora_paths: Option<Vec<PathBuf>>,
 
+    /// Number of threads to use when applying the LoRA adapters. If not specified,
+    /// the number of physical cores is used.
+    #[arg(long)]
+    pub lora_threads: Option<usize>,
+
     /// Number of layers to run on the GPU. If not specified, all layers will be run on the GPU.
     #[arg(long)]
     pub gpu_layers: Option<usize>,
@@ -516,6 +613,7 @@ impl ModelLoad {
             prefer_mmap: !self.no_mmap,
             context_size: self.num_ctx_tokens,
             lora_adapters: self.lora_paths.clone(),
+            lora_threads: self.lora_threads.unwrap_or_else(num_cpus::get_physical),
             use_gpu,
             gpu_layers: self.gpu_layers,
             rope_overrides: self.rope_scaling.to_rope_arguments(),
@@ -545,6 +643,21 @@ impl ModelLoad {
             tokenizer_source,
             params,
             |progress| match progress {
+                LoadProgress::PartLoading {
+                    file,
+                    current_part,
+                    total_parts,
+                } => {
+                    if total_parts > 1 {
+                        if let Some(sp) = sp.as_mut() {
+                            sp.update_text(format!(
+                                "Loading part {}/{total_parts} from '{}'",
+                                current_part + 1,
+                                file.display()
+                            ))
+                        };
+                    }
+                }
                 LoadProgress::HyperparametersLoaded => {
                     if let Some(sp) = sp.as_mut() {
                         sp.update_text("Loaded hyperparameters")
@@ -554,6 +667,19 @@ impl ModelLoad {
                     "ggml ctx size = {}",
                     bytesize::to_string(bytes as u64, false)
                 ),
+                LoadProgress::LoraTensorLoaded {
+                    source,
+                    current_tensor,
+                    tensor_count,
+                } => {
+                    if let Some(sp) = sp.as_mut() {
+                        sp.update_text(format!(
+                            "Loaded LoRA tensor {}/{tensor_count} from '{}'",
+                            current_tensor + 1,
+                            source.display()
+                        ));
+                    }
+                }
                 LoadProgress::LoraApplied { name, source } => {
                     if let Some(sp) = sp.as_mut() {
                         sp.update_text(format!(
@@ -648,26 +774,77 @@ pub struct Quantize {
     /// The GGML container type to target.
     ///
     /// Note that using GGML requires the original model to have
-    /// an unscored vocabulary, which is not the case for newer models.
+    /// an unscored vocabulary, which is not the case for newer models,
+    /// and that GGMF and GGJT v1/v2 cannot store most quantized types.
     #[arg(short, long, default_value_t = SaveContainerType::GgjtV3)]
     pub container_type: SaveContainerType,
 
     /// The format to convert to
     pub target: QuantizationTarget,
+
+    /// A TOML or JSON file with rules that override the type of specific tensors,
+    /// e.g. to keep the output and the first and last layers at a higher precision
+    #[arg(long)]
+    pub policy: Option<PathBuf>,
+
+    /// An importance matrix recorded with `imatrix`, used to quantize the weights
+    /// with less error where they are most activated. Only the legacy quantization
+    /// types (Q4_0, Q4_1, Q5_0, Q5_1 and Q8_0) support it
+    #[arg(long)]
+    pub imatrix: Option<PathBuf>,
+
+    /// A text file to measure the perplexity of the model over, before and after quantization
+    #[arg(long)]
+    pub perplexity_file: Option<PathBuf>,
+
+    /// Number of threads to quantize each tensor with. If not specified, the number of
+    /// physical cores is used.
+    #[arg(long, short = 't')]
+    pub num_threads: Option<usize>,
+
+    /// Measure the error of each quantized tensor (RMSE, maximum absolute error and
+    /// cosine similarity), and save it to this path as JSON
+    #[arg(long)]
+    pub report: Option<PathBuf>,
+
+    /// Save the Hugging Face tokenizer given with `--tokenizer-path` or
+    /// `--tokenizer-repository` in the quantized model, so that it does not
+    /// need to be specified again when the model is loaded
+    #[arg(long)]
+    pub embed_tokenizer: bool,
+
+    /// Print the type each tensor would be quantized to, the size of the quantized
+    /// model and the memory needed to load its tensors, without reading the
+    /// tensors or writing the destination. The memory of the key/value cache
+    /// of sessions is not included, as it depends on their context size
+    #[arg(long)]
+    pub dry_run: bool,
 }
 
 #[derive(Parser, Debug, ValueEnum, Clone, Copy)]
 pub enum SaveContainerType {
     /// GGML container.
     Ggml,
+    /// GGMF container. Only stores unquantized tensors.
+    Ggmf,
+    /// GGJT v1 container. Only stores unquantized tensors.
+    GgjtV1,
+    /// GGJT v2 container. Only stores unquantized and Q5 tensors.
+    GgjtV2,
     /// GGJT v3 container.
     GgjtV3,
+    /// GGUF container.
+    Gguf,
 }
 impl fmt::Display for SaveContainerType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             SaveContainerType::Ggml => write!(f, "ggml"),
+            SaveContainerType::Ggmf => write!(f, "ggmf"),
+            SaveContainerType::GgjtV1 => write!(f, "ggjt-v1"),
+            SaveContainerType::GgjtV2 => write!(f, "ggjt-v2"),
             SaveContainerType::GgjtV3 => write!(f, "ggjt-v3"),
+            SaveContainerType::Gguf => write!(f, "gguf"),
         }
     }
 }
@@ -675,13 +852,18 @@ impl From<SaveContainerType> for ggml_format::SaveContainerType {
     fn from(value: SaveContainerType) -> Self {
         match value {
             SaveContainerType::Ggml => ggml_format::SaveContainerType::Ggml,
+            SaveContainerType::Ggmf => ggml_format::SaveContainerType::Ggmf,
+            SaveContainerType::GgjtV1 => ggml_format::SaveContainerType::GgjtV1,
+            SaveContainerType::GgjtV2 => ggml_format::SaveContainerType::GgjtV2,
             SaveContainerType::GgjtV3 => ggml_format::SaveContainerType::GgjtV3,
+            SaveContainerType::Gguf => ggml_format::SaveContainerType::Gguf,
         }
     }
 }
 
 #[derive(Parser, Debug, ValueEnum, Clone, Copy)]
 #[clap(rename_all = "snake_case")]
+#[allow(non_camel_case_types)]
 pub enum QuantizationTarget {
     /// Quantized 4-bit (type 0).
     Q4_0,
@@ -693,15 +875,162 @@ pub enum QuantizationTarget {
     Q5_1,
     /// Quantized 8-bit (type 0).
     Q8_0,
+    /// Quantized 2-bit k-quant, with some tensors in 3 or 4 bits.
+    Q2_K,
+    /// Quantized 3-bit k-quant (small).
+    Q3_K_S,
+    /// Quantized 3-bit k-quant (medium), with some tensors in 4 or 5 bits.
+    Q3_K_M,
+    /// Quantized 3-bit k-quant (large), with some tensors in 5 bits.
+    Q3_K_L,
+    /// Quantized 4-bit k-quant (small).
+    Q4_K_S,
+    /// Quantized 4-bit k-quant (medium), with some tensors in 6 bits.
+    Q4_K_M,
+    /// Quantized 5-bit k-quant (small).
+    Q5_K_S,
+    /// Quantized 5-bit k-quant (medium), with some tensors in 6 bits.
+    Q5_K_M,
+    /// Quantized 6-bit k-quant.
+    Q6_K,
+    /// Float 16-bit, to expand a quantized model.
+    F16,
+    /// Float 32-bit, to expand a quantized model.
+    F32,
 }
-impl From<QuantizationTarget> for ElementType {
+impl From<QuantizationTarget> for FileTypeFormat {
     fn from(t: QuantizationTarget) -> Self {
         match t {
-            QuantizationTarget::Q4_0 => ElementType::Q4_0,
-            QuantizationTarget::Q4_1 => ElementType::Q4_1,
-            QuantizationTarget::Q5_0 => ElementType::Q5_0,
-            QuantizationTarget::Q5_1 => ElementType::Q5_1,
-            QuantizationTarget::Q8_0 => ElementType::Q8_0,
+            QuantizationTarget::Q4_0 => FileTypeFormat::MostlyQ4_0,
+            QuantizationTarget::Q4_1 => FileTypeFormat::MostlyQ4_1,
+            QuantizationTarget::Q5_0 => FileTypeFormat::MostlyQ5_0,
+            QuantizationTarget::Q5_1 => FileTypeFormat::MostlyQ5_1,
+            QuantizationTarget::Q8_0 => FileTypeFormat::MostlyQ8_0,
+            QuantizationTarget::Q2_K => FileTypeFormat::MostlyQ2_K,
+            QuantizationTarget::Q3_K_S => FileTypeFormat::MostlyQ3_K_S,
+            QuantizationTarget::Q3_K_M => FileTypeFormat::MostlyQ3_K_M,
+            QuantizationTarget::Q3_K_L => FileTypeFormat::MostlyQ3_K_L,
+            QuantizationTarget::Q4_K_S => FileTypeFormat::MostlyQ4_K_S,
+            QuantizationTarget::Q4_K_M => FileTypeFormat::MostlyQ4_K_M,
+            QuantizationTarget::Q5_K_S => FileTypeFormat::MostlyQ5_K_S,
+            QuantizationTarget::Q5_K_M => FileTypeFormat::MostlyQ5_K_M,
+            QuantizationTarget::Q6_K => FileTypeFormat::MostlyQ6_K,
+            QuantizationTarget::F16 => FileTypeFormat::MostlyF16,
+            QuantizationTarget::F32 => FileTypeFormat::F32,
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+pub struct MergeLora {
+    #[command(flatten)]
+    pub architecture: ModelArchitecture,
+
+    /// The path to the model to merge the adapters into
+    #[arg()]
+    pub source: PathBuf,
+
+    /// The path to save the merged model to
+    #[arg()]
+    pub destination: PathBuf,
+
+    #[command(flatten)]
+    pub tokenizer: ModelTokenizer,
+
+    /// The LoRA adapters to merge, in the order they should be applied. Each is either
+    /// a GGLA file or the directory of a PEFT adapter
+    #[arg(long, required = true, num_args(1..))]
+    pub lora_paths: Vec<PathBuf>,
+
+    /// The scaling to apply each adapter with, in the same order as `--lora-paths`.
+    /// If not specified, the scaling of each adapter is calculated from its parameters.
+    #[arg(long, num_args(1..))]
+    pub lora_scales: Option<Vec<f32>>,
+
+    /// The format to quantize the merged tensors to. If not specified, tensors keep their type.
+    #[arg(long)]
+    pub target: Option<QuantizationTarget>,
+}
+impl MergeLora {
+    pub fn adapters(&self) -> eyre::Result<Vec<llm::MergeLoraAdapter>> {
+        let scales = match &self.lora_scales {
+            Some(scales) if scales.len() != self.lora_paths.len() => eyre::bail!(
+                "{} LoRA scales were given for {} LoRA adapters",
+                scales.len(),
+                self.lora_paths.len()
+            ),
+            Some(scales) => scales.iter().copied().map(Some).collect(),
+            None => vec![None; self.lora_paths.len()],
+        };
+
+        Ok(self
+            .lora_paths
+            .iter()
+            .zip(scales)
+            .map(|(path, scaling)| llm::MergeLoraAdapter {
+                path: path.clone(),
+                scaling,
+            })
+            .collect())
+    }
+}
+
+#[derive(Parser, Debug)]
+pub struct Convert {
+    /// The model architecture to convert to. Will be read from the `model_type` in the
+    /// checkpoint's `config.json` if not specified.
+    #[arg(long, short = 'a')]
+    pub model_architecture: Option<llm::ModelArchitecture>,
+
+    /// The directory containing the Hugging Face checkpoint to convert
+    #[arg()]
+    pub source: PathBuf,
+
+    /// The path to save the converted model to
+    #[arg()]
+    pub destination: PathBuf,
+
+    /// The element type to store the model's weights as
+    #[arg(short, long, default_value_t = ConvertTarget::F16)]
+    pub target: ConvertTarget,
+}
+
+#[derive(Parser, Debug)]
+pub struct Upgrade {
+    #[command(flatten)]
+    pub architecture: ModelArchitecture,
+
+    /// The path to the model to upgrade
+    #[arg()]
+    pub source: PathBuf,
+
+    /// The path to save the upgraded model to. If not specified, the model is
+    /// upgraded in place, once the upgraded model has been written next to it
+    #[arg()]
+    pub destination: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug, ValueEnum, Clone, Copy)]
+#[clap(rename_all = "snake_case")]
+pub enum ConvertTarget {
+    /// 16-bit floating point.
+    F16,
+    /// 32-bit floating point.
+    F32,
+}
+impl fmt::Display for ConvertTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConvertTarget::F16 => write!(f, "f16"),
+            ConvertTarget::F32 => write!(f, "f32"),
+        }
+    }
+}
+impl From<ConvertTarget> for ElementType {
+    fn from(t: ConvertTarget) -> Self {
+        match t {
+            ConvertTarget::F16 => ElementType::F16,
+            ConvertTarget::F32 => ElementType::F32,
         }
     }
 }
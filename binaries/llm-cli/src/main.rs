@@ -1,12 +1,14 @@
 use std::{
+    cell::RefCell,
     convert::Infallible,
     fs::File,
-    io::{BufReader, BufWriter},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
 };
 
 use clap::Parser;
 use cli_args::Args;
-use color_eyre::eyre::{self, Context, ContextCompat};
+use color_eyre::eyre::{self, Context};
 use is_terminal::IsTerminal;
 
 mod cli_args;
@@ -31,7 +33,11 @@ fn main() -> eyre::Result<()> {
         Args::PromptTokens(args) => prompt_tokens(&args),
         Args::Repl(args) => interactive::repl(&args),
         Args::Chat(args) => interactive::chat(&args),
+        Args::Imatrix(args) => imatrix(&args),
         Args::Quantize(args) => quantize(&args),
+        Args::MergeLora(args) => merge_lora(&args),
+        Args::Convert(args) => convert(&args),
+        Args::Upgrade(args) => upgrade(&args),
     }
 }
 
@@ -47,6 +53,34 @@ fn infer(args: &cli_args::Infer) -> eyre::Result<()> {
         args.load_session.as_deref(),
         inference_session_config,
     );
+
+    if let Some(beam_search_parameters) = args.beam_search_parameters() {
+        infer_beam_search(
+            args,
+            model.as_ref(),
+            &mut session,
+            &prompt,
+            &beam_search_parameters,
+        );
+    } else {
+        infer_sampling(args, model.as_ref(), &mut session, &prompt, session_loaded)?;
+    }
+
+    if let Some(session_path) = args.save_session.as_ref().or(args.persist_session.as_ref()) {
+        // Write the memory to the cache file
+        snapshot::write_session(session, session_path);
+    }
+
+    Ok(())
+}
+
+fn infer_sampling(
+    args: &cli_args::Infer,
+    model: &dyn llm::Model,
+    session: &mut llm::InferenceSession,
+    prompt: &str,
+    session_loaded: bool,
+) -> eyre::Result<()> {
     let parameters = args
         .generate
         .inference_parameters(model.eot_token_id(), model.tokenizer().len())?;
@@ -58,10 +92,10 @@ fn infer(args: &cli_args::Infer) -> eyre::Result<()> {
     span.in_scope(|| {
         // do work inside the span...
         let res = session.infer::<Infallible>(
-            model.as_ref(),
+            model,
             &mut rng,
             &llm::InferenceRequest {
-                prompt: prompt.as_str().into(),
+                prompt: prompt.into(),
                 parameters: &parameters,
                 play_back_previous_tokens: session_loaded,
                 maximum_token_count: args.generate.num_predict,
@@ -105,12 +139,54 @@ fn infer(args: &cli_args::Infer) -> eyre::Result<()> {
         }
     });
 
-    if let Some(session_path) = args.save_session.as_ref().or(args.persist_session.as_ref()) {
-        // Write the memory to the cache file
-        snapshot::write_session(session, session_path);
+    Ok(())
+}
+
+fn infer_beam_search(
+    args: &cli_args::Infer,
+    model: &dyn llm::Model,
+    session: &mut llm::InferenceSession,
+    prompt: &str,
+    parameters: &llm::BeamSearchParameters,
+) {
+    if !args.hide_prompt {
+        util::print_token(prompt.to_string());
     }
 
-    Ok(())
+    let span = tracing::trace_span!("beam_search");
+    let res = span.in_scope(|| llm::beam_search(session, model, prompt, parameters));
+
+    match res {
+        Ok(hypotheses) if hypotheses.len() == 1 => {
+            util::print_token(String::from_utf8_lossy(&hypotheses[0].text).into_owned());
+            println!();
+        }
+        Ok(hypotheses) => {
+            println!();
+            for (index, hypothesis) in hypotheses.iter().enumerate() {
+                println!(
+                    "#{} (score {:.4}{}): {}",
+                    index + 1,
+                    hypothesis.score,
+                    if hypothesis.ended { "" } else { ", cut off" },
+                    String::from_utf8_lossy(&hypothesis.text)
+                );
+            }
+        }
+        Err(llm::InferenceError::ContextFull) => {
+            println!();
+            log::warn!("Context window full, stopping inference.")
+        }
+        Err(llm::InferenceError::TokenizationFailed(err)) => {
+            println!();
+            log::error!("A tokenization-related failure occurred: {}", err);
+        }
+        Err(llm::InferenceError::SamplerFailure(_))
+        | Err(llm::InferenceError::UserCallback(_))
+        | Err(llm::InferenceError::EndOfText) => {
+            unreachable!("cannot fail")
+        }
+    }
 }
 
 fn perplexity(args: &cli_args::Perplexity) -> eyre::Result<()> {
@@ -127,6 +203,43 @@ fn perplexity(args: &cli_args::Perplexity) -> eyre::Result<()> {
     Ok(())
 }
 
+fn imatrix(args: &cli_args::Imatrix) -> eyre::Result<()> {
+    if args.generate.use_gpu {
+        eyre::bail!("Recording an importance matrix is only supported on the CPU");
+    }
+
+    let prompt = load_prompt_file_with_prompt(&args.prompt_file, args.prompt.as_deref())?;
+    let inference_session_config = args.generate.inference_session_config();
+    let model = args.model_load.load(false)?;
+    let (mut session, _) =
+        snapshot::read_or_create_session(model.as_ref(), None, None, inference_session_config);
+
+    session.set_importance_matrix(Some(llm::ImportanceMatrix::default()));
+    session.perplexity(model.as_ref(), prompt.as_str(), |chunk, perplexity| {
+        println!("Perplexity[{chunk}]: {perplexity}");
+    })?;
+    let importance_matrix = session
+        .take_importance_matrix()
+        .expect("the importance matrix was set before evaluating");
+    if importance_matrix.is_empty() {
+        eyre::bail!(
+            "The prompt must be at least as long as the context ({} tokens)",
+            model.context_size()
+        );
+    }
+
+    let mut writer = BufWriter::new(File::create(&args.output)?);
+    importance_matrix.write(&mut writer)?;
+    writer.flush()?;
+    log::info!(
+        "Recorded the importance of {} tensors to {:?}",
+        importance_matrix.len(),
+        args.output
+    );
+
+    Ok(())
+}
+
 fn info(args: &cli_args::Info) -> eyre::Result<()> {
     struct InfoVisitor<'a>(&'a cli_args::Info);
     impl llm::ModelArchitectureVisitor<eyre::Result<()>> for InfoVisitor<'_> {
@@ -147,6 +260,12 @@ fn info(args: &cli_args::Info) -> eyre::Result<()> {
 
             log::info!("Container type: {:?}", loader.container_type);
             log::info!("Hyperparameters: {:?}", loader.hyperparameters);
+            if let Some(metadata) = &loader.metadata {
+                log::info!("Metadata:");
+                for (key, value) in metadata.iter() {
+                    log::info!("- {}: {}", key, metadata_value_summary(value));
+                }
+            }
             log::info!("Tokenizer vocabulary size: {}", loader.tokenizer.len());
 
             if args.tokenizer {
@@ -163,6 +282,29 @@ fn info(args: &cli_args::Info) -> eyre::Result<()> {
                 }
             }
 
+            fn metadata_value_summary(value: &llm::ggml_format::gguf::MetadataValue) -> String {
+                use llm::ggml_format::gguf::MetadataValue;
+                match value {
+                    // Arrays (e.g. the vocabulary) can be very large, so only describe them.
+                    MetadataValue::Array {
+                        element_type,
+                        values,
+                    } => format!("[{}; {}]", element_type, values.len()),
+                    MetadataValue::UInt8(v) => v.to_string(),
+                    MetadataValue::Int8(v) => v.to_string(),
+                    MetadataValue::UInt16(v) => v.to_string(),
+                    MetadataValue::Int16(v) => v.to_string(),
+                    MetadataValue::UInt32(v) => v.to_string(),
+                    MetadataValue::Int32(v) => v.to_string(),
+                    MetadataValue::Float32(v) => v.to_string(),
+                    MetadataValue::Bool(v) => v.to_string(),
+                    MetadataValue::String(v) => format!("{v:?}"),
+                    MetadataValue::UInt64(v) => v.to_string(),
+                    MetadataValue::Int64(v) => v.to_string(),
+                    MetadataValue::Float64(v) => v.to_string(),
+                }
+            }
+
             fn utf8_or_array(token: &[u8]) -> String {
                 std::str::from_utf8(token)
                     .map(|s| s.to_owned())
@@ -175,8 +317,7 @@ fn info(args: &cli_args::Info) -> eyre::Result<()> {
 
     args.model_and_tokenizer
         .architecture
-        .model_architecture
-        .wrap_err("a model architecture is required at present")?
+        .resolve(&args.model_and_tokenizer.model_path)?
         .visit(&mut InfoVisitor(args))
 }
 
@@ -218,9 +359,52 @@ fn quantize(args: &cli_args::Quantize) -> eyre::Result<()> {
             let args = self.0;
 
             let mut source: BufReader<File> = BufReader::new(std::fs::File::open(&args.source)?);
+            let tokenizer: llm::Tokenizer = args.tokenizer.to_source()?.retrieve(&args.source)?;
+            let policy = match &args.policy {
+                Some(path) => llm::QuantizationPolicy::load(path)
+                    .wrap_err("failed to load quantization policy")?,
+                None => llm::QuantizationPolicy::default(),
+            };
+            let importance_matrix = match &args.imatrix {
+                Some(path) => Some(
+                    llm::ImportanceMatrix::read(&mut BufReader::new(File::open(path)?))
+                        .wrap_err("failed to read importance matrix")?,
+                ),
+                None => None,
+            };
+            let parameters = llm::QuantizeParameters {
+                policy,
+                importance_matrix,
+                n_threads: args.num_threads.unwrap_or_else(num_cpus::get_physical),
+                measure_error: args.report.is_some(),
+                embed_tokenizer: args.embed_tokenizer,
+            };
+
+            if args.dry_run {
+                let dry_run = llm::quantize_dry_run::<M, _>(
+                    &mut source,
+                    tokenizer,
+                    args.container_type.into(),
+                    args.target.into(),
+                    &parameters,
+                )
+                .wrap_err("failed to plan quantization")?;
+                print_quantize_dry_run(&dry_run);
+                return Ok(());
+            }
+
             let mut destination: BufWriter<File> =
                 BufWriter::new(std::fs::File::create(&args.destination)?);
-            let tokenizer: llm::Tokenizer = args.tokenizer.to_source()?.retrieve(&args.source)?;
+            let perplexity_text = args
+                .perplexity_file
+                .as_deref()
+                .map(cli_args::read_prompt_file)
+                .transpose()?;
+            let report = RefCell::new(vec![]);
+            let perplexity_before = perplexity_text
+                .as_deref()
+                .map(|text| model_perplexity::<M>(&args.source, args.tokenizer.to_source()?, text))
+                .transpose()?;
 
             llm::quantize::<M, _, _>(
                 &mut source,
@@ -228,6 +412,7 @@ fn quantize(args: &cli_args::Quantize) -> eyre::Result<()> {
                 tokenizer,
                 args.container_type.into(),
                 args.target.into(),
+                &parameters,
                 |progress| match progress {
                     QuantizeProgress::HyperparametersLoaded => log::info!("Loaded hyperparameters"),
                     QuantizeProgress::TensorLoading {
@@ -243,10 +428,28 @@ fn quantize(args: &cli_args::Quantize) -> eyre::Result<()> {
                         name,
                         original_size,
                         reduced_size,
+                        element_type,
                         history,
                     } => log::info!(
-                    "Quantized tensor `{name}` from {original_size} to {reduced_size} bytes ({history:?})"
+                    "Quantized tensor `{name}` to {element_type} from {original_size} to {reduced_size} bytes ({history:?})"
                 ),
+                    QuantizeProgress::TensorMeasured {
+                        name,
+                        element_type,
+                        error,
+                    } => {
+                        log::info!(
+                            "Measured tensor `{name}`: RMSE {}, max abs error {}, cosine similarity {}",
+                            error.rmse,
+                            error.max_abs_error,
+                            error.cosine_similarity
+                        );
+                        report.borrow_mut().push(TensorReport {
+                            name: name.to_owned(),
+                            element_type: element_type.to_string(),
+                            error,
+                        });
+                    }
                     QuantizeProgress::TensorSkipped { name, size } => {
                         log::info!("Skipped tensor `{name}` ({size} bytes)")
                     }
@@ -259,16 +462,224 @@ fn quantize(args: &cli_args::Quantize) -> eyre::Result<()> {
                     ),
                 },
             )
-            .wrap_err("failed to quantize model")
+            .wrap_err("failed to quantize model")?;
+            destination.flush()?;
+
+            if let Some(path) = &args.report {
+                let file = BufWriter::new(File::create(path)?);
+                serde_json::to_writer_pretty(file, &report.into_inner())
+                    .wrap_err("failed to write quantization report")?;
+            }
+
+            if let (Some(text), Some(before)) = (&perplexity_text, perplexity_before) {
+                let after =
+                    model_perplexity::<M>(&args.destination, args.tokenizer.to_source()?, text)?;
+                log::info!("Perplexity before quantization: {before}");
+                log::info!("Perplexity after quantization: {after}");
+            }
+
+            Ok(())
         }
     }
 
     args.architecture
-        .model_architecture
-        .wrap_err("the architecture must be known for quantization")?
+        .resolve(&args.source)?
         .visit(&mut QuantizeVisitor(args))
 }
 
+/// Prints what quantizing would do, as calculated by `quantize --dry-run`.
+fn print_quantize_dry_run(dry_run: &llm::QuantizeDryRun) {
+    let mib = |bytes: usize| bytes as f64 / (1024.0 * 1024.0);
+
+    for tensor in &dry_run.tensors {
+        let change = if tensor.element_type == tensor.original_type {
+            format!("{} (kept)", tensor.original_type)
+        } else {
+            format!("{} -> {}", tensor.original_type, tensor.element_type)
+        };
+        println!(
+            "{}: {:?} {change}, {} -> {} bytes",
+            tensor.name, tensor.dims, tensor.original_size, tensor.size
+        );
+    }
+    println!();
+    println!(
+        "Tensor data: {:.2} MiB -> {:.2} MiB",
+        mib(dry_run.original_size),
+        mib(dry_run.reduced_size)
+    );
+    println!("File size: {:.2} MiB", mib(dry_run.file_size));
+    println!(
+        "Memory needed to load: {:.2} MiB without mmap, {:.2} MiB with mmap \
+         (excluding the key/value cache of sessions)",
+        mib(dry_run.memory),
+        mib(dry_run.memory_mmap)
+    );
+}
+
+#[derive(serde::Serialize)]
+/// The error of a quantized tensor, as saved by `quantize --report`.
+struct TensorReport {
+    name: String,
+    element_type: String,
+    #[serde(flatten)]
+    error: llm::QuantizationErrorStats,
+}
+
+/// Measures the perplexity of the model at `path` over all of `text`.
+fn model_perplexity<M: llm::KnownModel>(
+    path: &Path,
+    tokenizer_source: llm::TokenizerSource,
+    text: &str,
+) -> eyre::Result<f32> {
+    let model = llm::load::<M>(path, tokenizer_source, Default::default(), |_| {})
+        .wrap_err_with(|| format!("failed to load model from {path:?}"))?;
+    let mut session = model.start_session(Default::default());
+
+    // The perplexity of the last chunk covers all of the chunks before it.
+    let mut perplexity = f32::NAN;
+    session.perplexity(&model, text, |_, chunk_perplexity| {
+        perplexity = chunk_perplexity
+    })?;
+    Ok(perplexity)
+}
+
+fn merge_lora(args: &cli_args::MergeLora) -> eyre::Result<()> {
+    use llm::MergeLoraProgress;
+
+    struct MergeLoraVisitor<'a>(&'a cli_args::MergeLora);
+    impl llm::ModelArchitectureVisitor<eyre::Result<()>> for MergeLoraVisitor<'_> {
+        fn visit<M: llm::KnownModel>(&mut self) -> eyre::Result<()> {
+            let args = self.0;
+
+            let adapters = args.adapters()?;
+            let mut source = BufReader::new(File::open(&args.source)?);
+            let mut destination = BufWriter::new(File::create(&args.destination)?);
+            let tokenizer: llm::Tokenizer = args.tokenizer.to_source()?.retrieve(&args.source)?;
+
+            llm::merge_lora::<M, _, _>(
+                &mut source,
+                &mut destination,
+                tokenizer,
+                &adapters,
+                args.target.map(Into::into),
+                |progress| match progress {
+                    MergeLoraProgress::HyperparametersLoaded => {
+                        log::info!("Loaded hyperparameters")
+                    }
+                    MergeLoraProgress::AdapterLoaded { path, scaling } => {
+                        log::info!("Loaded adapter {path:?} (scaling {scaling})")
+                    }
+                    MergeLoraProgress::TensorPatched { name, n_adapters } => {
+                        log::info!("Patched tensor `{name}` with {n_adapters} adapter(s)")
+                    }
+                    MergeLoraProgress::TensorQuantized {
+                        name,
+                        original_size,
+                        reduced_size,
+                        element_type,
+                    } => log::info!(
+                        "Quantized tensor `{name}` to {element_type} from {original_size} to {reduced_size} bytes"
+                    ),
+                    MergeLoraProgress::Finished { n_patched } => {
+                        log::info!("Finished merging; {n_patched} tensors were patched")
+                    }
+                },
+            )
+            .wrap_err("failed to merge LoRA adapters")
+        }
+    }
+
+    args.architecture
+        .resolve(&args.source)?
+        .visit(&mut MergeLoraVisitor(args))
+}
+
+fn convert(args: &cli_args::Convert) -> eyre::Result<()> {
+    use llm::ConvertProgress;
+
+    let mut destination = BufWriter::new(File::create(&args.destination)?);
+    llm::convert_dynamic(
+        args.model_architecture,
+        &args.source,
+        &mut destination,
+        args.target.into(),
+        |progress| match progress {
+            ConvertProgress::HyperparametersLoaded => log::info!("Loaded hyperparameters"),
+            ConvertProgress::VocabularyLoaded { n_tokens } => {
+                log::info!("Loaded vocabulary ({n_tokens} tokens)")
+            }
+            ConvertProgress::TensorConverting {
+                name,
+                dims,
+                element_type,
+            } => log::info!("Converting tensor `{name}` ({dims:?} {element_type})"),
+            ConvertProgress::TensorSkipped { name } => log::info!("Skipped unused tensor `{name}`"),
+            ConvertProgress::Finished { n_tensors } => {
+                log::info!("Finished conversion of {n_tensors} tensors")
+            }
+        },
+    )
+    .wrap_err("failed to convert model")
+}
+
+fn upgrade(args: &cli_args::Upgrade) -> eyre::Result<()> {
+    use llm::UpgradeProgress;
+
+    struct UpgradeVisitor<'a>(&'a cli_args::Upgrade);
+    impl llm::ModelArchitectureVisitor<eyre::Result<()>> for UpgradeVisitor<'_> {
+        fn visit<M: llm::KnownModel>(&mut self) -> eyre::Result<()> {
+            let args = self.0;
+
+            // Upgrading in place writes the upgraded model next to the original first, so that
+            // the original is untouched if the upgrade fails.
+            let destination = match &args.destination {
+                Some(destination) => destination.clone(),
+                None => {
+                    let mut destination = args.source.clone().into_os_string();
+                    destination.push(".upgrade");
+                    PathBuf::from(destination)
+                }
+            };
+
+            let mut source = BufReader::new(File::open(&args.source)?);
+            let mut writer = BufWriter::new(File::create(&destination)?);
+            let result = llm::upgrade::<M, _, _>(&mut source, &mut writer, |progress| {
+                match progress {
+                    UpgradeProgress::HyperparametersLoaded => log::info!("Loaded hyperparameters"),
+                    UpgradeProgress::TensorCopied { name, size } => {
+                        log::info!("Copied tensor `{name}` ({size} bytes)")
+                    }
+                    UpgradeProgress::Finished {
+                        container_type,
+                        quantization_version,
+                    } => log::info!(
+                        "Upgraded model from {container_type:?} to GGJT v3 (quantization version {quantization_version})"
+                    ),
+                }
+            })
+            .wrap_err("failed to upgrade model")
+            .and_then(|()| writer.flush().wrap_err("failed to write upgraded model"));
+            drop(writer);
+
+            if args.destination.is_none() {
+                match result {
+                    Ok(()) => std::fs::rename(&destination, &args.source)
+                        .wrap_err("failed to replace the model with the upgraded model")?,
+                    Err(_) => {
+                        let _ = std::fs::remove_file(&destination);
+                    }
+                }
+            }
+            result
+        }
+    }
+
+    args.architecture
+        .resolve(&args.source)?
+        .visit(&mut UpgradeVisitor(args))
+}
+
 fn load_prompt_file_with_prompt(
     prompt_file: &cli_args::PromptFile,
     prompt: Option<&str>,
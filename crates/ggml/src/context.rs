@@ -25,6 +25,10 @@ pub struct Context {
     /// The storage for this context. This is stored so that the buffer can be dropped when the context is dropped.
     storage: Option<ContextStorage>,
 
+    /// Buffers holding the data of tensors that do not live in the storage.
+    /// See [Context::allocate_tensor_data].
+    owned_buffers: Vec<Buffer>,
+
     /// Whether the context can offload tensors to the GPU
     pub can_offload: bool,
 }
@@ -68,6 +72,9 @@ pub enum ContextStorage {
     Buffer(Buffer),
     /// Use the provided memory mapped file as memory.
     Mmap(Mmap),
+    /// Use the provided static memory (e.g. a model embedded in the binary) as memory.
+    /// This behaves like [Self::Mmap].
+    Static(&'static [u8]),
     /// Allocate `mem_size` bytes of memory.
     Allocate {
         /// The size, in bytes, of the memory in to allocate.
@@ -83,6 +90,15 @@ impl ContextStorage {
         }
     }
 
+    /// Returns the memory backing the tensors if this is a `Mmap` or `Static` variant.
+    pub fn as_mapped(&self) -> Option<&[u8]> {
+        match self {
+            Self::Mmap(v) => Some(v),
+            Self::Static(v) => Some(v),
+            _ => None,
+        }
+    }
+
     /// Returns the `Buffer` if this is a `Buffer` variant.
     pub fn as_buffer(&self) -> Option<&Buffer> {
         match self {
@@ -96,6 +112,7 @@ impl ContextStorage {
         match self {
             // This is a bit naughty...
             Self::Mmap(mmap) => (mmap.as_ptr().cast_mut() as *mut c_void, mmap.len()),
+            Self::Static(data) => (data.as_ptr().cast_mut() as *mut c_void, data.len()),
             _ => (
                 ggml_sys::ggml_get_mem_buffer(ctx.as_ptr()),
                 ggml_sys::ggml_get_mem_size(ctx.as_ptr()),
@@ -109,6 +126,7 @@ impl PartialEq for ContextStorage {
         match (self, other) {
             (Buffer(l0), Buffer(r0)) => l0 == r0,
             (Mmap(l0), Mmap(r0)) => l0.as_ptr() == r0.as_ptr(),
+            (Static(l0), Static(r0)) => l0.as_ptr() == r0.as_ptr(),
             (Allocate { mem_size: l }, Allocate { mem_size: r }) => l == r,
             _ => false,
         }
@@ -131,6 +149,12 @@ impl Context {
                 // We are mmapping so ggml does not need to allocate any memory for us
                 no_alloc: true,
             },
+            ContextStorage::Static(data) => sys::ggml_init_params {
+                mem_size: data.len(),
+                mem_buffer: std::ptr::null_mut(),
+                // As with mmap, the tensors point into the provided memory
+                no_alloc: true,
+            },
             ContextStorage::Allocate { mem_size } => sys::ggml_init_params {
                 mem_size: *mem_size,
                 // Null here means we want ggml to own this memory.
@@ -144,6 +168,7 @@ impl Context {
         Self {
             inner: ContextInner::new(raw),
             storage: Some(storage),
+            owned_buffers: vec![],
             can_offload: false,
         }
     }
@@ -159,6 +184,11 @@ impl Context {
         Self::new(ContextStorage::Mmap(mmap))
     }
 
+    /// Creates a new [Context] whose tensors point into the specified static memory.
+    pub fn new_with_static(data: &'static [u8]) -> Self {
+        Self::new(ContextStorage::Static(data))
+    }
+
     /// Creates a new [Context] with the specified memory size.
     /// The memory will be allocated by GGML.
     pub fn new_with_allocate(mem_size: usize) -> Self {
@@ -246,6 +276,17 @@ impl Context {
     pub fn storage(&self) -> &ContextStorage {
         self.storage.as_ref().unwrap()
     }
+
+    /// Allocates memory for the data of `tensor`, which will be freed when this [Context] is dropped.
+    ///
+    /// This is for tensors created in a memory-mapped context whose data is not present
+    /// in the mapped file, such as tensors assembled from several files.
+    pub fn allocate_tensor_data(&mut self, tensor: &mut Tensor) {
+        let buffer = Buffer::new(tensor.nbytes());
+        // SAFETY: the buffer is exactly as large as the tensor's data, and lives as long as the context
+        unsafe { tensor.set_data(buffer.data) };
+        self.owned_buffers.push(buffer);
+    }
 }
 // Operations
 impl Context {
@@ -564,6 +605,83 @@ impl Context {
         self.new_tensor_raw(tensor)
     }
 
+    /// In-place; rotates `a`, which holds vectors of `a.get_ne()[0]` elements that have already
+    /// been rotated by [Self::op_rope_inplace], as if all of them had been encoded `shift`
+    /// positions further along. `shift` can be negative to move them back.
+    ///
+    /// Unlike [Self::op_rope_inplace], every vector is rotated by the same amount. This is
+    /// used to move keys in the memory of a model without recomputing them.
+    pub fn op_rope_shift_inplace(
+        &self,
+        a: &Tensor,
+        shift: isize,
+        ndims: usize,
+        mode: i32,
+        overrides: Option<&RoPEOverrides>,
+    ) -> Tensor {
+        // RoPE encodes the vectors of the third dimension at successive positions, so keep all
+        // of them in the first slice of it.
+        let n_columns = a.get_ne()[0] as usize;
+        let mut rows = self.op_reshape_3d(a, n_columns, a.nelements() / n_columns, 1);
+
+        // RoPE only encodes non-negative positions. Rotating a pair of elements back is the
+        // same as negating the second element, rotating it forward, and negating it again.
+        let conjugation = (shift < 0).then(|| {
+            let neox = mode & 2 != 0;
+            let signs: Vec<u8> = (0..n_columns)
+                .map(|i| {
+                    let second = if neox {
+                        i < n_columns / ndims * ndims && i % ndims >= ndims / 2
+                    } else {
+                        i % 2 == 1
+                    };
+                    if second {
+                        -1.0f32
+                    } else {
+                        1.0
+                    }
+                })
+                .flat_map(f32::to_ne_bytes)
+                .collect();
+            let mut conjugation = self.new_tensor_1d(Type::F32, n_columns);
+            unsafe { conjugation.write_data(&signs) };
+            conjugation
+        });
+        if let Some(conjugation) = &conjugation {
+            rows = self.op_mul(&rows, conjugation);
+        }
+
+        let n_past = i32::try_from(shift.unsigned_abs()).unwrap();
+        let tensor = unsafe {
+            if let Some(custom_args) = overrides {
+                sys::ggml_rope_custom_inplace(
+                    self.as_ptr(),
+                    rows.ptr.as_ptr(),
+                    n_past,
+                    usize_to_i32(ndims),
+                    mode,
+                    0,
+                    custom_args.frequency_base as f32,
+                    custom_args.frequency_scale,
+                )
+            } else {
+                sys::ggml_rope_inplace(
+                    self.as_ptr(),
+                    rows.ptr.as_ptr(),
+                    n_past,
+                    usize_to_i32(ndims),
+                    mode,
+                    0,
+                )
+            }
+        };
+        let tensor = self.new_tensor_raw(tensor);
+        match &conjugation {
+            Some(conjugation) => self.op_mul(&tensor, conjugation),
+            None => tensor,
+        }
+    }
+
     /// Attention with LInear BIases (Ref: <https://arxiv.org/pdf/2108.12409.pdf>)
     pub fn op_alibi(&self, a: &Tensor, n_past: usize, n_head: usize, bias_max: f32) -> Tensor {
         let tensor = unsafe {
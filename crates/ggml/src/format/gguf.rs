@@ -0,0 +1,461 @@
+//! Types and helpers for the GGUF container format.
+//!
+//! GGUF files store the model's hyperparameters and vocabulary as typed key-value
+//! metadata, followed by a table describing each tensor and the (aligned) tensor data.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{BufRead, Write},
+};
+
+use crate::{
+    format::LoadError,
+    util::{
+        read_bytes, read_bytes_with_len, read_f32, read_i32, read_u32, read_u64, write_u32,
+        write_u64,
+    },
+};
+
+/// The metadata key that stores the alignment of the tensor data.
+pub const KEY_ALIGNMENT: &str = "general.alignment";
+/// The metadata key that stores the architecture of the model.
+pub const KEY_ARCHITECTURE: &str = "general.architecture";
+/// The alignment of the tensor data if [KEY_ALIGNMENT] is not present.
+pub const DEFAULT_ALIGNMENT: u64 = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The type of a [MetadataValue].
+pub enum MetadataValueType {
+    /// An unsigned 8-bit integer.
+    UInt8,
+    /// A signed 8-bit integer.
+    Int8,
+    /// An unsigned 16-bit integer.
+    UInt16,
+    /// A signed 16-bit integer.
+    Int16,
+    /// An unsigned 32-bit integer.
+    UInt32,
+    /// A signed 32-bit integer.
+    Int32,
+    /// A 32-bit float.
+    Float32,
+    /// A boolean.
+    Bool,
+    /// A UTF-8 string.
+    String,
+    /// An array of values of a single type.
+    Array,
+    /// An unsigned 64-bit integer.
+    UInt64,
+    /// A signed 64-bit integer.
+    Int64,
+    /// A 64-bit float.
+    Float64,
+}
+impl TryFrom<u32> for MetadataValueType {
+    type Error = ();
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Self::UInt8,
+            1 => Self::Int8,
+            2 => Self::UInt16,
+            3 => Self::Int16,
+            4 => Self::UInt32,
+            5 => Self::Int32,
+            6 => Self::Float32,
+            7 => Self::Bool,
+            8 => Self::String,
+            9 => Self::Array,
+            10 => Self::UInt64,
+            11 => Self::Int64,
+            12 => Self::Float64,
+            _ => return Err(()),
+        })
+    }
+}
+impl From<MetadataValueType> for u32 {
+    fn from(value: MetadataValueType) -> Self {
+        match value {
+            MetadataValueType::UInt8 => 0,
+            MetadataValueType::Int8 => 1,
+            MetadataValueType::UInt16 => 2,
+            MetadataValueType::Int16 => 3,
+            MetadataValueType::UInt32 => 4,
+            MetadataValueType::Int32 => 5,
+            MetadataValueType::Float32 => 6,
+            MetadataValueType::Bool => 7,
+            MetadataValueType::String => 8,
+            MetadataValueType::Array => 9,
+            MetadataValueType::UInt64 => 10,
+            MetadataValueType::Int64 => 11,
+            MetadataValueType::Float64 => 12,
+        }
+    }
+}
+impl fmt::Display for MetadataValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                MetadataValueType::UInt8 => "u8",
+                MetadataValueType::Int8 => "i8",
+                MetadataValueType::UInt16 => "u16",
+                MetadataValueType::Int16 => "i16",
+                MetadataValueType::UInt32 => "u32",
+                MetadataValueType::Int32 => "i32",
+                MetadataValueType::Float32 => "f32",
+                MetadataValueType::Bool => "bool",
+                MetadataValueType::String => "string",
+                MetadataValueType::Array => "array",
+                MetadataValueType::UInt64 => "u64",
+                MetadataValueType::Int64 => "i64",
+                MetadataValueType::Float64 => "f64",
+            }
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A value stored in the metadata of a GGUF file.
+pub enum MetadataValue {
+    /// An unsigned 8-bit integer.
+    UInt8(u8),
+    /// A signed 8-bit integer.
+    Int8(i8),
+    /// An unsigned 16-bit integer.
+    UInt16(u16),
+    /// A signed 16-bit integer.
+    Int16(i16),
+    /// An unsigned 32-bit integer.
+    UInt32(u32),
+    /// A signed 32-bit integer.
+    Int32(i32),
+    /// A 32-bit float.
+    Float32(f32),
+    /// A boolean.
+    Bool(bool),
+    /// A UTF-8 string.
+    String(String),
+    /// An array of values that all have the type `element_type`.
+    Array {
+        /// The type of the elements of the array.
+        element_type: MetadataValueType,
+        /// The elements of the array.
+        values: Vec<MetadataValue>,
+    },
+    /// An unsigned 64-bit integer.
+    UInt64(u64),
+    /// A signed 64-bit integer.
+    Int64(i64),
+    /// A 64-bit float.
+    Float64(f64),
+}
+impl MetadataValue {
+    /// The type of this value.
+    pub fn value_type(&self) -> MetadataValueType {
+        match self {
+            MetadataValue::UInt8(_) => MetadataValueType::UInt8,
+            MetadataValue::Int8(_) => MetadataValueType::Int8,
+            MetadataValue::UInt16(_) => MetadataValueType::UInt16,
+            MetadataValue::Int16(_) => MetadataValueType::Int16,
+            MetadataValue::UInt32(_) => MetadataValueType::UInt32,
+            MetadataValue::Int32(_) => MetadataValueType::Int32,
+            MetadataValue::Float32(_) => MetadataValueType::Float32,
+            MetadataValue::Bool(_) => MetadataValueType::Bool,
+            MetadataValue::String(_) => MetadataValueType::String,
+            MetadataValue::Array { .. } => MetadataValueType::Array,
+            MetadataValue::UInt64(_) => MetadataValueType::UInt64,
+            MetadataValue::Int64(_) => MetadataValueType::Int64,
+            MetadataValue::Float64(_) => MetadataValueType::Float64,
+        }
+    }
+
+    /// Returns this value as a `usize` if it is a non-negative integer that fits.
+    ///
+    /// Writers disagree on the integer type used for counts, so all integer types are accepted.
+    pub fn as_countable(&self) -> Option<usize> {
+        match *self {
+            MetadataValue::UInt8(v) => Some(v.into()),
+            MetadataValue::Int8(v) => v.try_into().ok(),
+            MetadataValue::UInt16(v) => Some(v.into()),
+            MetadataValue::Int16(v) => v.try_into().ok(),
+            MetadataValue::UInt32(v) => v.try_into().ok(),
+            MetadataValue::Int32(v) => v.try_into().ok(),
+            MetadataValue::UInt64(v) => v.try_into().ok(),
+            MetadataValue::Int64(v) => v.try_into().ok(),
+            _ => None,
+        }
+    }
+
+    /// Returns this value as a `f32` if it is a float.
+    pub fn as_f32(&self) -> Option<f32> {
+        match *self {
+            MetadataValue::Float32(v) => Some(v),
+            MetadataValue::Float64(v) => Some(v as f32),
+            _ => None,
+        }
+    }
+
+    /// Returns this value as a `bool` if it is a boolean.
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            MetadataValue::Bool(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// Returns this value as a `&str` if it is a string.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            MetadataValue::String(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// Returns the elements of this value if it is an array.
+    pub fn as_array(&self) -> Option<&[MetadataValue]> {
+        match self {
+            MetadataValue::Array { values, .. } => Some(values),
+            _ => None,
+        }
+    }
+
+    fn read<E: std::error::Error>(
+        reader: &mut dyn BufRead,
+        value_type: MetadataValueType,
+        version: u32,
+    ) -> Result<Self, LoadError<E>> {
+        Ok(match value_type {
+            MetadataValueType::UInt8 => MetadataValue::UInt8(read_bytes::<1>(reader)?[0]),
+            MetadataValueType::Int8 => {
+                MetadataValue::Int8(i8::from_le_bytes(read_bytes::<1>(reader)?))
+            }
+            MetadataValueType::UInt16 => {
+                MetadataValue::UInt16(u16::from_le_bytes(read_bytes::<2>(reader)?))
+            }
+            MetadataValueType::Int16 => {
+                MetadataValue::Int16(i16::from_le_bytes(read_bytes::<2>(reader)?))
+            }
+            MetadataValueType::UInt32 => MetadataValue::UInt32(read_u32(reader)?),
+            MetadataValueType::Int32 => MetadataValue::Int32(read_i32(reader)?),
+            MetadataValueType::Float32 => MetadataValue::Float32(read_f32(reader)?),
+            MetadataValueType::Bool => MetadataValue::Bool(read_bytes::<1>(reader)?[0] != 0),
+            MetadataValueType::String => MetadataValue::String(read_string(reader, version)?),
+            MetadataValueType::Array => {
+                let element_type = read_u32(reader)?;
+                let element_type = MetadataValueType::try_from(element_type)
+                    .map_err(|_| LoadError::InvalidMetadataValueType(element_type))?;
+                let len = read_length(reader, version)?;
+                let values = (0..len)
+                    .map(|_| MetadataValue::read(reader, element_type, version))
+                    .collect::<Result<_, _>>()?;
+                MetadataValue::Array {
+                    element_type,
+                    values,
+                }
+            }
+            MetadataValueType::UInt64 => MetadataValue::UInt64(read_u64(reader)?),
+            MetadataValueType::Int64 => {
+                MetadataValue::Int64(i64::from_le_bytes(read_bytes::<8>(reader)?))
+            }
+            MetadataValueType::Float64 => {
+                MetadataValue::Float64(f64::from_le_bytes(read_bytes::<8>(reader)?))
+            }
+        })
+    }
+
+    /// Write this value (without its type) to a writer, using the layout of GGUF `version`.
+    pub fn write(&self, writer: &mut dyn Write, version: u32) -> std::io::Result<()> {
+        match self {
+            MetadataValue::UInt8(v) => writer.write_all(&v.to_le_bytes()),
+            MetadataValue::Int8(v) => writer.write_all(&v.to_le_bytes()),
+            MetadataValue::UInt16(v) => writer.write_all(&v.to_le_bytes()),
+            MetadataValue::Int16(v) => writer.write_all(&v.to_le_bytes()),
+            MetadataValue::UInt32(v) => write_u32(writer, *v),
+            MetadataValue::Int32(v) => writer.write_all(&v.to_le_bytes()),
+            MetadataValue::Float32(v) => writer.write_all(&v.to_le_bytes()),
+            MetadataValue::Bool(v) => writer.write_all(&[u8::from(*v)]),
+            MetadataValue::String(v) => write_string(writer, v, version),
+            MetadataValue::Array {
+                element_type,
+                values,
+            } => {
+                write_u32(writer, (*element_type).into())?;
+                write_length(writer, values.len(), version)?;
+                for value in values {
+                    value.write(writer, version)?;
+                }
+                Ok(())
+            }
+            MetadataValue::UInt64(v) => write_u64(writer, *v),
+            MetadataValue::Int64(v) => writer.write_all(&v.to_le_bytes()),
+            MetadataValue::Float64(v) => writer.write_all(&v.to_le_bytes()),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+/// Errors that can occur while retrieving a value from [Metadata].
+pub enum MetadataError {
+    #[error("missing metadata key {key:?}")]
+    /// The key was not present in the metadata.
+    MissingKey {
+        /// The key that was missing.
+        key: String,
+    },
+    #[error("metadata key {key:?} has unexpected type {actual_type}")]
+    /// The key was present, but its value did not have the expected type.
+    InvalidType {
+        /// The key that was looked up.
+        key: String,
+        /// The type of the value that was found.
+        actual_type: MetadataValueType,
+    },
+    #[error("metadata key {key:?} has a value that is not supported")]
+    /// The key was present and had the expected type, but its value is not supported.
+    UnsupportedValue {
+        /// The key that was looked up.
+        key: String,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// The key-value metadata of a GGUF file.
+pub struct Metadata(BTreeMap<String, MetadataValue>);
+impl Metadata {
+    /// Get the value for `key`, if present.
+    pub fn get(&self, key: &str) -> Option<&MetadataValue> {
+        self.0.get(key)
+    }
+
+    /// Get the value for `key`, converted with `getter` (e.g. [MetadataValue::as_countable]).
+    ///
+    /// Returns an error if the key is missing or `getter` does not accept its value.
+    pub fn get_with_type<'a, T>(
+        &'a self,
+        key: &str,
+        getter: impl Fn(&'a MetadataValue) -> Option<T>,
+    ) -> Result<T, MetadataError> {
+        self.get_optional(key, getter)?
+            .ok_or_else(|| MetadataError::MissingKey {
+                key: key.to_owned(),
+            })
+    }
+
+    /// Like [Metadata::get_with_type], but returns `None` if the key is missing.
+    pub fn get_optional<'a, T>(
+        &'a self,
+        key: &str,
+        getter: impl Fn(&'a MetadataValue) -> Option<T>,
+    ) -> Result<Option<T>, MetadataError> {
+        let Some(value) = self.0.get(key) else {
+            return Ok(None);
+        };
+        getter(value)
+            .map(Some)
+            .ok_or_else(|| MetadataError::InvalidType {
+                key: key.to_owned(),
+                actual_type: value.value_type(),
+            })
+    }
+
+    /// Insert a value, returning the previous value for `key` if there was one.
+    pub fn insert(
+        &mut self,
+        key: impl Into<String>,
+        value: MetadataValue,
+    ) -> Option<MetadataValue> {
+        self.0.insert(key.into(), value)
+    }
+
+    /// Iterate over the key-value pairs, ordered by key.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &MetadataValue)> {
+        self.0.iter()
+    }
+
+    /// The number of key-value pairs.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether there are no key-value pairs.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The alignment of the tensor data described by this metadata.
+    pub fn alignment(&self) -> Result<u64, MetadataError> {
+        Ok(self
+            .get_optional(KEY_ALIGNMENT, MetadataValue::as_countable)?
+            .map_or(DEFAULT_ALIGNMENT, |a| a as u64))
+    }
+
+    pub(crate) fn read<E: std::error::Error>(
+        reader: &mut dyn BufRead,
+        n_kv: usize,
+        version: u32,
+    ) -> Result<Self, LoadError<E>> {
+        let mut metadata = Metadata::default();
+        for _ in 0..n_kv {
+            let key = read_string(reader, version)?;
+            let value_type = read_u32(reader)?;
+            let value_type = MetadataValueType::try_from(value_type)
+                .map_err(|_| LoadError::InvalidMetadataValueType(value_type))?;
+            let value = MetadataValue::read(reader, value_type, version)?;
+            metadata.insert(key, value);
+        }
+        Ok(metadata)
+    }
+
+    /// Write the key-value pairs to a writer, using the layout of GGUF `version`.
+    pub fn write(&self, writer: &mut dyn Write, version: u32) -> std::io::Result<()> {
+        for (key, value) in self.iter() {
+            write_string(writer, key, version)?;
+            write_u32(writer, value.value_type().into())?;
+            value.write(writer, version)?;
+        }
+        Ok(())
+    }
+}
+
+/// Read a length or count. GGUF version 1 stores these as 32-bit integers; later versions use 64 bits.
+pub(crate) fn read_length<E: std::error::Error>(
+    reader: &mut dyn BufRead,
+    version: u32,
+) -> Result<usize, LoadError<E>> {
+    Ok(if version == 1 {
+        read_u32(reader)?.try_into()?
+    } else {
+        read_u64(reader)?.try_into()?
+    })
+}
+
+/// Write a length or count using the layout of GGUF `version`.
+pub fn write_length(writer: &mut dyn Write, length: usize, version: u32) -> std::io::Result<()> {
+    if version == 1 {
+        let length = u32::try_from(length)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        write_u32(writer, length)
+    } else {
+        write_u64(writer, length as u64)
+    }
+}
+
+/// Read a length-prefixed UTF-8 string.
+pub(crate) fn read_string<E: std::error::Error>(
+    reader: &mut dyn BufRead,
+    version: u32,
+) -> Result<String, LoadError<E>> {
+    let len = read_length(reader, version)?;
+    Ok(String::from_utf8(read_bytes_with_len(reader, len)?)?)
+}
+
+/// Write a length-prefixed UTF-8 string using the layout of GGUF `version`.
+pub fn write_string(writer: &mut dyn Write, value: &str, version: u32) -> std::io::Result<()> {
+    write_length(writer, value.len(), version)?;
+    writer.write_all(value.as_bytes())
+}
@@ -3,6 +3,9 @@
 //! To handle a specific model, implement [LoadHandler] for your model
 //! and call [load] with an instance of your handler. It is up to you
 //! to process the data from the handler and construct your model.
+//!
+//! Both the legacy GGML-family formats and GGUF are supported; for GGUF files,
+//! the hyperparameters are read from the [Metadata] instead of a raw header.
 
 use std::{
     error::Error,
@@ -11,7 +14,8 @@ use std::{
 };
 
 use crate::{
-    util::{has_data_left, read_bytes_with_len, read_f32, read_i32, read_u32},
+    format::gguf::{self, Metadata, MetadataError, MetadataValue},
+    util::{has_data_left, read_bytes_with_len, read_f32, read_i32, read_u32, read_u64},
     ContainerType, ElementType,
 };
 
@@ -63,9 +67,23 @@ pub enum LoadError<E: Error> {
         /// The format type that was encountered.
         ftype: u32,
     },
+    #[error("tensor {tensor_name} has {n_dims} dimensions, but at most 2 are supported")]
+    /// One of the tensors encountered had more dimensions than are supported.
+    UnsupportedTensorDimensions {
+        /// The name of the tensor.
+        tensor_name: String,
+        /// The number of dimensions of the tensor.
+        n_dims: usize,
+    },
     #[error("invariant broken: {0}")]
     /// An invariant was broken.
     InvariantBroken(String),
+    #[error("invalid metadata value type {0}")]
+    /// A GGUF metadata value had a type that is not known to this version of `ggml`.
+    InvalidMetadataValueType(u32),
+    #[error("invalid metadata: {0}")]
+    /// A GGUF metadata value required by the loader was missing or had the wrong type.
+    InvalidMetadata(#[from] MetadataError),
 }
 
 #[derive(Debug, Clone)]
@@ -110,7 +128,7 @@ impl TensorLoadInfo {
     ///
     /// Do not use this if loading with `mmap`.
     pub fn read_data<R: BufRead + Seek>(&self, reader: &mut R) -> std::io::Result<Vec<u8>> {
-        let n_bytes = self.n_elements * crate::type_size(self.element_type);
+        let n_bytes = self.calc_size();
         let mut data = vec![0; n_bytes];
         reader.seek(SeekFrom::Start(self.start_offset))?;
         reader.read_exact(&mut data)?;
@@ -152,11 +170,18 @@ pub trait LoadHandler<E: Error> {
         &mut self,
         reader: &mut dyn BufRead,
     ) -> Result<PartialHyperparameters, E>;
+    /// Called when the model's hyperparameters need to be read from the metadata of a GGUF file.
+    ///
+    /// This is called instead of [LoadHandler::read_hyperparameters] for GGUF files.
+    fn read_metadata(&mut self, metadata: &Metadata) -> Result<PartialHyperparameters, E>;
     /// Called when a new [crate::Tensor] is read for the model.
     fn tensor_buffer(&mut self, info: TensorLoadInfo) -> Result<(), E>;
 }
 
 /// Load a GGML model from a `reader` with the [LoadHandler], which will be called when certain events occur.
+///
+/// Tensors can have at most two dimensions. GGUF files allow up to four, so those with 3-D or
+/// 4-D tensors fail to load with [LoadError::UnsupportedTensorDimensions].
 pub fn load<E: Error, R: BufRead + Seek>(
     reader: &mut R,
     handler: &mut impl LoadHandler<E>,
@@ -167,8 +192,9 @@ pub fn load<E: Error, R: BufRead + Seek>(
     match container_type {
         ContainerType::Ggml
         | ContainerType::Ggmf(1)
-        | ContainerType::Ggjt(1 | 2 | 3)
-        | ContainerType::Ggla(1) => {}
+        | ContainerType::Ggjt(1..=3)
+        | ContainerType::Ggla(1)
+        | ContainerType::Gguf(1..=3) => {}
         _ => return Err(LoadError::InvalidFormatVersion(container_type)),
     }
 
@@ -176,6 +202,10 @@ pub fn load<E: Error, R: BufRead + Seek>(
         .container_type(container_type)
         .map_err(LoadError::ImplementationError)?;
 
+    if let ContainerType::Gguf(version) = container_type {
+        return load_gguf(reader, handler, version);
+    }
+
     // Load hyper params
     let hparams = handler
         .read_hyperparameters(reader)
@@ -192,6 +222,7 @@ pub fn load<E: Error, R: BufRead + Seek>(
                 // Legacy model, set empty score
                 0.
             }
+            ContainerType::Gguf(_) => unreachable!("GGUF files are loaded by load_gguf"),
         };
         handler
             .vocabulary_token(i, token, token_score)
@@ -204,7 +235,113 @@ pub fn load<E: Error, R: BufRead + Seek>(
         ContainerType::Ggjt(_version) | ContainerType::Ggla(_version) => {
             load_weights(reader, handler, true)
         }
+        ContainerType::Gguf(_) => unreachable!("GGUF files are loaded by load_gguf"),
+    }
+}
+
+/// Loads the remainder of a GGUF file, after its magic and version have been read.
+fn load_gguf<E: Error, R: BufRead + Seek>(
+    reader: &mut R,
+    handler: &mut impl LoadHandler<E>,
+    version: u32,
+) -> Result<(), LoadError<E>> {
+    let n_tensors = gguf::read_length(reader, version)?;
+    let n_kv = gguf::read_length(reader, version)?;
+
+    // Load metadata, from which the hyperparameters are read
+    let metadata = Metadata::read(reader, n_kv, version)?;
+    let hparams = handler
+        .read_metadata(&metadata)
+        .map_err(LoadError::ImplementationError)?;
+    let n_vocab = hparams.n_vocab;
+
+    // Load vocabulary, if the file embeds one
+    if let Some(tokens) = metadata.get_optional("tokenizer.ggml.tokens", MetadataValue::as_array)? {
+        if tokens.len() < n_vocab {
+            return Err(LoadError::InvariantBroken(format!(
+                "{} tokens in metadata >= {n_vocab}",
+                tokens.len()
+            )));
+        }
+        let scores = metadata.get_optional("tokenizer.ggml.scores", MetadataValue::as_array)?;
+
+        for (i, token) in tokens.iter().take(n_vocab).enumerate() {
+            let token = token.as_str().ok_or_else(|| MetadataError::InvalidType {
+                key: "tokenizer.ggml.tokens".to_owned(),
+                actual_type: token.value_type(),
+            })?;
+            let token_score = scores
+                .and_then(|s| s.get(i))
+                .and_then(MetadataValue::as_f32)
+                .unwrap_or_default();
+            handler
+                .vocabulary_token(i, token.as_bytes().to_vec(), token_score)
+                .map_err(LoadError::ImplementationError)?;
+        }
+    }
+
+    // Load tensor infos; their offsets are relative to the start of the tensor data
+    let mut infos = Vec::new();
+    for _ in 0..n_tensors {
+        let name = gguf::read_string(reader, version)?;
+        let n_dims: usize = read_u32(reader)?.try_into()?;
+
+        let mut n_elements: usize = 1;
+        let mut dims = [1usize, 1];
+        if n_dims > dims.len() {
+            return Err(LoadError::UnsupportedTensorDimensions {
+                tensor_name: name,
+                n_dims,
+            });
+        }
+
+        #[allow(clippy::needless_range_loop)]
+        for i in 0..n_dims {
+            let dim: usize = if version == 1 {
+                read_u32(reader)?.try_into()?
+            } else {
+                read_u64(reader)?.try_into()?
+            };
+            dims[i] = dim;
+            n_elements *= dim;
+        }
+
+        let ftype = read_u32(reader)?;
+        let ftype =
+            crate::Type::try_from(ftype).map_err(|_| LoadError::UnsupportedElementType {
+                tensor_name: name.clone(),
+                ftype,
+            })?;
+        let offset = read_u64(reader)?;
+
+        infos.push(TensorLoadInfo {
+            name,
+            dims,
+            n_dims,
+            n_elements,
+            element_type: ftype,
+            start_offset: offset,
+        });
+    }
+
+    let alignment = metadata.alignment()?;
+    if alignment == 0 {
+        return Err(LoadError::InvariantBroken(format!(
+            "{} > 0",
+            gguf::KEY_ALIGNMENT
+        )));
     }
+    let offset_curr = reader.stream_position()?;
+    let data_start = offset_curr + (alignment - offset_curr % alignment) % alignment;
+
+    for mut info in infos {
+        info.start_offset += data_start;
+        handler
+            .tensor_buffer(info)
+            .map_err(LoadError::ImplementationError)?;
+    }
+
+    Ok(())
 }
 
 /// # Params
@@ -246,10 +383,8 @@ fn load_weights<E: Error, R: BufRead + Seek>(
 
         // sanity check
         match ftype {
-            ElementType::Q4_0 | ElementType::Q4_1 => {
-                if dims[0] % 64 != 0 {
-                    return Err(LoadError::InvariantBroken(format!("{dims:?}[0] % 64 == 0")));
-                }
+            ElementType::Q4_0 | ElementType::Q4_1 if dims[0] % 64 != 0 => {
+                return Err(LoadError::InvariantBroken(format!("{dims:?}[0] % 64 == 0")));
             }
             _ => {}
         }
@@ -1,5 +1,6 @@
 //! Loading and saving of [GGML](https://github.com/ggerganov/ggml) files.
 
+pub mod gguf;
 mod loader;
 mod saver;
 
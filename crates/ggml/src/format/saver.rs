@@ -1,4 +1,5 @@
-//! The saver module implements a way to save a model to disk in the GGJT format.
+//! The saver module implements a way to save a model to disk in the GGML-family
+//! formats and in GGUF.
 //!
 //! To implement a saver for your model, implement [SaveHandler] for your model
 //! and provide data as appropriate, then call [save] with an instance of
@@ -6,10 +7,16 @@
 
 use std::{
     error::Error,
-    io::{Seek, Write},
+    io::{Seek, SeekFrom, Write},
 };
 
-use crate::{util, ContainerType, ElementType};
+use crate::{
+    format::{
+        gguf::{self, Metadata, MetadataValue, MetadataValueType},
+        TensorLoadInfo,
+    },
+    util, ContainerType, ElementType,
+};
 
 #[derive(Debug, thiserror::Error)]
 /// Errors that can occur while writing a model.
@@ -30,6 +37,17 @@ pub enum SaveError<E: Error> {
     /// support vocabulary scoring, despite the model having a scored vocabulary.
     #[error("container type does not support vocabulary scoring")]
     VocabularyScoringNotSupported,
+    /// An attempt was made to save a tensor with a type that the container type
+    /// cannot store. See [SaveContainerType::supports_element_type].
+    #[error("tensor {tensor_name} has type {element_type}, which {container_type:?} cannot store")]
+    UnsupportedElementType {
+        /// The name of the tensor.
+        tensor_name: String,
+        /// The type of the tensor.
+        element_type: ElementType,
+        /// The container type.
+        container_type: SaveContainerType,
+    },
 }
 
 /// A handler for saving a GGML model.
@@ -37,6 +55,15 @@ pub trait SaveHandler<E: Error> {
     /// Called when the hyperparameters must be written.
     fn write_hyperparameters(&mut self, writer: &mut dyn Write) -> Result<(), E>;
 
+    /// Called when the hyperparameters must be added to the metadata of a GGUF file.
+    ///
+    /// This is called instead of [SaveHandler::write_hyperparameters] for GGUF files. The
+    /// vocabulary is added to the metadata by [save]. The default implementation adds nothing.
+    fn write_metadata(&mut self, metadata: &mut Metadata) -> Result<(), E> {
+        let _ = metadata;
+        Ok(())
+    }
+
     /// Called when information for a tensor is to be written.
     fn tensor_data(&mut self, tensor_name: &str) -> Result<TensorSaveInfo, E>;
 }
@@ -59,6 +86,9 @@ pub struct TensorSaveInfo {
     pub data: Vec<u8>,
 }
 
+/// The version of GGUF that models are saved as.
+const GGUF_VERSION: u32 = 3;
+
 /// The container of the model to save.
 ///
 /// This is separate from [ContainerType] to ensure that the user
@@ -67,22 +97,72 @@ pub struct TensorSaveInfo {
 pub enum SaveContainerType {
     /// The GGML container.
     Ggml,
-    /// The GGJT container.
+    /// The GGMF container, version 1.
+    Ggmf,
+    /// The GGJT container, version 1.
+    GgjtV1,
+    /// The GGJT container, version 2.
+    GgjtV2,
+    /// The GGJT container, version 3.
     GgjtV3,
+    /// The GGUF container, version 3.
+    Gguf,
+}
+impl SaveContainerType {
+    /// Whether tensors of `element_type` can be saved in this container.
+    ///
+    /// The layout of some quantized types has changed since the older containers were
+    /// introduced, and the readers of those containers expect the layout of their time.
+    /// GGMF and GGJT version 1 can only store unquantized tensors, and GGJT version 2
+    /// can also store the Q5 types. GGML is not versioned, and can store any type.
+    pub fn supports_element_type(self, element_type: ElementType) -> bool {
+        match self {
+            SaveContainerType::Ggmf | SaveContainerType::GgjtV1 => {
+                matches!(element_type, ElementType::F32 | ElementType::F16)
+            }
+            SaveContainerType::GgjtV2 => matches!(
+                element_type,
+                ElementType::F32 | ElementType::F16 | ElementType::Q5_0 | ElementType::Q5_1
+            ),
+            SaveContainerType::Ggml | SaveContainerType::GgjtV3 | SaveContainerType::Gguf => true,
+        }
+    }
+
+    /// Whether this container stores a score for each token of the vocabulary.
+    fn supports_vocabulary_scoring(self) -> bool {
+        self != SaveContainerType::Ggml
+    }
+
+    /// Whether this container aligns the data of each tensor to 32 bytes.
+    fn aligns_tensor_data(self) -> bool {
+        matches!(
+            self,
+            SaveContainerType::GgjtV1 | SaveContainerType::GgjtV2 | SaveContainerType::GgjtV3
+        )
+    }
 }
 impl From<SaveContainerType> for ContainerType {
     fn from(value: SaveContainerType) -> Self {
         match value {
             SaveContainerType::Ggml => ContainerType::Ggml,
+            SaveContainerType::Ggmf => ContainerType::Ggmf(1),
+            SaveContainerType::GgjtV1 => ContainerType::Ggjt(1),
+            SaveContainerType::GgjtV2 => ContainerType::Ggjt(2),
             SaveContainerType::GgjtV3 => ContainerType::Ggjt(3),
+            SaveContainerType::Gguf => ContainerType::Gguf(GGUF_VERSION),
         }
     }
 }
 
 /// Saves a model to the given writer.
 ///
-/// Only GGML and GGJT version 2 are supported. If using GGML,
-/// the vocabulary *must* have scores of 0.0.
+/// If using GGML, the vocabulary *must* have scores of 0.0. The older containers can only
+/// store some types of tensors; see [SaveContainerType::supports_element_type].
+///
+/// For GGUF, the hyperparameters are written with [SaveHandler::write_metadata], and the
+/// vocabulary is stored in the metadata, so its tokens must be valid UTF-8. As the table of
+/// tensors precedes their data in GGUF files, the data of every tensor is kept in memory
+/// until all of them have been provided by the handler.
 pub fn save<E: Error, W: Write + Seek>(
     writer: &mut W,
     handler: &mut dyn SaveHandler<E>,
@@ -90,15 +170,71 @@ pub fn save<E: Error, W: Write + Seek>(
     vocabulary: &[(Vec<u8>, f32)],
     tensor_names: &[String],
 ) -> Result<(), SaveError<E>> {
-    // Write header and hyperparameters
-    ContainerType::from(container_type).write(writer)?;
+    write_model(
+        writer,
+        handler,
+        container_type,
+        vocabulary,
+        tensor_names.len(),
+        |handler, i| {
+            let name = &tensor_names[i];
+            let tensor = handler
+                .tensor_data(name)
+                .map_err(SaveError::ImplementationError)?;
+            TensorEntry::new(container_type, name, tensor)
+        },
+    )
+}
+
+/// Calculates the size (in bytes) of the file that [save] would write, without the data of
+/// the tensors.
+///
+/// The handler is only asked for the hyperparameters. The tensors are described by `tensors`
+/// instead, in the order they would be saved in, so that the size of a model can be known
+/// before its tensors are produced.
+pub fn saved_size<E: Error>(
+    handler: &mut dyn SaveHandler<E>,
+    container_type: SaveContainerType,
+    vocabulary: &[(Vec<u8>, f32)],
+    tensors: &[TensorLoadInfo],
+) -> Result<u64, SaveError<E>> {
+    let mut writer = ByteCounter::default();
+    write_model(
+        &mut writer,
+        handler,
+        container_type,
+        vocabulary,
+        tensors.len(),
+        |_, i| TensorEntry::layout(container_type, &tensors[i]),
+    )?;
+    Ok(writer.position)
+}
 
-    if container_type == SaveContainerType::Ggml
+/// Writes a model, getting the `i`th of its `n_tensors` tensors from `tensor`. See [save].
+fn write_model<'a, E: Error, W: Write + Seek>(
+    writer: &mut W,
+    handler: &mut dyn SaveHandler<E>,
+    container_type: SaveContainerType,
+    vocabulary: &[(Vec<u8>, f32)],
+    n_tensors: usize,
+    mut tensor: impl FnMut(&mut dyn SaveHandler<E>, usize) -> Result<TensorEntry<'a>, SaveError<E>>,
+) -> Result<(), SaveError<E>> {
+    if !container_type.supports_vocabulary_scoring()
         && vocabulary.iter().any(|(_, score)| *score != 0.0)
     {
         return Err(SaveError::VocabularyScoringNotSupported);
     }
 
+    if container_type == SaveContainerType::Gguf {
+        let tensors = (0..n_tensors)
+            .map(|i| tensor(handler, i))
+            .collect::<Result<Vec<_>, _>>()?;
+        return write_gguf(writer, handler, vocabulary, &tensors);
+    }
+
+    // Write header and hyperparameters
+    ContainerType::from(container_type).write(writer)?;
+
     handler
         .write_hyperparameters(writer)
         .map_err(SaveError::ImplementationError)?;
@@ -108,53 +244,264 @@ pub fn save<E: Error, W: Write + Seek>(
         util::write_u32(writer, token.len().try_into()?)?;
         writer.write_all(token)?;
 
-        if container_type != SaveContainerType::Ggml {
+        if container_type.supports_vocabulary_scoring() {
             util::write_f32(writer, *score)?;
         }
     }
 
     // Write tensors
-    for name in tensor_names {
+    for i in 0..n_tensors {
+        let tensor = tensor(handler, i)?;
+
+        // Write tensor header
+        util::write_i32(writer, tensor.n_dims.try_into()?)?;
+        util::write_i32(writer, tensor.name.len().try_into()?)?;
+        util::write_u32(writer, tensor.element_type.into())?;
+        for &dim in &tensor.dims[0..tensor.n_dims] {
+            util::write_i32(writer, dim.try_into()?)?;
+        }
+
+        // Write tensor name
+        writer.write_all(tensor.name.as_bytes())?;
+
+        // Align to nearest 32 bytes
+        if container_type.aligns_tensor_data() {
+            write_padding(writer, 32)?;
+        }
+
+        // Write tensor data
+        tensor.write_data(writer)?;
+    }
+
+    Ok(())
+}
+
+/// Writes a model as GGUF. See [save].
+fn write_gguf<E: Error, W: Write + Seek>(
+    writer: &mut W,
+    handler: &mut dyn SaveHandler<E>,
+    vocabulary: &[(Vec<u8>, f32)],
+    tensors: &[TensorEntry],
+) -> Result<(), SaveError<E>> {
+    let version = GGUF_VERSION;
+
+    // Build the metadata
+    let mut metadata = Metadata::default();
+    handler
+        .write_metadata(&mut metadata)
+        .map_err(SaveError::ImplementationError)?;
+    if !vocabulary.is_empty() {
+        let tokens = vocabulary
+            .iter()
+            .enumerate()
+            .map(|(i, (token, _))| {
+                String::from_utf8(token.clone())
+                    .map(MetadataValue::String)
+                    .map_err(|_| SaveError::InvariantBroken(format!("token {i} is valid UTF-8")))
+            })
+            .collect::<Result<_, _>>()?;
+        metadata.insert(
+            "tokenizer.ggml.tokens",
+            MetadataValue::Array {
+                element_type: MetadataValueType::String,
+                values: tokens,
+            },
+        );
+        metadata.insert(
+            "tokenizer.ggml.scores",
+            MetadataValue::Array {
+                element_type: MetadataValueType::Float32,
+                values: vocabulary
+                    .iter()
+                    .map(|(_, score)| MetadataValue::Float32(*score))
+                    .collect(),
+            },
+        );
+    }
+    let alignment = metadata
+        .alignment()
+        .map_err(|err| SaveError::InvariantBroken(err.to_string()))?;
+    if alignment == 0 {
+        return Err(SaveError::InvariantBroken(format!(
+            "{} > 0",
+            gguf::KEY_ALIGNMENT
+        )));
+    }
+
+    // Write header and metadata
+    ContainerType::Gguf(version).write(writer)?;
+    gguf::write_length(writer, tensors.len(), version)?;
+    gguf::write_length(writer, metadata.len(), version)?;
+    metadata.write(writer, version)?;
+
+    // Write the table of tensors; their offsets are relative to the start of the tensor data
+    let mut offset = 0;
+    for tensor in tensors {
+        gguf::write_string(writer, tensor.name, version)?;
+        util::write_u32(writer, tensor.n_dims.try_into()?)?;
+        for &dim in &tensor.dims[0..tensor.n_dims] {
+            util::write_u64(writer, dim.try_into()?)?;
+        }
+        util::write_u32(writer, tensor.element_type.into())?;
+        util::write_u64(writer, offset)?;
+
+        let end = offset + u64::try_from(tensor.size)?;
+        offset = end + (alignment - end % alignment) % alignment;
+    }
+
+    // Write tensor data
+    for tensor in tensors {
+        write_padding(writer, alignment)?;
+        tensor.write_data(writer)?;
+    }
+
+    Ok(())
+}
+
+/// A tensor to write, checked to be saveable in the container.
+struct TensorEntry<'a> {
+    name: &'a str,
+    n_dims: usize,
+    dims: [usize; 2],
+    element_type: ElementType,
+    /// The size (in bytes) of the data of the tensor.
+    size: usize,
+    /// The data of the tensor, or `None` if only the size of the file is being calculated.
+    data: Option<Vec<u8>>,
+}
+impl<'a> TensorEntry<'a> {
+    /// Checks that the tensor `name` provided by a handler can be saved.
+    fn new<E: Error>(
+        container_type: SaveContainerType,
+        name: &'a str,
+        tensor: TensorSaveInfo,
+    ) -> Result<Self, SaveError<E>> {
         let TensorSaveInfo {
             n_dims,
             dims,
             element_type,
             data,
-        } = handler
-            .tensor_data(name)
-            .map_err(SaveError::ImplementationError)?;
+        } = tensor;
+        let entry = Self::checked(container_type, name, n_dims, dims, element_type, Some(data))?;
+        if let Some(data) = &entry.data {
+            if data.len() != entry.size {
+                let n_elements = dims[0..n_dims].iter().product::<usize>();
+                return Err(SaveError::InvariantBroken(format!(
+                    "{name}: data is {} bytes, expected {} bytes for {n_elements} {element_type} elements",
+                    data.len(),
+                    entry.size
+                )));
+            }
+        }
+        Ok(entry)
+    }
+
+    /// Checks that a tensor laid out as `tensor` can be saved, without its data.
+    fn layout<E: Error>(
+        container_type: SaveContainerType,
+        tensor: &'a TensorLoadInfo,
+    ) -> Result<Self, SaveError<E>> {
+        Self::checked(
+            container_type,
+            &tensor.name,
+            tensor.n_dims,
+            tensor.dims,
+            tensor.element_type,
+            None,
+        )
+    }
+
+    fn checked<E: Error>(
+        container_type: SaveContainerType,
+        name: &'a str,
+        n_dims: usize,
+        dims: [usize; 2],
+        element_type: ElementType,
+        data: Option<Vec<u8>>,
+    ) -> Result<Self, SaveError<E>> {
+        if !container_type.supports_element_type(element_type) {
+            return Err(SaveError::UnsupportedElementType {
+                tensor_name: name.to_owned(),
+                element_type,
+                container_type,
+            });
+        }
 
         match element_type {
-            ElementType::Q4_0 | ElementType::Q4_1 => {
-                if dims[0] % 64 != 0 {
-                    return Err(SaveError::InvariantBroken(format!("{dims:?}[0] % 64 == 0")));
-                }
+            ElementType::Q4_0 | ElementType::Q4_1 if dims[0] % 64 != 0 => {
+                return Err(SaveError::InvariantBroken(format!("{dims:?}[0] % 64 == 0")));
             }
             _ => {}
         }
 
-        // Write tensor header
-        util::write_i32(writer, n_dims.try_into()?)?;
-        util::write_i32(writer, name.len().try_into()?)?;
-        util::write_u32(writer, element_type.into())?;
-        for &dim in &dims[0..n_dims] {
-            util::write_i32(writer, dim.try_into()?)?;
+        // Quantized types are stored in blocks that may not span rows
+        let block_size = crate::blck_size(element_type);
+        if dims[0] % block_size != 0 {
+            return Err(SaveError::InvariantBroken(format!(
+                "{name}: {dims:?}[0] % {block_size} == 0 for {element_type}"
+            )));
         }
+        let n_elements = dims[0..n_dims].iter().product::<usize>();
 
-        // Write tensor name
-        writer.write_all(name.as_bytes())?;
+        Ok(Self {
+            name,
+            n_dims,
+            dims,
+            element_type,
+            size: super::data_size(element_type, n_elements),
+            data,
+        })
+    }
 
-        // Align to nearest 32 bytes
-        if container_type != SaveContainerType::Ggml {
-            let offset_curr = writer.stream_position()?;
-            let offset_aligned = (offset_curr + 31) & !31;
-            let padding = usize::try_from(offset_aligned - offset_curr)?;
-            writer.write_all(&vec![0; padding])?;
+    /// Writes the data of the tensor, or skips over it if there is none.
+    fn write_data<E: Error, W: Write + Seek>(&self, writer: &mut W) -> Result<(), SaveError<E>> {
+        match &self.data {
+            Some(data) => writer.write_all(data)?,
+            None => {
+                writer.seek(SeekFrom::Current(self.size.try_into()?))?;
+            }
         }
-
-        // Write tensor data
-        writer.write_all(&data)?;
+        Ok(())
     }
+}
 
+/// Writes zeroes until the position of the writer is a multiple of `alignment`.
+fn write_padding<E: Error, W: Write + Seek>(
+    writer: &mut W,
+    alignment: u64,
+) -> Result<(), SaveError<E>> {
+    let offset_curr = writer.stream_position()?;
+    let padding = usize::try_from((alignment - offset_curr % alignment) % alignment)?;
+    writer.write_all(&vec![0; padding])?;
     Ok(())
 }
+
+/// A writer that discards what is written to it, and only keeps track of its position.
+#[derive(Default)]
+struct ByteCounter {
+    position: u64,
+}
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+impl Seek for ByteCounter {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => {
+                u64::try_from(i128::from(self.position) + i128::from(offset)).ok()
+            }
+            SeekFrom::End(_) => None,
+        };
+        self.position = position
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid seek"))?;
+        Ok(self.position)
+    }
+}
@@ -42,6 +42,9 @@ pub enum ContainerType {
     Ggjt(u32),
     /// LoRA adapter format.
     Ggla(u32),
+    /// [mmap](https://en.wikipedia.org/wiki/Mmap)-able format that stores typed key-value
+    /// metadata (see [format::gguf]) alongside the tensors.
+    Gguf(u32),
 }
 impl ContainerType {
     /// Does this container type support mmap?
@@ -51,6 +54,7 @@ impl ContainerType {
             ContainerType::Ggmf(_) => false,
             ContainerType::Ggla(_) => false,
             ContainerType::Ggjt(_) => true,
+            ContainerType::Gguf(_) => true,
         }
     }
 
@@ -74,6 +78,10 @@ impl ContainerType {
                 let version = util::read_u32(reader)?;
                 ContainerType::Ggla(version)
             }
+            crate::FILE_MAGIC_GGUF => {
+                let version = util::read_u32(reader)?;
+                ContainerType::Gguf(version)
+            }
             magic => {
                 return Err(crate::format::LoadError::InvalidMagic(format::FormatMagic(
                     magic,
@@ -102,6 +110,10 @@ impl ContainerType {
                 util::write_u32(writer, FILE_MAGIC_GGLA)?;
                 util::write_u32(writer, *version)?;
             }
+            ContainerType::Gguf(version) => {
+                util::write_u32(writer, FILE_MAGIC_GGUF)?;
+                util::write_u32(writer, *version)?;
+            }
         }
         Ok(())
     }
@@ -115,12 +127,17 @@ pub const FILE_MAGIC_GGMF: u32 = 0x67676d66;
 pub const FILE_MAGIC_GGJT: u32 = 0x67676a74;
 /// Magic constant for `ggla` files (LoRA adapter).
 pub const FILE_MAGIC_GGLA: u32 = 0x67676C61;
+/// Magic constant for `gguf` files (versioned, with key-value metadata).
+pub const FILE_MAGIC_GGUF: u32 = 0x46554747;
 
 /// The current quantization version.
 pub const QNT_VERSION: u32 = sys::GGML_QNT_VERSION;
 /// The factor by which to divide `ftype` to determine the current quantization version.
 pub const QNT_VERSION_FACTOR: u32 = sys::GGML_QNT_VERSION_FACTOR;
 
+/// The number of elements in a block of the k-quant types.
+pub const QK_K: usize = sys::QK_K as usize;
+
 /// The size of a `ggml` object.
 pub const OBJECT_SIZE: usize = sys::GGML_OBJECT_SIZE;
 
@@ -256,6 +273,32 @@ impl std::fmt::Display for Type {
         }
     }
 }
+impl std::str::FromStr for Type {
+    type Err = ();
+
+    /// Parses a type from its name, as written by [Display](std::fmt::Display).
+    /// The name is case-insensitive.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "q4_0" => Ok(Type::Q4_0),
+            "q4_1" => Ok(Type::Q4_1),
+            "q5_0" => Ok(Type::Q5_0),
+            "q5_1" => Ok(Type::Q5_1),
+            "q8_0" => Ok(Type::Q8_0),
+            "q8_1" => Ok(Type::Q8_1),
+            "q2_k" => Ok(Type::Q2_K),
+            "q3_k" => Ok(Type::Q3_K),
+            "q4_k" => Ok(Type::Q4_K),
+            "q5_k" => Ok(Type::Q5_K),
+            "q6_k" => Ok(Type::Q6_K),
+            "i32" => Ok(Type::I32),
+            "f16" => Ok(Type::F16),
+            "f32" => Ok(Type::F32),
+            "i8" => Ok(Type::I8),
+            _ => Err(()),
+        }
+    }
+}
 impl Type {
     /// Returns whether this type is quantized.
     pub fn is_quantized(&self) -> bool {
@@ -307,6 +350,11 @@ impl Buffer {
     pub fn size(&self) -> usize {
         self.layout.size()
     }
+
+    /// Returns a pointer to the start of the buffer.
+    pub fn data(&self) -> *mut c_void {
+        self.data
+    }
 }
 
 impl Drop for Buffer {
@@ -467,6 +515,51 @@ pub fn quantize_q8_0(src: &[f32], n_elements: usize, n_elements_0: usize) -> Qua
     quantize_impl(src, n_elements, n_elements_0, sys::ggml_quantize_q8_0)
 }
 
+/// Quantizes `src` into `dst` using `q2_K` quantization.
+///
+/// You must ensure that `src.len() == n_elements`, and `n_elements_0`
+/// is the first dimension of `src`, which must be a multiple of [QK_K].
+pub fn quantize_q2_k(src: &[f32], n_elements: usize, n_elements_0: usize) -> QuantizationResult {
+    assert_eq!(n_elements_0 % QK_K, 0);
+    quantize_impl(src, n_elements, n_elements_0, sys::ggml_quantize_q2_K)
+}
+
+/// Quantizes `src` into `dst` using `q3_K` quantization.
+///
+/// You must ensure that `src.len() == n_elements`, and `n_elements_0`
+/// is the first dimension of `src`, which must be a multiple of [QK_K].
+pub fn quantize_q3_k(src: &[f32], n_elements: usize, n_elements_0: usize) -> QuantizationResult {
+    assert_eq!(n_elements_0 % QK_K, 0);
+    quantize_impl(src, n_elements, n_elements_0, sys::ggml_quantize_q3_K)
+}
+
+/// Quantizes `src` into `dst` using `q4_K` quantization.
+///
+/// You must ensure that `src.len() == n_elements`, and `n_elements_0`
+/// is the first dimension of `src`, which must be a multiple of [QK_K].
+pub fn quantize_q4_k(src: &[f32], n_elements: usize, n_elements_0: usize) -> QuantizationResult {
+    assert_eq!(n_elements_0 % QK_K, 0);
+    quantize_impl(src, n_elements, n_elements_0, sys::ggml_quantize_q4_K)
+}
+
+/// Quantizes `src` into `dst` using `q5_K` quantization.
+///
+/// You must ensure that `src.len() == n_elements`, and `n_elements_0`
+/// is the first dimension of `src`, which must be a multiple of [QK_K].
+pub fn quantize_q5_k(src: &[f32], n_elements: usize, n_elements_0: usize) -> QuantizationResult {
+    assert_eq!(n_elements_0 % QK_K, 0);
+    quantize_impl(src, n_elements, n_elements_0, sys::ggml_quantize_q5_K)
+}
+
+/// Quantizes `src` into `dst` using `q6_K` quantization.
+///
+/// You must ensure that `src.len() == n_elements`, and `n_elements_0`
+/// is the first dimension of `src`, which must be a multiple of [QK_K].
+pub fn quantize_q6_k(src: &[f32], n_elements: usize, n_elements_0: usize) -> QuantizationResult {
+    assert_eq!(n_elements_0 % QK_K, 0);
+    quantize_impl(src, n_elements, n_elements_0, sys::ggml_quantize_q6_K)
+}
+
 fn quantize_impl(
     src: &[f32],
     n_elements: usize,
@@ -493,6 +586,64 @@ fn quantize_impl(
     QuantizationResult { output, history }
 }
 
+/// Converts `src`, the data of `n_elements` elements of type `element_type`, to F32.
+///
+/// Every type can be converted, including the quantized types. You must ensure that
+/// `n_elements` is a multiple of the block size of `element_type`, and that `src` is
+/// the size of `n_elements` elements.
+pub fn dequantize(element_type: Type, src: &[u8], n_elements: usize) -> Vec<f32> {
+    assert_eq!(n_elements % blck_size(element_type), 0);
+    assert_eq!(
+        src.len(),
+        format::data_size(element_type, n_elements),
+        "the data does not match the number of elements"
+    );
+
+    match element_type {
+        Type::F32 => src
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect(),
+        Type::I32 => src
+            .chunks_exact(4)
+            .map(|chunk| i32::from_le_bytes(chunk.try_into().unwrap()) as f32)
+            .collect(),
+        Type::I8 => src.iter().map(|&x| x as i8 as f32).collect(),
+        // `ggml` only uses Q8_1 for intermediate results, and cannot convert it back.
+        // Its blocks are an F32 scale, an F32 sum, and the 8-bit values.
+        Type::Q8_1 => src
+            .chunks_exact(type_size(element_type))
+            .flat_map(|block| {
+                let d = f32::from_le_bytes(block[..4].try_into().unwrap());
+                block[8..].iter().map(move |&q| q as i8 as f32 * d)
+            })
+            .collect(),
+        _ => {
+            init_tables();
+
+            let to_float = unsafe { sys::ggml_internal_get_type_traits(element_type.into()) }
+                .to_float
+                .expect("every other type can be converted to F32");
+            let mut output = vec![0.0; n_elements];
+            unsafe {
+                to_float(
+                    src.as_ptr() as *const c_void,
+                    output.as_mut_ptr(),
+                    n_elements.try_into().unwrap(),
+                )
+            };
+            output
+        }
+    }
+}
+
+/// Initializes the lookup tables that `ggml` may use to convert F16 values, which
+/// happens when the first context is created.
+fn init_tables() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| drop(Context::new_with_allocate(0)));
+}
+
 /// Returns true if the current system has BLAS support.
 pub fn cpu_has_blas() -> bool {
     unsafe { sys::ggml_cpu_has_blas() != 0 }
@@ -45,6 +45,57 @@ fn will_fail_on_scored_ggml_save() {
     );
 }
 
+#[test]
+fn will_fail_on_k_quant_save_with_partial_blocks() {
+    let element_type = crate::Type::Q4_K;
+    let dims = [128, 2];
+    let model = Model {
+        tensors: BTreeMap::from([(
+            "tensor_0".to_string(),
+            format::TensorSaveInfo {
+                n_dims: 2,
+                dims,
+                element_type,
+                data: vec![0; format::data_size(element_type, dims[0] * dims[1])],
+            },
+        )]),
+        ..Default::default()
+    };
+
+    let mut buffer = Vec::new();
+    let result = format::save(
+        &mut std::io::Cursor::new(&mut buffer),
+        &mut MockSaveHandler { model: &model },
+        format::SaveContainerType::GgjtV3,
+        &model.tokenizer,
+        &model.tensors.keys().cloned().collect::<Vec<String>>(),
+    );
+    assert!(matches!(result, Err(format::SaveError::InvariantBroken(_))));
+}
+
+#[test]
+fn can_dequantize() {
+    let values: Vec<i8> = (-16..16).collect();
+
+    // A Q8_0 block is an F16 scale (0.5) followed by the 8-bit values.
+    let mut q8_0 = 0x3800u16.to_le_bytes().to_vec();
+    q8_0.extend(values.iter().map(|&q| q as u8));
+    // A Q8_1 block is an F32 scale and an F32 sum, followed by the 8-bit values.
+    let mut q8_1 = [0.5f32.to_le_bytes(), (-8.0f32).to_le_bytes()].concat();
+    q8_1.extend(values.iter().map(|&q| q as u8));
+
+    let expected: Vec<f32> = values.iter().map(|&q| q as f32 * 0.5).collect();
+    assert_eq!(crate::dequantize(crate::Type::Q8_0, &q8_0, 32), expected);
+    assert_eq!(crate::dequantize(crate::Type::Q8_1, &q8_1, 32), expected);
+
+    let f32_data: Vec<u8> = expected.iter().flat_map(|x| x.to_le_bytes()).collect();
+    assert_eq!(crate::dequantize(crate::Type::F32, &f32_data, 32), expected);
+    assert_eq!(
+        crate::dequantize(crate::Type::I8, &[0xff, 2], 2),
+        vec![-1.0, 2.0]
+    );
+}
+
 #[test]
 fn can_roundtrip_loader_and_saver_ggjt_v3() {
     let tokenizer = vec![
@@ -57,17 +108,223 @@ fn can_roundtrip_loader_and_saver_ggjt_v3() {
     roundtrip_test(format::SaveContainerType::GgjtV3, tokenizer).unwrap();
 }
 
-fn roundtrip_test(
-    save_container_type: format::SaveContainerType,
-    tokenizer: Vec<(Vec<u8>, f32)>,
-) -> anyhow::Result<()> {
+#[test]
+fn can_roundtrip_loader_and_saver_legacy_containers() {
+    let tokenizer = vec![
+        ("blazingly".as_bytes().to_vec(), 0.1),
+        ("fast".as_bytes().to_vec(), 0.2),
+        ("memory".as_bytes().to_vec(), 0.3),
+        ("efficient".as_bytes().to_vec(), 0.4),
+    ];
+
+    for container_type in [
+        format::SaveContainerType::Ggmf,
+        format::SaveContainerType::GgjtV1,
+        format::SaveContainerType::GgjtV2,
+    ] {
+        roundtrip_test(container_type, tokenizer.clone()).unwrap();
+    }
+}
+
+#[test]
+fn can_roundtrip_loader_and_saver_gguf() {
+    let tokenizer = vec![
+        ("blazingly".as_bytes().to_vec(), 0.1),
+        ("fast".as_bytes().to_vec(), 0.2),
+        ("memory".as_bytes().to_vec(), 0.3),
+        ("efficient".as_bytes().to_vec(), 0.4),
+    ];
+
+    roundtrip_test(format::SaveContainerType::Gguf, tokenizer).unwrap();
+}
+
+#[test]
+fn will_fail_on_legacy_save_of_changed_quantization_layouts() {
+    let save = |container_type, element_type| {
+        let dims = [64, 2];
+        let model = Model {
+            tensors: BTreeMap::from([(
+                "tensor_0".to_string(),
+                format::TensorSaveInfo {
+                    n_dims: 2,
+                    dims,
+                    element_type,
+                    data: vec![0; format::data_size(element_type, dims[0] * dims[1])],
+                },
+            )]),
+            ..Default::default()
+        };
+        format::save(
+            &mut std::io::Cursor::new(Vec::new()),
+            &mut MockSaveHandler { model: &model },
+            container_type,
+            &model.tokenizer,
+            &model.tensors.keys().cloned().collect::<Vec<String>>(),
+        )
+    };
+
+    assert!(save(format::SaveContainerType::GgjtV2, crate::Type::Q5_1).is_ok());
+    assert!(save(format::SaveContainerType::GgjtV3, crate::Type::Q8_0).is_ok());
+    for (container_type, element_type) in [
+        (format::SaveContainerType::Ggmf, crate::Type::Q5_1),
+        (format::SaveContainerType::GgjtV1, crate::Type::Q8_0),
+        (format::SaveContainerType::GgjtV2, crate::Type::Q8_0),
+        (format::SaveContainerType::GgjtV2, crate::Type::Q4_K),
+    ] {
+        assert!(matches!(
+            save(container_type, element_type),
+            Err(format::SaveError::UnsupportedElementType { .. })
+        ));
+    }
+}
+
+#[test]
+fn can_load_gguf() {
+    let tokenizer = vec![
+        ("blazingly".as_bytes().to_vec(), 0.1),
+        ("fast".as_bytes().to_vec(), 0.2),
+        ("memory".as_bytes().to_vec(), 0.3),
+        ("efficient".as_bytes().to_vec(), 0.4),
+    ];
+    let model = random_model(tokenizer);
+
+    // Write the model by hand, with another version and alignment than the saver uses.
+    let version = 2;
+    let alignment = 64;
+    let mut metadata = format::gguf::Metadata::default();
+    let hp = &model.hyperparameters;
+    metadata.insert(
+        "test.some_hyperparameter",
+        format::gguf::MetadataValue::UInt32(hp.some_hyperparameter),
+    );
+    metadata.insert(
+        "test.some_other_hyperparameter",
+        format::gguf::MetadataValue::UInt32(hp.some_other_hyperparameter),
+    );
+    metadata.insert(
+        format::gguf::KEY_ALIGNMENT,
+        format::gguf::MetadataValue::UInt32(alignment),
+    );
+    metadata.insert(
+        "tokenizer.ggml.tokens",
+        format::gguf::MetadataValue::Array {
+            element_type: format::gguf::MetadataValueType::String,
+            values: model
+                .tokenizer
+                .iter()
+                .map(|(t, _)| {
+                    format::gguf::MetadataValue::String(String::from_utf8(t.clone()).unwrap())
+                })
+                .collect(),
+        },
+    );
+    metadata.insert(
+        "tokenizer.ggml.scores",
+        format::gguf::MetadataValue::Array {
+            element_type: format::gguf::MetadataValueType::Float32,
+            values: model
+                .tokenizer
+                .iter()
+                .map(|(_, s)| format::gguf::MetadataValue::Float32(*s))
+                .collect(),
+        },
+    );
+
+    let mut buffer = Vec::new();
+    ContainerType::Gguf(version).write(&mut buffer).unwrap();
+    format::gguf::write_length(&mut buffer, model.tensors.len(), version).unwrap();
+    format::gguf::write_length(&mut buffer, metadata.len(), version).unwrap();
+    metadata.write(&mut buffer, version).unwrap();
+
+    let mut offset = 0;
+    for (name, tensor) in &model.tensors {
+        format::gguf::write_string(&mut buffer, name, version).unwrap();
+        util::write_u32(&mut buffer, tensor.n_dims.try_into().unwrap()).unwrap();
+        for dim in &tensor.dims[0..tensor.n_dims] {
+            util::write_u64(&mut buffer, *dim as u64).unwrap();
+        }
+        util::write_u32(&mut buffer, tensor.element_type.into()).unwrap();
+        util::write_u64(&mut buffer, offset).unwrap();
+        offset += padded_len(tensor.data.len(), alignment as usize) as u64;
+    }
+    for tensor in model.tensors.values() {
+        buffer.resize(padded_len(buffer.len(), alignment as usize), 0);
+        buffer.extend_from_slice(&tensor.data);
+    }
+
+    let mut cursor = std::io::Cursor::new(&buffer);
+    let mut load_handler = MockLoadHandler {
+        data: &buffer,
+        loaded_model: Model::default(),
+        expected_container_type: ContainerType::Gguf(version),
+    };
+    format::load(&mut cursor, &mut load_handler).unwrap();
+    assert_eq!(load_handler.loaded_model, model);
+}
+
+#[test]
+fn will_fail_on_gguf_tensors_with_more_than_two_dimensions() {
+    let version = 2;
+    let mut metadata = format::gguf::Metadata::default();
+    metadata.insert(
+        "test.some_hyperparameter",
+        format::gguf::MetadataValue::UInt32(1),
+    );
+    metadata.insert(
+        "test.some_other_hyperparameter",
+        format::gguf::MetadataValue::UInt32(2),
+    );
+    metadata.insert(
+        "tokenizer.ggml.tokens",
+        format::gguf::MetadataValue::Array {
+            element_type: format::gguf::MetadataValueType::String,
+            values: vec![],
+        },
+    );
+
+    let mut buffer = Vec::new();
+    ContainerType::Gguf(version).write(&mut buffer).unwrap();
+    format::gguf::write_length(&mut buffer, 1, version).unwrap();
+    format::gguf::write_length(&mut buffer, metadata.len(), version).unwrap();
+    metadata.write(&mut buffer, version).unwrap();
+    format::gguf::write_string(&mut buffer, "cube", version).unwrap();
+    util::write_u32(&mut buffer, 3).unwrap();
+    for dim in [2u64, 2, 2] {
+        util::write_u64(&mut buffer, dim).unwrap();
+    }
+    util::write_u32(&mut buffer, ElementType::F32.into()).unwrap();
+    util::write_u64(&mut buffer, 0).unwrap();
+    buffer.resize(padded_len(buffer.len(), 32) + 8 * 4, 0);
+
+    let mut cursor = std::io::Cursor::new(&buffer);
+    let mut load_handler = MockLoadHandler {
+        data: &buffer,
+        loaded_model: Model::default(),
+        expected_container_type: ContainerType::Gguf(version),
+    };
+    let result = format::load(&mut cursor, &mut load_handler);
+    assert!(
+        matches!(
+            result,
+            Err(format::LoadError::UnsupportedTensorDimensions { ref tensor_name, n_dims: 3 })
+                if tensor_name == "cube"
+        ),
+        "{result:?}"
+    );
+}
+
+fn padded_len(len: usize, alignment: usize) -> usize {
+    len + (alignment - len % alignment) % alignment
+}
+
+fn random_model(tokenizer: Vec<(Vec<u8>, f32)>) -> Model {
     let mut rng = rand::thread_rng();
     let element_type = crate::Type::F16;
-    let model = Model {
+    Model {
         hyperparameters: Hyperparameters {
             some_hyperparameter: random(),
             some_other_hyperparameter: random(),
-            tokenizer_size: tokenizer.len().try_into()?,
+            tokenizer_size: tokenizer.len().try_into().unwrap(),
         },
         tokenizer,
         tensors: (0..10)
@@ -94,7 +351,14 @@ fn roundtrip_test(
                 )
             })
             .collect(),
-    };
+    }
+}
+
+fn roundtrip_test(
+    save_container_type: format::SaveContainerType,
+    tokenizer: Vec<(Vec<u8>, f32)>,
+) -> anyhow::Result<()> {
+    let model = random_model(tokenizer);
 
     // Save the model.
     let mut buffer = Vec::new();
@@ -108,6 +372,27 @@ fn roundtrip_test(
         &model.tensors.keys().cloned().collect::<Vec<String>>(),
     )?;
 
+    // The size of the file can be calculated from the layout of the tensors alone.
+    let layouts = model
+        .tensors
+        .iter()
+        .map(|(name, tensor)| format::TensorLoadInfo {
+            name: name.clone(),
+            n_dims: tensor.n_dims,
+            dims: tensor.dims,
+            n_elements: tensor.dims[0..tensor.n_dims].iter().product(),
+            element_type: tensor.element_type,
+            start_offset: 0,
+        })
+        .collect::<Vec<_>>();
+    let saved_size = format::saved_size(
+        &mut save_handler,
+        save_container_type,
+        &model.tokenizer,
+        &layouts,
+    )?;
+    assert_eq!(saved_size, u64::try_from(buffer.len())?);
+
     // Load the model and confirm that it is the same as the original.
     let mut cursor = std::io::Cursor::new(&buffer);
     let mut load_handler = MockLoadHandler {
@@ -160,6 +445,19 @@ impl format::SaveHandler<DummyError> for MockSaveHandler<'_> {
         Ok(())
     }
 
+    fn write_metadata(&mut self, metadata: &mut format::gguf::Metadata) -> Result<(), DummyError> {
+        let hp = &self.model.hyperparameters;
+        metadata.insert(
+            "test.some_hyperparameter",
+            format::gguf::MetadataValue::UInt32(hp.some_hyperparameter),
+        );
+        metadata.insert(
+            "test.some_other_hyperparameter",
+            format::gguf::MetadataValue::UInt32(hp.some_other_hyperparameter),
+        );
+        Ok(())
+    }
+
     fn tensor_data(&mut self, tensor_name: &str) -> Result<format::TensorSaveInfo, DummyError> {
         self.model
             .tensors
@@ -201,6 +499,32 @@ impl format::LoadHandler<DummyError> for MockLoadHandler<'_> {
         })
     }
 
+    fn read_metadata(
+        &mut self,
+        metadata: &format::gguf::Metadata,
+    ) -> Result<format::PartialHyperparameters, DummyError> {
+        let get = |key| {
+            metadata
+                .get_with_type(key, format::gguf::MetadataValue::as_countable)
+                .unwrap()
+                .try_into()
+                .unwrap()
+        };
+        let n_vocab = metadata
+            .get_with_type(
+                "tokenizer.ggml.tokens",
+                format::gguf::MetadataValue::as_array,
+            )
+            .unwrap()
+            .len();
+        self.loaded_model.hyperparameters = Hyperparameters {
+            some_hyperparameter: get("test.some_hyperparameter"),
+            some_other_hyperparameter: get("test.some_other_hyperparameter"),
+            tokenizer_size: n_vocab.try_into().unwrap(),
+        };
+        Ok(format::PartialHyperparameters { n_vocab })
+    }
+
     fn tensor_buffer(&mut self, info: format::TensorLoadInfo) -> Result<(), DummyError> {
         let data = format::TensorSaveInfo {
             n_dims: info.n_dims,
@@ -214,3 +538,50 @@ impl format::LoadHandler<DummyError> for MockLoadHandler<'_> {
         Ok(())
     }
 }
+
+#[test]
+fn can_shift_rotary_positions() {
+    let values: Vec<u8> = (0..12)
+        .flat_map(|i| (i as f32 * 0.7).sin().to_ne_bytes())
+        .collect();
+    let overrides = RoPEOverrides {
+        frequency_scale: 0.5,
+        frequency_base: 5_000,
+    };
+    // Six columns with four rotated dimensions leave two of them untouched in the NeoX mode.
+    for (mode, overrides) in [(0, None), (2, None), (0, Some(&overrides))] {
+        let context = Context::new_with_allocate(1024 * 1024);
+        let rope_at = |position| {
+            let mut vectors = context.new_tensor_3d(Type::F32, 6, 2, 1);
+            unsafe { vectors.write_data(&values) };
+            context.op_rope_inplace(&vectors, position, 4, mode, overrides)
+        };
+
+        let (at_2, at_5) = (rope_at(2), rope_at(5));
+        let forward = context.op_rope_shift_inplace(&rope_at(2), 3, 4, mode, overrides);
+        let back = context.op_rope_shift_inplace(&rope_at(5), -3, 4, mode, overrides);
+        let mut graph = ComputationGraph::new();
+        for tensor in [&at_2, &at_5, &forward, &back] {
+            graph.build_forward_expand(tensor);
+        }
+        GraphExecutionPlan::new(&mut graph, 1).execute(&context);
+
+        let read = |tensor: &Tensor| {
+            let mut data = vec![0; tensor.nbytes()];
+            unsafe { tensor.read_data(0, &mut data) };
+            data.chunks_exact(4)
+                .map(|b| f32::from_ne_bytes(b.try_into().unwrap()))
+                .collect::<Vec<_>>()
+        };
+        for (actual, expected) in [(&forward, &at_5), (&back, &at_2)] {
+            let (actual, expected) = (read(actual), read(expected));
+            assert!(
+                actual
+                    .iter()
+                    .zip(&expected)
+                    .all(|(a, e)| (a - e).abs() < 1e-5),
+                "mode {mode}: {actual:?} != {expected:?}"
+            );
+        }
+    }
+}
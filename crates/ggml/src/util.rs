@@ -1,6 +1,6 @@
 //! Utilities for reading and writing.
 
-use std::io::{BufRead, Write};
+use std::io::{BufRead, Read, Write};
 
 /// Read a fixed-size array of bytes from a reader.
 pub fn read_bytes<const N: usize>(reader: &mut dyn BufRead) -> Result<[u8; N], std::io::Error> {
@@ -19,6 +19,11 @@ pub fn read_u32(reader: &mut dyn BufRead) -> Result<u32, std::io::Error> {
     Ok(u32::from_le_bytes(read_bytes::<4>(reader)?))
 }
 
+/// Read a `u64` from a reader.
+pub fn read_u64(reader: &mut dyn BufRead) -> Result<u64, std::io::Error> {
+    Ok(u64::from_le_bytes(read_bytes::<8>(reader)?))
+}
+
 /// Read a `f32` from a reader.
 pub fn read_f32(reader: &mut dyn BufRead) -> Result<f32, std::io::Error> {
     Ok(f32::from_le_bytes(read_bytes::<4>(reader)?))
@@ -42,8 +47,13 @@ pub fn read_bytes_with_len(
     reader: &mut dyn BufRead,
     len: usize,
 ) -> Result<Vec<u8>, std::io::Error> {
-    let mut bytes = vec![0u8; len];
-    reader.read_exact(&mut bytes)?;
+    // Don't trust `len` for the allocation: a corrupt or misinterpreted file
+    // could claim an arbitrarily large length.
+    let mut bytes = Vec::new();
+    (&mut *reader).take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
     Ok(bytes)
 }
 
@@ -57,6 +67,11 @@ pub fn write_u32(writer: &mut dyn Write, value: u32) -> Result<(), std::io::Erro
     writer.write_all(&value.to_le_bytes())
 }
 
+/// Write a `u64` from a writer.
+pub fn write_u64(writer: &mut dyn Write, value: u64) -> Result<(), std::io::Error> {
+    writer.write_all(&value.to_le_bytes())
+}
+
 /// Write a `f32` from a writer.
 pub fn write_f32(writer: &mut dyn Write, value: f32) -> Result<(), std::io::Error> {
     writer.write_all(&value.to_le_bytes())
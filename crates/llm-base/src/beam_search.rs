@@ -0,0 +1,523 @@
+//! Implements beam search decoding, which keeps the most probable continuations of a prompt in
+//! sessions forked from one another.
+
+use std::convert::Infallible;
+
+use crate::{
+    BatchedSequence, InferenceError, InferenceFeedback, InferenceSession, Model, OutputRequest,
+    Prompt, TokenId,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// The parameters for [beam_search].
+pub struct BeamSearchParameters {
+    /// The number of hypotheses that are extended at each step.
+    pub beam_width: usize,
+    /// The power of its length that the log-probability of a hypothesis is divided by to score
+    /// it. Values above 0 favour longer hypotheses, and values below 0 favour shorter ones.
+    pub length_penalty: f32,
+    /// Whether to stop as soon as `beam_width` hypotheses have ended. Otherwise, the search
+    /// continues until none of the remaining beams can score better than the ended hypotheses,
+    /// even at the longest length they can reach.
+    pub early_stopping: bool,
+    /// The number of hypotheses to return.
+    pub n_best: usize,
+    /// The maximum number of tokens to generate. If this is `None`, the search continues until
+    /// it is done or the context window is full.
+    pub maximum_token_count: Option<usize>,
+}
+impl Default for BeamSearchParameters {
+    fn default() -> Self {
+        Self {
+            beam_width: 4,
+            length_penalty: 1.0,
+            early_stopping: false,
+            n_best: 1,
+            maximum_token_count: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A continuation of the prompt found by [beam_search].
+pub struct BeamSearchHypothesis {
+    /// The generated tokens, excluding the end-of-text token.
+    pub tokens: Vec<TokenId>,
+    /// The decoded bytes of the generated tokens.
+    pub text: Vec<u8>,
+    /// The sum of the log-probabilities of the generated tokens, including the end-of-text
+    /// token if the hypothesis ended.
+    pub log_probability: f32,
+    /// The log-probability of the hypothesis, normalized by its length as configured by
+    /// [BeamSearchParameters::length_penalty]. Hypotheses are ranked by this.
+    pub score: f32,
+    /// Whether the hypothesis ended with the end-of-text token, rather than being cut off.
+    pub ended: bool,
+}
+
+/// A hypothesis that is still being extended, with the session that has evaluated it.
+struct Beam {
+    session: InferenceSession,
+    tokens: Vec<TokenId>,
+    log_probability: f32,
+}
+impl Beam {
+    fn hypothesis(
+        &self,
+        n_decoded: usize,
+        log_probability: f32,
+        length_penalty: f32,
+        ended: bool,
+    ) -> BeamSearchHypothesis {
+        BeamSearchHypothesis {
+            tokens: self.tokens.clone(),
+            text: self.session.decoded_tokens[n_decoded..].to_vec(),
+            log_probability,
+            score: score(log_probability, self.tokens.len(), length_penalty),
+            ended,
+        }
+    }
+}
+
+/// Feeds the `prompt` to the `session`, and searches for the most probable continuations of
+/// it with beam search, returning the best [BeamSearchParameters::n_best] of them, best first.
+///
+/// At each step, every beam is extended with its most probable next tokens, and the
+/// `beam_width` most probable of all of the extensions are kept. Beams that are extended more
+/// than once are [forked](InferenceSession::fork), so that they share the memory of their
+/// common tokens, and all of the beams are evaluated together (see [Model::evaluate_batch]).
+///
+/// The session is left with the prompt fed, so that the chosen hypothesis can be fed to it to
+/// continue from there.
+pub fn beam_search<'a>(
+    session: &mut InferenceSession,
+    model: &dyn Model,
+    prompt: impl Into<Prompt<'a>>,
+    parameters: &BeamSearchParameters,
+) -> Result<Vec<BeamSearchHypothesis>, InferenceError> {
+    let prompt = prompt.into();
+    if !prompt.is_empty() {
+        session.feed_prompt(model, prompt, &mut OutputRequest::default(), |_| {
+            Ok::<_, Infallible>(InferenceFeedback::Continue)
+        })?;
+    }
+
+    let beam_width = parameters.beam_width.max(1);
+    let n_kept = beam_width.max(parameters.n_best);
+    let length_penalty = parameters.length_penalty;
+    let maximum_token_count = parameters.maximum_token_count.unwrap_or(usize::MAX);
+    let n_decoded = session.decoded_tokens.len();
+    // The beams stop growing when the context window is full.
+    let maximum_length =
+        maximum_token_count.min(model.context_size().saturating_sub(session.n_past + 1));
+    let eot_token_id = model.eot_token_id();
+
+    let mut beams = vec![Beam {
+        session: session.fork(model),
+        tokens: vec![],
+        log_probability: 0.0,
+    }];
+    let mut ended: Vec<BeamSearchHypothesis> = vec![];
+
+    loop {
+        let length = beams[0].tokens.len();
+        if length >= maximum_token_count || beams[0].session.n_past + 1 >= model.context_size() {
+            break;
+        }
+
+        // Rank the most probable extensions of all of the beams.
+        let mut candidates: Vec<_> = beams
+            .iter()
+            .enumerate()
+            .flat_map(|(index, beam)| {
+                top_tokens(&beam.session.last_logits, 2 * beam_width)
+                    .into_iter()
+                    .map(move |(token, log_probability)| {
+                        (index, token, beam.log_probability + log_probability)
+                    })
+            })
+            .collect();
+        candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+        // End the hypotheses whose best extensions are the end-of-text token, and keep the
+        // best other extensions.
+        let mut selected = vec![];
+        for (rank, (index, token, log_probability)) in candidates.into_iter().enumerate() {
+            if token == eot_token_id {
+                if rank < beam_width {
+                    ended.push(beams[index].hypothesis(
+                        n_decoded,
+                        log_probability,
+                        length_penalty,
+                        true,
+                    ));
+                }
+            } else {
+                selected.push((index, token, log_probability));
+                if selected.len() == beam_width {
+                    break;
+                }
+            }
+        }
+        sort_hypotheses(&mut ended);
+        ended.truncate(n_kept);
+
+        let Some(&(_, _, best_log_probability)) = selected.first() else {
+            break;
+        };
+        if ended.len() >= beam_width {
+            if parameters.early_stopping {
+                break;
+            }
+            // Stop if none of the beams can score better than the ended hypotheses anymore.
+            let best_score = best_attainable_score(
+                best_log_probability,
+                length + 1,
+                maximum_length,
+                length_penalty,
+            );
+            if ended[beam_width - 1].score >= best_score {
+                break;
+            }
+        }
+
+        // Extend the beams, forking those that are extended more than once.
+        let mut n_children = vec![0; beams.len()];
+        for (index, _, _) in &selected {
+            n_children[*index] += 1;
+        }
+        let mut parents: Vec<_> = beams.into_iter().map(Some).collect();
+        beams = selected
+            .into_iter()
+            .map(|(index, token, log_probability)| {
+                n_children[index] -= 1;
+                let parent = parents[index].as_mut().unwrap();
+                let mut tokens = parent.tokens.clone();
+                tokens.push(token);
+
+                let mut session = if n_children[index] > 0 {
+                    parent.session.fork(model)
+                } else {
+                    parents[index].take().unwrap().session
+                };
+                session.tokens.push(token);
+
+                Beam {
+                    session,
+                    tokens,
+                    log_probability,
+                }
+            })
+            .collect();
+        drop(parents);
+
+        // Evaluate the new tokens of all of the beams at once.
+        let mut output_requests: Vec<OutputRequest> = std::iter::repeat_with(Default::default)
+            .take(beams.len())
+            .collect();
+        let mut sequences: Vec<_> = beams
+            .iter_mut()
+            .zip(&mut output_requests)
+            .map(
+                |(
+                    Beam {
+                        session, tokens, ..
+                    },
+                    output_request,
+                )| BatchedSequence {
+                    session,
+                    input_tokens: &tokens[tokens.len() - 1..],
+                    output_request,
+                },
+            )
+            .collect();
+        InferenceSession::evaluate_batch(model, &mut sequences);
+        for beam in &mut beams {
+            let token = *beam.tokens.last().unwrap();
+            beam.session.decode_next_token(model, token)?;
+        }
+    }
+
+    // Fall back to the hypotheses that were cut off if not enough of them ended.
+    if ended.len() < parameters.n_best {
+        ended.extend(
+            beams.iter().map(|beam| {
+                beam.hypothesis(n_decoded, beam.log_probability, length_penalty, false)
+            }),
+        );
+        sort_hypotheses(&mut ended);
+    }
+    ended.truncate(parameters.n_best);
+
+    Ok(ended)
+}
+
+/// Returns the `k` most probable tokens given the `logits`, most probable first, with their
+/// log-probabilities.
+fn top_tokens(logits: &[f32], k: usize) -> Vec<(TokenId, f32)> {
+    let max_logit = logits.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+    let log_sum = logits
+        .iter()
+        .map(|logit| (logit - max_logit).exp())
+        .sum::<f32>()
+        .ln()
+        + max_logit;
+
+    let descending = |a: &usize, b: &usize| logits[*b].total_cmp(&logits[*a]);
+    let mut tokens: Vec<usize> = (0..logits.len()).collect();
+    let k = k.min(tokens.len());
+    if k < tokens.len() {
+        tokens.select_nth_unstable_by(k, descending);
+        tokens.truncate(k);
+    }
+    tokens.sort_by(descending);
+
+    tokens
+        .into_iter()
+        .map(|token| (token as TokenId, logits[token] - log_sum))
+        .collect()
+}
+
+/// Normalizes the `log_probability` of a hypothesis of `length` tokens.
+fn score(log_probability: f32, length: usize, length_penalty: f32) -> f32 {
+    log_probability / (length.max(1) as f32).powf(length_penalty)
+}
+
+/// Returns the best score that a beam of `length` tokens with the `log_probability` can reach
+/// by growing up to `maximum_length` tokens. Its log-probability can only decrease, but a
+/// positive `length_penalty` divides it by a larger number as it grows.
+fn best_attainable_score(
+    log_probability: f32,
+    length: usize,
+    maximum_length: usize,
+    length_penalty: f32,
+) -> f32 {
+    if length_penalty > 0.0 {
+        score(log_probability, maximum_length.max(length), length_penalty)
+    } else {
+        score(log_probability, length, length_penalty)
+    }
+}
+
+fn sort_hypotheses(hypotheses: &mut [BeamSearchHypothesis]) {
+    hypotheses.sort_by(|a, b| b.score.total_cmp(&a.score));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+    use crate::{
+        tokenizer::EmbeddedTokenizer, BatchedSequence, InferenceSessionConfig, MemoryLayout,
+        ModelContext, ModelParameters, Tokenizer,
+    };
+
+    /// A model of the tokens `""` (the end-of-text token), `"a"`, `"b"` and `"c"`, whose next
+    /// token only depends on the last one, with the probabilities in [Self::PROBABILITIES].
+    struct MarkovModel {
+        context: ModelContext,
+        tokenizer: Tokenizer,
+        n_evaluations: AtomicUsize,
+    }
+    unsafe impl Send for MarkovModel {}
+    unsafe impl Sync for MarkovModel {}
+    impl MarkovModel {
+        const PROBABILITIES: [[f32; 4]; 4] = [
+            [0.25, 0.25, 0.25, 0.25],
+            [0.05, 0.15, 0.5, 0.3],
+            [0.9, 0.05, 0.025, 0.025],
+            [0.1, 0.7, 0.15, 0.05],
+        ];
+
+        fn new() -> Self {
+            let mut tokenizer = EmbeddedTokenizer::default();
+            for (id, token) in ["", "a", "b", "c"].into_iter().enumerate() {
+                tokenizer.push_token(id as TokenId, token.as_bytes().to_vec(), 0.0);
+            }
+            Self {
+                context: ModelContext::new(
+                    ggml::Context::new_with_allocate(0),
+                    HashMap::new(),
+                    vec![],
+                ),
+                tokenizer: tokenizer.into(),
+                n_evaluations: AtomicUsize::new(0),
+            }
+        }
+    }
+    impl Model for MarkovModel {
+        fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
+            let params = ModelParameters {
+                context_size: self.context_size(),
+                ..Default::default()
+            };
+            InferenceSession::new(config, &params, 1, 1, 4)
+        }
+
+        fn evaluate(
+            &self,
+            session: &mut InferenceSession,
+            input_tokens: &[TokenId],
+            _output_request: &mut OutputRequest,
+        ) {
+            self.n_evaluations.fetch_add(1, Ordering::Relaxed);
+            let last = *input_tokens.last().unwrap() as usize;
+            session.last_logits = Self::PROBABILITIES[last].map(f32::ln).to_vec();
+            session.n_past += input_tokens.len();
+        }
+
+        fn evaluate_batch(&self, sequences: &mut [BatchedSequence]) {
+            for sequence in sequences {
+                self.evaluate(
+                    sequence.session,
+                    sequence.input_tokens,
+                    sequence.output_request,
+                );
+            }
+        }
+
+        fn tokenizer(&self) -> &Tokenizer {
+            &self.tokenizer
+        }
+
+        fn context(&self) -> &ModelContext {
+            &self.context
+        }
+
+        fn context_mut(&mut self) -> &mut ModelContext {
+            &mut self.context
+        }
+
+        fn context_size(&self) -> usize {
+            16
+        }
+
+        fn bot_token_id(&self) -> Option<TokenId> {
+            None
+        }
+
+        fn eot_token_id(&self) -> TokenId {
+            0
+        }
+
+        fn supports_rewind(&self) -> bool {
+            true
+        }
+
+        fn memory_layout(&self) -> Option<MemoryLayout> {
+            None
+        }
+
+        fn supports_remove_range(&self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn beam_search_ends_forks_and_falls_back_to_cut_off_hypotheses() {
+        let model = MarkovModel::new();
+        let mut session = model.start_session(Default::default());
+        let parameters = BeamSearchParameters {
+            beam_width: 2,
+            length_penalty: 0.0,
+            early_stopping: false,
+            n_best: 2,
+            maximum_token_count: Some(3),
+        };
+        let hypotheses = beam_search(&mut session, &model, &[1][..], &parameters).unwrap();
+
+        // "a" is extended with both "b" and "c", and "ab" ends right after. "ca" is extended
+        // twice as well, and the search is cut off with neither of them ended, so the best of
+        // them makes up for the missing ended hypothesis.
+        let summary: Vec<_> = hypotheses
+            .iter()
+            .map(|h| (h.tokens.clone(), h.text.clone(), h.ended))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (vec![2], b"b".to_vec(), true),
+                (vec![3, 1, 2], b"cab".to_vec(), false),
+            ]
+        );
+        assert!((hypotheses[0].log_probability - (0.5f32 * 0.9).ln()).abs() < 1e-5);
+        assert!((hypotheses[1].log_probability - (0.3f32 * 0.7 * 0.5).ln()).abs() < 1e-5);
+        assert_eq!(hypotheses[0].score, hypotheses[0].log_probability);
+
+        // The session is left with only the prompt fed.
+        assert_eq!(session.tokens(), &[1]);
+        assert_eq!(session.n_past, 1);
+    }
+
+    #[test]
+    fn beam_search_stops_when_the_ended_hypotheses_cannot_be_beaten() {
+        // "b" ends right after "a", and the best other extension "ba" is much less probable.
+        let search = |length_penalty| {
+            let model = MarkovModel::new();
+            let parameters = BeamSearchParameters {
+                beam_width: 1,
+                length_penalty,
+                n_best: 1,
+                ..Default::default()
+            };
+            let mut session = model.start_session(Default::default());
+            let hypotheses = beam_search(&mut session, &model, &[1][..], &parameters).unwrap();
+            assert_eq!(hypotheses.len(), 1);
+            assert_eq!(
+                (&hypotheses[0].tokens, hypotheses[0].ended),
+                (&vec![2], true)
+            );
+            model.n_evaluations.into_inner()
+        };
+
+        // Without a length penalty, "ba" can only score lower as it grows, so the search stops
+        // after evaluating the prompt and "b".
+        assert_eq!(search(0.0), 2);
+        // With one, a long enough continuation of "ba" could still score higher.
+        assert!(search(1.0) > 2);
+    }
+
+    #[test]
+    fn top_tokens_are_most_probable_first() {
+        let logits = [1.0, 3.0, 2.0, 0.0];
+        let top = top_tokens(&logits, 2);
+        assert_eq!(
+            top.iter().map(|(token, _)| *token).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+
+        // The log-probabilities are normalized over all of the tokens.
+        let all = top_tokens(&logits, 10);
+        assert_eq!(all.len(), logits.len());
+        let total: f32 = all.iter().map(|(_, lp)| lp.exp()).sum();
+        assert!((total - 1.0).abs() < 1e-5);
+        assert_eq!(top[0], all[0]);
+    }
+
+    #[test]
+    fn length_penalty_favours_longer_hypotheses() {
+        // Without a penalty, the shorter hypothesis is more probable.
+        assert!(score(-2.0, 2, 0.0) > score(-3.0, 4, 0.0));
+        // Normalizing by length favours the longer one.
+        assert!(score(-2.0, 2, 1.0) < score(-3.0, 4, 1.0));
+        // Empty hypotheses are not divided by zero.
+        assert_eq!(score(-1.0, 0, 1.0), -1.0);
+    }
+
+    #[test]
+    fn positive_length_penalty_bounds_scores_at_the_maximum_length() {
+        // Without a penalty or with a negative one, growing only lowers the score.
+        assert_eq!(best_attainable_score(-2.0, 2, 8, 0.0), -2.0);
+        assert_eq!(
+            best_attainable_score(-2.0, 2, 8, -1.0),
+            score(-2.0, 2, -1.0)
+        );
+        // With a positive one, the longest beams can score the highest.
+        assert_eq!(best_attainable_score(-2.0, 2, 8, 1.0), -0.25);
+        assert_eq!(best_attainable_score(-2.0, 2, 1, 1.0), -1.0);
+    }
+}
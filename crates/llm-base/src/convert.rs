@@ -0,0 +1,522 @@
+//! Implements conversion of Hugging Face checkpoints to GGML models.
+
+use crate::{
+    loader::FileTypeFormat, model::HyperparametersWriteError, safetensors::SafeTensors,
+    tokenizer::HuggingFaceTokenizer, Hyperparameters, KnownModel, TokenizerLoadError,
+};
+use ggml::format::{SaveContainerType, SaveError, SaveHandler, TensorSaveInfo};
+use half::f16;
+use std::{
+    collections::HashMap,
+    io::{Seek, Write},
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+#[derive(Clone, Debug)]
+/// Progress of conversion.
+pub enum ConvertProgress<'a> {
+    /// Hyperparameters have been read from the `config.json`.
+    HyperparametersLoaded,
+    /// The vocabulary has been read from the `tokenizer.json`.
+    VocabularyLoaded {
+        /// Number of tokens in the vocabulary.
+        n_tokens: usize,
+    },
+    /// A tensor is being converted.
+    TensorConverting {
+        /// Name of the tensor in the converted model.
+        name: &'a str,
+        /// Size of the tensor.
+        dims: [usize; 2],
+        /// Type the tensor is being converted to.
+        element_type: ggml::Type,
+    },
+    /// A tensor in the checkpoint is not used by the model, and has been skipped.
+    TensorSkipped {
+        /// Name of the tensor in the checkpoint.
+        name: &'a str,
+    },
+    /// A model has been converted.
+    Finished {
+        /// Number of tensors in the converted model.
+        n_tensors: usize,
+    },
+}
+
+#[derive(Error, Debug)]
+/// Errors encountered during the conversion process.
+pub enum ConvertError {
+    #[error("non-specific I/O error")]
+    /// A non-specific IO error.
+    Io(#[from] std::io::Error),
+    #[error("invalid integer conversion")]
+    /// One of the integers encountered could not be converted to a more appropriate type.
+    InvalidIntegerConversion(#[from] std::num::TryFromIntError),
+    #[error("could not open file {path:?}")]
+    /// A file failed to open.
+    OpenFileFailed {
+        /// The original error.
+        source: std::io::Error,
+        /// The path that failed.
+        path: PathBuf,
+    },
+    #[error("could not parse the config at {path:?}")]
+    /// The `config.json` could not be parsed.
+    InvalidConfig {
+        /// The original error.
+        source: serde_json::Error,
+        /// The path to the config.
+        path: PathBuf,
+    },
+    #[error("the config does not have a valid value for {key:?}")]
+    /// A required value was missing from the `config.json`, or had the wrong type.
+    InvalidConfigValue {
+        /// The key of the value.
+        key: String,
+    },
+    #[error("no .safetensors files were found in {path:?}")]
+    /// The checkpoint does not contain any tensors in the safetensors format.
+    NoSafeTensors {
+        /// The directory that was searched.
+        path: PathBuf,
+    },
+    #[error("could not load the tokenizer")]
+    /// The `tokenizer.json` could not be loaded.
+    TokenizerLoad(#[from] TokenizerLoadError),
+    #[error("the tokenizer has {tokenizer} tokens, but the model only has {model}")]
+    /// The tokenizer has more tokens than the model has embeddings for.
+    VocabularyTooLarge {
+        /// The number of tokens in the tokenizer.
+        tokenizer: usize,
+        /// The size of the model's vocabulary.
+        model: usize,
+    },
+    #[error("this model architecture does not support conversion")]
+    /// The model does not implement conversion from Hugging Face checkpoints.
+    UnsupportedArchitecture,
+    /// Attempted to convert to an invalid target.
+    #[error("invalid conversion target {element_type:?}; only F16 and F32 are supported")]
+    InvalidConversionTarget {
+        /// The conversion target.
+        element_type: ggml::Type,
+    },
+    #[error("could not convert tensor {name:?}: {reason}")]
+    /// A tensor in the checkpoint could not be converted.
+    UnsupportedTensor {
+        /// The name of the tensor in the checkpoint.
+        name: String,
+        /// Why the tensor could not be converted.
+        reason: String,
+    },
+    /// An invariant was broken.
+    #[error("invariant broken: {invariant} in {path:?}")]
+    InvariantBroken {
+        /// The path that failed.
+        path: PathBuf,
+        /// The invariant that was broken.
+        invariant: String,
+    },
+    /// An error was encountered while writing the hyperparameters.
+    #[error("an error was encountered while writing the hyperparameters")]
+    HyperparametersWriteError(#[source] HyperparametersWriteError),
+    /// An attempt was made to save a model with a container type that does not
+    /// support vocabulary scoring, despite the model having a scored vocabulary.
+    #[error("container type does not support vocabulary scoring")]
+    VocabularyScoringNotSupported,
+}
+impl ConvertError {
+    pub(crate) fn from_format_error(value: SaveError<ConvertError>, path: PathBuf) -> Self {
+        match value {
+            SaveError::Io(io) => ConvertError::Io(io),
+            SaveError::InvalidIntegerConversion(e) => ConvertError::InvalidIntegerConversion(e),
+            SaveError::ImplementationError(e) => e,
+            SaveError::InvariantBroken(invariant) => {
+                ConvertError::InvariantBroken { path, invariant }
+            }
+            SaveError::VocabularyScoringNotSupported => ConvertError::VocabularyScoringNotSupported,
+            err @ SaveError::UnsupportedElementType { .. } => ConvertError::InvariantBroken {
+                path,
+                invariant: err.to_string(),
+            },
+        }
+    }
+}
+
+/// The `config.json` of a Hugging Face checkpoint.
+#[derive(Debug, Clone, Default)]
+pub struct HfConfig(serde_json::Map<String, serde_json::Value>);
+impl HfConfig {
+    /// Reads the config from the `config.json` at `path`.
+    pub fn load(path: &Path) -> Result<Self, ConvertError> {
+        let contents = std::fs::read(path).map_err(|source| ConvertError::OpenFileFailed {
+            source,
+            path: path.to_owned(),
+        })?;
+        serde_json::from_slice(&contents)
+            .map(Self)
+            .map_err(|source| ConvertError::InvalidConfig {
+                source,
+                path: path.to_owned(),
+            })
+    }
+
+    /// Gets the unsigned integer `key`, failing if it is missing or of another type.
+    pub fn get_usize(&self, key: &str) -> Result<usize, ConvertError> {
+        self.get_optional_usize(key)?
+            .ok_or_else(|| invalid_config_value(key))
+    }
+
+    /// Gets the unsigned integer `key`, if present.
+    pub fn get_optional_usize(&self, key: &str) -> Result<Option<usize>, ConvertError> {
+        self.get_optional(key, |v| v.as_u64().and_then(|v| usize::try_from(v).ok()))
+    }
+
+    /// Gets the number `key`, if present.
+    pub fn get_optional_f64(&self, key: &str) -> Result<Option<f64>, ConvertError> {
+        self.get_optional(key, serde_json::Value::as_f64)
+    }
+
+    /// Gets the boolean `key`, if present.
+    pub fn get_optional_bool(&self, key: &str) -> Result<Option<bool>, ConvertError> {
+        self.get_optional(key, serde_json::Value::as_bool)
+    }
+
+    /// Gets the string `key`, if present.
+    pub fn get_optional_str(&self, key: &str) -> Result<Option<&str>, ConvertError> {
+        self.get_optional(key, serde_json::Value::as_str)
+    }
+
+    /// Gets the names of the model classes listed under `architectures` (e.g. `LlamaForCausalLM`).
+    pub fn architectures(&self) -> Vec<&str> {
+        self.0
+            .get("architectures")
+            .and_then(serde_json::Value::as_array)
+            .map(|a| a.iter().filter_map(serde_json::Value::as_str).collect())
+            .unwrap_or_default()
+    }
+
+    fn get_optional<'a, T>(
+        &'a self,
+        key: &str,
+        getter: impl Fn(&'a serde_json::Value) -> Option<T>,
+    ) -> Result<Option<T>, ConvertError> {
+        match self.0.get(key) {
+            None | Some(serde_json::Value::Null) => Ok(None),
+            Some(value) => getter(value)
+                .map(Some)
+                .ok_or_else(|| invalid_config_value(key)),
+        }
+    }
+}
+impl From<serde_json::Map<String, serde_json::Value>> for HfConfig {
+    fn from(value: serde_json::Map<String, serde_json::Value>) -> Self {
+        Self(value)
+    }
+}
+
+fn invalid_config_value(key: &str) -> ConvertError {
+    ConvertError::InvalidConfigValue {
+        key: key.to_owned(),
+    }
+}
+
+/// How the data of a tensor in a Hugging Face checkpoint must be rearranged for the model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TensorTransform {
+    /// The data is used as-is.
+    None,
+    /// The matrix is transposed; used for weights stored as `Conv1D` (e.g. GPT-2).
+    Transpose,
+    /// The rows of each attention head are reordered to undo the permutation that the Hugging Face
+    /// conversion script applies to LLaMA's query and key weights for its rotary embeddings.
+    UnpermuteRotary {
+        /// The number of attention heads.
+        n_head: usize,
+    },
+}
+impl TensorTransform {
+    /// Applies the transform to the row-major matrix `data` with the given `shape`, returning the
+    /// transformed data and its shape.
+    pub(crate) fn apply(self, data: Vec<f32>, shape: [usize; 2]) -> (Vec<f32>, [usize; 2]) {
+        let [rows, cols] = shape;
+        match self {
+            TensorTransform::None => (data, shape),
+            TensorTransform::Transpose => {
+                let mut out = vec![0.0; data.len()];
+                for r in 0..rows {
+                    for c in 0..cols {
+                        out[c * rows + r] = data[r * cols + c];
+                    }
+                }
+                (out, [cols, rows])
+            }
+            TensorTransform::UnpermuteRotary { n_head } => {
+                let head_dim = rows / n_head;
+                let half = head_dim / 2;
+                let mut out = vec![0.0; data.len()];
+                for h in 0..n_head {
+                    for i in 0..half {
+                        for j in 0..2 {
+                            let src = h * head_dim + j * half + i;
+                            let dst = h * head_dim + 2 * i + j;
+                            out[dst * cols..(dst + 1) * cols]
+                                .copy_from_slice(&data[src * cols..(src + 1) * cols]);
+                        }
+                    }
+                }
+                (out, shape)
+            }
+        }
+    }
+}
+
+/// Converts the Hugging Face checkpoint in `directory` to a GGJT model written to `writer`.
+///
+/// The directory must contain a `config.json`, a `tokenizer.json` and the weights as one or
+/// more `*.safetensors` files. The model's two-dimensional tensors are stored as `element_type`,
+/// which must be [F16](ggml::Type::F16) or [F32](ggml::Type::F32); the rest are stored as F32.
+pub fn convert<M: KnownModel, W: Write + Seek>(
+    directory: &Path,
+    writer: &mut W,
+    element_type: ggml::Type,
+    progress_callback: impl Fn(ConvertProgress),
+) -> Result<(), ConvertError> {
+    let format = match element_type {
+        ggml::Type::F16 => FileTypeFormat::MostlyF16,
+        ggml::Type::F32 => FileTypeFormat::F32,
+        _ => return Err(ConvertError::InvalidConversionTarget { element_type }),
+    };
+
+    // Read the hyperparameters
+    let config = HfConfig::load(&directory.join("config.json"))?;
+    let mut hyperparameters = M::Hyperparameters::read_hf_config(&config)?;
+    if let Some(ft) = hyperparameters.file_type_mut() {
+        ft.format = format;
+    }
+    progress_callback(ConvertProgress::HyperparametersLoaded);
+
+    // Read the vocabulary
+    let vocabulary = read_vocabulary(
+        &directory.join("tokenizer.json"),
+        hyperparameters.n_vocabulary(),
+    )?;
+    progress_callback(ConvertProgress::VocabularyLoaded {
+        n_tokens: vocabulary.len(),
+    });
+
+    // Find the tensors the model uses
+    let mut paths = std::fs::read_dir(directory)
+        .map_err(|source| ConvertError::OpenFileFailed {
+            source,
+            path: directory.to_owned(),
+        })?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.retain(|p| p.extension().map_or(false, |e| e == "safetensors"));
+    paths.sort();
+    if paths.is_empty() {
+        return Err(ConvertError::NoSafeTensors {
+            path: directory.to_owned(),
+        });
+    }
+
+    let mut files: Vec<SafeTensors> = vec![];
+    let mut tensors: HashMap<String, SourceTensor> = HashMap::new();
+    let mut tensor_names = vec![];
+    for path in paths {
+        let file = SafeTensors::open(&path)
+            .map_err(|source| ConvertError::OpenFileFailed { source, path })?;
+        for (index, (hf_name, info)) in file.tensors().iter().enumerate() {
+            let Some((name, transform)) = hyperparameters.tensor_from_hf(hf_name) else {
+                progress_callback(ConvertProgress::TensorSkipped { name: hf_name });
+                continue;
+            };
+
+            // Some checkpoints store the same tensor in more than one of their files.
+            if let Some(existing) = tensors.get(&name) {
+                let existing_file = files.get(existing.file).unwrap_or(&file);
+                let (_, existing_info) = &existing_file.tensors()[existing.index];
+                if existing_info.dtype != info.dtype
+                    || existing_info.shape != info.shape
+                    || existing_file.data(existing_info) != file.data(info)
+                {
+                    return Err(ConvertError::UnsupportedTensor {
+                        name: hf_name.clone(),
+                        reason: "it is stored more than once, with different data".to_owned(),
+                    });
+                }
+                continue;
+            }
+
+            tensor_names.push(name.clone());
+            tensors.insert(
+                name,
+                SourceTensor {
+                    file: files.len(),
+                    index,
+                    transform,
+                },
+            );
+        }
+        files.push(file);
+    }
+
+    // Checkpoints with tied embeddings use the token embedding as the output projection.
+    if config.get_optional_bool("tie_word_embeddings")? == Some(true) {
+        if let Some((embedding, output)) = hyperparameters.tied_hf_tensors() {
+            let embedding = hyperparameters.tensor_from_hf(embedding);
+            let output = hyperparameters.tensor_from_hf(output);
+            if let (Some((embedding, _)), Some((output, transform))) = (embedding, output) {
+                if !tensors.contains_key(&output) {
+                    if let Some(source) = tensors.get(&embedding) {
+                        let source = SourceTensor {
+                            transform,
+                            ..*source
+                        };
+                        tensor_names.push(output.clone());
+                        tensors.insert(output, source);
+                    }
+                }
+            }
+        }
+    }
+
+    // Save the model, converting the tensors as we go
+    let mut saver = ConvertSaver {
+        element_type,
+        hyperparameters: &hyperparameters,
+        files: &files,
+        tensors: &tensors,
+        progress_callback: &progress_callback,
+    };
+    ggml::format::save(
+        writer,
+        &mut saver,
+        SaveContainerType::GgjtV3,
+        &vocabulary,
+        &tensor_names,
+    )
+    .map_err(|err| ConvertError::from_format_error(err, directory.to_owned()))?;
+
+    progress_callback(ConvertProgress::Finished {
+        n_tensors: tensor_names.len(),
+    });
+
+    Ok(())
+}
+
+/// Reads the vocabulary of the `tokenizer.json` at `path`, padded to `n_vocab` tokens.
+fn read_vocabulary(path: &Path, n_vocab: usize) -> Result<Vec<(Vec<u8>, f32)>, ConvertError> {
+    let tokenizer = tokenizers::Tokenizer::from_file(path).map_err(|error| TokenizerLoadError {
+        path: path.to_owned(),
+        error,
+    })?;
+
+    let len = tokenizer.get_vocab_size(true);
+    if len > n_vocab {
+        return Err(ConvertError::VocabularyTooLarge {
+            tokenizer: len,
+            model: n_vocab,
+        });
+    }
+
+    Ok(HuggingFaceTokenizer::new(tokenizer).vocabulary(n_vocab))
+}
+
+#[derive(Clone, Copy)]
+struct SourceTensor {
+    file: usize,
+    index: usize,
+    transform: TensorTransform,
+}
+
+struct ConvertSaver<'a, F: Fn(ConvertProgress), H: Hyperparameters> {
+    element_type: ggml::Type,
+    hyperparameters: &'a H,
+    files: &'a [SafeTensors],
+    tensors: &'a HashMap<String, SourceTensor>,
+    progress_callback: &'a F,
+}
+impl<F: Fn(ConvertProgress), H: Hyperparameters> SaveHandler<ConvertError>
+    for ConvertSaver<'_, F, H>
+{
+    fn write_hyperparameters(&mut self, writer: &mut dyn Write) -> Result<(), ConvertError> {
+        self.hyperparameters
+            .write_ggml(writer)
+            .map_err(ConvertError::HyperparametersWriteError)?;
+        Ok(())
+    }
+
+    fn tensor_data(&mut self, tensor_name: &str) -> Result<TensorSaveInfo, ConvertError> {
+        let source = self.tensors.get(tensor_name).expect(
+            "tensor not found; should be impossible due to handler being populated from checkpoint",
+        );
+        let file = &self.files[source.file];
+        let (hf_name, info) = &file.tensors()[source.index];
+
+        let unsupported = |reason: String| ConvertError::UnsupportedTensor {
+            name: hf_name.clone(),
+            reason,
+        };
+        let shape = match info.shape[..] {
+            [n] => [1, n],
+            [rows, cols] => [rows, cols],
+            _ => return Err(unsupported(format!("unsupported shape {:?}", info.shape))),
+        };
+        let n_dims = info.shape.len();
+        let data = file
+            .read_f32(info)
+            .map_err(|e| unsupported(e.to_string()))?;
+        let (data, [rows, cols]) = source.transform.apply(data, shape);
+
+        // ggml lists dimensions innermost first
+        let dims = [cols, rows];
+        let element_type = if n_dims == 2 {
+            self.element_type
+        } else {
+            ggml::Type::F32
+        };
+        (self.progress_callback)(ConvertProgress::TensorConverting {
+            name: tensor_name,
+            dims,
+            element_type,
+        });
+
+        let data = match element_type {
+            ggml::Type::F16 => data
+                .iter()
+                .flat_map(|v| f16::from_f32(*v).to_le_bytes())
+                .collect(),
+            _ => data.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        };
+
+        Ok(TensorSaveInfo {
+            n_dims,
+            dims,
+            element_type,
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unpermute_rotary_interleaves_the_halves_of_each_head() {
+        // Two heads of four rows each, with one column. The Hugging Face layout stores the first
+        // elements of each rotated pair, then the second elements: `[0, 1, 2, 3]` is the pairs
+        // `(0, 2)` and `(1, 3)`.
+        let data = vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0];
+        let (out, shape) = TensorTransform::UnpermuteRotary { n_head: 2 }.apply(data, [8, 1]);
+        assert_eq!(shape, [8, 1]);
+        assert_eq!(out, [0.0, 2.0, 1.0, 3.0, 4.0, 6.0, 5.0, 7.0]);
+
+        // Whole rows are moved.
+        let data: Vec<f32> = (0..8).map(|v| v as f32).collect();
+        let (out, _) = TensorTransform::UnpermuteRotary { n_head: 1 }.apply(data, [4, 2]);
+        assert_eq!(out, [0.0, 1.0, 4.0, 5.0, 2.0, 3.0, 6.0, 7.0]);
+    }
+}
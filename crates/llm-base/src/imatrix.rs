@@ -0,0 +1,413 @@
+//! Implements importance matrices, which record how strongly each input column of the weights
+//! of a model is activated, and the quantization of weights weighted by that importance.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{BufRead, Write},
+};
+
+use ggml::util;
+use half::f16;
+
+use crate::loader::truncate_tensor_name;
+
+/// The number of elements in a block of the legacy quantization types.
+const QK: usize = 32;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// The importance of the input columns of the weights of a model, collected by evaluating the
+/// model over calibration text with [InferenceSession::set_importance_matrix](crate::InferenceSession::set_importance_matrix).
+///
+/// The importance of a column is the mean of its squared activations. Quantizing with an
+/// importance matrix keeps the columns that are most activated more accurate.
+pub struct ImportanceMatrix {
+    entries: HashMap<String, ImportanceEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ImportanceEntry {
+    /// The sum of the squared activations of each column.
+    sum_sq: Vec<f32>,
+    /// The number of activations that have been summed for each column.
+    n_samples: u64,
+}
+
+impl ImportanceMatrix {
+    /// Accumulates `activations`, the inputs of the weight named `name`, laid out as rows of
+    /// `n_columns` elements.
+    ///
+    /// Activations whose number of columns does not match the previous activations of the
+    /// weight are ignored.
+    pub fn accumulate(&mut self, name: &str, n_columns: usize, activations: &[f32]) {
+        let entry = self
+            .entries
+            .entry(truncate_tensor_name(name).to_string())
+            .or_insert_with(|| ImportanceEntry {
+                sum_sq: vec![0.0; n_columns],
+                n_samples: 0,
+            });
+        if entry.sum_sq.len() != n_columns {
+            return;
+        }
+
+        for row in activations.chunks_exact(n_columns) {
+            for (sum_sq, x) in entry.sum_sq.iter_mut().zip(row) {
+                *sum_sq += x * x;
+            }
+            entry.n_samples += 1;
+        }
+    }
+
+    /// Returns the importance of each input column of the weight named `name`, if any of its
+    /// activations were recorded.
+    pub fn importance(&self, name: &str) -> Option<Vec<f32>> {
+        let entry = self.entries.get(truncate_tensor_name(name))?;
+        if entry.n_samples == 0 {
+            return None;
+        }
+        let n_samples = entry.n_samples as f32;
+        Some(entry.sum_sq.iter().map(|s| s / n_samples).collect())
+    }
+
+    /// Returns the number of weights whose activations were recorded.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns whether no activations were recorded.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Reads an importance matrix written by [ImportanceMatrix::write].
+    pub fn read(reader: &mut dyn BufRead) -> Result<Self, std::io::Error> {
+        let n_entries = util::read_u32(reader)?;
+        let mut entries = HashMap::new();
+        for _ in 0..n_entries {
+            let name_len = util::read_u32(reader)? as usize;
+            let name = String::from_utf8(util::read_bytes_with_len(reader, name_len)?)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            let n_samples = util::read_u64(reader)?;
+            let n_columns = util::read_u32(reader)? as usize;
+            let sum_sq = util::read_bytes_with_len(reader, n_columns * 4)?
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+                .collect();
+            entries.insert(name, ImportanceEntry { sum_sq, n_samples });
+        }
+        Ok(Self { entries })
+    }
+
+    /// Writes the importance matrix to `writer`.
+    ///
+    /// The format is the number of weights, followed by the name, number of samples and
+    /// per-column sums of squared activations of each weight.
+    pub fn write(&self, writer: &mut dyn Write) -> Result<(), std::io::Error> {
+        let entries: BTreeMap<_, _> = self.entries.iter().collect();
+        util::write_u32(writer, to_u32(entries.len())?)?;
+        for (name, entry) in entries {
+            util::write_u32(writer, to_u32(name.len())?)?;
+            writer.write_all(name.as_bytes())?;
+            util::write_u64(writer, entry.n_samples)?;
+            util::write_u32(writer, to_u32(entry.sum_sq.len())?)?;
+            for sum_sq in &entry.sum_sq {
+                util::write_f32(writer, *sum_sq)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn to_u32(value: usize) -> Result<u32, std::io::Error> {
+    value
+        .try_into()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+/// The legacy quantization types, which can be quantized with importance weights.
+pub(crate) enum LegacyBlock {
+    Q4_0,
+    Q4_1,
+    Q5_0,
+    Q5_1,
+    Q8_0,
+}
+
+/// Quantizes `src`, made of rows of `n_elements_0` elements, choosing the scale of each block
+/// to minimise the quantization error weighted by the `importance` of each column.
+///
+/// The output has the same layout as the `ggml` quantization functions.
+pub(crate) fn quantize_weighted(
+    block: LegacyBlock,
+    src: &[f32],
+    n_elements_0: usize,
+    importance: &[f32],
+) -> ggml::QuantizationResult {
+    assert_eq!(importance.len(), n_elements_0);
+    assert_eq!(n_elements_0 % QK, 0);
+
+    let mut output = Vec::with_capacity(src.len() / QK * block.size());
+    let mut history = vec![0i64; 16];
+    for row in src.chunks_exact(n_elements_0) {
+        let sigma2 = row.iter().map(|x| x * x).sum::<f32>() / n_elements_0 as f32;
+        for (x, importance) in row.chunks_exact(QK).zip(importance.chunks_exact(QK)) {
+            // Weigh each element by the importance of its column and by its own magnitude,
+            // as `llama.cpp` does.
+            let mut weights = [0.0; QK];
+            for ((w, x), importance) in weights.iter_mut().zip(x).zip(importance) {
+                *w = importance * (sigma2 + x * x).sqrt();
+            }
+            if weights.iter().sum::<f32>() <= 0.0 {
+                weights = [1.0; QK];
+            }
+
+            block.quantize(x, &weights, &mut output, &mut history);
+        }
+    }
+
+    ggml::QuantizationResult { output, history }
+}
+
+impl LegacyBlock {
+    /// The size of a block in bytes.
+    fn size(self) -> usize {
+        match self {
+            LegacyBlock::Q4_0 => 2 + QK / 2,
+            LegacyBlock::Q4_1 => 4 + QK / 2,
+            LegacyBlock::Q5_0 => 6 + QK / 2,
+            LegacyBlock::Q5_1 => 8 + QK / 2,
+            LegacyBlock::Q8_0 => 2 + QK,
+        }
+    }
+
+    fn quantize(self, x: &[f32], weights: &[f32; QK], output: &mut Vec<u8>, history: &mut [i64]) {
+        match self {
+            LegacyBlock::Q4_0 => {
+                let (d, q) = quantize_symmetric(x, weights, 8, -8, 7);
+                output.extend(f16::from_f32(d).to_le_bytes());
+                write_nibbles(&q.map(|q| (q + 8) as u8), output, history, 0);
+            }
+            LegacyBlock::Q4_1 => {
+                let (d, m, q) = quantize_asymmetric(x, weights, 15);
+                output.extend(f16::from_f32(d).to_le_bytes());
+                output.extend(f16::from_f32(m).to_le_bytes());
+                write_nibbles(&q, output, history, 0);
+            }
+            LegacyBlock::Q5_0 => {
+                let (d, q) = quantize_symmetric(x, weights, 16, -16, 15);
+                output.extend(f16::from_f32(d).to_le_bytes());
+                write_high_bits(&q.map(|q| (q + 16) as u8), output, history);
+            }
+            LegacyBlock::Q5_1 => {
+                let (d, m, q) = quantize_asymmetric(x, weights, 31);
+                output.extend(f16::from_f32(d).to_le_bytes());
+                output.extend(f16::from_f32(m).to_le_bytes());
+                write_high_bits(&q, output, history);
+            }
+            LegacyBlock::Q8_0 => {
+                let (d, q) = quantize_symmetric(x, weights, -127, -127, 127);
+                output.extend(f16::from_f32(d).to_le_bytes());
+                for q in q {
+                    output.push(q as i8 as u8);
+                    history[((q + 128) / 16) as usize] += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Writes the low four bits of `q`, with the `i`th and `i + 16`th values sharing a byte.
+fn write_nibbles(q: &[u8; QK], output: &mut Vec<u8>, history: &mut [i64], shift: u32) {
+    for j in 0..QK / 2 {
+        output.push((q[j] & 0xF) | ((q[j + QK / 2] & 0xF) << 4));
+        history[(q[j] >> shift) as usize] += 1;
+        history[(q[j + QK / 2] >> shift) as usize] += 1;
+    }
+}
+
+/// Writes the fifth bit of each value of `q` as a little-endian `u32`, followed by their low
+/// four bits.
+fn write_high_bits(q: &[u8; QK], output: &mut Vec<u8>, history: &mut [i64]) {
+    let qh = q
+        .iter()
+        .enumerate()
+        .fold(0u32, |qh, (j, q)| qh | (u32::from(q >> 4) << j));
+    output.extend(qh.to_le_bytes());
+    write_nibbles(q, output, history, 1);
+}
+
+/// Finds the scale `d` and the quantized values `q` in `q_min..=q_max` that minimise the
+/// weighted error of `x ≈ d * q`. The element with the largest magnitude is initially mapped to
+/// `-n_max`, as `ggml` does.
+fn quantize_symmetric(
+    x: &[f32],
+    weights: &[f32; QK],
+    n_max: i32,
+    q_min: i32,
+    q_max: i32,
+) -> (f32, [i32; QK]) {
+    let max = x
+        .iter()
+        .copied()
+        .fold(0.0f32, |max, x| if x.abs() > max.abs() { x } else { max });
+    if max == 0.0 {
+        return (0.0, [0; QK]);
+    }
+
+    let mut best = (0.0, [0; QK], f32::INFINITY);
+    for step in -9..=9 {
+        let iscale = -(n_max as f32 + 0.1 * step as f32) / max;
+        let mut q = [0; QK];
+        for (q, x) in q.iter_mut().zip(x) {
+            *q = ((x * iscale).round() as i32).clamp(q_min, q_max);
+        }
+
+        let (sum_xq, sum_q2) =
+            x.iter()
+                .zip(&q)
+                .zip(weights)
+                .fold((0.0, 0.0), |(sum_xq, sum_q2), ((x, q), w)| {
+                    let q = *q as f32;
+                    (sum_xq + w * x * q, sum_q2 + w * q * q)
+                });
+        if sum_q2 <= 0.0 {
+            continue;
+        }
+        let d = sum_xq / sum_q2;
+
+        let error = weighted_error(x, weights, |i| d * q[i] as f32);
+        if error < best.2 {
+            best = (d, q, error);
+        }
+    }
+    (best.0, best.1)
+}
+
+/// Finds the scale `d`, the minimum `m` and the quantized values `q` in `0..=n_max` that
+/// minimise the weighted error of `x ≈ d * q + m`.
+fn quantize_asymmetric(x: &[f32], weights: &[f32; QK], n_max: u8) -> (f32, f32, [u8; QK]) {
+    let min = x.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = x.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if max == min {
+        return (0.0, min, [0; QK]);
+    }
+
+    let quantize = |d: f32, m: f32| {
+        let mut q = [0; QK];
+        for (q, x) in q.iter_mut().zip(x) {
+            *q = ((x - m) / d).round().clamp(0.0, n_max as f32) as u8;
+        }
+        q
+    };
+
+    // Start from the scale that `ggml` uses, and try slightly different ranges
+    let d = (max - min) / n_max as f32;
+    let q = quantize(d, min);
+    let mut best = (
+        d,
+        min,
+        q,
+        weighted_error(x, weights, |i| d * q[i] as f32 + min),
+    );
+    for step in -9..=9 {
+        let q = quantize((max - min) / (n_max as f32 + 0.1 * step as f32), min);
+
+        // Solve the weighted least squares problem for `d` and `m` given `q`
+        let (mut sum_w, mut sum_q, mut sum_q2, mut sum_x, mut sum_xq) = (0.0, 0.0, 0.0, 0.0, 0.0);
+        for ((x, q), w) in x.iter().zip(&q).zip(weights) {
+            let q = *q as f32;
+            sum_w += w;
+            sum_q += w * q;
+            sum_q2 += w * q * q;
+            sum_x += w * x;
+            sum_xq += w * x * q;
+        }
+        let det = sum_w * sum_q2 - sum_q * sum_q;
+        if det <= 0.0 {
+            continue;
+        }
+        let d = (sum_w * sum_xq - sum_q * sum_x) / det;
+        let m = (sum_q2 * sum_x - sum_q * sum_xq) / det;
+
+        let error = weighted_error(x, weights, |i| d * q[i] as f32 + m);
+        if error < best.3 {
+            best = (d, m, q, error);
+        }
+    }
+    (best.0, best.1, best.2)
+}
+
+fn weighted_error(x: &[f32], weights: &[f32; QK], dequantize: impl Fn(usize) -> f32) -> f32 {
+    x.iter()
+        .zip(weights)
+        .enumerate()
+        .map(|(i, (x, w))| w * (x - dequantize(i)).powi(2))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_roundtrip_importance_matrix() {
+        let mut importance_matrix = ImportanceMatrix::default();
+        importance_matrix.accumulate("a.weight", 2, &[1.0, 2.0, 3.0, 4.0]);
+        importance_matrix.accumulate("b.weight", 3, &[1.0, -1.0, 0.5]);
+        assert_eq!(
+            importance_matrix.importance("a.weight"),
+            Some(vec![5.0, 10.0])
+        );
+
+        let mut buffer = vec![];
+        importance_matrix.write(&mut buffer).unwrap();
+        let read = ImportanceMatrix::read(&mut buffer.as_slice()).unwrap();
+        assert_eq!(read, importance_matrix);
+    }
+
+    #[test]
+    fn weighted_quantization_favours_important_columns() {
+        let n_elements_0 = 64;
+        let src: Vec<f32> = (0..n_elements_0 * 4)
+            .map(|i| (i as f32 * 0.37).sin() * (1.0 + (i % 7) as f32))
+            .collect();
+        let importance: Vec<f32> = (0..n_elements_0)
+            .map(|i| if i % 8 == 0 { 1000.0 } else { 1.0 })
+            .collect();
+
+        for block in [LegacyBlock::Q4_0, LegacyBlock::Q4_1] {
+            let error = |quantization_importance: &[f32]| {
+                let result = quantize_weighted(block, &src, n_elements_0, quantization_importance);
+                assert_eq!(result.output.len(), src.len() / QK * block.size());
+
+                let dequantized = dequantize_4_bit(block, &result.output);
+                src.iter()
+                    .zip(dequantized)
+                    .enumerate()
+                    .map(|(i, (x, y))| importance[i % n_elements_0] * (x - y).powi(2))
+                    .sum::<f32>()
+            };
+
+            let weighted = error(&importance);
+            let uniform = error(&vec![1.0; n_elements_0]);
+            assert!(weighted < uniform, "{block:?}: {weighted} >= {uniform}");
+        }
+    }
+
+    fn dequantize_4_bit(block: LegacyBlock, data: &[u8]) -> Vec<f32> {
+        data.chunks_exact(block.size())
+            .flat_map(|b| {
+                let d = f16::from_le_bytes([b[0], b[1]]).to_f32();
+                let (m, qs) = match block {
+                    LegacyBlock::Q4_0 => (-8.0 * d, &b[2..]),
+                    _ => (f16::from_le_bytes([b[2], b[3]]).to_f32(), &b[4..]),
+                };
+                let low = qs.iter().map(move |q| (q & 0xF) as f32 * d + m);
+                let high = qs.iter().map(move |q| (q >> 4) as f32 * d + m);
+                low.chain(high).collect::<Vec<_>>()
+            })
+            .collect()
+    }
+}
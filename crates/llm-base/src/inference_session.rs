@@ -1,6 +1,11 @@
 use ggml::{Buffer, ComputationGraph, Context, GraphExecutionPlan, Tensor};
 use serde::Serialize;
-use std::{cell::RefCell, fmt::Display, sync::Arc};
+use std::{
+    cell::RefCell,
+    fmt::Display,
+    ops::Range,
+    sync::{Arc, Mutex},
+};
 use thiserror::Error;
 use tracing::{instrument, log};
 
@@ -8,7 +13,8 @@ use tracing::{instrument, log};
 use ggml::accelerator::metal::MetalContext;
 
 use crate::{
-    mulf, util, InferenceParameters, Model, ModelParameters, OutputRequest, Prompt, TokenId,
+    mulf, util, ImportanceMatrix, InferenceParameters, MemoryLayout, Model, ModelContext,
+    ModelParameters, OutputRequest, PositionEncoding, Prompt, SessionLoraAdapter, TokenId,
     TokenUtf8Buffer, TokenizationError,
 };
 
@@ -27,6 +33,96 @@ fn scratch_buffers() -> ScratchBuffers {
     ]
 }
 
+/// The buffers used while evaluating a model. Sessions forked from one another share them
+/// (see [InferenceSession::fork]), as they are only needed during evaluation.
+struct EvaluationBuffers {
+    ctx0: Context,
+    scratch: ScratchBuffers,
+}
+impl EvaluationBuffers {
+    fn new(n_layer: usize) -> Self {
+        // Allocate buffer for storing intermediate values during evaluation (ctx0 backing)
+        // For the first run, we need to guess a maximum buffer size so we can measure
+        // the actual memory consumption of the temporary ggml context.
+        //
+        // These numbers are from `llama.cpp`, and could potentially be more efficient.
+        let buf_size = {
+            let buf_size_mb = if n_layer >= 80 {
+                1536
+            } else if n_layer >= 60 {
+                1280
+            } else {
+                1024
+            };
+            buf_size_mb * 1024 * 1024
+        };
+
+        let eval = Buffer::new(buf_size);
+        EvaluationBuffers {
+            ctx0: ggml::Context::new_with_buffer(eval),
+            scratch: scratch_buffers(),
+        }
+    }
+}
+
+/// Returns the evaluation buffers held by a session, taking them from the `pool` it shares
+/// with the sessions it was forked from or into, or allocating them if there are none left.
+fn acquire_buffers<'a>(
+    buffers: &'a mut Option<EvaluationBuffers>,
+    pool: &Mutex<Vec<EvaluationBuffers>>,
+    n_layer: usize,
+) -> &'a mut EvaluationBuffers {
+    buffers.get_or_insert_with(|| {
+        pool.lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| EvaluationBuffers::new(n_layer))
+    })
+}
+
+/// Keys and values of tokens at the start of a session that are shared with the sessions
+/// forked from it. They are never modified.
+pub(crate) struct SharedMemory {
+    // Must be kept alive for the tensors
+    _context: Arc<Context>,
+    memory_k: Tensor,
+    memory_v: Tensor,
+    /// The number of tokens each layer of the memory has room for.
+    capacity: usize,
+    /// The number of tokens in the memory.
+    n_tokens: usize,
+}
+impl Clone for SharedMemory {
+    fn clone(&self) -> Self {
+        SharedMemory {
+            _context: self._context.clone(),
+            memory_k: self.memory_k.share(),
+            memory_v: self.memory_v.share(),
+            capacity: self.capacity,
+            n_tokens: self.n_tokens,
+        }
+    }
+}
+
+/// The input tokens of a session, to be evaluated together with those of other sessions (see
+/// [KnownModel::evaluate_batch](crate::KnownModel::evaluate_batch)).
+pub struct BatchedSequence<'a> {
+    /// The session to evaluate the input tokens in.
+    pub session: &'a mut InferenceSession,
+    /// The tokens to evaluate.
+    pub input_tokens: &'a [TokenId],
+    /// The additional data to fetch from the model for the tokens.
+    pub output_request: &'a mut OutputRequest,
+}
+
+/// A part of the memory that the input tokens of a session attend to.
+struct MemoryPart<'a> {
+    memory_k: &'a Tensor,
+    memory_v: &'a Tensor,
+    capacity: usize,
+    n_tokens: usize,
+}
+
 /// Result of graph building
 pub struct GraphOutputs {
     /// The output containing the model's result
@@ -83,14 +179,39 @@ pub struct InferenceSession {
     #[doc(hidden)]
     pub last_logits: Vec<f32>,
 
+    /// The memory of the tokens at the start of the session that is shared with other
+    /// sessions, in order. The tokens in `memory_k` and `memory_v` follow them.
+    shared_memory: Vec<SharedMemory>,
+
+    /// The number of tokens each layer of `memory_k` and `memory_v` has room for.
+    memory_capacity: usize,
+
+    /// How the model stores tokens in the memory; known once the session has been forked.
+    memory_layout: Option<MemoryLayout>,
+
     #[cfg(feature = "metal")]
     metal_context: Option<MetalContext>,
 
-    ctx0: Context,
+    /// The buffers used to evaluate the model, while this session holds them.
+    buffers: Option<EvaluationBuffers>,
+
+    /// The evaluation buffers that are not in use, shared with the sessions forked from or
+    /// into this one.
+    buffer_pool: Arc<Mutex<Vec<EvaluationBuffers>>>,
 
     n_embd: usize,
 
-    scratch: ScratchBuffers,
+    n_layer: usize,
+
+    context_size: usize,
+
+    use_gpu: bool,
+
+    /// The LoRA adapters evaluated alongside the model.
+    lora_adapters: Vec<Arc<SessionLoraAdapter>>,
+
+    /// The importance matrix that the inputs of the model's weights are recorded into, if any.
+    importance_matrix: Option<ImportanceMatrix>,
 }
 
 pub struct BuildContext<'session> {
@@ -100,12 +221,363 @@ pub struct BuildContext<'session> {
     pub memory_k: &'session Tensor,
     pub memory_v: &'session Tensor,
     pub scratch: &'session ScratchBuffers,
+    pub lora_adapters: &'session [Arc<SessionLoraAdapter>],
+    pub(crate) activations: Option<&'session RefCell<Vec<RecordedActivation>>>,
+    pub(crate) memory: SessionMemory<'session>,
+}
+
+/// The context for building a graph that evaluates the input tokens of several sessions at
+/// once (see [InferenceSession::compute_batch]).
+pub struct BatchBuildContext<'session> {
+    pub ctx0: RefCell<&'session mut Context>,
+    /// The input tokens of all of the sessions, one after the other.
+    pub embd: &'session Tensor,
+    pub scratch: &'session ScratchBuffers,
+    /// The memory of each of the sessions, in order.
+    pub sequences: Vec<SessionMemory<'session>>,
+}
+impl<'session> BatchBuildContext<'session> {
+    pub fn get_scratch(&self, idx: usize) -> Option<&Buffer> {
+        Some(&self.scratch[idx])
+    }
+}
+
+/// The memory of a session that is being evaluated, which the keys and values of its input
+/// tokens are stored into and read back from.
+///
+/// The memory may be split between sessions that were forked from one another (see
+/// [InferenceSession::fork]); its methods take care of that.
+pub struct SessionMemory<'session> {
+    memory_k: &'session Tensor,
+    memory_v: &'session Tensor,
+    shared_memory: &'session [SharedMemory],
+    memory_capacity: usize,
+    n_past: usize,
+    n_embd: usize,
+    n_tokens: usize,
+    offset: usize,
+}
+
+/// A copy of the input of a weight, made while building the graph so that it can be recorded
+/// into the session's [ImportanceMatrix] once the graph has been computed.
+pub(crate) struct RecordedActivation {
+    weight_name: String,
+    // Owns the data of `tensor`, as the scratch buffers of `ctx0` are reused during computation.
+    context: Context,
+    tensor: Tensor,
 }
 
 impl<'session> BuildContext<'session> {
     pub fn get_scratch(&self, idx: usize) -> Option<&Buffer> {
         Some(&self.scratch[idx])
     }
+
+    /// Multiplies the model weight `weight` by `input` like [Context::op_mul_mat], adding the
+    /// terms of the session's LoRA adapters that patch `weight`.
+    ///
+    /// If the session is recording an [ImportanceMatrix], `input` is recorded as well.
+    pub fn lora_mul_mat(&self, ctx0: &Context, weight: &Tensor, input: &Tensor) -> Tensor {
+        let recorded;
+        let input = match self.activations {
+            Some(activations) => {
+                recorded = record_activation(ctx0, activations, weight, input);
+                &recorded
+            }
+            None => input,
+        };
+
+        let output = ctx0.op_mul_mat(weight, input);
+        if self.lora_adapters.is_empty() {
+            return output;
+        }
+
+        let name = weight.name();
+        self.lora_adapters.iter().fold(output, |output, adapter| {
+            adapter.apply(ctx0, &name, input, output)
+        })
+    }
+
+    /// Stores the keys and values of the input tokens for layer `il` in the memory of the
+    /// session. See [SessionMemory::store].
+    pub fn store_memory(
+        &self,
+        ctx0: &Context,
+        gf: &mut ComputationGraph,
+        layout: &MemoryLayout,
+        il: usize,
+        k: &Tensor,
+        v: &Tensor,
+    ) {
+        self.memory.store(ctx0, gf, layout, il, k, v)
+    }
+
+    /// Multiplies the keys of layer `il` in the memory of the session by the queries `q`. See
+    /// [SessionMemory::keys_mul_mat].
+    pub fn memory_keys_mul_mat(
+        &self,
+        ctx0: &Context,
+        gf: &mut ComputationGraph,
+        il: usize,
+        n_head: usize,
+        q: &Tensor,
+    ) -> Tensor {
+        self.memory.keys_mul_mat(ctx0, gf, il, n_head, q)
+    }
+
+    /// Multiplies the values of layer `il` in the memory of the session by the attention
+    /// weights `kq`. See [SessionMemory::values_mul_mat].
+    pub fn memory_values_mul_mat(
+        &self,
+        ctx0: &Context,
+        layout: &MemoryLayout,
+        il: usize,
+        n_head: usize,
+        kq: &Tensor,
+    ) -> Tensor {
+        self.memory.values_mul_mat(ctx0, layout, il, n_head, kq)
+    }
+}
+
+impl<'session> SessionMemory<'session> {
+    /// The number of tokens in the memory before the input tokens.
+    pub fn n_past(&self) -> usize {
+        self.n_past
+    }
+
+    /// The number of input tokens of the session.
+    pub fn n_tokens(&self) -> usize {
+        self.n_tokens
+    }
+
+    /// The position of the first input token of the session among the input tokens of the
+    /// graph; zero unless several sessions are evaluated at once.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Stores the keys `k` and values `v` of the input tokens for layer `il` in the memory of
+    /// the session, laid out as described by `layout`. Both hold `n_embd` elements for each
+    /// input token; `v` is transposed here if the layout needs it.
+    pub fn store(
+        &self,
+        ctx0: &Context,
+        gf: &mut ComputationGraph,
+        layout: &MemoryLayout,
+        il: usize,
+        k: &Tensor,
+        v: &Tensor,
+    ) {
+        let n = self.n_tokens;
+        let n_embd = self.n_embd;
+        let capacity = self.memory_capacity;
+        let position = self.n_past - self.shared_memory.iter().map(|m| m.n_tokens).sum::<usize>();
+        let memory_k_size = self.memory_k.element_size();
+        let memory_v_size = self.memory_v.element_size();
+
+        let k_memory = ctx0.op_view_1d(
+            self.memory_k,
+            n * n_embd,
+            (memory_k_size * n_embd) * (il * capacity + position),
+        );
+        gf.build_forward_expand(&ctx0.op_cpy(k, &k_memory));
+
+        if layout.transposed_values {
+            let v_memory = ctx0.op_view_2d(
+                self.memory_v,
+                (n, n_embd),
+                capacity * memory_v_size,
+                (il * capacity) * memory_v_size * n_embd + position * memory_v_size,
+            );
+            gf.build_forward_expand(&ctx0.op_cpy(&ctx0.op_transpose(v), &v_memory));
+        } else {
+            let v_memory = ctx0.op_view_1d(
+                self.memory_v,
+                n * n_embd,
+                (memory_v_size * n_embd) * (il * capacity + position),
+            );
+            gf.build_forward_expand(&ctx0.op_cpy(v, &v_memory));
+        }
+    }
+
+    /// Multiplies the keys of layer `il` of the tokens in the memory of the session, including
+    /// the input tokens, by the queries `q`, laid out as `(n_embd / n_head, n, n_head)` for `n`
+    /// input tokens.
+    ///
+    /// Returns the attention scores, laid out as `(n_past + n, n, n_head)`.
+    pub fn keys_mul_mat(
+        &self,
+        ctx0: &Context,
+        gf: &mut ComputationGraph,
+        il: usize,
+        n_head: usize,
+        q: &Tensor,
+    ) -> Tensor {
+        let n_embd = self.n_embd;
+        let scores: Vec<_> = self
+            .memory_parts()
+            .iter()
+            .map(|part| {
+                let k = ctx0.op_permute(
+                    &ctx0.op_reshape_3d(
+                        &ctx0.op_view_1d(
+                            part.memory_k,
+                            part.n_tokens * n_embd,
+                            il * part.capacity * part.memory_k.element_size() * n_embd,
+                        ),
+                        n_embd / n_head,
+                        n_head,
+                        part.n_tokens,
+                    ),
+                    (0, 2, 1, 3),
+                );
+                (ctx0.op_mul_mat(&k, q), part.n_tokens)
+            })
+            .collect();
+
+        if scores.len() == 1 {
+            return scores.into_iter().next().unwrap().0;
+        }
+
+        // Concatenate the scores of the parts of the memory.
+        let [_, n, _, _] = scores[0].0.get_ne();
+        let n_tokens = scores.iter().map(|(_, n_tokens)| n_tokens).sum();
+        let result = ctx0.new_tensor_3d(ggml::Type::F32, n_tokens, n as usize, n_head);
+        let nb = result.get_nb();
+        let mut start = 0;
+        for (part, n_tokens) in scores {
+            let view = ctx0.op_view_3d(
+                &result,
+                (n_tokens, n as usize, n_head),
+                (nb[1], nb[2]),
+                start * nb[0],
+            );
+            gf.build_forward_expand(&ctx0.op_cpy(&part, &view));
+            start += n_tokens;
+        }
+        result
+    }
+
+    /// Multiplies the values of layer `il` of the tokens in the memory of the session, laid out
+    /// as described by `layout`, by the attention weights `kq`, laid out like the scores
+    /// returned by [Self::keys_mul_mat].
+    ///
+    /// Returns the attention output, laid out as `(n_embd / n_head, n, n_head)`.
+    pub fn values_mul_mat(
+        &self,
+        ctx0: &Context,
+        layout: &MemoryLayout,
+        il: usize,
+        n_head: usize,
+        kq: &Tensor,
+    ) -> Tensor {
+        let n_embd = self.n_embd;
+        let parts = self.memory_parts();
+        let ne = kq.get_ne();
+        let nb = kq.get_nb();
+
+        let mut result: Option<Tensor> = None;
+        let mut start = 0;
+        for part in &parts {
+            let memory_v_size = part.memory_v.element_size();
+            let v = if layout.transposed_values {
+                ctx0.op_view_3d(
+                    part.memory_v,
+                    (part.n_tokens, n_embd / n_head, n_head),
+                    (
+                        part.capacity * memory_v_size,
+                        part.capacity * memory_v_size * n_embd / n_head,
+                    ),
+                    il * part.capacity * memory_v_size * n_embd,
+                )
+            } else {
+                ctx0.op_cpy(
+                    &ctx0.op_permute(
+                        &ctx0.op_reshape_3d(
+                            &ctx0.op_view_1d(
+                                part.memory_v,
+                                part.n_tokens * n_embd,
+                                il * part.capacity * memory_v_size * n_embd,
+                            ),
+                            n_embd / n_head,
+                            n_head,
+                            part.n_tokens,
+                        ),
+                        (1, 2, 0, 3),
+                    ),
+                    &ctx0.new_tensor_3d(
+                        part.memory_v.get_type(),
+                        part.n_tokens,
+                        n_embd / n_head,
+                        n_head,
+                    ),
+                )
+            };
+
+            let weights = if parts.len() == 1 {
+                kq.share()
+            } else {
+                ctx0.op_view_3d(
+                    kq,
+                    (part.n_tokens, ne[1] as usize, ne[2] as usize),
+                    (nb[1], nb[2]),
+                    start * nb[0],
+                )
+            };
+            let kqv = ctx0.op_mul_mat(&v, &weights);
+            result = Some(match result {
+                Some(result) => ctx0.op_add(&result, &kqv),
+                None => kqv,
+            });
+            start += part.n_tokens;
+        }
+        result.unwrap()
+    }
+
+    /// The parts of the memory of the session, in order, with the number of tokens in each of
+    /// them that the input tokens attend to.
+    fn memory_parts(&self) -> Vec<MemoryPart<'_>> {
+        let n_shared: usize = self.shared_memory.iter().map(|m| m.n_tokens).sum();
+        self.shared_memory
+            .iter()
+            .map(|m| MemoryPart {
+                memory_k: &m.memory_k,
+                memory_v: &m.memory_v,
+                capacity: m.capacity,
+                n_tokens: m.n_tokens,
+            })
+            .chain(std::iter::once(MemoryPart {
+                memory_k: self.memory_k,
+                memory_v: self.memory_v,
+                capacity: self.memory_capacity,
+                n_tokens: self.n_past - n_shared + self.n_tokens,
+            }))
+            .collect()
+    }
+}
+
+/// Copies `input` into a tensor of its own, and returns the copy reshaped like `input` so
+/// that the copy is part of the graph.
+fn record_activation(
+    ctx0: &Context,
+    activations: &RefCell<Vec<RecordedActivation>>,
+    weight: &Tensor,
+    input: &Tensor,
+) -> Tensor {
+    let n_columns = input.get_ne()[0] as usize;
+    let n_elements = input.nelements();
+
+    // Leave room for ggml to align the tensor data.
+    let context =
+        Context::new_with_allocate(ggml::format::tensor_size(ggml::Type::F32, n_elements) + 16);
+    let tensor = context.new_tensor_2d(ggml::Type::F32, n_columns, n_elements / n_columns);
+    let copy = ctx0.op_cpy(input, &tensor);
+    activations.borrow_mut().push(RecordedActivation {
+        weight_name: weight.name(),
+        context,
+        tensor,
+    });
+
+    ctx0.op_reshape(&copy, input)
 }
 
 unsafe impl Send for InferenceSession {}
@@ -124,74 +596,20 @@ impl InferenceSession {
             ..
         } = *params;
 
-        let context_byte_size = {
-            let mut size = 0;
-            size += mulf!(
-                context_size,
-                n_layer,
-                n_embd,
-                ggml::type_sizef(config.memory_k_type.into())
-            ); // memory_k
-            size += mulf!(
-                context_size,
-                n_layer,
-                n_embd,
-                ggml::type_sizef(config.memory_v_type.into())
-            ); // memory_v
-            size += (5 + 10 * n_layer) * 256; // object overhead
-
-            size
-        };
-
         if use_gpu {
             ggml::accelerator::initialize(0);
             ggml::accelerator::set_scratch_size(config.n_batch * 1024 * 1024);
         }
 
-        let session_ctx = Arc::new(ggml::Context::new_with_allocate(context_byte_size));
-
-        // Initialize key + value memory tensors
-        let n_mem = n_layer * context_size;
-        let n_elements = n_embd * n_mem;
-        let (memory_k, memory_v) = kv_memory(&session_ctx, &config, use_gpu, n_elements);
-
-        let scratch = scratch_buffers();
-
-        // Allocate buffer for storing intermediate values during evaluation (ctx0 backing)
-        // For the first run, we need to guess a maximum buffer size so we can measure
-        // the actual memory consumption of the temporary ggml context.
-        //
-        // These numbers are from `llama.cpp`, and could potentially be more efficient.
-        let buf_size = {
-            let buf_size_mb = if n_layer >= 80 {
-                1536
-            } else if n_layer >= 60 {
-                1280
-            } else {
-                1024
-            };
-            buf_size_mb * 1024 * 1024
-        };
+        let (session_ctx, context_byte_size, memory_k, memory_v) =
+            kv_memory(&config, use_gpu, n_layer, n_embd, context_size);
 
-        let eval = Buffer::new(buf_size);
-        let ctx0 = ggml::Context::new_with_buffer(eval);
+        let buffers = EvaluationBuffers::new(n_layer);
 
         // Set up Metal support
         #[cfg(feature = "metal")]
-        let metal_context = {
-            if use_gpu {
-                let mut metal_context = MetalContext::new(config.n_threads);
-                metal_context.add_scratch_buffer(ctx0.storage().as_buffer().unwrap());
-
-                for buf in scratch.iter() {
-                    metal_context.add_scratch_buffer(buf);
-                }
-                metal_context.add_context(session_ctx.clone());
-                Some(metal_context)
-            } else {
-                None
-            }
-        };
+        let metal_context = use_gpu
+            .then(|| metal_context(config.n_threads, &buffers, std::iter::once(&session_ctx)));
 
         InferenceSession {
             _session_ctx: session_ctx,
@@ -204,18 +622,347 @@ impl InferenceSession {
             tokens: vec![],
             decoded_tokens: vec![],
             last_logits: vec![0.0; n_vocab],
+            shared_memory: vec![],
+            memory_capacity: context_size,
+            memory_layout: None,
             #[cfg(feature = "metal")]
             metal_context,
-            ctx0,
+            buffers: Some(buffers),
+            buffer_pool: Default::default(),
             n_embd,
-            scratch,
+            n_layer,
+            context_size,
+            use_gpu,
+            lora_adapters: vec![],
+            importance_matrix: None,
+        }
+    }
+
+    /// Creates a new session that continues from the current state of this one, e.g. to explore
+    /// several continuations of the same prompt.
+    ///
+    /// For models that support it (see [Model::memory_layout]), the two sessions share the keys
+    /// and values of the tokens fed so far, and each of them only allocates memory for the
+    /// tokens fed from now on. The shared memory is never modified: rewinding past the start of
+    /// a session's own memory only forgets tokens, while removing or replacing tokens before it
+    /// (and taking a snapshot) copies the shared memory into the session's own first. For other
+    /// models, the memory is copied.
+    ///
+    /// The buffers used to evaluate the model are also shared between forked sessions, unless
+    /// they are evaluated with Metal. The importance matrix being recorded, if any, is not
+    /// carried over.
+    pub fn fork(&mut self, model: &dyn Model) -> InferenceSession {
+        let (context, memory_size, memory_k, memory_v, memory_capacity) =
+            match model.memory_layout() {
+                Some(layout) => {
+                    self.memory_layout = Some(layout);
+                    self.share_memory();
+
+                    let capacity = self.memory_capacity;
+                    let (context, memory_size, memory_k, memory_v) = kv_memory(
+                        &self.config,
+                        self.use_gpu,
+                        self.n_layer,
+                        self.n_embd,
+                        capacity,
+                    );
+                    (context, memory_size, memory_k, memory_v, capacity)
+                }
+                None => {
+                    let (context, memory_size, mut memory_k, mut memory_v) = kv_memory(
+                        &self.config,
+                        self.use_gpu,
+                        self.n_layer,
+                        self.n_embd,
+                        self.memory_capacity,
+                    );
+                    // SAFETY: The new tensors have the same size as ours, and nothing else is
+                    // using the memory of either.
+                    unsafe {
+                        memory_k.write_data(std::slice::from_raw_parts(
+                            self.memory_k.data() as *const u8,
+                            self.memory_k.nbytes(),
+                        ));
+                        memory_v.write_data(std::slice::from_raw_parts(
+                            self.memory_v.data() as *const u8,
+                            self.memory_v.nbytes(),
+                        ));
+                    }
+                    (
+                        context,
+                        memory_size,
+                        memory_k,
+                        memory_v,
+                        self.memory_capacity,
+                    )
+                }
+            };
+
+        #[cfg(feature = "metal")]
+        let (buffers, metal_context) = match &self.metal_context {
+            Some(_) => {
+                let buffers = EvaluationBuffers::new(self.n_layer);
+                let contexts = self
+                    .shared_memory
+                    .iter()
+                    .map(|m| &m._context)
+                    .chain(std::iter::once(&context));
+                let metal_context = metal_context(self.config.n_threads, &buffers, contexts);
+                (Some(buffers), Some(metal_context))
+            }
+            None => (None, None),
+        };
+        #[cfg(not(feature = "metal"))]
+        let buffers = None;
+
+        let session = InferenceSession {
+            _session_ctx: context,
+            _memory_size: memory_size,
+            config: self.config,
+            memory_k,
+            memory_v,
+            n_past: self.n_past,
+            mem_per_token: self.mem_per_token,
+            tokens: self.tokens.clone(),
+            decoded_tokens: self.decoded_tokens.clone(),
+            last_logits: self.last_logits.clone(),
+            shared_memory: self.shared_memory.clone(),
+            memory_capacity,
+            memory_layout: self.memory_layout.clone(),
+            #[cfg(feature = "metal")]
+            metal_context,
+            buffers,
+            buffer_pool: self.buffer_pool.clone(),
+            n_embd: self.n_embd,
+            n_layer: self.n_layer,
+            context_size: self.context_size,
+            use_gpu: self.use_gpu,
+            lora_adapters: self.lora_adapters.clone(),
+            importance_matrix: None,
+        };
+
+        // Let the new session use our evaluation buffers while we are not evaluating.
+        self.release_buffers();
+
+        session
+    }
+
+    /// Moves the tokens in the memory of this session into its shared memory, so that sessions
+    /// forked from it can attend to them, and allocates new memory for the tokens that follow.
+    fn share_memory(&mut self) {
+        let n_tokens = self.n_past - self.n_shared();
+        if n_tokens == 0 {
+            return;
+        }
+
+        let capacity = self.context_size - self.n_past;
+        let (context, memory_size, memory_k, memory_v) = kv_memory(
+            &self.config,
+            self.use_gpu,
+            self.n_layer,
+            self.n_embd,
+            capacity,
+        );
+        self.register_memory(&context);
+
+        self.shared_memory.push(SharedMemory {
+            _context: std::mem::replace(&mut self._session_ctx, context),
+            memory_k: std::mem::replace(&mut self.memory_k, memory_k),
+            memory_v: std::mem::replace(&mut self.memory_v, memory_v),
+            capacity: self.memory_capacity,
+            n_tokens,
+        });
+        self._memory_size = memory_size;
+        self.memory_capacity = capacity;
+    }
+
+    /// Copies the shared memory of this session into memory of its own, so that all of its
+    /// tokens can be modified.
+    fn unshare_memory(&mut self) {
+        if self.shared_memory.is_empty() {
+            return;
+        }
+
+        let context_size = self.context_size;
+        let (context, memory_size, memory_k, memory_v) = kv_memory(
+            &self.config,
+            self.use_gpu,
+            self.n_layer,
+            self.n_embd,
+            context_size,
+        );
+        self.register_memory(&context);
+
+        let n_embd = self.n_embd;
+        let transposed_values = self
+            .memory_layout
+            .as_ref()
+            .map_or(false, |layout| layout.transposed_values);
+        let memory_k_size = memory_k.element_size();
+        let memory_v_size = memory_v.element_size();
+        let parts: Vec<_> = self
+            .shared_memory
+            .iter()
+            .map(|m| (&m.memory_k, &m.memory_v, m.capacity, m.n_tokens))
+            .chain(std::iter::once((
+                &self.memory_k,
+                &self.memory_v,
+                self.memory_capacity,
+                self.n_past - self.n_shared(),
+            )))
+            .collect();
+
+        let buffers = acquire_buffers(&mut self.buffers, &self.buffer_pool, self.n_layer);
+        // Copy one layer at a time, so that the graph stays small.
+        for il in 0..self.n_layer {
+            buffers.ctx0.recreate();
+            let ctx0 = &buffers.ctx0;
+            let mut gf = ComputationGraph::new();
+
+            let mut start = 0;
+            for &(part_k, part_v, capacity, n_tokens) in &parts {
+                if n_tokens == 0 {
+                    continue;
+                }
+
+                let k_src = ctx0.op_view_1d(
+                    part_k,
+                    n_tokens * n_embd,
+                    il * capacity * n_embd * memory_k_size,
+                );
+                let k_dst = ctx0.op_view_1d(
+                    &memory_k,
+                    n_tokens * n_embd,
+                    (il * context_size + start) * n_embd * memory_k_size,
+                );
+                gf.build_forward_expand(&ctx0.op_cpy(&k_src, &k_dst));
+
+                let (v_src, v_dst) = if transposed_values {
+                    (
+                        ctx0.op_view_2d(
+                            part_v,
+                            (n_tokens, n_embd),
+                            capacity * memory_v_size,
+                            il * capacity * n_embd * memory_v_size,
+                        ),
+                        ctx0.op_view_2d(
+                            &memory_v,
+                            (n_tokens, n_embd),
+                            context_size * memory_v_size,
+                            il * context_size * n_embd * memory_v_size + start * memory_v_size,
+                        ),
+                    )
+                } else {
+                    (
+                        ctx0.op_view_1d(
+                            part_v,
+                            n_tokens * n_embd,
+                            il * capacity * n_embd * memory_v_size,
+                        ),
+                        ctx0.op_view_1d(
+                            &memory_v,
+                            n_tokens * n_embd,
+                            (il * context_size + start) * n_embd * memory_v_size,
+                        ),
+                    )
+                };
+                gf.build_forward_expand(&ctx0.op_cpy(&v_src, &v_dst));
+
+                start += n_tokens;
+            }
+
+            let mut plan = GraphExecutionPlan::new(&mut gf, self.config.n_threads);
+            plan.execute(ctx0);
+        }
+
+        self._session_ctx = context;
+        self._memory_size = memory_size;
+        self.memory_k = memory_k;
+        self.memory_v = memory_v;
+        self.memory_capacity = context_size;
+        self.shared_memory.clear();
+        self.release_buffers();
+    }
+
+    /// Forgets the memory of the tokens from position `n_past` onwards. Shared memory past it
+    /// is dropped instead of being modified, and new memory is allocated for the tokens that
+    /// follow.
+    fn forget_memory(&mut self, n_past: usize) {
+        if n_past >= self.n_shared() {
+            return;
+        }
+
+        let mut n_kept = 0;
+        self.shared_memory.retain_mut(|m| {
+            m.n_tokens = m.n_tokens.min(n_past - n_kept);
+            n_kept += m.n_tokens;
+            m.n_tokens > 0
+        });
+
+        let capacity = self.context_size - n_past;
+        let (context, memory_size, memory_k, memory_v) = kv_memory(
+            &self.config,
+            self.use_gpu,
+            self.n_layer,
+            self.n_embd,
+            capacity,
+        );
+        self.register_memory(&context);
+
+        self._session_ctx = context;
+        self._memory_size = memory_size;
+        self.memory_k = memory_k;
+        self.memory_v = memory_v;
+        self.memory_capacity = capacity;
+    }
+
+    /// The number of tokens in the shared memory of this session.
+    fn n_shared(&self) -> usize {
+        self.shared_memory.iter().map(|m| m.n_tokens).sum()
+    }
+
+    /// Makes newly-allocated memory available to Metal, if it is in use.
+    fn register_memory(&mut self, #[allow(unused_variables)] context: &Arc<Context>) {
+        #[cfg(feature = "metal")]
+        if let Some(ref mut metal_context) = self.metal_context {
+            metal_context.add_context(context.clone());
+        }
+    }
+
+    /// Evaluates `input_tokens` with `model`, and lets the sessions this one was forked from or
+    /// into use the evaluation buffers afterwards.
+    fn evaluate(
+        &mut self,
+        model: &dyn Model,
+        input_tokens: &[TokenId],
+        output_request: &mut OutputRequest,
+    ) {
+        model.evaluate(self, input_tokens, output_request);
+        self.release_buffers();
+    }
+
+    /// Returns the evaluation buffers of this session to the pool it shares with the sessions
+    /// it was forked from or into. A session that is on its own keeps them.
+    fn release_buffers(&mut self) {
+        #[cfg(feature = "metal")]
+        if self.metal_context.is_some() {
+            // Metal only knows about the buffers of this session.
+            return;
+        }
+
+        if Arc::strong_count(&self.buffer_pool) == 1 {
+            self.buffer_pool.lock().unwrap().clear();
+            return;
+        }
+        if let Some(buffers) = self.buffers.take() {
+            self.buffer_pool.lock().unwrap().push(buffers);
         }
     }
 
     /// Compute a model (possibly building a graph in the provided closure when called for the first time and/or when parameters have)
     pub fn compute<F>(
         &mut self,
-        #[allow(unused_variables)] model_context: Arc<Context>,
+        #[allow(unused_variables)] model_context: &ModelContext,
         input_tokens: &[TokenId],
         builder: F,
     ) -> GraphOutputs
@@ -223,18 +970,32 @@ impl InferenceSession {
         F: FnOnce(BuildContext) -> (ComputationGraph, GraphOutputs),
     {
         // Build a graph
-        self.ctx0.recreate();
-        let ctx0 = &mut self.ctx0;
+        let buffers = acquire_buffers(&mut self.buffers, &self.buffer_pool, self.n_layer);
+        buffers.ctx0.recreate();
+        let ctx0 = &mut buffers.ctx0;
         let mut embd = ctx0
             .new_tensor_1d(ggml::Type::I32, input_tokens.len())
             .set_name("embd");
 
+        let activations = RefCell::new(vec![]);
         let bc = BuildContext {
             ctx0: RefCell::new(ctx0),
             embd: &embd,
             memory_k: &self.memory_k,
             memory_v: &self.memory_v,
-            scratch: &mut self.scratch,
+            scratch: &buffers.scratch,
+            lora_adapters: &self.lora_adapters,
+            activations: self.importance_matrix.is_some().then_some(&activations),
+            memory: SessionMemory {
+                memory_k: &self.memory_k,
+                memory_v: &self.memory_v,
+                shared_memory: &self.shared_memory,
+                memory_capacity: self.memory_capacity,
+                n_past: self.n_past,
+                n_embd: self.n_embd,
+                n_tokens: input_tokens.len(),
+                offset: 0,
+            },
         };
         let (mut built_gf, built_result) = builder(bc);
 
@@ -242,7 +1003,10 @@ impl InferenceSession {
         #[cfg(feature = "metal")]
         {
             if let Some(ref mut metal_context) = self.metal_context {
-                metal_context.add_context(model_context);
+                metal_context.add_context(model_context.context.clone());
+                for adapter in &self.lora_adapters {
+                    metal_context.add_context(adapter.context.clone());
+                }
             }
         }
 
@@ -275,6 +1039,22 @@ impl InferenceSession {
             plan.execute(ctx0);
         }
 
+        // Record the inputs of the weights
+        if let Some(importance_matrix) = &mut self.importance_matrix {
+            for RecordedActivation {
+                weight_name,
+                context: _context,
+                tensor,
+            } in activations.into_inner()
+            {
+                let mut data = vec![0.0f32; tensor.nelements()];
+                // SAFETY: `tensor` is an F32 tensor of `data.len()` elements, and its data is
+                // owned by `_context`, which is alive.
+                unsafe { tensor.read_data(0, bytemuck::cast_slice_mut(&mut data)) };
+                importance_matrix.accumulate(&weight_name, tensor.get_ne()[0] as usize, &data);
+            }
+        }
+
         // Adjust the required memory per token if we didn't know that already
         if self.mem_per_token == 0 {
             self.mem_per_token = ctx0.used_mem() / self.n_embd;
@@ -290,6 +1070,111 @@ impl InferenceSession {
         }
     }
 
+    /// Compute the input tokens of several sessions of a model at once, building the graph
+    /// that evaluates all of them in the provided closure. The closure returns the outputs of
+    /// each session, in order.
+    ///
+    /// The sessions are evaluated with the evaluation buffers and the configuration of the
+    /// first one. Returns `None` without building a graph if they cannot be evaluated together:
+    /// if there are none, if any of them has no input tokens, or if any of them evaluates LoRA
+    /// adapters, records an importance matrix or is evaluated with Metal. Models should then
+    /// [evaluate](crate::KnownModel::evaluate) the sessions one by one.
+    pub fn compute_batch<F>(
+        sequences: &mut [BatchedSequence],
+        builder: F,
+    ) -> Option<Vec<GraphOutputs>>
+    where
+        F: FnOnce(BatchBuildContext) -> (ComputationGraph, Vec<GraphOutputs>),
+    {
+        let can_batch = |sequence: &BatchedSequence| {
+            let session = &sequence.session;
+            #[cfg(feature = "metal")]
+            if session.metal_context.is_some() {
+                return false;
+            }
+            !sequence.input_tokens.is_empty()
+                && session.lora_adapters.is_empty()
+                && session.importance_matrix.is_none()
+        };
+        if sequences.is_empty() || !sequences.iter().all(can_batch) {
+            return None;
+        }
+
+        let input_tokens: Vec<TokenId> = sequences
+            .iter()
+            .flat_map(|sequence| sequence.input_tokens.iter().copied())
+            .collect();
+
+        // Build a graph, with the buffers of the first session
+        let first = &mut sequences[0].session;
+        let n_threads = first.config.n_threads;
+        acquire_buffers(&mut first.buffers, &first.buffer_pool, first.n_layer);
+        let mut buffers = first.buffers.take().unwrap();
+        buffers.ctx0.recreate();
+
+        let (mut built_gf, built_outputs) = {
+            let ctx0 = &mut buffers.ctx0;
+            let mut embd = ctx0
+                .new_tensor_1d(ggml::Type::I32, input_tokens.len())
+                .set_name("embd");
+
+            let mut offset = 0;
+            let memories = sequences
+                .iter()
+                .map(|sequence| {
+                    let memory = sequence.session.memory(sequence.input_tokens.len(), offset);
+                    offset += sequence.input_tokens.len();
+                    memory
+                })
+                .collect();
+
+            let bc = BatchBuildContext {
+                ctx0: RefCell::new(ctx0),
+                embd: &embd,
+                scratch: &buffers.scratch,
+                sequences: memories,
+            };
+            let built = builder(bc);
+
+            // Write input tokens
+            unsafe { embd.write_data(bytemuck::cast_slice(&input_tokens)) };
+
+            built
+        };
+
+        // Compute the graph
+        for outputs in &built_outputs {
+            built_gf.build_forward_expand(&outputs.result);
+        }
+        let mut plan = GraphExecutionPlan::new(&mut built_gf, n_threads);
+        plan.execute(&buffers.ctx0);
+
+        // Adjust n_past to new length.
+        for sequence in sequences.iter_mut() {
+            sequence.session.n_past += sequence.input_tokens.len();
+        }
+
+        // The outputs stay valid in the buffers of the first session.
+        sequences[0].session.buffers = Some(buffers);
+
+        Some(built_outputs)
+    }
+
+    /// The memory of this session, for building a graph that evaluates `n_tokens` input tokens
+    /// starting at `offset` among the input tokens of the graph.
+    fn memory(&self, n_tokens: usize, offset: usize) -> SessionMemory<'_> {
+        SessionMemory {
+            memory_k: &self.memory_k,
+            memory_v: &self.memory_v,
+            shared_memory: &self.shared_memory,
+            memory_capacity: self.memory_capacity,
+            n_past: self.n_past,
+            n_embd: self.n_embd,
+            n_tokens,
+            offset,
+        }
+    }
+
     /// Feed a prompt to the model for this session.
     #[instrument(skip_all)]
     pub fn feed_prompt<'a, E: std::error::Error + Send + Sync + 'static, P: Into<Prompt<'a>>>(
@@ -304,12 +1189,15 @@ impl InferenceSession {
         let vocab = model.tokenizer();
         let prompt_tokens = prompt.into().to_tokens(vocab, beginning_of_sentence)?;
 
-        if self.n_past + prompt_tokens.len() >= model.context_size() {
+        if self.config.context_overflow == ContextOverflowPolicy::Error
+            && self.n_past + prompt_tokens.len() >= model.context_size()
+        {
             return Err(InferenceError::ContextFull);
         }
 
         'outer: for batch in prompt_tokens.chunks(self.config.n_batch) {
-            model.evaluate(self, batch, output_request);
+            self.make_room(model, batch.len())?;
+            self.evaluate(model, batch, output_request);
             for &tk in batch {
                 let should_call_callback = Some(tk) != model.bot_token_id();
 
@@ -368,10 +1256,268 @@ impl InferenceSession {
 
         // Decrement the n_past tokens counter.
         self.n_past -= num;
+        self.forget_memory(self.n_past);
 
         Ok(deleted_tokens)
     }
 
+    /// Makes room in the context window for `n_tokens` more tokens, as configured by
+    /// [InferenceSessionConfig::context_overflow].
+    fn make_room(&mut self, model: &dyn Model, n_tokens: usize) -> Result<(), InferenceError> {
+        let context_size = model.context_size();
+        if self.n_past + n_tokens < context_size {
+            return Ok(());
+        }
+
+        let ContextOverflowPolicy::Shift { n_keep, n_discard } = self.config.context_overflow
+        else {
+            return Err(InferenceError::ContextFull);
+        };
+        let layout = model.memory_layout().ok_or(InferenceError::ContextFull)?;
+
+        // Discard at least as many tokens as are needed to fit the new ones.
+        let n_movable = self.n_past.min(self.tokens.len()).saturating_sub(n_keep);
+        let n_needed = self.n_past + n_tokens + 1 - context_size;
+        let n_discard = n_discard.unwrap_or(n_movable / 2).max(n_needed);
+        if n_discard > n_movable {
+            return Err(InferenceError::ContextFull);
+        }
+
+        log::trace!("Discarding {n_discard} tokens after the first {n_keep} to make room");
+        self.splice(
+            model,
+            &layout,
+            n_keep..n_keep + n_discard,
+            &[],
+            &mut Default::default(),
+        );
+
+        Ok(())
+    }
+
+    /// Removes the tokens in `range` from this session, moving the tokens after them back.
+    ///
+    /// This is like [rewind](Self::rewind), but the tokens can be anywhere in the session,
+    /// e.g. a retracted turn in the middle of a conversation. The tokens after `range` are
+    /// not evaluated again: their keys and values are moved in the memory as they are (with
+    /// the keys of rotary models rotated to their new positions), so they keep what they had
+    /// attended to in the removed tokens.
+    ///
+    /// Returns the removed tokens.
+    pub fn remove_range(
+        &mut self,
+        model: &dyn Model,
+        range: Range<usize>,
+    ) -> Result<Vec<TokenId>, RewindError> {
+        let layout = self.range_layout(model, &range)?;
+
+        Ok(self.splice(model, &layout, range, &[], &mut Default::default()))
+    }
+
+    /// Replaces the tokens in `range` in this session with `tokens`, which are evaluated at
+    /// the positions of the replaced tokens, e.g. to swap the system prompt of a conversation.
+    ///
+    /// The tokens after `range` are moved like with [remove_range](Self::remove_range), and
+    /// the [OutputRequest] receives the outputs of evaluating `tokens`. The logits of the last
+    /// token of the session are kept unless it is replaced.
+    ///
+    /// Returns the replaced tokens.
+    pub fn replace_range(
+        &mut self,
+        model: &dyn Model,
+        range: Range<usize>,
+        tokens: &[TokenId],
+        output_request: &mut OutputRequest,
+    ) -> Result<Vec<TokenId>, RewindError> {
+        let layout = self.range_layout(model, &range)?;
+        if self.n_past - range.len() + tokens.len() >= model.context_size() {
+            return Err(RewindError::ContextFull);
+        }
+
+        Ok(self.splice(model, &layout, range, tokens, output_request))
+    }
+
+    /// Checks that the tokens in `range` can be removed from this session, and returns how the
+    /// model stores them.
+    fn range_layout(
+        &self,
+        model: &dyn Model,
+        range: &Range<usize>,
+    ) -> Result<MemoryLayout, RewindError> {
+        let layout = model
+            .memory_layout()
+            .filter(|_| model.supports_remove_range())
+            .ok_or(RewindError::UnsupportedArchitecture)?;
+
+        // The tokens of a batch are evaluated before they are recorded, so a prompt that was
+        // halted may have recorded fewer tokens than it evaluated.
+        let n_tokens = self.n_past.min(self.tokens.len());
+        if range.start > range.end || range.end > n_tokens {
+            return Err(RewindError::InvalidRange {
+                range: range.clone(),
+                n_tokens,
+            });
+        }
+
+        Ok(layout)
+    }
+
+    /// Replaces the tokens in `range` with `tokens` in the memory and in the tokens of this
+    /// session, and returns the replaced tokens.
+    fn splice(
+        &mut self,
+        model: &dyn Model,
+        layout: &MemoryLayout,
+        range: Range<usize>,
+        tokens: &[TokenId],
+        output_request: &mut OutputRequest,
+    ) -> Vec<TokenId> {
+        let n_after = self.n_past - range.end;
+        if range.start < self.n_shared() {
+            self.unshare_memory();
+        }
+        self.move_memory(layout, range.end, range.start + tokens.len());
+
+        // Evaluate the new tokens in the room made for them.
+        if !tokens.is_empty() {
+            let last_logits = self.last_logits.clone();
+            self.n_past = range.start;
+            for batch in tokens.chunks(self.config.n_batch) {
+                self.evaluate(model, batch, output_request);
+            }
+            if n_after > 0 {
+                self.last_logits = last_logits;
+            }
+        }
+        self.n_past = range.start + tokens.len() + n_after;
+
+        match model.tokenizer() {
+            crate::Tokenizer::Embedded(tokenizer) => {
+                // Replace the corresponding chars in decoded, counting from the end like
+                // `rewind`.
+                let token_len = |id: &TokenId| tokenizer.token(*id as usize).len();
+                let after_len: usize = self.tokens[range.end..].iter().map(token_len).sum();
+                let replaced_len: usize = self.tokens[range.clone()].iter().map(token_len).sum();
+                let decoded_end = self.decoded_tokens.len().saturating_sub(after_len);
+                let decoded_start = decoded_end.saturating_sub(replaced_len);
+                let decoded = tokens
+                    .iter()
+                    .flat_map(|id| tokenizer.token(*id as usize))
+                    .collect::<Vec<_>>();
+                self.decoded_tokens
+                    .splice(decoded_start..decoded_end, decoded);
+
+                self.tokens.splice(range, tokens.iter().copied()).collect()
+            }
+            crate::Tokenizer::HuggingFace(_) => {
+                let replaced = self.tokens.splice(range, tokens.iter().copied()).collect();
+
+                // Hugging Face tokenizers decode a token differently depending on the tokens
+                // before it, so decode all of them again, leaving out an incomplete character
+                // at the end like `get_newly_decoded_portion_huggingface`.
+                let decoded = model.tokenizer().decode(self.tokens.clone(), true);
+                self.decoded_tokens = String::from_utf8_lossy(&decoded)
+                    .trim_end_matches('\u{FFFD}')
+                    .as_bytes()
+                    .to_vec();
+
+                replaced
+            }
+        }
+    }
+
+    /// Moves the keys and values of the tokens from position `from` onwards in the memory to
+    /// position `to`, neither of which may be in the shared memory. Rotary keys are rotated to
+    /// their new positions.
+    fn move_memory(&mut self, layout: &MemoryLayout, from: usize, to: usize) {
+        let n_move = self.n_past - from;
+        if n_move == 0 || from == to {
+            return;
+        }
+
+        let n_embd = self.n_embd;
+        let context_size = self.memory_capacity;
+        let memory_k_size = self.memory_k.element_size();
+        let memory_v_size = self.memory_v.element_size();
+        let shift = to as isize - from as isize;
+        // Positions in the memory of this session
+        let n_shared = self.n_shared();
+        let (from, to) = (from - n_shared, to - n_shared);
+
+        // Move one layer at a time, so that the copies of the tokens fit in `ctx0`.
+        let buffers = acquire_buffers(&mut self.buffers, &self.buffer_pool, self.n_layer);
+        for il in 0..self.n_layer {
+            buffers.ctx0.recreate();
+            let ctx0 = &buffers.ctx0;
+            let mut gf = ComputationGraph::new();
+
+            // The tokens are copied out before they are written back, as the source and the
+            // destination can overlap.
+            let k_offset = il * context_size * n_embd * memory_k_size;
+            let k_src = ctx0.op_view_1d(
+                &self.memory_k,
+                n_move * n_embd,
+                k_offset + from * n_embd * memory_k_size,
+            );
+            let k_dst = ctx0.op_view_1d(
+                &self.memory_k,
+                n_move * n_embd,
+                k_offset + to * n_embd * memory_k_size,
+            );
+            let mut keys = ctx0.op_cpy(
+                &k_src,
+                &ctx0.new_tensor_1d(ggml::Type::F32, n_move * n_embd),
+            );
+            if let PositionEncoding::Rotary {
+                n_embd_head,
+                n_rot,
+                mode,
+                overrides,
+            } = &layout.position_encoding
+            {
+                keys = ctx0.op_rope_shift_inplace(
+                    &ctx0.op_reshape_2d(&keys, *n_embd_head, n_move * n_embd / n_embd_head),
+                    shift,
+                    *n_rot,
+                    *mode,
+                    overrides.as_ref(),
+                );
+            }
+            gf.build_forward_expand(&ctx0.op_cpy(&keys, &k_dst));
+
+            let v_offset = il * context_size * n_embd * memory_v_size;
+            let (v_src, v_dst) = if layout.transposed_values {
+                let view = |token| {
+                    ctx0.op_view_2d(
+                        &self.memory_v,
+                        (n_move, n_embd),
+                        context_size * memory_v_size,
+                        v_offset + token * memory_v_size,
+                    )
+                };
+                (view(from), view(to))
+            } else {
+                let view = |token| {
+                    ctx0.op_view_1d(
+                        &self.memory_v,
+                        n_move * n_embd,
+                        v_offset + token * n_embd * memory_v_size,
+                    )
+                };
+                (view(from), view(to))
+            };
+            let values = ctx0.op_cpy(
+                &v_src,
+                &ctx0.new_tensor_1d(ggml::Type::F32, n_move * n_embd),
+            );
+            gf.build_forward_expand(&ctx0.op_cpy(&values, &v_dst));
+
+            let mut plan = GraphExecutionPlan::new(&mut gf, self.config.n_threads);
+            plan.execute(ctx0);
+        }
+        self.release_buffers();
+    }
+
     /// Infer the next token for this session.
     #[instrument(level = "trace", skip_all)]
     pub fn infer_next_token(
@@ -381,9 +1527,72 @@ impl InferenceSession {
         output_request: &mut OutputRequest,
         rng: &mut impl rand::Rng,
     ) -> Result<Vec<u8>, InferenceError> {
-        if self.n_past + 1 >= model.context_size() {
-            return Err(InferenceError::ContextFull);
+        let next_token = self.sample_next_token(model, params, rng)?;
+
+        // Then, evaluate the network again to compute the new last_logits
+        self.evaluate(model, &[next_token], output_request);
+
+        self.decode_next_token(model, next_token)
+    }
+
+    /// Infer the next token for each of `sessions`, evaluating all of them at once (see
+    /// [Model::evaluate_batch]), e.g. for the concurrent users of a server.
+    ///
+    /// Returns the result of each session, in order, as [Self::infer_next_token] would.
+    #[instrument(level = "trace", skip_all)]
+    pub fn infer_next_token_batch(
+        model: &dyn Model,
+        sessions: &mut [&mut InferenceSession],
+        params: &InferenceParameters,
+        rng: &mut impl rand::Rng,
+    ) -> Vec<Result<Vec<u8>, InferenceError>> {
+        let next_tokens: Vec<_> = sessions
+            .iter_mut()
+            .map(|session| session.sample_next_token(model, params, rng))
+            .collect();
+
+        // Then, evaluate the network again to compute the new last_logits
+        let mut output_requests: Vec<OutputRequest> = std::iter::repeat_with(Default::default)
+            .take(sessions.len())
+            .collect();
+        let mut sequences: Vec<_> = sessions
+            .iter_mut()
+            .zip(&next_tokens)
+            .zip(&mut output_requests)
+            .filter_map(|((session, next_token), output_request)| {
+                Some(BatchedSequence {
+                    session,
+                    input_tokens: std::slice::from_ref(next_token.as_ref().ok()?),
+                    output_request,
+                })
+            })
+            .collect();
+        Self::evaluate_batch(model, &mut sequences);
+
+        sessions
+            .iter_mut()
+            .zip(next_tokens)
+            .map(|(session, next_token)| session.decode_next_token(model, next_token?))
+            .collect()
+    }
+
+    /// Evaluates the input tokens of several sessions at once with `model`, and lets the
+    /// sessions they were forked from or into use the evaluation buffers afterwards.
+    pub(crate) fn evaluate_batch(model: &dyn Model, sequences: &mut [BatchedSequence]) {
+        model.evaluate_batch(sequences);
+        for sequence in sequences {
+            sequence.session.release_buffers();
         }
+    }
+
+    /// Makes room for the next token and samples it, adding it to the tokens of this session.
+    fn sample_next_token(
+        &mut self,
+        model: &dyn Model,
+        params: &InferenceParameters,
+        rng: &mut impl rand::Rng,
+    ) -> Result<TokenId, InferenceError> {
+        self.make_room(model, 1)?;
 
         let next_token = crate::samplers::sample_token(
             params.sampler.clone(),
@@ -396,9 +1605,15 @@ impl InferenceSession {
         // Update the tokens for this session
         self.tokens.push(next_token);
 
-        // Then, evaluate the network again to compute the new last_logits
-        model.evaluate(self, &[next_token], output_request);
+        Ok(next_token)
+    }
 
+    /// Decodes the next token once it has been evaluated.
+    pub(crate) fn decode_next_token(
+        &mut self,
+        model: &dyn Model,
+        next_token: TokenId,
+    ) -> Result<Vec<u8>, InferenceError> {
         // Return the next token
         if next_token as TokenId == model.eot_token_id() {
             Err(InferenceError::EndOfText)
@@ -536,6 +1751,10 @@ impl InferenceSession {
             let start = i * context_size;
             let end = (i + 1) * context_size;
 
+            // Evaluate each chunk from an empty context, as it fills the whole context.
+            self.forget_memory(0);
+            self.n_past = 0;
+
             let num_batches = (context_size + n_batch - 1) / n_batch;
 
             let mut logits = vec![];
@@ -557,8 +1776,8 @@ impl InferenceSession {
                     tokens[batch_start] = model.bot_token_id().unwrap_or(1);
                 }
 
-                model.evaluate(
-                    self,
+                self.evaluate(
+                    model,
                     &tokens[batch_start..batch_start + batch_size],
                     &mut output_request,
                 );
@@ -593,6 +1812,8 @@ impl InferenceSession {
     /// ggml context. While the provided `InferenceSnapshotRef` object is alive,
     /// no other methods for this model object should be called.
     pub unsafe fn get_snapshot(&mut self) -> InferenceSnapshotRef<'_> {
+        self.unshare_memory();
+
         let memory_k = unsafe {
             std::slice::from_raw_parts(self.memory_k.data() as *mut u8, self.memory_k.nbytes())
         };
@@ -650,10 +1871,46 @@ impl InferenceSession {
     pub fn decoded_tokens(&self) -> &[u8] {
         self.decoded_tokens.as_ref()
     }
+
+    /// The LoRA adapters evaluated alongside the model in this session.
+    pub fn lora_adapters(&self) -> &[Arc<SessionLoraAdapter>] {
+        &self.lora_adapters
+    }
+
+    /// Sets the LoRA adapters to evaluate alongside the model in this session. They apply to
+    /// the tokens fed from now on; tokens already in the session's memory are not re-evaluated.
+    ///
+    /// Unlike the adapters applied to a [Model] (see [ModelContext]), these do not modify the
+    /// weights of the model, so other sessions of the same model are unaffected.
+    pub fn set_lora_adapters(&mut self, lora_adapters: Vec<Arc<SessionLoraAdapter>>) {
+        self.lora_adapters = lora_adapters;
+    }
+
+    /// The importance matrix that this session records the inputs of the model's weights into.
+    pub fn importance_matrix(&self) -> Option<&ImportanceMatrix> {
+        self.importance_matrix.as_ref()
+    }
+
+    /// Sets the importance matrix to record the inputs of the model's weights into whenever the
+    /// model is evaluated, e.g. by [perplexity](Self::perplexity) over calibration text. Pass
+    /// `None` to stop recording.
+    ///
+    /// Recording is only supported when the model is evaluated on the CPU.
+    pub fn set_importance_matrix(&mut self, importance_matrix: Option<ImportanceMatrix>) {
+        self.importance_matrix = importance_matrix;
+    }
+
+    /// Stops recording, and returns the importance matrix that was recorded into.
+    pub fn take_importance_matrix(&mut self) -> Option<ImportanceMatrix> {
+        self.importance_matrix.take()
+    }
 }
 
 impl Drop for InferenceSession {
     fn drop(&mut self) {
+        // Let the sessions this one was forked from or into use the evaluation buffers.
+        self.release_buffers();
+
         // If we are using an accelerator, we need to free the scratch memory.
         // The k/v memory is freed by the ctx0 destructor.
         ggml::accelerator::free_scratch();
@@ -683,7 +1940,8 @@ pub enum InferenceError {
     /// A tokenization-related failure occurred.
     TokenizationFailed(#[from] TokenizationError),
     #[error("the context window is full")]
-    /// The context window for the model is full.
+    /// The context window for the model is full, and no room could be made in it as configured
+    /// by [InferenceSessionConfig::context_overflow].
     ContextFull,
     #[error("reached end of text")]
     /// The model has produced an end of text token, signalling that it thinks that the text should end here.
@@ -708,6 +1966,19 @@ pub enum RewindError {
     /// Model architecture does not support delete
     #[error("model architecture does not support deletes")]
     UnsupportedArchitecture,
+
+    /// The range of tokens to remove or replace is not within the session
+    #[error("the range {range:?} is not within the {n_tokens} tokens of the session")]
+    InvalidRange {
+        /// The range of tokens.
+        range: Range<usize>,
+        /// The number of tokens in the session that have been both evaluated and recorded.
+        n_tokens: usize,
+    },
+
+    /// The replacement tokens do not fit in the context window
+    #[error("the replacement tokens do not fit in the context window")]
+    ContextFull,
 }
 
 #[derive(Error, Debug)]
@@ -823,6 +2094,12 @@ pub struct InferenceSessionConfig {
     /// A reasonable default value is 8, as most modern high-performance computers have
     /// 8 physical cores. Adjust to your needs.
     pub n_threads: usize,
+    /// What to do when the context window is full.
+    ///
+    /// By default, [InferenceSession::feed_prompt] and [InferenceSession::infer_next_token]
+    /// return [InferenceError::ContextFull].
+    #[serde(default)]
+    pub context_overflow: ContextOverflowPolicy,
 }
 
 impl Default for InferenceSessionConfig {
@@ -832,10 +2109,36 @@ impl Default for InferenceSessionConfig {
             memory_v_type: ModelKVMemoryType::Float16,
             n_batch: 8,
             n_threads: 8,
+            context_overflow: ContextOverflowPolicy::Error,
         }
     }
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+/// What an [InferenceSession] does when its context window is full.
+pub enum ContextOverflowPolicy {
+    /// Return [InferenceError::ContextFull].
+    #[default]
+    Error,
+    /// Discard the oldest tokens after the first `n_keep` tokens to make room, and move the
+    /// tokens after them back in the session's memory, so that generation can continue
+    /// indefinitely.
+    ///
+    /// Keeping the first tokens (e.g. a system prompt, or a handful of "attention sink"
+    /// tokens) maintains the quality of the generated text much better than discarding them.
+    ///
+    /// This requires the model to describe its [memory layout](crate::Model::memory_layout);
+    /// for other models, [InferenceError::ContextFull] is still returned.
+    Shift {
+        /// The number of tokens at the start of the context that are never discarded.
+        n_keep: usize,
+        /// The number of tokens to discard at once. If `None`, half of the tokens after the
+        /// first `n_keep` are discarded. More tokens are discarded if needed to make room for
+        /// the tokens being fed.
+        n_discard: Option<usize>,
+    },
+}
+
 #[derive(Debug, Clone, Copy)]
 /// Settings specific to [InferenceSession::infer].
 pub struct InferenceRequest<'a> {
@@ -988,13 +2291,42 @@ pub fn conversation_inference_callback<'a, E: std::error::Error + Send + Sync +
     }
 }
 
-/// Create the memory K/V tensors for the inference-session.
+/// Create the context and the memory K/V tensors for the inference-session, with room for
+/// `capacity` tokens in each layer. Also returns the size of the context.
 fn kv_memory(
-    context: &Context,
     config: &InferenceSessionConfig,
     use_gpu: bool,
-    n_elements: usize,
-) -> (Tensor, Tensor) {
+    n_layer: usize,
+    n_embd: usize,
+    capacity: usize,
+) -> (Arc<Context>, usize, Tensor, Tensor) {
+    let context_byte_size = {
+        let mut size = 0;
+        size += mulf!(
+            capacity,
+            n_layer,
+            n_embd,
+            ggml::type_sizef(config.memory_k_type.into())
+        ); // memory_k
+        size += mulf!(
+            capacity,
+            n_layer,
+            n_embd,
+            ggml::type_sizef(config.memory_v_type.into())
+        ); // memory_v
+        size += (5 + 10 * n_layer) * 256; // object overhead
+
+        size
+    };
+
+    // The memory can be shared by the forks of a session, which are `Send` and may be moved to
+    // other threads. Once it is shared, it is only read from.
+    #[allow(clippy::arc_with_non_send_sync)]
+    let context = Arc::new(ggml::Context::new_with_allocate(context_byte_size));
+
+    // Initialize key + value memory tensors
+    let n_mem = n_layer * capacity;
+    let n_elements = n_embd * n_mem;
     let memory_k = context
         .new_tensor_1d(config.memory_k_type.into(), n_elements)
         .set_name("memory_k");
@@ -1013,5 +2345,25 @@ fn kv_memory(
         memory_v.offload_no_scratch();
     }
 
-    (memory_k, memory_v)
+    (context, context_byte_size, memory_k, memory_v)
+}
+
+/// Create the Metal context for evaluating a session with `buffers` and the memory in
+/// `contexts`.
+#[cfg(feature = "metal")]
+fn metal_context<'a>(
+    n_threads: usize,
+    buffers: &EvaluationBuffers,
+    contexts: impl Iterator<Item = &'a Arc<Context>>,
+) -> MetalContext {
+    let mut metal_context = MetalContext::new(n_threads);
+    metal_context.add_scratch_buffer(buffers.ctx0.storage().as_buffer().unwrap());
+
+    for buf in buffers.scratch.iter() {
+        metal_context.add_scratch_buffer(buf);
+    }
+    for context in contexts {
+        metal_context.add_context(context.clone());
+    }
+    metal_context
 }
@@ -7,11 +7,17 @@
 //! As a user, you probably want to use the [llm](https://crates.io/crates/llm) crate instead.
 #![deny(missing_docs)]
 
+mod beam_search;
+mod convert;
+mod imatrix;
 mod inference_session;
 mod loader;
 mod lora;
+mod merge_lora;
 mod quantize;
+mod safetensors;
 mod tokenizer;
+mod upgrade;
 
 pub mod model;
 pub mod samplers;
@@ -19,29 +25,42 @@ pub mod util;
 
 use std::sync::{Arc, Mutex};
 
+pub use beam_search::{beam_search, BeamSearchHypothesis, BeamSearchParameters};
+pub use convert::{convert, ConvertError, ConvertProgress, HfConfig, TensorTransform};
 pub use ggml;
 pub use ggml::Type as ElementType;
+pub use imatrix::ImportanceMatrix;
 
 pub use inference_session::{
-    conversation_inference_callback, feed_prompt_callback, GraphOutputs, InferenceError,
-    InferenceFeedback, InferenceRequest, InferenceResponse, InferenceSession,
-    InferenceSessionConfig, InferenceSnapshot, InferenceSnapshotRef, InferenceStats,
-    ModelKVMemoryType, RewindError, SnapshotError,
+    conversation_inference_callback, feed_prompt_callback, BatchedSequence, ContextOverflowPolicy,
+    GraphOutputs, InferenceError, InferenceFeedback, InferenceRequest, InferenceResponse,
+    InferenceSession, InferenceSessionConfig, InferenceSnapshot, InferenceSnapshotRef,
+    InferenceStats, ModelKVMemoryType, RewindError, SnapshotError,
 };
 pub use llm_samplers::prelude::{Sampler, SamplerChain};
 pub use loader::{
-    load, load_progress_callback_stdout, ContainerType, FileType, FileTypeFormat, FormatMagic,
-    LoadError, LoadProgress, Loader, TensorLoader,
+    load, load_from_bytes, load_from_reader, load_progress_callback_stdout, probe, ContainerType,
+    FileType, FileTypeFormat, FormatMagic, LoadError, LoadProgress, Loader, TensorLoader,
+    TensorSplit,
 };
-pub use lora::{LoraAdapter, LoraParameters};
+pub use lora::{LoraAdapter, LoraError, LoraParameters, SessionLoraAdapter};
 pub use memmap2::Mmap;
-pub use model::{Hyperparameters, KnownModel, Model, ModelParameters, OutputRequest};
-pub use quantize::{quantize, QuantizeError, QuantizeProgress};
+pub use merge_lora::{merge_lora, MergeLoraAdapter, MergeLoraError, MergeLoraProgress};
+pub use model::{
+    Hyperparameters, KnownModel, MemoryLayout, Model, ModelContext, ModelParameters, OutputRequest,
+    PositionEncoding, TensorRole,
+};
+pub use quantize::{
+    quantize, quantize_dry_run, QuantizationErrorStats, QuantizationPolicy,
+    QuantizationPolicyError, QuantizationRule, QuantizeDryRun, QuantizeError, QuantizeParameters,
+    QuantizeProgress, TensorQuantizePlan,
+};
 pub use regex::Regex;
 pub use tokenizer::{
     InvalidTokenBias, Prompt, TokenBias, TokenId, TokenizationError, Tokenizer, TokenizerLoadError,
     TokenizerSource,
 };
+pub use upgrade::{upgrade, UpgradeError, UpgradeProgress};
 pub use util::TokenUtf8Buffer;
 
 #[derive(Clone, Debug)]
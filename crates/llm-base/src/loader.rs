@@ -3,17 +3,22 @@ use std::{
     error::Error,
     fmt::{Debug, Display, Formatter},
     fs::File,
-    io::{BufRead, BufReader, Read, Seek, SeekFrom},
+    io::{BufRead, BufReader, Cursor, Read, Seek, SeekFrom},
     path::{Path, PathBuf},
 };
 
 use crate::{
-    util, Hyperparameters, KnownModel, LoraAdapter, LoraParameters, ModelParameters, TokenId,
+    model::ModelTensor,
+    tokenizer::{HuggingFaceTokenizer, TOKENIZER_TENSOR_NAME},
+    util, Hyperparameters, KnownModel, LoraAdapter, ModelContext, ModelParameters, TokenId,
     Tokenizer, TokenizerLoadError, TokenizerSource,
 };
 pub use ggml::{format::FormatMagic, ContainerType};
 use ggml::{
-    format::{LoadError as FormatLoadError, PartialHyperparameters, TensorLoadInfo},
+    format::{
+        gguf::{Metadata, MetadataError, MetadataValue},
+        LoadError as FormatLoadError, PartialHyperparameters, TensorLoadInfo,
+    },
     Context, MAX_NAME_LENGTH,
 };
 use memmap2::Mmap;
@@ -178,6 +183,16 @@ impl Display for FileTypeFormat {
 /// These can be used to report progress to the user.
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub enum LoadProgress {
+    /// A file of the model is about to be read. Models split into several parts
+    /// (`model.bin`, `model.bin.1`, ...) report this once for each part.
+    PartLoading {
+        /// The file of the part.
+        file: PathBuf,
+        /// The current part (0-indexed).
+        current_part: usize,
+        /// The number of total parts.
+        total_parts: usize,
+    },
     /// The hyperparameters have been loaded from the model.
     HyperparametersLoaded,
     /// The context has been created.
@@ -185,6 +200,15 @@ pub enum LoadProgress {
         /// The size of the context.
         bytes: usize,
     },
+    /// A tensor of a LoRA adapter that is converted as it is loaded has been read.
+    LoraTensorLoaded {
+        /// The adapter the tensor was read from.
+        source: PathBuf,
+        /// The current tensor (0-indexed).
+        current_tensor: usize,
+        /// The number of total tensors in the adapter.
+        tensor_count: usize,
+    },
     /// A tensor was patched with a LoRA.
     LoraApplied {
         /// The name of the patched tensor.
@@ -192,18 +216,18 @@ pub enum LoadProgress {
         /// LoRA file the patch was applied from.
         source: PathBuf,
     },
-    /// A tensor from the current part has been loaded.
+    /// A tensor has been loaded.
     TensorLoaded {
         /// The current tensor (0-indexed).
         current_tensor: usize,
         /// The number of total tensors.
         tensor_count: usize,
     },
-    /// A model part has finished fully loading.
+    /// The model has finished fully loading.
     Loaded {
-        /// The number of bytes in the part.
+        /// The number of bytes in the model's files.
         file_size: u64,
-        /// The number of tensors in the part.
+        /// The number of tensors in the model.
         tensor_count: usize,
     },
 }
@@ -299,6 +323,18 @@ pub enum LoadError {
         /// The path that failed.
         path: PathBuf,
     },
+    /// The tensor `tensor_name` has more dimensions than are supported.
+    #[error(
+        "tensor `{tensor_name}` has {n_dims} dimensions, but at most 2 are supported, in {path:?}"
+    )]
+    UnsupportedTensorDimensions {
+        /// The name of the tensor.
+        tensor_name: String,
+        /// The number of dimensions of the tensor.
+        n_dims: usize,
+        /// The path that failed.
+        path: PathBuf,
+    },
     /// An invariant was broken.
     ///
     /// This error is not relevant unless `loader2` is being used.
@@ -319,14 +355,6 @@ pub enum LoadError {
         /// The path that failed.
         path: PathBuf,
     },
-    /// Multiple parts of the model were found.
-    ///
-    /// Multi-part models are not supported. Please convert the model to a single part.
-    #[error("multipart models are not supported")]
-    MultipartNotSupported {
-        /// The paths that were found.
-        paths: Vec<PathBuf>,
-    },
     /// The tokenizer could not be loaded.
     #[error("could not load tokenizer {path:?}: {error}")]
     TokenizerLoadFail {
@@ -346,11 +374,37 @@ pub enum LoadError {
         /// The path that failed.
         path: PathBuf,
     },
+    #[error("invalid metadata value type {value_type} in {path:?}")]
+    /// A GGUF metadata value had a type that is not known to this version of `llm`.
+    InvalidMetadataValueType {
+        /// The path that failed.
+        path: PathBuf,
+        /// The type that was encountered.
+        value_type: u32,
+    },
+    #[error("invalid metadata: {0}")]
+    /// A GGUF metadata value required by the model was missing or had the wrong type.
+    InvalidMetadata(#[from] MetadataError),
+    #[error(
+        "the tensors of {path:?} use quantization version {quantization_version}, but only version {} is supported",
+        ggml::QNT_VERSION
+    )]
+    /// The model is quantized with an older layout of the quantized types, which this version
+    /// of `llm` cannot use. Such models must be quantized again from their original weights.
+    UnsupportedQuantizationVersion {
+        /// The path that failed.
+        path: PathBuf,
+        /// The quantization version of the model.
+        quantization_version: u32,
+    },
 }
 impl From<util::FindAllModelFilesError> for LoadError {
     fn from(value: util::FindAllModelFilesError) -> Self {
         match value {
             util::FindAllModelFilesError::NoParentPath { path } => LoadError::NoParentPath { path },
+            util::FindAllModelFilesError::MissingPart { path } => {
+                LoadError::FileDoesNotExist { path }
+            }
             util::FindAllModelFilesError::IO(err) => LoadError::Io(err),
         }
     }
@@ -385,10 +439,22 @@ impl LoadError {
                     ftype,
                 }
             }
+            FormatLoadError::UnsupportedTensorDimensions {
+                tensor_name,
+                n_dims,
+            } => LoadError::UnsupportedTensorDimensions {
+                tensor_name,
+                n_dims,
+                path,
+            },
             FormatLoadError::InvariantBroken(invariant) => LoadError::InvariantBroken {
                 path: Some(path),
                 invariant,
             },
+            FormatLoadError::InvalidMetadataValueType(value_type) => {
+                LoadError::InvalidMetadataValueType { path, value_type }
+            }
+            FormatLoadError::InvalidMetadata(err) => LoadError::InvalidMetadata(err),
         }
     }
 }
@@ -398,14 +464,16 @@ pub trait TensorLoader<E: std::error::Error> {
     /// Gets a tensor from the loader.
     fn load(&mut self, name: &str) -> Result<ggml::Tensor, E>;
     /// Finish loading the model, returning the context.
-    fn finish(self) -> Context;
+    fn finish(self) -> ModelContext;
 }
 
 /// Load a GGML model from the `path` and configure it per the `params`. The status
 /// of the loading process will be reported through `load_progress_callback`.
 ///
-/// Note that the model must be a single-part model, and the model in `path`
-/// *must* match the architecture of `M`.
+/// If the model is split into several parts (`model.bin`, `model.bin.1`, ...), the parts
+/// are loaded together and the tensors split between them are joined (see [KnownModel::tensor_split]).
+///
+/// Note that the model in `path` *must* match the architecture of `M`.
 ///
 /// # Panics
 ///
@@ -413,7 +481,7 @@ pub trait TensorLoader<E: std::error::Error> {
 ///   before execution, so this function will panic if the model does not match
 ///   the architecture.
 ///
-///   This is a limitation of the GGML format, which does not
+///   This is a limitation of the legacy GGML formats, which do not
 ///   store any information about the architecture.
 pub fn load<M: KnownModel>(
     path: &Path,
@@ -428,62 +496,179 @@ pub fn load<M: KnownModel>(
     }
 
     let paths = util::find_all_model_files(path)?;
-    if paths.len() != 1 {
-        return Err(LoadError::MultipartNotSupported { paths });
-    }
-
-    let file = File::open(path).map_err(|e| LoadError::OpenFileFailed {
-        source: e,
-        path: path.to_owned(),
-    })?;
-    let mut reader = BufReader::new(&file);
-    log::trace!("Read model file from {:?}", path);
+    let sources = paths
+        .into_iter()
+        .map(|part_path| {
+            let file = File::open(&part_path).map_err(|e| LoadError::OpenFileFailed {
+                source: e,
+                path: part_path.clone(),
+            })?;
+            log::trace!("Read model file from {:?}", part_path);
+            Ok((part_path, PartData::File(file)))
+        })
+        .collect::<Result<Vec<_>, LoadError>>()?;
 
     let tokenizer = tokenizer_source.retrieve(path)?;
+    load_parts::<M>(sources, tokenizer, params, load_progress_callback)
+}
+
+/// Load a GGML model from the `reader` and configure it per the `params`. The status
+/// of the loading process will be reported through `load_progress_callback`.
+///
+/// This behaves like [load] for a single-part model, except that the model's data is always
+/// copied into memory, as it cannot be memory-mapped. Paths in errors will be empty.
+pub fn load_from_reader<M: KnownModel>(
+    reader: impl Read + Seek,
+    tokenizer_source: TokenizerSource,
+    params: ModelParameters,
+    load_progress_callback: impl FnMut(LoadProgress),
+) -> Result<M, LoadError> {
+    let tokenizer = tokenizer_source.retrieve(Path::new(""))?;
+    load_parts::<M>(
+        vec![(PathBuf::new(), PartData::Reader(Box::new(reader)))],
+        tokenizer,
+        params,
+        load_progress_callback,
+    )
+}
+
+/// Load a GGML model from `bytes` and configure it per the `params`. The status
+/// of the loading process will be reported through `load_progress_callback`.
+///
+/// This behaves like [load] for a single-part model. If the model would be memory-mapped
+/// from a file, its tensors instead point directly into `bytes`, so that nothing is copied.
+/// To load a model from bytes that are not `'static`, use [load_from_reader] with a
+/// [Cursor](std::io::Cursor). Paths in errors will be empty.
+pub fn load_from_bytes<M: KnownModel>(
+    bytes: &'static [u8],
+    tokenizer_source: TokenizerSource,
+    params: ModelParameters,
+    load_progress_callback: impl FnMut(LoadProgress),
+) -> Result<M, LoadError> {
+    let tokenizer = tokenizer_source.retrieve(Path::new(""))?;
+    load_parts::<M>(
+        vec![(PathBuf::new(), PartData::Static(Cursor::new(bytes)))],
+        tokenizer,
+        params,
+        load_progress_callback,
+    )
+}
+
+/// Returns the quantization version of the tensors of a model in `container_type`, whose file
+/// type records `recorded_version`.
+///
+/// llama.cpp does not record the quantization version in GGJT files, and leaves it at 0. Each
+/// version of GGJT was introduced along with a new layout of the quantized types, so the
+/// quantization version of those files is implied by the version of their container instead.
+pub(crate) fn quantization_version(container_type: ContainerType, recorded_version: u32) -> u32 {
+    match (container_type, recorded_version) {
+        (ContainerType::Ggjt(2), 0) => 1,
+        (ContainerType::Ggjt(3), 0) => 2,
+        _ => recorded_version,
+    }
+}
+
+/// Loads a model from its parts. This is shared by all of the `load` functions.
+fn load_parts<M: KnownModel>(
+    sources: Vec<(PathBuf, PartData<'_>)>,
+    tokenizer: Tokenizer,
+    params: ModelParameters,
+    load_progress_callback: impl FnMut(LoadProgress),
+) -> Result<M, LoadError> {
+    let total_parts = sources.len();
     let mut loader = Loader::new(tokenizer, load_progress_callback);
 
-    ggml::format::load(&mut reader, &mut loader)
-        .map_err(|err| LoadError::from_format_error(err, path.to_owned()))?;
-    log::trace!("Loaded GGML model from reader");
+    let mut parts = Vec::with_capacity(total_parts);
+    for (current_part, (part_path, mut data)) in sources.into_iter().enumerate() {
+        (loader.load_progress_callback)(LoadProgress::PartLoading {
+            file: part_path.clone(),
+            current_part,
+            total_parts,
+        });
+
+        let tensors = {
+            let mut reader = BufReader::new(data.reader());
+            if current_part == 0 {
+                ggml::format::load(&mut reader, &mut loader)
+                    .map_err(|err| LoadError::from_format_error(err, part_path.clone()))?;
+                std::mem::take(&mut loader.tensors)
+            } else {
+                // Every part repeats the header of the first part, so only its tensors are kept.
+                let mut part_loader: Loader<M::Hyperparameters, _> =
+                    Loader::new(Tokenizer::empty_embedded(), |_| {});
+                ggml::format::load(&mut reader, &mut part_loader)
+                    .map_err(|err| LoadError::from_format_error(err, part_path.clone()))?;
+                if part_loader.hyperparameters != loader.hyperparameters {
+                    return Err(LoadError::InvariantBroken {
+                        path: Some(part_path),
+                        invariant: "every part has the same hyperparameters as the first"
+                            .to_owned(),
+                    });
+                }
+                part_loader.tensors
+            }
+        };
+        log::trace!("Loaded GGML model part from reader");
+
+        parts.push(ModelPart {
+            path: part_path,
+            data,
+            tensors,
+        });
+    }
+    let JoinedTensors {
+        mut tensors,
+        split_tensors,
+    } = join_part_tensors(&parts, M::tensor_split)?;
 
     let Loader {
         hyperparameters,
         tokenizer,
-        tensors,
         mut load_progress_callback,
         container_type,
         ..
     } = loader;
 
-    let quantization_version = (&hyperparameters as &M::Hyperparameters)
-        .file_type()
-        .map(|ft| ft.quantization_version)
-        .unwrap_or_default();
-    let quantization_version = if quantization_version == 0 {
-        // HACK: I think llama.cpp does not actually write the quantization version correctly,
-        // so we need to guess it from the container type.
-        if container_type == ggml::ContainerType::Ggjt(2) {
-            1
-        } else if container_type == ggml::ContainerType::Ggjt(3) {
-            2
-        } else {
-            quantization_version
+    // A Hugging Face tokenizer saved with the model takes precedence over its vocabulary
+    let tokenizer = match (tokenizer, tensors.remove(TOKENIZER_TENSOR_NAME)) {
+        (Tokenizer::Embedded(_), Some(info)) => {
+            let data = info.read_data(&mut BufReader::new(parts[0].data.reader()))?;
+            HuggingFaceTokenizer::from_tensor_data(&data)
+                .map_err(|error| LoadError::TokenizerLoadFail {
+                    path: parts[0].path.clone(),
+                    error,
+                })?
+                .into()
         }
-    } else {
-        quantization_version
+        (tokenizer, _) => tokenizer,
     };
+
+    let quantization_version = quantization_version(
+        container_type,
+        (&hyperparameters as &M::Hyperparameters)
+            .file_type()
+            .map(|ft| ft.quantization_version)
+            .unwrap_or_default(),
+    );
     log::trace!(
         "Determined quantization version of model as {:?}",
         quantization_version
     );
 
-    // TODO: this is temporary while we figure out how to handle this
-    if tensors.values().any(|t| t.element_type.is_quantized()) {
-        assert_eq!(quantization_version, 2, "quantization version must be 2");
+    if quantization_version != ggml::QNT_VERSION
+        && tensors.values().any(|t| t.element_type.is_quantized())
+    {
+        return Err(LoadError::UnsupportedQuantizationVersion {
+            path: parts[0].path.clone(),
+            quantization_version,
+        });
     }
 
-    let use_mmap =
-        params.prefer_mmap && container_type.support_mmap() && params.lora_adapters.is_none();
+    // Tensors patched by LoRA adapters are copied out of the mapping when they are loaded,
+    // so adapters do not prevent the rest of the model from being mapped.
+    let use_mmap = params.prefer_mmap
+        && container_type.support_mmap()
+        && matches!(parts[0].data, PartData::File(_) | PartData::Static(_));
 
     let ctx_size = tensors
         .values()
@@ -491,66 +676,48 @@ pub fn load<M: KnownModel>(
         .sum::<usize>();
     log::trace!("Context size: {:?}", ctx_size);
 
-    let mut lora_adapters: Option<Vec<LoraAdapter>> = None;
-    if let Some(lora_paths) = &params.lora_adapters {
-        let adapters: Result<Vec<_>, _> = lora_paths
-            .iter()
-            .map(|lora_path| {
-                // Read the LoRA file
-                let lora_file = File::open(lora_path).map_err(|e| LoadError::OpenFileFailed {
-                    source: e,
-                    path: lora_path.to_owned(),
-                })?;
-                let mut lora_reader = BufReader::new(&lora_file);
-                // TODO: Consider updating the progress callback to report the progress of the LoRA file.
-                // Most LoRAs are small enough that this is not necessary, but it would be nice to have.
-                let mut lora_loader: Loader<LoraParameters, _> =
-                    Loader::new(Tokenizer::empty_embedded(), |_| {});
-                ggml::format::load(&mut lora_reader, &mut lora_loader)
-                    .map_err(|err| LoadError::from_format_error(err, lora_path.to_owned()))?;
-
-                // Collect the names of the tensors that should be patched
-                let tensors_to_patch = lora_loader
-                    .tensors
-                    .keys()
-                    .filter_map(|k| Some(k.rsplit_once('.')?.0.to_owned()))
-                    .collect();
-
-                log::trace!("Loaded LoRA weights");
-                // Return the LoRA patches
-                Ok::<_, LoadError>(LoraAdapter {
-                    scaling: lora_loader.hyperparameters.calculate_scaling(),
-                    tensors: lora_loader.tensors,
-                    tensors_to_patch,
-                    file: lora_file,
-                    path: lora_path.to_owned(),
+    let lora_adapters = params
+        .lora_adapters
+        .as_ref()
+        .map(|lora_paths| {
+            lora_paths
+                .iter()
+                .map(|lora_path| {
+                    let mut adapter = LoraAdapter::load_any(
+                        lora_path,
+                        &hyperparameters,
+                        &mut load_progress_callback,
+                    )?;
+                    adapter.n_threads = params.lora_threads;
+                    Ok(adapter)
                 })
-            })
-            .collect();
-        lora_adapters = Some(adapters?);
-    }
+                .collect::<Result<Vec<_>, LoadError>>()
+        })
+        .transpose()?;
 
     (load_progress_callback)(LoadProgress::ContextSize { bytes: ctx_size });
-    let (context, file_size) = if use_mmap {
-        let file = File::open(path)?;
-        unsafe {
-            let mmap = Mmap::map(&file)?;
-            let file_size = mmap.len() as u64;
-            (Context::new_with_mmap(mmap), file_size)
-        }
-    } else {
-        (Context::new_with_allocate(ctx_size), file.metadata()?.len())
+    // Only the first part is mapped; tensors split between parts are joined into memory
+    // owned by the context.
+    let context = match (use_mmap, &parts[0].data) {
+        (true, PartData::File(file)) => unsafe { Context::new_with_mmap(Mmap::map(file)?) },
+        (true, PartData::Static(cursor)) => Context::new_with_static(cursor.get_ref()),
+        _ => Context::new_with_allocate(ctx_size),
     };
+    let file_size = parts
+        .iter_mut()
+        .map(|part| part.data.reader().seek(SeekFrom::End(0)))
+        .sum::<Result<u64, std::io::Error>>()?;
 
     let tensors_len = tensors.len();
     let tl = MmapCompatibleLoader {
-        path: path.to_owned(),
-        file,
+        parts,
         tensors,
+        split_tensors,
         context,
         lora_adapters,
         load_progress_callback: &mut load_progress_callback,
         loaded_tensors: Default::default(),
+        originals: Default::default(),
     };
 
     let model = KnownModel::new(hyperparameters, params, tokenizer, tl)?;
@@ -565,6 +732,206 @@ pub fn load<M: KnownModel>(
     Ok(model)
 }
 
+/// How a two-dimensional tensor is divided between the parts of a multi-part model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TensorSplit {
+    /// Each part holds a slice of every row, and the slices are joined along the first dimension.
+    ByColumns,
+    /// Each part holds a contiguous range of rows, and the ranges are joined along the second dimension.
+    ByRows,
+}
+
+/// A source that model data can be read from.
+pub(crate) trait ReadSeek: Read + Seek {}
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// Where the data of a part of a model comes from.
+enum PartData<'a> {
+    File(File),
+    Reader(Box<dyn ReadSeek + 'a>),
+    /// Memory that outlives the model, and can be used by it without copying.
+    Static(Cursor<&'static [u8]>),
+}
+impl PartData<'_> {
+    fn reader(&mut self) -> &mut dyn ReadSeek {
+        match self {
+            Self::File(file) => file,
+            Self::Reader(reader) => reader.as_mut(),
+            Self::Static(cursor) => cursor,
+        }
+    }
+}
+
+/// A part of a model and the tensors stored in it.
+struct ModelPart<'a> {
+    /// The path of the part, or an empty path if the part was not loaded from a file.
+    path: PathBuf,
+    data: PartData<'a>,
+    tensors: HashMap<String, TensorLoadInfo>,
+}
+
+/// The tensors of a model, joined from all of its parts.
+struct JoinedTensors {
+    tensors: HashMap<String, TensorLoadInfo>,
+    /// How each of the tensors split between the parts is split.
+    split_tensors: HashMap<String, TensorSplit>,
+}
+
+/// Joins the tensors of the parts of a model, which are split as `tensor_split` returns
+/// (see [KnownModel::tensor_split]).
+fn join_part_tensors(
+    parts: &[ModelPart<'_>],
+    tensor_split: impl Fn(&str) -> TensorSplit,
+) -> Result<JoinedTensors, LoadError> {
+    let (first, rest) = parts.split_first().expect("models have at least one part");
+    if rest.is_empty() {
+        return Ok(JoinedTensors {
+            tensors: first.tensors.clone(),
+            split_tensors: HashMap::new(),
+        });
+    }
+
+    for part in rest {
+        if let Some(name) = part
+            .tensors
+            .keys()
+            .find(|n| !first.tensors.contains_key(*n))
+        {
+            return Err(LoadError::UnknownTensor {
+                tensor_name: name.clone(),
+                path: part.path.clone(),
+            });
+        }
+    }
+
+    let mut tensors = HashMap::new();
+    let mut split_tensors = HashMap::new();
+    for (name, info) in &first.tensors {
+        let split = (info.n_dims > 1).then(|| tensor_split(name));
+
+        let mut joined = info.clone();
+        for part in rest {
+            let part_info = part
+                .tensors
+                .get(name)
+                .ok_or_else(|| LoadError::UnknownTensor {
+                    tensor_name: name.clone(),
+                    path: part.path.clone(),
+                })?;
+
+            let compatible = part_info.element_type == info.element_type
+                && part_info.n_dims == info.n_dims
+                && match split {
+                    None => part_info.dims == info.dims,
+                    Some(TensorSplit::ByColumns) => part_info.dims[1] == info.dims[1],
+                    Some(TensorSplit::ByRows) => part_info.dims[0] == info.dims[0],
+                };
+            if !compatible {
+                return Err(LoadError::InvariantBroken {
+                    path: Some(part.path.clone()),
+                    invariant: format!(
+                        "the tensor {name} has the same type and compatible dimensions in every part"
+                    ),
+                });
+            }
+
+            match split {
+                None => {}
+                Some(TensorSplit::ByColumns) => joined.dims[0] += part_info.dims[0],
+                Some(TensorSplit::ByRows) => joined.dims[1] += part_info.dims[1],
+            }
+        }
+        joined.n_elements = joined.dims().iter().product();
+
+        if let Some(split) = split {
+            split_tensors.insert(name.clone(), split);
+        }
+        tensors.insert(name.clone(), joined);
+    }
+
+    Ok(JoinedTensors {
+        tensors,
+        split_tensors,
+    })
+}
+
+/// Checks whether the model file at `path` could contain a model of type `M`.
+///
+/// The file is parsed with the hyperparameters of `M`, and the resulting tensor names
+/// are checked with [KnownModel::matches_tensors]. This is used to guess the architecture
+/// of a model file, so a file that cannot be parsed is reported as not matching instead
+/// of as an error.
+pub fn probe<M: KnownModel>(path: &Path) -> Result<bool, LoadError> {
+    let file = File::open(path).map_err(|e| LoadError::OpenFileFailed {
+        source: e,
+        path: path.to_owned(),
+    })?;
+    let mut reader = ProbeReader {
+        inner: BufReader::new(&file),
+        remaining: PROBE_READ_LIMIT,
+    };
+
+    let mut loader: Loader<M::Hyperparameters, _> =
+        Loader::new(Tokenizer::empty_embedded(), |_| {});
+    if let Err(err) = ggml::format::load(&mut reader, &mut loader) {
+        log::trace!(
+            "{path:?} did not parse as {}: {err}",
+            std::any::type_name::<M>()
+        );
+        return Ok(false);
+    }
+
+    Ok(M::matches_tensors(&|name| {
+        loader.tensors.contains_key(name)
+    }))
+}
+
+/// The most bytes [probe] will read (excluding skipped tensor data) before giving up.
+///
+/// Parsing a file with the wrong hyperparameters can produce arbitrary vocabulary and tensor
+/// counts, so this bounds the work done for a mismatch. Real headers are far smaller.
+const PROBE_READ_LIMIT: u64 = 32 * 1024 * 1024;
+
+/// A reader that fails once it has read more than a fixed number of bytes.
+struct ProbeReader<R> {
+    inner: R,
+    remaining: u64,
+}
+impl<R: BufRead> Read for ProbeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+impl<R: BufRead> BufRead for ProbeReader<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        if self.remaining == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "probe read limit reached",
+            ));
+        }
+        let buf = self.inner.fill_buf()?;
+        let len = buf
+            .len()
+            .min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        Ok(&buf[..len])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.remaining = self.remaining.saturating_sub(amt as u64);
+        self.inner.consume(amt);
+    }
+}
+impl<R: Seek> Seek for ProbeReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
 /// A GGML format loader for LLMs.
 pub struct Loader<Hp: Hyperparameters, F: FnMut(LoadProgress)> {
     // Input
@@ -579,7 +946,12 @@ pub struct Loader<Hp: Hyperparameters, F: FnMut(LoadProgress)> {
     pub container_type: ContainerType,
     /// The hyperparameters of the model.
     pub hyperparameters: Hp,
+    /// The metadata of the model, if it was loaded from a GGUF file.
+    pub metadata: Option<Metadata>,
     /// The tensors of the model.
+    ///
+    /// Tensors from GGUF files are stored under the names returned by
+    /// [Hyperparameters::tensor_name_from_gguf].
     pub tensors: HashMap<String, TensorLoadInfo>,
 }
 impl<Hp: Hyperparameters, F: FnMut(LoadProgress)> Loader<Hp, F> {
@@ -590,6 +962,7 @@ impl<Hp: Hyperparameters, F: FnMut(LoadProgress)> Loader<Hp, F> {
 
             container_type: ContainerType::Ggml,
             hyperparameters: Hp::default(),
+            metadata: None,
             tokenizer,
             tensors: HashMap::default(),
         }
@@ -610,6 +983,16 @@ impl<Hp: Hyperparameters, F: FnMut(LoadProgress)> ggml::format::LoadHandler<Load
                 Err(err) => return Err(LoadError::InvalidIntegerConversion(err)),
             };
 
+            let token = match &self.metadata {
+                Some(metadata) => crate::tokenizer::gguf_token_to_bytes(
+                    metadata
+                        .get("tokenizer.ggml.model")
+                        .and_then(MetadataValue::as_str),
+                    token,
+                ),
+                None => token,
+            };
+
             mv.push_token(id, token, score);
         }
 
@@ -631,20 +1014,40 @@ impl<Hp: Hyperparameters, F: FnMut(LoadProgress)> ggml::format::LoadHandler<Load
         Ok(partial)
     }
 
-    fn tensor_buffer(&mut self, info: TensorLoadInfo) -> Result<(), LoadError> {
+    fn read_metadata(&mut self, metadata: &Metadata) -> Result<PartialHyperparameters, LoadError> {
+        let hyperparameters = Hp::read_gguf(metadata)?;
+        let partial = PartialHyperparameters {
+            n_vocab: hyperparameters.n_vocabulary(),
+        };
+        self.hyperparameters = hyperparameters;
+        self.metadata = Some(metadata.clone());
+        (self.load_progress_callback)(LoadProgress::HyperparametersLoaded);
+
+        Ok(partial)
+    }
+
+    fn tensor_buffer(&mut self, mut info: TensorLoadInfo) -> Result<(), LoadError> {
+        if let ContainerType::Gguf(_) = self.container_type {
+            info.name = self.hyperparameters.tensor_name_from_gguf(&info.name);
+        }
         self.tensors.insert(info.name.clone(), info);
         Ok(())
     }
 }
 
 struct MmapCompatibleLoader<'a> {
-    path: PathBuf,
-    file: File,
+    parts: Vec<ModelPart<'a>>,
+    /// The tensors of the model, joined from all of its parts.
     tensors: HashMap<String, TensorLoadInfo>,
+    /// The tensors that are split between parts. All other tensors are loaded from the first part.
+    split_tensors: HashMap<String, TensorSplit>,
     context: Context,
     lora_adapters: Option<Vec<LoraAdapter>>,
     load_progress_callback: &'a mut dyn FnMut(LoadProgress),
     loaded_tensors: HashMap<String, ggml::Tensor>,
+    /// Copies of the data of the patched tensors that are not in the mapping, taken before the
+    /// adapters were applied to them, so that the adapters can be removed later.
+    originals: HashMap<String, Vec<u8>>,
 }
 impl TensorLoader<LoadError> for MmapCompatibleLoader<'_> {
     fn load(&mut self, name: &str) -> Result<ggml::Tensor, LoadError> {
@@ -653,16 +1056,46 @@ impl TensorLoader<LoadError> for MmapCompatibleLoader<'_> {
             path: Default::default(),
         })?;
 
-        let mut main_context = FileContext::new(
-            &self.context,
-            &mut self.file,
-            &self.path,
-            self.context.storage().as_mmap(),
-        );
-
-        let mut tensor = main_context.get_tensor(info)?;
+        let mut tensor = match self.split_tensors.get(name) {
+            Some(split) => load_split_tensor(&mut self.context, &mut self.parts, info, *split)?,
+            None => {
+                let main_part = &mut self.parts[0];
+                let mut main_context = FileContext::new(
+                    &self.context,
+                    main_part.data.reader(),
+                    &main_part.path,
+                    self.context.storage().as_mapped(),
+                );
+                main_context.get_tensor(info)?
+            }
+        };
 
         if let Some(lora_adapters) = &mut self.lora_adapters {
+            let patched = lora_adapters
+                .iter()
+                .any(|adapter| adapter.tensors_to_patch.contains(name));
+            let mapped = !self.split_tensors.contains_key(name)
+                && self.context.storage().as_mapped().is_some();
+            if patched && mapped {
+                // The mapping is read-only, so the tensor gets its own copy of the data to patch.
+                let mapped_data = unsafe { tensor.data() } as *const u8;
+                self.context.allocate_tensor_data(&mut tensor);
+                // SAFETY: both regions are `nbytes` long, and the new buffer is freshly allocated.
+                unsafe {
+                    std::ptr::copy_nonoverlapping(
+                        mapped_data,
+                        tensor.data() as *mut u8,
+                        tensor.nbytes(),
+                    );
+                }
+            } else if patched {
+                // The data cannot be pointed back at, so it is kept to be restored.
+                let mut original = vec![0; tensor.nbytes()];
+                // SAFETY: the buffer is exactly `nbytes` long.
+                unsafe { tensor.read_data(0, &mut original) };
+                self.originals.insert(name.to_owned(), original);
+            }
+
             for lora_adapter in lora_adapters {
                 lora_adapter.patch(info, &mut tensor)?;
                 (self.load_progress_callback)(LoadProgress::LoraApplied {
@@ -681,23 +1114,43 @@ impl TensorLoader<LoadError> for MmapCompatibleLoader<'_> {
         Ok(tensor)
     }
 
-    fn finish(self) -> Context {
-        self.context
+    fn finish(mut self) -> ModelContext {
+        let mapped = self.context.storage().as_mapped().is_some();
+        let tensors = self
+            .loaded_tensors
+            .into_iter()
+            .map(|(name, tensor)| {
+                let tensor = ModelTensor {
+                    info: self.tensors.remove(&name).unwrap(),
+                    mapped: mapped && !self.split_tensors.contains_key(&name),
+                    tensor,
+                    buffer: None,
+                    original: self.originals.remove(&name),
+                };
+                (name, tensor)
+            })
+            .collect();
+        ModelContext::new(
+            self.context,
+            tensors,
+            self.lora_adapters.unwrap_or_default(),
+        )
     }
 }
 
 pub(crate) struct FileContext<'a> {
     context: &'a Context,
-    file: &'a mut File,
+    file: &'a mut dyn ReadSeek,
     path: &'a Path,
-    mmap: Option<&'a Mmap>,
+    /// The memory the tensors point into, if the file is memory-mapped.
+    mmap: Option<&'a [u8]>,
 }
 impl<'a> FileContext<'a> {
     pub(crate) fn new(
         context: &'a Context,
-        file: &'a mut File,
+        file: &'a mut dyn ReadSeek,
         path: &'a Path,
-        mmap: Option<&'a Mmap>,
+        mmap: Option<&'a [u8]>,
     ) -> Self {
         Self {
             context,
@@ -752,20 +1205,94 @@ impl<'a> FileContext<'a> {
             }
         }
 
-        // The tensor name is truncated to its maximum length.
-        let tensor_name = if name.len() >= MAX_NAME_LENGTH {
-            &name[name.len() - MAX_NAME_LENGTH..]
-        } else {
-            name
-        };
+        Ok(tensor.set_name(truncate_tensor_name(name)))
+    }
+}
+
+/// Loads a tensor that is split between the parts of a model by joining its parts.
+fn load_split_tensor(
+    context: &mut Context,
+    parts: &mut [ModelPart<'_>],
+    info: &TensorLoadInfo,
+    split: TensorSplit,
+) -> Result<ggml::Tensor, LoadError> {
+    let mut tensor = context.new_tensor_2d(info.element_type, info.dims[0], info.dims[1]);
+    if context.storage().as_mapped().is_some() {
+        // The joined tensor is not present in any of the files, so it cannot be mapped.
+        context.allocate_tensor_data(&mut tensor);
+    }
+
+    let data: &mut [u8] =
+        unsafe { std::slice::from_raw_parts_mut(tensor.data() as *mut u8, tensor.nbytes()) };
+    read_split_tensor(parts, info, split, data)?;
+
+    Ok(tensor.set_name(truncate_tensor_name(&info.name)))
+}
+
+/// Reads the parts of a split tensor into `data`, which holds the joined tensor.
+fn read_split_tensor(
+    parts: &mut [ModelPart<'_>],
+    info: &TensorLoadInfo,
+    split: TensorSplit,
+    data: &mut [u8],
+) -> Result<(), LoadError> {
+    let n_rows = info.dims[1];
+    let row_size = data.len() / n_rows;
+
+    let mut offset = 0;
+    for part in parts {
+        let part_info = &part.tensors[&info.name];
+        let part_size = part_info.calc_size();
+        let reader = part.data.reader();
+        reader.seek(SeekFrom::Start(part_info.start_offset))?;
+
+        match split {
+            TensorSplit::ByColumns => {
+                let part_row_size = part_size / n_rows;
+                let mut part_data = vec![0; part_size];
+                reader.read_exact(&mut part_data)?;
+                for (row, part_row) in data
+                    .chunks_exact_mut(row_size)
+                    .zip(part_data.chunks_exact(part_row_size))
+                {
+                    row[offset..offset + part_row_size].copy_from_slice(part_row);
+                }
+                offset += part_row_size;
+            }
+            TensorSplit::ByRows => {
+                reader.read_exact(&mut data[offset..offset + part_size])?;
+                offset += part_size;
+            }
+        }
+    }
+
+    Ok(())
+}
 
-        Ok(tensor.set_name(tensor_name))
+/// Truncates the tensor name to the maximum length supported by GGML.
+pub(crate) fn truncate_tensor_name(name: &str) -> &str {
+    if name.len() >= MAX_NAME_LENGTH {
+        &name[name.len() - MAX_NAME_LENGTH..]
+    } else {
+        name
     }
 }
 
 /// A implementation for `load_progress_callback` that outputs to `stdout`.
 pub fn load_progress_callback_stdout(progress: LoadProgress) {
     match progress {
+        LoadProgress::PartLoading {
+            file,
+            current_part,
+            total_parts,
+        } => {
+            if total_parts > 1 {
+                println!(
+                    "Loading part {}/{total_parts} from {file:?}",
+                    current_part + 1
+                );
+            }
+        }
         LoadProgress::HyperparametersLoaded => println!("Loaded hyperparameters"),
         LoadProgress::ContextSize { bytes } => println!(
             "ggml ctx size = {:.2} MB\n",
@@ -792,6 +1319,19 @@ pub fn load_progress_callback_stdout(progress: LoadProgress) {
                 tensor_count
             );
         }
+        LoadProgress::LoraTensorLoaded {
+            source,
+            current_tensor,
+            tensor_count,
+        } => {
+            let current_tensor = current_tensor + 1;
+            if current_tensor % 8 == 0 {
+                println!(
+                    "Loaded LoRA tensor {current_tensor}/{tensor_count} from '{}'",
+                    source.display()
+                );
+            }
+        }
         LoadProgress::LoraApplied { name, source } => {
             println!(
                 "Patched tensor {} via LoRA from '{}'",
@@ -801,3 +1341,160 @@ pub fn load_progress_callback_stdout(progress: LoadProgress) {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ggml::ElementType;
+
+    /// Builds a part from tensors of `f32`s, stored one after another.
+    fn part(tensors: &[(&str, &[usize], &[f32])]) -> ModelPart<'static> {
+        let mut data = vec![];
+        let mut infos = HashMap::new();
+        for &(name, dims, values) in tensors {
+            let mut info_dims = [1; 2];
+            info_dims[..dims.len()].copy_from_slice(dims);
+            infos.insert(
+                name.to_owned(),
+                TensorLoadInfo {
+                    name: name.to_owned(),
+                    n_dims: dims.len(),
+                    dims: info_dims,
+                    n_elements: values.len(),
+                    element_type: ElementType::F32,
+                    start_offset: data.len() as u64,
+                },
+            );
+            data.extend(values.iter().flat_map(|v| v.to_le_bytes()));
+        }
+
+        ModelPart {
+            path: PathBuf::new(),
+            data: PartData::Reader(Box::new(Cursor::new(data))),
+            tensors: infos,
+        }
+    }
+
+    fn read_joined(parts: &mut [ModelPart<'_>], joined: &JoinedTensors, name: &str) -> Vec<f32> {
+        let info = &joined.tensors[name];
+        let mut data = vec![0; info.calc_size()];
+        read_split_tensor(parts, info, joined.split_tensors[name], &mut data).unwrap();
+        data.chunks_exact(4)
+            .map(|c| f32::from_le_bytes(c.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_join_part_tensors() {
+        // Two rows of two columns, split down the middle in both directions.
+        let mut parts = [
+            part(&[
+                ("by_rows", &[2, 1], &[1.0, 2.0]),
+                ("by_columns", &[1, 2], &[1.0, 3.0]),
+                ("norm", &[2], &[5.0, 6.0]),
+            ]),
+            part(&[
+                ("by_rows", &[2, 1], &[3.0, 4.0]),
+                ("by_columns", &[1, 2], &[2.0, 4.0]),
+                ("norm", &[2], &[5.0, 6.0]),
+            ]),
+        ];
+        let tensor_split = |name: &str| match name {
+            "by_columns" => TensorSplit::ByColumns,
+            _ => TensorSplit::ByRows,
+        };
+
+        let joined = join_part_tensors(&parts, tensor_split).unwrap();
+        assert_eq!(joined.tensors["by_rows"].dims, [2, 2]);
+        assert_eq!(joined.tensors["by_columns"].dims, [2, 2]);
+        assert_eq!(joined.tensors["by_columns"].n_elements, 4);
+        // One-dimensional tensors are copied in every part, so they are not joined.
+        assert_eq!(joined.tensors["norm"].dims(), [2]);
+        assert!(!joined.split_tensors.contains_key("norm"));
+
+        let expected = [1.0, 2.0, 3.0, 4.0];
+        assert_eq!(read_joined(&mut parts, &joined, "by_rows"), expected);
+        assert_eq!(read_joined(&mut parts, &joined, "by_columns"), expected);
+    }
+
+    #[test]
+    fn test_remove_lora_applied_while_loading() {
+        // Neither tensor is in a mapping: the first is read into memory, and the second is
+        // joined from the rows of two parts.
+        let weight = [1.0, 2.0, 3.0, 4.0];
+        for parts in [
+            vec![part(&[("w", &[2, 2], &weight)])],
+            vec![
+                part(&[("w", &[2, 1], &weight[..2])]),
+                part(&[("w", &[2, 1], &weight[2..])]),
+            ],
+        ] {
+            let n_parts = parts.len();
+            let (a, b) = ([1.0, 2.0], [3.0, -1.0]);
+            let adapter = LoraAdapter::from_matrices("adapter", 2.0, 1, &[("w", &a, &b)]);
+            let joined = join_part_tensors(&parts, |_| TensorSplit::ByRows).unwrap();
+            let ctx_size = joined
+                .tensors
+                .values()
+                .map(|ti| ti.calc_absolute_size(false))
+                .sum();
+            let mut loader = MmapCompatibleLoader {
+                parts,
+                tensors: joined.tensors,
+                split_tensors: joined.split_tensors,
+                context: Context::new_with_allocate(ctx_size),
+                lora_adapters: Some(vec![adapter]),
+                load_progress_callback: &mut |_| {},
+                loaded_tensors: Default::default(),
+                originals: Default::default(),
+            };
+            let tensor = loader.load("w").unwrap();
+            let mut model = loader.finish();
+
+            let data = || -> Vec<f32> {
+                let mut data = [0; 16];
+                // SAFETY: the tensor is not being written to.
+                unsafe { tensor.read_data(0, &mut data) };
+                data.chunks_exact(4)
+                    .map(|c| f32::from_le_bytes(c.try_into().unwrap()))
+                    .collect()
+            };
+            let patched = |scaling: f32| -> Vec<f32> {
+                (0..4)
+                    .map(|i| weight[i] + b[i / 2] * a[i % 2] * scaling)
+                    .collect()
+            };
+
+            assert_eq!(data(), patched(2.0), "{n_parts} part(s)");
+            model.set_lora_scaling(Path::new("adapter"), 0.5).unwrap();
+            assert_eq!(data(), patched(0.5), "{n_parts} part(s)");
+            model.remove_lora(Path::new("adapter")).unwrap();
+            assert_eq!(data(), weight, "{n_parts} part(s)");
+        }
+    }
+
+    #[test]
+    fn test_join_part_tensors_mismatch() {
+        let tensor_split = |_: &str| TensorSplit::ByRows;
+
+        // A tensor that is missing from one of the parts cannot be joined.
+        let parts = [
+            part(&[("a", &[2], &[1.0, 2.0]), ("b", &[2], &[1.0, 2.0])]),
+            part(&[("a", &[2], &[1.0, 2.0])]),
+        ];
+        assert!(matches!(
+            join_part_tensors(&parts, tensor_split),
+            Err(LoadError::UnknownTensor { tensor_name, .. }) if tensor_name == "b"
+        ));
+
+        // Rows can only be joined if they have the same length.
+        let parts = [
+            part(&[("a", &[2, 1], &[1.0, 2.0])]),
+            part(&[("a", &[1, 2], &[1.0, 2.0])]),
+        ];
+        assert!(matches!(
+            join_part_tensors(&parts, tensor_split),
+            Err(LoadError::InvariantBroken { .. })
+        ));
+    }
+}
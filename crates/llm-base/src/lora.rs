@@ -1,14 +1,21 @@
 use crate::{
-    loader::FileContext, model::HyperparametersWriteError, util, FileType, Hyperparameters,
-    LoadError,
+    loader::{truncate_tensor_name, FileContext, ReadSeek},
+    model::HyperparametersWriteError,
+    safetensors::SafeTensors,
+    util, FileType, Hyperparameters, LoadError, LoadProgress, Loader, TensorTransform, Tokenizer,
 };
 
-use ggml::{format::TensorLoadInfo, GraphExecutionPlan};
+use ggml::{format::TensorLoadInfo, Context, GraphExecutionPlan, Tensor};
+use serde::Deserialize;
 use std::{
     collections::{HashMap, HashSet},
     fs::File,
-    path::PathBuf,
+    io::{BufReader, Cursor},
+    path::{Path, PathBuf},
+    sync::Arc,
 };
+use thiserror::Error;
+use tracing::log;
 
 #[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
 /// Parameters for a [LoRA](https://arxiv.org/abs/2106.09685) adapter.
@@ -38,6 +45,20 @@ impl Hyperparameters for LoraParameters {
         Ok(())
     }
 
+    fn read_gguf(_metadata: &ggml::format::gguf::Metadata) -> Result<Self, LoadError> {
+        Err(LoadError::InvariantBroken {
+            path: None,
+            invariant: "LoRA adapters must be stored in the GGLA format".to_owned(),
+        })
+    }
+
+    fn write_gguf(
+        &self,
+        _metadata: &mut ggml::format::gguf::Metadata,
+    ) -> Result<(), HyperparametersWriteError> {
+        Err(HyperparametersWriteError::GgufNotSupported)
+    }
+
     fn n_vocabulary(&self) -> usize {
         // LoRA adapters do not have a vocabulary.
         0
@@ -52,6 +73,34 @@ impl Hyperparameters for LoraParameters {
     }
 }
 
+#[derive(Error, Debug)]
+/// Errors encountered while changing the LoRA adapters of a loaded model.
+pub enum LoraError {
+    #[error("could not load or apply the adapter")]
+    /// The adapter could not be loaded, or applied to a tensor.
+    Load(#[from] LoadError),
+    /// The adapter has already been applied to the model.
+    #[error("the adapter {path:?} has already been applied")]
+    AlreadyApplied {
+        /// The path to the adapter.
+        path: PathBuf,
+    },
+    /// No adapter with this path has been applied to the model.
+    #[error("the adapter {path:?} has not been applied")]
+    NotApplied {
+        /// The path to the adapter.
+        path: PathBuf,
+    },
+    /// The adapter patches a tensor that is not in the model.
+    #[error("the adapter {path:?} patches {tensor_name:?}, which is not in the model")]
+    UnknownTensor {
+        /// The path to the adapter.
+        path: PathBuf,
+        /// The name of the tensor.
+        tensor_name: String,
+    },
+}
+
 /// [LoRA](https://arxiv.org/abs/2106.09685) adapter for a model.
 pub struct LoraAdapter {
     /// Scaling to apply to the LoRA weights.
@@ -60,13 +109,202 @@ pub struct LoraAdapter {
     pub tensors: HashMap<String, TensorLoadInfo>,
     /// Names of the tensors that should be patched.
     pub tensors_to_patch: HashSet<String>,
-    /// File containing the LoRA weights.
-    pub file: File,
-    /// Path to the LoRA file.
+    /// The number of threads to use when patching a tensor. Defaults to 8.
+    pub n_threads: usize,
+    /// Path to the LoRA file, or to the directory of a PEFT adapter.
     pub path: PathBuf,
+    /// Where the LoRA weights are read from.
+    data: LoraData,
+}
+
+/// Where the weights of a [LoraAdapter] are read from.
+enum LoraData {
+    /// A GGLA file.
+    File(File),
+    /// Weights that have been converted in memory, with the layout of a GGLA file.
+    Memory(Cursor<Vec<u8>>),
+}
+impl LoraData {
+    fn reader(&mut self) -> &mut dyn ReadSeek {
+        match self {
+            Self::File(file) => file,
+            Self::Memory(cursor) => cursor,
+        }
+    }
+}
+
+/// The parts of a PEFT `adapter_config.json` that are needed to apply the adapter.
+#[derive(Deserialize)]
+struct PeftConfig {
+    r: usize,
+    lora_alpha: f32,
+    #[serde(default)]
+    use_rslora: bool,
+    #[serde(default)]
+    rank_pattern: HashMap<String, serde_json::Value>,
+    #[serde(default)]
+    alpha_pattern: HashMap<String, serde_json::Value>,
+}
+impl PeftConfig {
+    fn scaling(&self) -> f32 {
+        if self.use_rslora {
+            self.lora_alpha / (self.r as f32).sqrt()
+        } else {
+            self.lora_alpha / self.r as f32
+        }
+    }
 }
 
 impl LoraAdapter {
+    /// Loads the GGLA adapter at `path`. Its scaling is calculated from its [LoraParameters].
+    pub fn load(path: &Path) -> Result<Self, LoadError> {
+        // Read the LoRA file
+        let file = File::open(path).map_err(|e| LoadError::OpenFileFailed {
+            source: e,
+            path: path.to_owned(),
+        })?;
+        let mut reader = BufReader::new(&file);
+        let mut loader: Loader<LoraParameters, _> =
+            Loader::new(Tokenizer::empty_embedded(), |_| {});
+        ggml::format::load(&mut reader, &mut loader)
+            .map_err(|err| LoadError::from_format_error(err, path.to_owned()))?;
+
+        // Collect the names of the tensors that should be patched
+        let tensors_to_patch = loader
+            .tensors
+            .keys()
+            .filter_map(|k| Some(k.rsplit_once('.')?.0.to_owned()))
+            .collect();
+
+        log::trace!("Loaded LoRA weights");
+        Ok(LoraAdapter {
+            scaling: loader.hyperparameters.calculate_scaling(),
+            tensors: loader.tensors,
+            tensors_to_patch,
+            n_threads: 8,
+            path: path.to_owned(),
+            data: LoraData::File(file),
+        })
+    }
+
+    /// Loads the [PEFT](https://github.com/huggingface/peft) adapter in `directory`, which must
+    /// contain an `adapter_config.json` and an `adapter_model.safetensors`.
+    ///
+    /// The modules the adapter patches are mapped onto the tensors of the model with
+    /// `hyperparameters` (see [Hyperparameters::tensor_from_hf]), and its scaling is calculated
+    /// from `r` and `lora_alpha`. The weights are converted into memory as they are read, which is
+    /// reported through [LoadProgress::LoraTensorLoaded].
+    pub fn load_peft<H: Hyperparameters>(
+        directory: &Path,
+        hyperparameters: &H,
+        mut load_progress_callback: impl FnMut(LoadProgress),
+    ) -> Result<Self, LoadError> {
+        let config_path = directory.join("adapter_config.json");
+        let config_file = File::open(&config_path).map_err(|e| LoadError::OpenFileFailed {
+            source: e,
+            path: config_path.clone(),
+        })?;
+        let config: PeftConfig =
+            serde_json::from_reader(BufReader::new(config_file)).map_err(|e| {
+                LoadError::InvariantBroken {
+                    path: Some(config_path.clone()),
+                    invariant: format!("the adapter config should be valid: {e}"),
+                }
+            })?;
+        if !config.rank_pattern.is_empty() || !config.alpha_pattern.is_empty() {
+            return Err(LoadError::InvariantBroken {
+                path: Some(config_path),
+                invariant: "all modules should share the same `r` and `lora_alpha`".to_owned(),
+            });
+        }
+
+        let weights_path = directory.join("adapter_model.safetensors");
+        let weights = SafeTensors::open(&weights_path).map_err(|e| LoadError::OpenFileFailed {
+            source: e,
+            path: weights_path.clone(),
+        })?;
+
+        let mut data = vec![];
+        let mut tensors = HashMap::new();
+        let mut tensors_to_patch = HashSet::new();
+        let tensor_count = weights.tensors().len();
+        for (current_tensor, (peft_name, info)) in weights.tensors().iter().enumerate() {
+            let unknown_tensor = || LoadError::UnknownTensor {
+                tensor_name: peft_name.clone(),
+                path: weights_path.clone(),
+            };
+            let (module, matrix) = parse_peft_tensor_name(peft_name).ok_or_else(unknown_tensor)?;
+            let (name, transform) = hyperparameters
+                .tensor_from_hf(&format!("{module}.weight"))
+                .ok_or_else(unknown_tensor)?;
+
+            let [rows, cols] = match info.shape[..] {
+                [rows, cols] => [rows, cols],
+                _ => {
+                    return Err(LoadError::InvariantBroken {
+                        path: Some(weights_path),
+                        invariant: format!("{peft_name} should be two-dimensional"),
+                    })
+                }
+            };
+            let values = weights.read_f32(info)?;
+            // GGLA stores `A` as `[r, n_in]` and `B` as `[r, n_out]` (innermost first), while
+            // PEFT stores them as `[r, n_in]` and `[n_out, r]` (outermost first). The rows of `B`
+            // are the rows of the patched weight, so they are rearranged like the weight's.
+            let (values, [rows, cols]) = match (matrix, transform) {
+                ("A", _) => TensorTransform::Transpose.apply(values, [rows, cols]),
+                (_, TensorTransform::UnpermuteRotary { .. }) => {
+                    transform.apply(values, [rows, cols])
+                }
+                _ => (values, [rows, cols]),
+            };
+
+            tensors.insert(
+                format!("{name}.lora{matrix}"),
+                TensorLoadInfo {
+                    name: format!("{name}.lora{matrix}"),
+                    n_dims: 2,
+                    dims: [cols, rows],
+                    n_elements: values.len(),
+                    element_type: ggml::Type::F32,
+                    start_offset: data.len() as u64,
+                },
+            );
+            data.extend(values.iter().flat_map(|v| v.to_le_bytes()));
+            tensors_to_patch.insert(name);
+
+            load_progress_callback(LoadProgress::LoraTensorLoaded {
+                source: directory.to_owned(),
+                current_tensor,
+                tensor_count,
+            });
+        }
+
+        log::trace!("Loaded PEFT LoRA weights");
+        Ok(LoraAdapter {
+            scaling: config.scaling(),
+            tensors,
+            tensors_to_patch,
+            n_threads: 8,
+            path: directory.to_owned(),
+            data: LoraData::Memory(Cursor::new(data)),
+        })
+    }
+
+    /// Loads the adapter at `path`, which is either a GGLA file or the directory of a PEFT
+    /// adapter for a model with `hyperparameters`.
+    pub(crate) fn load_any<H: Hyperparameters>(
+        path: &Path,
+        hyperparameters: &H,
+        load_progress_callback: impl FnMut(LoadProgress),
+    ) -> Result<Self, LoadError> {
+        if path.is_dir() {
+            Self::load_peft(path, hyperparameters, load_progress_callback)
+        } else {
+            Self::load(path)
+        }
+    }
+
     /// Patch a tensor via LoRA
     pub fn patch(
         &mut self,
@@ -106,7 +344,7 @@ impl LoraAdapter {
         // Create a temporary context for the patching operations
         // TODO: test if GPU can be enabled (make it configurable)
         let patch_context = ggml::Context::new_with_allocate(patch_context_size);
-        let mut patch_file = FileContext::new(&patch_context, &mut self.file, &self.path, None);
+        let mut patch_file = FileContext::new(&patch_context, self.data.reader(), &self.path, None);
 
         // Load the A and B tensors
         let a = patch_file.get_tensor(&a_info)?;
@@ -127,8 +365,7 @@ impl LoraAdapter {
         // Compute the graph
         gf.build_forward_expand(&output);
 
-        //TODO: maybe pass the model's thread count to this context
-        let mut plan = GraphExecutionPlan::new(&mut gf, 8);
+        let mut plan = GraphExecutionPlan::new(&mut gf, self.n_threads);
         plan.execute(&patch_context);
 
         // Overwrite the original tensor.
@@ -141,6 +378,45 @@ impl LoraAdapter {
         Ok(())
     }
 
+    /// Builds an adapter of rank `r` from the F32 `A` and `B` matrices of each tensor it
+    /// patches, laid out as in a GGLA file.
+    #[cfg(test)]
+    pub(crate) fn from_matrices(
+        path: &str,
+        scaling: f32,
+        r: usize,
+        matrices: &[(&str, &[f32], &[f32])],
+    ) -> Self {
+        let mut data = vec![];
+        let mut tensors = HashMap::new();
+        for &(name, a, b) in matrices {
+            for (matrix, values) in [("A", a), ("B", b)] {
+                let name = format!("{name}.lora{matrix}");
+                tensors.insert(
+                    name.clone(),
+                    TensorLoadInfo {
+                        name,
+                        n_dims: 2,
+                        dims: [r, values.len() / r],
+                        n_elements: values.len(),
+                        element_type: ggml::Type::F32,
+                        start_offset: data.len() as u64,
+                    },
+                );
+                data.extend(values.iter().flat_map(|v| v.to_le_bytes()));
+            }
+        }
+
+        LoraAdapter {
+            scaling,
+            tensors,
+            tensors_to_patch: matrices.iter().map(|m| m.0.to_owned()).collect(),
+            n_threads: 1,
+            path: PathBuf::from(path),
+            data: LoraData::Memory(Cursor::new(data)),
+        }
+    }
+
     fn get_info(&self, name: &str) -> Result<TensorLoadInfo, LoadError> {
         self.tensors
             .get(name)
@@ -151,3 +427,148 @@ impl LoraAdapter {
             })
     }
 }
+
+/// A [LoRA](https://arxiv.org/abs/2106.09685) adapter that is evaluated alongside a model,
+/// instead of being patched into its weights.
+///
+/// The `A` and `B` matrices of the adapter are kept in memory of their own, and each
+/// multiplication of an input `x` by a weight `W` the adapter patches becomes `W·x + B·(A·x)·s`.
+/// As the weights of the model are left untouched, sessions of the same model can use different
+/// adapters at the same time; see [InferenceSession::set_lora_adapters](crate::InferenceSession::set_lora_adapters).
+///
+/// # Safety
+/// This implements [Send] and [Sync] as it is immutable after construction.
+pub struct SessionLoraAdapter {
+    /// Scaling to apply to the LoRA weights.
+    pub scaling: f32,
+    /// Path to the LoRA file.
+    pub path: PathBuf,
+    /// The transposed `A` and the `B` matrix for each patched tensor, by the name of the
+    /// tensor in the model's context.
+    tensors: HashMap<String, (Tensor, Tensor)>,
+    /// The context owning the matrices. Must be kept alive for the adapter.
+    #[allow(dead_code)]
+    pub(crate) context: Arc<Context>,
+}
+
+unsafe impl Send for SessionLoraAdapter {}
+unsafe impl Sync for SessionLoraAdapter {}
+
+impl SessionLoraAdapter {
+    /// Reads the matrices of `adapter` into memory, keeping its scaling.
+    ///
+    /// Only adapters with unquantized matrices are supported.
+    pub fn from_adapter(mut adapter: LoraAdapter) -> Result<Self, LoadError> {
+        let context_size = adapter
+            .tensors
+            .values()
+            .map(|info| info.calc_absolute_size(false))
+            .sum::<usize>();
+        let context = Context::new_with_allocate(context_size);
+
+        let infos = adapter
+            .tensors_to_patch
+            .iter()
+            .map(|name| {
+                let a_info = adapter.get_info(&format!("{}.loraA", name))?;
+                let b_info = adapter.get_info(&format!("{}.loraB", name))?;
+                Ok((name.clone(), a_info, b_info))
+            })
+            .collect::<Result<Vec<_>, LoadError>>()?;
+
+        let reader = adapter.data.reader();
+        let mut tensors = HashMap::new();
+        for (name, a_info, b_info) in infos {
+            if a_info.n_dims != 2 || a_info.element_type.is_quantized() {
+                return Err(LoadError::InvariantBroken {
+                    path: Some(adapter.path.clone()),
+                    invariant: format!(
+                        "{} must be an unquantized two-dimensional tensor",
+                        a_info.name
+                    ),
+                });
+            }
+
+            // `A` is stored as `[r, n_in]`; multiplying the input by it needs `[n_in, r]`.
+            let [r, n_in] = [a_info.dims[0], a_info.dims[1]];
+            let element_size = ggml::type_size(a_info.element_type);
+            let a_data = a_info.read_data(&mut BufReader::new(&mut *reader))?;
+            let mut a_transposed = vec![0; a_data.len()];
+            for (i, element) in a_data.chunks_exact(element_size).enumerate() {
+                let (row, column) = (i / r, i % r);
+                let offset = (column * n_in + row) * element_size;
+                a_transposed[offset..offset + element_size].copy_from_slice(element);
+            }
+            let mut a = context.new_tensor_2d(a_info.element_type, n_in, r);
+            // SAFETY: the tensor was just created, and is exactly as large as the data.
+            unsafe { a.write_data(&a_transposed) };
+
+            let b = FileContext::new(&context, &mut *reader, &adapter.path, None)
+                .get_tensor(&b_info)?;
+
+            tensors.insert(truncate_tensor_name(&name).to_owned(), (a, b));
+        }
+
+        // The adapter is shared by sessions on any thread; see the safety note above.
+        #[allow(clippy::arc_with_non_send_sync)]
+        let context = Arc::new(context);
+        Ok(Self {
+            scaling: adapter.scaling,
+            path: adapter.path,
+            tensors,
+            context,
+        })
+    }
+
+    /// Adds the term of this adapter to `output`, the product of `weight` and `input` in `ctx0`.
+    /// If the adapter does not patch `weight`, `output` is returned as is.
+    pub(crate) fn apply(
+        &self,
+        ctx0: &Context,
+        weight_name: &str,
+        input: &Tensor,
+        output: Tensor,
+    ) -> Tensor {
+        let Some((a, b)) = self.tensors.get(weight_name) else {
+            return output;
+        };
+
+        let mut term = ctx0.op_mul_mat(b, &ctx0.op_mul_mat(a, input));
+        if self.scaling != 1.0 {
+            term = ctx0.op_scale(&term, &ctx0.new_f32(self.scaling));
+        }
+        ctx0.op_add(&output, &term)
+    }
+}
+
+/// Splits the name of a tensor in a PEFT adapter, such as
+/// `base_model.model.model.layers.0.self_attn.q_proj.lora_A.weight`, into the name of the patched
+/// module (`model.layers.0.self_attn.q_proj`) and the matrix (`A` or `B`).
+fn parse_peft_tensor_name(name: &str) -> Option<(&str, &str)> {
+    let name = name.strip_prefix("base_model.model.").unwrap_or(name);
+    let (module, matrix) = name.strip_suffix(".weight")?.rsplit_once(".lora_")?;
+    matches!(matrix, "A" | "B").then_some((module, matrix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_parse_peft_tensor_names() {
+        assert_eq!(
+            parse_peft_tensor_name(
+                "base_model.model.model.layers.0.self_attn.q_proj.lora_A.weight"
+            ),
+            Some(("model.layers.0.self_attn.q_proj", "A"))
+        );
+        assert_eq!(
+            parse_peft_tensor_name("base_model.model.transformer.h.0.attn.c_attn.lora_B.weight"),
+            Some(("transformer.h.0.attn.c_attn", "B"))
+        );
+        assert_eq!(
+            parse_peft_tensor_name("base_model.model.model.embed_tokens.lora_embedding_A"),
+            None
+        );
+    }
+}
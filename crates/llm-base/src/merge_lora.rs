@@ -0,0 +1,338 @@
+//! Implements merging of LoRA adapters into the weights of a model.
+
+use crate::{
+    loader::{FileContext, FileTypeFormat},
+    model::HyperparametersWriteError,
+    quantize::{QuantizationPolicy, QuantizationPreset, TensorTargets},
+    Hyperparameters, KnownModel, LoadError, LoadProgress, Loader, LoraAdapter, Tokenizer,
+};
+use ggml::format::{SaveContainerType, SaveError, SaveHandler, TensorLoadInfo, TensorSaveInfo};
+use regex::Regex;
+use std::{
+    collections::HashMap,
+    io::{BufRead, Seek, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use thiserror::Error;
+
+#[derive(Clone, Debug)]
+/// Progress of merging LoRA adapters into a model.
+pub enum MergeLoraProgress<'a> {
+    /// Hyperparameters have been loaded.
+    HyperparametersLoaded,
+    /// A LoRA adapter has been loaded.
+    AdapterLoaded {
+        /// Path to the adapter.
+        path: &'a Path,
+        /// The scaling the adapter will be applied with.
+        scaling: f32,
+    },
+    /// A tensor has been patched by the adapters.
+    TensorPatched {
+        /// Name of the tensor.
+        name: &'a str,
+        /// Number of adapters that patched the tensor.
+        n_adapters: usize,
+    },
+    /// A tensor has been quantized.
+    TensorQuantized {
+        /// Name of the tensor.
+        name: &'a str,
+        /// The original size (in bytes) of the tensor data.
+        original_size: usize,
+        /// The reduced size (in bytes) of the tensor data.
+        reduced_size: usize,
+        /// The type the tensor was quantized to.
+        element_type: ggml::Type,
+    },
+    /// The adapters have been merged into the model.
+    Finished {
+        /// Number of tensors that were patched.
+        n_patched: usize,
+    },
+}
+
+#[derive(Error, Debug)]
+/// Errors encountered while merging LoRA adapters into a model.
+pub enum MergeLoraError {
+    #[error("could not load model or adapter")]
+    /// There was an error while attempting to load the model or one of the adapters.
+    Load(#[from] LoadError),
+    #[error("non-specific I/O error")]
+    /// A non-specific IO error.
+    Io(#[from] std::io::Error),
+    #[error("invalid integer conversion")]
+    /// One of the integers encountered could not be converted to a more appropriate type.
+    InvalidIntegerConversion(#[from] std::num::TryFromIntError),
+    /// An invariant was broken.
+    #[error("invariant broken: {invariant} in {path:?}")]
+    InvariantBroken {
+        /// The path that failed.
+        path: PathBuf,
+        /// The invariant that was broken.
+        invariant: String,
+    },
+    /// An adapter patches a tensor that is not in the model.
+    #[error("the adapter {path:?} patches {tensor_name:?}, which is not in the model")]
+    UnknownTensor {
+        /// The path to the adapter.
+        path: PathBuf,
+        /// The name of the tensor.
+        tensor_name: String,
+    },
+    /// Attempted to quantize to an invalid target.
+    #[error("invalid quantization target {format:?}")]
+    InvalidQuantizationTarget {
+        /// The quantization target.
+        format: FileTypeFormat,
+    },
+    /// An error was encountered while writing the hyperparameters.
+    #[error("an error was encountered while writing the hyperparameters")]
+    HyperparametersWriteError(#[source] HyperparametersWriteError),
+    /// An attempt was made to save a model with a container type that does not
+    /// support vocabulary scoring, despite the model having a scored vocabulary.
+    #[error("container type does not support vocabulary scoring")]
+    VocabularyScoringNotSupported,
+}
+impl MergeLoraError {
+    pub(crate) fn from_format_error(value: SaveError<MergeLoraError>, path: PathBuf) -> Self {
+        match value {
+            SaveError::Io(io) => MergeLoraError::Io(io),
+            SaveError::InvalidIntegerConversion(e) => MergeLoraError::InvalidIntegerConversion(e),
+            SaveError::ImplementationError(e) => e,
+            SaveError::InvariantBroken(invariant) => {
+                MergeLoraError::InvariantBroken { path, invariant }
+            }
+            SaveError::VocabularyScoringNotSupported => {
+                MergeLoraError::VocabularyScoringNotSupported
+            }
+            err @ SaveError::UnsupportedElementType { .. } => MergeLoraError::InvariantBroken {
+                path,
+                invariant: err.to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// A LoRA adapter to merge into a model.
+pub struct MergeLoraAdapter {
+    /// Path to the GGLA adapter, or to the directory of a PEFT adapter.
+    pub path: PathBuf,
+    /// The scaling to apply the adapter with. If `None`, the scaling is calculated from the
+    /// adapter's [LoraParameters](crate::LoraParameters).
+    pub scaling: Option<f32>,
+}
+
+/// Merges the LoRA `adapters` into the model read from `reader`, and writes the result to `writer`
+/// as a standalone GGJT model.
+///
+/// The adapters are applied in order. If `quantization_type` is set, the F32 and F16 tensors that
+/// the model allows to be quantized are quantized after patching, as [quantize](crate::quantize)
+/// would; tensors that were already quantized keep their type.
+pub fn merge_lora<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
+    reader: &mut R,
+    writer: &mut W,
+    tokenizer: Tokenizer,
+    adapters: &[MergeLoraAdapter],
+    quantization_type: Option<FileTypeFormat>,
+    progress_callback: impl Fn(MergeLoraProgress),
+) -> Result<(), MergeLoraError> {
+    // Sanity check
+    let preset = quantization_type
+        .map(|format| {
+            QuantizationPreset::try_from(format)
+                .map_err(|_| MergeLoraError::InvalidQuantizationTarget { format })
+        })
+        .transpose()?;
+
+    // Load the model
+    let progress_callback = Arc::new(progress_callback);
+
+    let mut loader = Loader::<M::Hyperparameters, _>::new(tokenizer, {
+        let progress_callback = progress_callback.clone();
+        move |p| {
+            if let LoadProgress::HyperparametersLoaded = p {
+                progress_callback(MergeLoraProgress::HyperparametersLoaded)
+            }
+        }
+    });
+    ggml::format::load(reader, &mut loader)
+        .map_err(|err| LoadError::from_format_error(err, PathBuf::default()))?;
+
+    let Loader {
+        mut hyperparameters,
+        tokenizer,
+        tensors,
+        ..
+    } = loader;
+
+    // Load the adapters, and check that they only patch tensors in the model
+    let mut lora_adapters = vec![];
+    for adapter in adapters {
+        let mut lora_adapter = LoraAdapter::load_any(&adapter.path, &hyperparameters, |_| {})?;
+        if let Some(scaling) = adapter.scaling {
+            lora_adapter.scaling = scaling;
+        }
+        if let Some(tensor_name) = lora_adapter
+            .tensors_to_patch
+            .iter()
+            .find(|name| !tensors.contains_key(*name))
+        {
+            return Err(MergeLoraError::UnknownTensor {
+                path: adapter.path.clone(),
+                tensor_name: tensor_name.clone(),
+            });
+        }
+        progress_callback(MergeLoraProgress::AdapterLoaded {
+            path: &adapter.path,
+            scaling: lora_adapter.scaling,
+        });
+        lora_adapters.push(lora_adapter);
+    }
+
+    if let (Some(format), Some(ft)) = (quantization_type, hyperparameters.file_type_mut()) {
+        ft.quantization_version = ggml::QNT_VERSION;
+        ft.format = format;
+    }
+
+    let tokenizer = tokenizer.vocabulary(hyperparameters.n_vocabulary());
+
+    // Save the patched model
+    let to_quantize = M::quantize_tensors();
+    let to_skip = M::skip_quantize_tensors();
+    let targets = preset.map(|preset| {
+        TensorTargets::new::<M>(
+            preset,
+            &QuantizationPolicy::default(),
+            tensors.keys().map(String::as_str),
+        )
+        .expect("an empty policy has no invalid types")
+    });
+    let mut saver = MergeLoraSaver {
+        targets: targets.as_ref(),
+        hyperparameters: &hyperparameters,
+        tensors: &tensors,
+        lora_adapters: &mut lora_adapters,
+        to_quantize: &to_quantize,
+        to_skip: &to_skip,
+        source_reader: reader,
+        progress_callback: |p| progress_callback(p),
+        n_patched: 0,
+    };
+    ggml::format::save(
+        writer,
+        &mut saver,
+        SaveContainerType::GgjtV3,
+        &tokenizer,
+        &tensors.keys().cloned().collect::<Vec<_>>(),
+    )
+    .map_err(|err| MergeLoraError::from_format_error(err, PathBuf::default()))?;
+
+    progress_callback(MergeLoraProgress::Finished {
+        n_patched: saver.n_patched,
+    });
+
+    Ok(())
+}
+
+struct MergeLoraSaver<'a, F: Fn(MergeLoraProgress), H: Hyperparameters, R: BufRead + Seek> {
+    // Input
+    targets: Option<&'a TensorTargets>,
+    hyperparameters: &'a H,
+    tensors: &'a HashMap<String, TensorLoadInfo>,
+    lora_adapters: &'a mut [LoraAdapter],
+    to_quantize: &'a [Regex],
+    to_skip: &'a [Regex],
+    source_reader: &'a mut R,
+    progress_callback: F,
+
+    // Output
+    n_patched: usize,
+}
+impl<F: Fn(MergeLoraProgress), H: Hyperparameters, R: BufRead + Seek> MergeLoraSaver<'_, F, H, R> {
+    /// Reads the tensor and applies every adapter that patches it.
+    fn patched_data(&mut self, tensor: &TensorLoadInfo) -> Result<Vec<u8>, MergeLoraError> {
+        let context = ggml::Context::new_with_allocate(tensor.calc_absolute_size(false));
+        let mut patched = FileContext::new(&context, self.source_reader, Path::new(""), None)
+            .get_tensor(tensor)?;
+        for lora_adapter in self.lora_adapters.iter_mut() {
+            lora_adapter.patch(tensor, &mut patched)?;
+        }
+
+        // SAFETY: the tensor's data is owned by `context`, which outlives this slice.
+        let data =
+            unsafe { std::slice::from_raw_parts(patched.data() as *const u8, patched.nbytes()) };
+        Ok(data.to_vec())
+    }
+}
+impl<F: Fn(MergeLoraProgress), H: Hyperparameters, R: BufRead + Seek> SaveHandler<MergeLoraError>
+    for MergeLoraSaver<'_, F, H, R>
+{
+    fn write_hyperparameters(&mut self, writer: &mut dyn Write) -> Result<(), MergeLoraError> {
+        self.hyperparameters
+            .write_ggml(writer)
+            .map_err(MergeLoraError::HyperparametersWriteError)?;
+        Ok(())
+    }
+
+    fn tensor_data(&mut self, tensor_name: &str) -> Result<TensorSaveInfo, MergeLoraError> {
+        let tensor = self.tensors.get(tensor_name).expect(
+            "tensor not found; should be impossible due to handler being populated from loader",
+        );
+
+        let n_adapters = self
+            .lora_adapters
+            .iter()
+            .filter(|a| a.tensors_to_patch.contains(tensor_name))
+            .count();
+        let data = if n_adapters > 0 {
+            let data = self.patched_data(tensor)?;
+            self.n_patched += 1;
+            (self.progress_callback)(MergeLoraProgress::TensorPatched {
+                name: tensor_name,
+                n_adapters,
+            });
+            data
+        } else {
+            tensor.read_data(self.source_reader)?
+        };
+
+        // Quantize only 2D tensors that have not been quantized yet
+        let quantization_target = self
+            .targets
+            .filter(|_| {
+                tensor.n_dims == 2
+                    && matches!(tensor.element_type, ggml::Type::F32 | ggml::Type::F16)
+                    && self.to_quantize.iter().any(|re| re.is_match(tensor_name))
+                    && !self.to_skip.iter().any(|re| re.is_match(tensor_name))
+            })
+            .and_then(|targets| targets.target(tensor_name, tensor.dims[0]));
+        let (element_type, data) = match quantization_target {
+            Some(target) => {
+                let result = target.quantize(
+                    tensor.element_type,
+                    &data,
+                    tensor.n_elements,
+                    tensor.dims[0],
+                );
+                (self.progress_callback)(MergeLoraProgress::TensorQuantized {
+                    name: tensor_name,
+                    original_size: data.len(),
+                    reduced_size: result.output.len(),
+                    element_type: target.into(),
+                });
+                (target.into(), result.output)
+            }
+            None => (tensor.element_type, data),
+        };
+
+        Ok(TensorSaveInfo {
+            n_dims: tensor.n_dims,
+            dims: tensor.dims,
+            element_type,
+            data,
+        })
+    }
+}
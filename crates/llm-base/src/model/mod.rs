@@ -1,19 +1,28 @@
 //! Large language model traits and types
 
 use std::{
+    collections::HashMap,
     error::Error,
     fmt::Debug,
     io::{BufRead, Write},
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use ggml::accelerator::Backend;
+use ggml::{
+    accelerator::Backend,
+    format::{gguf::Metadata, TensorLoadInfo},
+    Buffer, Context, Tensor,
+};
 use regex::Regex;
 use thiserror::Error;
 
 use crate::{
-    loader::TensorLoader, tokenizer::TokenId, FileType, InferenceSession, InferenceSessionConfig,
-    LoadError, LoadProgress, Tokenizer, TokenizerSource,
+    convert::{ConvertError, HfConfig, TensorTransform},
+    loader::TensorLoader,
+    tokenizer::TokenId,
+    BatchedSequence, FileType, InferenceSession, InferenceSessionConfig, LoadError, LoadProgress,
+    LoraAdapter, LoraError, TensorSplit, Tokenizer, TokenizerSource,
 };
 
 /// Common functions for model evaluation
@@ -65,9 +74,34 @@ pub trait KnownModel: Send + Sync {
         output_request: &mut OutputRequest,
     );
 
+    /// This function is called by the provided [InferenceSession]s to generate output by
+    /// evaluating the input tokens of each of them, e.g. the next tokens of several users of a
+    /// server.
+    ///
+    /// Models that implement this evaluate all of the sequences in one graph, so that the
+    /// weights are only multiplied once for all of them; by default, the sequences are
+    /// [evaluated](Self::evaluate) one by one. Implementations should also evaluate them one
+    /// by one when [InferenceSession::compute_batch] cannot batch them.
+    fn evaluate_batch(&self, sequences: &mut [BatchedSequence]) {
+        for sequence in sequences {
+            self.evaluate(
+                sequence.session,
+                sequence.input_tokens,
+                sequence.output_request,
+            );
+        }
+    }
+
     /// Get the hyperparameters for this model.
     fn hyperparameters(&self) -> &Self::Hyperparameters;
 
+    /// Get the context holding the weights of this model.
+    fn context(&self) -> &ModelContext;
+
+    /// Get the context holding the weights of this model mutably, e.g. to change the
+    /// LoRA adapters applied to it.
+    fn context_mut(&mut self) -> &mut ModelContext;
+
     /// Get the tokenizer for this model.
     fn tokenizer(&self) -> &Tokenizer;
 
@@ -87,11 +121,54 @@ pub trait KnownModel: Send + Sync {
     /// Get the list of regexes to use to determine if a tensor in this model should not be quantized.
     fn skip_quantize_tensors() -> Vec<Regex>;
 
+    /// Get the [TensorRole] of the tensor named `name`, which decides the type it is quantized to
+    /// by the mixed k-quant presets. Tensors are treated as [TensorRole::Other] by default.
+    fn tensor_role(_name: &str) -> TensorRole {
+        TensorRole::Other
+    }
+
     /// Returns whether the model supports deleting tokens.
     fn supports_rewind(&self) -> bool {
         // Assume we can't delete unless otherwise specified
         false
     }
+
+    /// Returns how the model stores tokens in the memory of its sessions, or `None` if tokens
+    /// cannot be moved within it, e.g. because the model learns an embedding for each absolute
+    /// position.
+    fn memory_layout(&self) -> Option<MemoryLayout> {
+        None
+    }
+
+    /// Returns whether the model supports removing and replacing tokens in the middle of a
+    /// session (see [InferenceSession::remove_range]). By default, this is supported if the
+    /// model describes its [memory layout](Self::memory_layout).
+    fn supports_remove_range(&self) -> bool {
+        self.memory_layout().is_some()
+    }
+
+    /// Returns how the two-dimensional tensor `name` is divided between the parts of a
+    /// multi-part model. One-dimensional tensors are never split; every part has a copy.
+    fn tensor_split(name: &str) -> TensorSplit
+    where
+        Self: Sized,
+    {
+        let _ = name;
+        TensorSplit::ByRows
+    }
+
+    /// Returns whether a model file with the given tensors (as reported by `has_tensor`)
+    /// could be a model of this architecture.
+    ///
+    /// This is used to guess the architecture of a model file; see [crate::probe].
+    fn matches_tensors(has_tensor: &dyn Fn(&str) -> bool) -> bool
+    where
+        Self: Sized,
+    {
+        // Assume we can't recognise the model unless otherwise specified
+        let _ = has_tensor;
+        false
+    }
 }
 
 /// A type-erased model to allow for interacting with a model without knowing
@@ -111,9 +188,26 @@ pub trait Model: Send + Sync {
         output_request: &mut OutputRequest,
     );
 
+    /// This function is called by the provided [InferenceSession]s to generate output by
+    /// evaluating the input tokens of each of them.
+    ///
+    /// Only LLaMA models evaluate the sequences together in one graph. The other architectures
+    /// evaluate them one at a time, and so does LLaMA if any of the sequences has no input
+    /// tokens, evaluates LoRA adapters, records an importance matrix or is evaluated with Metal
+    /// (see [InferenceSession::compute_batch]). Either way, each sequence gets the same outputs
+    /// as if it had been [evaluated](Self::evaluate) on its own.
+    fn evaluate_batch(&self, sequences: &mut [BatchedSequence]);
+
     /// Get the tokenizer for this model.
     fn tokenizer(&self) -> &Tokenizer;
 
+    /// Get the context holding the weights of this model.
+    fn context(&self) -> &ModelContext;
+
+    /// Get the context holding the weights of this model mutably, e.g. to change the
+    /// LoRA adapters applied to it.
+    fn context_mut(&mut self) -> &mut ModelContext;
+
     /// Get the context size (configured with [ModelParameters::context_size]) used by
     /// this model.
     fn context_size(&self) -> usize;
@@ -126,6 +220,14 @@ pub trait Model: Send + Sync {
 
     /// Returns whether the model supports deleting tokens.
     fn supports_rewind(&self) -> bool;
+
+    /// Returns how the model stores tokens in the memory of its sessions, or `None` if tokens
+    /// cannot be moved within it.
+    fn memory_layout(&self) -> Option<MemoryLayout>;
+
+    /// Returns whether the model supports removing and replacing tokens in the middle of a
+    /// session.
+    fn supports_remove_range(&self) -> bool;
 }
 impl<H: Hyperparameters, M: KnownModel<Hyperparameters = H>> Model for M {
     fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
@@ -141,10 +243,22 @@ impl<H: Hyperparameters, M: KnownModel<Hyperparameters = H>> Model for M {
         KnownModel::evaluate(self, session, input_tokens, output_request)
     }
 
+    fn evaluate_batch(&self, sequences: &mut [BatchedSequence]) {
+        KnownModel::evaluate_batch(self, sequences)
+    }
+
     fn tokenizer(&self) -> &Tokenizer {
         KnownModel::tokenizer(self)
     }
 
+    fn context(&self) -> &ModelContext {
+        KnownModel::context(self)
+    }
+
+    fn context_mut(&mut self) -> &mut ModelContext {
+        KnownModel::context_mut(self)
+    }
+
     fn context_size(&self) -> usize {
         KnownModel::context_size(self)
     }
@@ -160,6 +274,226 @@ impl<H: Hyperparameters, M: KnownModel<Hyperparameters = H>> Model for M {
     fn supports_rewind(&self) -> bool {
         KnownModel::supports_rewind(self)
     }
+
+    fn memory_layout(&self) -> Option<MemoryLayout> {
+        KnownModel::memory_layout(self)
+    }
+
+    fn supports_remove_range(&self) -> bool {
+        KnownModel::supports_remove_range(self)
+    }
+}
+
+/// The context holding the weights of a loaded model, along with the tensors in it.
+///
+/// Keeping track of the tensors allows the [LoRA](https://arxiv.org/abs/2106.09685) adapters
+/// applied to the model to be changed after it has been loaded. An adapter is applied by adding
+/// its scaled `BA` product to each tensor it patches. When it is removed or its scaling is
+/// changed, the tensors it patches are restored to their original data and the adapters are
+/// reapplied. Memory-mapped tensors are pointed back at their original data in the mapping; all
+/// other tensors keep a copy of their original data from when they were first patched.
+///
+/// Tensors that have been offloaded to an accelerator are not updated.
+pub struct ModelContext {
+    /// The context the weights live in.
+    pub(crate) context: Arc<Context>,
+    tensors: HashMap<String, ModelTensor>,
+    lora_adapters: Vec<LoraAdapter>,
+}
+impl ModelContext {
+    pub(crate) fn new(
+        context: Context,
+        tensors: HashMap<String, ModelTensor>,
+        lora_adapters: Vec<LoraAdapter>,
+    ) -> Self {
+        // Models are `Send` and `Sync`, and their sessions keep the weights alive from any
+        // thread. The weights are only written to through `&mut self`, when no session is
+        // evaluating the model.
+        #[allow(clippy::arc_with_non_send_sync)]
+        let context = Arc::new(context);
+        Self {
+            context,
+            tensors,
+            lora_adapters,
+        }
+    }
+
+    /// Returns the LoRA adapters applied to the model, in the order they were applied.
+    pub fn lora_adapters(&self) -> &[LoraAdapter] {
+        &self.lora_adapters
+    }
+
+    /// Applies `adapter` to the model with its [scaling](LoraAdapter::scaling), which can be
+    /// overridden before calling this.
+    ///
+    /// If patching a tensor fails, the tensors patched before it are restored, so that the model
+    /// is left as it was.
+    pub fn apply_lora(&mut self, mut adapter: LoraAdapter) -> Result<(), LoraError> {
+        if self.lora_adapters.iter().any(|a| a.path == adapter.path) {
+            return Err(LoraError::AlreadyApplied { path: adapter.path });
+        }
+        if let Some(tensor_name) = adapter
+            .tensors_to_patch
+            .iter()
+            .find(|name| !self.tensors.contains_key(*name))
+        {
+            return Err(LoraError::UnknownTensor {
+                tensor_name: tensor_name.clone(),
+                path: adapter.path,
+            });
+        }
+
+        let mapping = self.context.storage().as_mapped();
+        let names: Vec<_> = adapter.tensors_to_patch.iter().cloned().collect();
+        for (index, name) in names.iter().enumerate() {
+            let tensor = self.tensors.get_mut(name).unwrap();
+            tensor.make_writable(mapping);
+            if let Err(err) = adapter.patch(&tensor.info, &mut tensor.tensor) {
+                for name in &names[..=index] {
+                    let tensor = self.tensors.get_mut(name).unwrap();
+                    // The adapters were applied to the tensor before, so this is not expected to
+                    // fail; if it does, the error that caused the rollback is more useful.
+                    let _ = repatch(tensor, mapping, &mut self.lora_adapters);
+                }
+                return Err(err.into());
+            }
+        }
+        self.lora_adapters.push(adapter);
+
+        Ok(())
+    }
+
+    /// Removes the adapter loaded from `path` from the model, and returns it.
+    pub fn remove_lora(&mut self, path: &Path) -> Result<LoraAdapter, LoraError> {
+        let index = self.adapter_index(path)?;
+        let adapter = self.lora_adapters.remove(index);
+
+        let mapping = self.context.storage().as_mapped();
+        for name in &adapter.tensors_to_patch {
+            let tensor = self.tensors.get_mut(name).unwrap();
+            repatch(tensor, mapping, &mut self.lora_adapters)?;
+        }
+
+        Ok(adapter)
+    }
+
+    /// Replaces the adapter loaded from `path` with `adapter`, and returns the removed adapter.
+    pub fn replace_lora(
+        &mut self,
+        path: &Path,
+        adapter: LoraAdapter,
+    ) -> Result<LoraAdapter, LoraError> {
+        let removed = self.remove_lora(path)?;
+        self.apply_lora(adapter)?;
+        Ok(removed)
+    }
+
+    /// Changes the scaling of the adapter loaded from `path` to `scaling`.
+    pub fn set_lora_scaling(&mut self, path: &Path, scaling: f32) -> Result<(), LoraError> {
+        let index = self.adapter_index(path)?;
+        let adapter = &mut self.lora_adapters[index];
+        if adapter.scaling == scaling {
+            return Ok(());
+        }
+        adapter.scaling = scaling;
+
+        let mapping = self.context.storage().as_mapped();
+        for name in self.lora_adapters[index].tensors_to_patch.clone() {
+            let tensor = self.tensors.get_mut(&name).unwrap();
+            repatch(tensor, mapping, &mut self.lora_adapters)?;
+        }
+
+        Ok(())
+    }
+
+    fn adapter_index(&self, path: &Path) -> Result<usize, LoraError> {
+        self.lora_adapters
+            .iter()
+            .position(|a| a.path == path)
+            .ok_or_else(|| LoraError::NotApplied {
+                path: path.to_owned(),
+            })
+    }
+}
+
+/// A tensor of a loaded model.
+pub(crate) struct ModelTensor {
+    pub info: TensorLoadInfo,
+    pub tensor: Tensor,
+    /// Whether the original data of the tensor is in the mapping of the model file.
+    pub mapped: bool,
+    /// The data of the tensor, if it had to be copied out of the mapping to be patched.
+    pub buffer: Option<Buffer>,
+    /// A copy of the original data of a tensor that is not in the mapping, taken before it was
+    /// first patched.
+    pub original: Option<Vec<u8>>,
+}
+impl ModelTensor {
+    /// Returns a pointer to the original data of the tensor in `mapping`, if it is there.
+    fn mapped_data(&self, mapping: Option<&[u8]>) -> Option<*mut std::ffi::c_void> {
+        let mapping = mapping.filter(|_| self.mapped)?;
+        Some(mapping[self.info.start_offset as usize..].as_ptr() as *mut _)
+    }
+
+    /// Prepares the tensor to be patched. Its data is copied out of the read-only `mapping` if
+    /// it is still there, and kept to be restored otherwise.
+    fn make_writable(&mut self, mapping: Option<&[u8]>) {
+        let Some(mapped_data) = self.mapped_data(mapping) else {
+            if self.original.is_none() {
+                let mut original = vec![0; self.tensor.nbytes()];
+                // SAFETY: the buffer is exactly `nbytes` long.
+                unsafe { self.tensor.read_data(0, &mut original) };
+                self.original = Some(original);
+            }
+            return;
+        };
+        if unsafe { self.tensor.data() } != mapped_data {
+            return;
+        }
+
+        let buffer = Buffer::new(self.tensor.nbytes());
+        // SAFETY: the buffer is at least `nbytes` long, and lives as long as the tensor points to it.
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                mapped_data as *const u8,
+                buffer.data() as *mut u8,
+                self.tensor.nbytes(),
+            );
+            self.tensor.set_data(buffer.data());
+        }
+        self.buffer = Some(buffer);
+    }
+
+    /// Restores the original data of the tensor, either by pointing it back at `mapping` or from
+    /// the copy taken before it was first patched.
+    fn restore(&mut self, mapping: Option<&[u8]>) {
+        if let Some(mapped_data) = self.mapped_data(mapping) {
+            // SAFETY: the mapping lives as long as the context the tensor belongs to.
+            unsafe { self.tensor.set_data(mapped_data) };
+            self.buffer = None;
+        } else if let Some(original) = &self.original {
+            // SAFETY: the copy was taken from this tensor, so it is exactly `nbytes` long.
+            unsafe { self.tensor.write_data(original) };
+        }
+    }
+}
+
+/// Restores the original data of `tensor`, and patches it again with those of `adapters` that
+/// patch it.
+fn repatch(
+    tensor: &mut ModelTensor,
+    mapping: Option<&[u8]>,
+    adapters: &mut [LoraAdapter],
+) -> Result<(), LoadError> {
+    tensor.restore(mapping);
+    for adapter in adapters.iter_mut() {
+        if !adapter.tensors_to_patch.contains(&tensor.info.name) {
+            continue;
+        }
+        tensor.make_writable(mapping);
+        adapter.patch(&tensor.info, &mut tensor.tensor)?;
+    }
+    Ok(())
 }
 
 /// Implemented by model hyperparameters for interacting with hyperparameters
@@ -171,6 +505,59 @@ pub trait Hyperparameters: Sized + Default + Debug + PartialEq + Eq {
     /// Write the parameters in GGML format to a writer.
     fn write_ggml(&self, writer: &mut dyn Write) -> Result<(), HyperparametersWriteError>;
 
+    /// Read the parameters from the metadata of a GGUF file.
+    fn read_gguf(metadata: &Metadata) -> Result<Self, LoadError>;
+
+    /// Write the parameters to the metadata of a GGUF file, including the architecture of the
+    /// model and the tokenizer its vocabulary is stored for (`tokenizer.ggml.model`).
+    fn write_gguf(&self, metadata: &mut Metadata) -> Result<(), HyperparametersWriteError>;
+
+    /// Map the name of a tensor in a GGUF file to the name this model loads it by.
+    ///
+    /// GGUF uses architecture-independent tensor names (e.g. `blk.0.attn_q.weight`); models
+    /// keep using the names from the legacy formats. See [util::translate_gguf_tensor_name](crate::util::translate_gguf_tensor_name).
+    ///
+    /// The default implementation returns the name unchanged.
+    fn tensor_name_from_gguf(&self, name: &str) -> String {
+        name.to_owned()
+    }
+
+    /// Map the name this model loads a tensor by to its name in a GGUF file. This is the
+    /// inverse of [Hyperparameters::tensor_name_from_gguf].
+    ///
+    /// The default implementation returns the name unchanged.
+    fn tensor_name_to_gguf(&self, name: &str) -> String {
+        name.to_owned()
+    }
+
+    /// Build the parameters from the `config.json` of a Hugging Face checkpoint. See [crate::convert].
+    ///
+    /// The default implementation reports that the model cannot be converted.
+    fn read_hf_config(config: &HfConfig) -> Result<Self, ConvertError> {
+        let _ = config;
+        Err(ConvertError::UnsupportedArchitecture)
+    }
+
+    /// Map the name of a tensor in a Hugging Face checkpoint to the name this model loads it by,
+    /// along with how its data must be rearranged. Returns `None` for tensors the model does not
+    /// use. See [util::translate_hf_tensor_name](crate::util::translate_hf_tensor_name).
+    ///
+    /// The default implementation uses no tensors.
+    fn tensor_from_hf(&self, name: &str) -> Option<(String, TensorTransform)> {
+        let _ = name;
+        None
+    }
+
+    /// The names of the token embedding and the output projection in a Hugging Face checkpoint.
+    /// Checkpoints with `tie_word_embeddings` only store the embedding, which is then also written
+    /// as the output projection.
+    ///
+    /// The default implementation returns `None`, for models that do not need the output projection
+    /// to be stored.
+    fn tied_hf_tensors(&self) -> Option<(&'static str, &'static str)> {
+        None
+    }
+
     /// Get the number of tokens in the embedded vocabulary, if any.
     fn n_vocabulary(&self) -> usize;
 
@@ -180,6 +567,56 @@ pub trait Hyperparameters: Sized + Default + Debug + PartialEq + Eq {
     /// Get mutable access to filetype of the model.
     fn file_type_mut(&mut self) -> Option<&mut FileType>;
 }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// The role a tensor plays in a model. The mixed k-quant presets (e.g.
+/// [FileTypeFormat::MostlyQ4_K_M](crate::FileTypeFormat::MostlyQ4_K_M)) quantize the tensors
+/// that are most sensitive to quantization with more bits.
+pub enum TensorRole {
+    /// The output projection, from the hidden state to the logits.
+    Output,
+    /// The value projection of an attention block, or its fused query/key/value projection.
+    AttentionValue,
+    /// The output projection of an attention block.
+    AttentionOutput,
+    /// The down projection of a feed-forward block.
+    FeedForwardDown,
+    /// Any other tensor.
+    Other,
+}
+
+#[derive(Debug, Clone)]
+/// How a model stores the tokens it has evaluated in the memory of an [InferenceSession].
+///
+/// Models that describe their layout allow tokens to be removed from the middle of the memory
+/// (e.g. to make room when the context is full) without evaluating the remaining tokens again.
+pub struct MemoryLayout {
+    /// How the model encodes the positions of the tokens.
+    pub position_encoding: PositionEncoding,
+    /// Whether the values are stored transposed, with the values of each embedding dimension
+    /// contiguous, rather than with the values of each token contiguous like the keys.
+    pub transposed_values: bool,
+}
+
+#[derive(Debug, Clone)]
+/// How a model encodes the positions of the tokens in its context.
+pub enum PositionEncoding {
+    /// The keys are rotated by [RoPE](https://arxiv.org/abs/2104.09864) before they are stored,
+    /// so they have to be rotated again when they move to another position.
+    Rotary {
+        /// The number of elements of the keys of each attention head.
+        n_embd_head: usize,
+        /// The number of elements of each key that are rotated.
+        n_rot: usize,
+        /// The RoPE mode passed to [ggml::Context::op_rope_inplace].
+        mode: i32,
+        /// The RoPE overrides the model is evaluated with.
+        overrides: Option<ggml::RoPEOverrides>,
+    },
+    /// The positions are encoded by [ALiBi](https://arxiv.org/abs/2108.12409) biases, which only
+    /// depend on the order of the keys, so they can be moved as they are.
+    Alibi,
+}
+
 #[derive(Error, Debug)]
 /// Reported from functions that write
 pub enum HyperparametersWriteError {
@@ -189,6 +626,17 @@ pub enum HyperparametersWriteError {
     #[error("invalid integer conversion")]
     /// One of the integers encountered could not be converted to a more appropriate type.
     InvalidIntegerConversion(#[from] std::num::TryFromIntError),
+    #[error("the parameters cannot be stored in GGUF")]
+    /// The parameters belong to something that is not stored in GGUF files.
+    GgufNotSupported,
+    #[error("the {name} {value} cannot be stored in this format")]
+    /// A parameter has a value that the format being written cannot store.
+    UnrepresentableValue {
+        /// The name of the parameter.
+        name: &'static str,
+        /// Its value.
+        value: usize,
+    },
 }
 
 /// Parameters for model-wide behaviour.
@@ -202,7 +650,16 @@ pub struct ModelParameters {
     /// consumes more resources, but produces more consistent and coherent responses.
     pub context_size: usize,
     /// The [LoRA](https://arxiv.org/abs/2106.09685) adapters to use when loading the model. If `None`, no adapters will be used.
+    ///
+    /// Each adapter is either a GGLA file, or a directory containing a [PEFT](https://github.com/huggingface/peft)
+    /// adapter (see [LoraAdapter::load_peft]).
+    ///
+    /// If the model is memory-mapped, only the tensors patched by the adapters are copied into memory.
+    /// Otherwise, a copy of the original data of those tensors is kept, so that the adapters can be
+    /// removed or rescaled later (see [ModelContext]).
     pub lora_adapters: Option<Vec<PathBuf>>,
+    /// The number of threads to use when patching the model with the `lora_adapters`.
+    pub lora_threads: usize,
     /// Whether to use GPU acceleration when available
     pub use_gpu: bool,
     /// If `use_gpu` is active this defines the number of layers to offload to the gpu. If `None`, all layers will be offloaded.
@@ -217,6 +674,7 @@ impl Default for ModelParameters {
             prefer_mmap: true,
             context_size: 2048,
             lora_adapters: None,
+            lora_threads: 8,
             use_gpu: false,
             gpu_layers: None,
             rope_overrides: None,
@@ -260,3 +718,162 @@ pub struct OutputRequest {
     /// `n_batch * n_embd`.
     pub embeddings: Option<Vec<f32>>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ggml::ElementType;
+
+    /// Builds a model context holding 2x2 F32 `tensors`.
+    fn model(tensors: &[(&str, [f32; 4])]) -> ModelContext {
+        let context = Context::new_with_allocate(1024);
+        let tensors = tensors
+            .iter()
+            .map(|(name, values)| {
+                let mut tensor = context.new_tensor_2d(ElementType::F32, 2, 2);
+                let data: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+                // SAFETY: the tensor was just created, and is exactly as large as the data.
+                unsafe { tensor.write_data(&data) };
+                let info = TensorLoadInfo {
+                    name: name.to_string(),
+                    n_dims: 2,
+                    dims: [2, 2],
+                    n_elements: 4,
+                    element_type: ElementType::F32,
+                    start_offset: 0,
+                };
+                let tensor = ModelTensor {
+                    info,
+                    tensor,
+                    mapped: false,
+                    buffer: None,
+                    original: None,
+                };
+                (name.to_string(), tensor)
+            })
+            .collect();
+        ModelContext::new(context, tensors, vec![])
+    }
+
+    fn data(model: &ModelContext, name: &str) -> Vec<f32> {
+        let mut data = [0; 16];
+        // SAFETY: the tensor is not being written to.
+        unsafe { model.tensors[name].tensor.read_data(0, &mut data) };
+        data.chunks_exact(4)
+            .map(|c| f32::from_le_bytes(c.try_into().unwrap()))
+            .collect()
+    }
+
+    /// Adds the rank 1 patches `B·A·s` to `weight`.
+    fn patched(weight: [f32; 4], patches: &[([f32; 2], [f32; 2], f32)]) -> Vec<f32> {
+        (0..4)
+            .map(|i| {
+                let (row, column) = (i / 2, i % 2);
+                weight[i]
+                    + patches
+                        .iter()
+                        .map(|(a, b, s)| b[row] * a[column] * s)
+                        .sum::<f32>()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lora_adapters_can_be_applied_removed_and_rescaled() {
+        let (weight_x, weight_y) = ([1.0, 2.0, 3.0, 4.0], [-1.0, 0.5, 2.0, 0.0]);
+        let mut model = model(&[("x", weight_x), ("y", weight_y)]);
+
+        let (one_x, one_y) = (([1.0, 2.0], [3.0, -1.0]), ([0.5, 1.0], [1.0, 1.0]));
+        let one = LoraAdapter::from_matrices(
+            "one",
+            2.0,
+            1,
+            &[("x", &one_x.0, &one_x.1), ("y", &one_y.0, &one_y.1)],
+        );
+        let two_x = ([-1.0, 1.0], [2.0, 0.5]);
+        let two = LoraAdapter::from_matrices("two", 1.0, 1, &[("x", &two_x.0, &two_x.1)]);
+
+        model.apply_lora(one).unwrap();
+        model.apply_lora(two).unwrap();
+        assert_eq!(
+            data(&model, "x"),
+            patched(
+                weight_x,
+                &[(one_x.0, one_x.1, 2.0), (two_x.0, two_x.1, 1.0)],
+            ),
+        );
+        assert_eq!(
+            data(&model, "y"),
+            patched(weight_y, &[(one_y.0, one_y.1, 2.0)]),
+        );
+
+        model.set_lora_scaling(Path::new("one"), 0.5).unwrap();
+        assert_eq!(
+            data(&model, "x"),
+            patched(
+                weight_x,
+                &[(one_x.0, one_x.1, 0.5), (two_x.0, two_x.1, 1.0)],
+            ),
+        );
+        assert_eq!(
+            data(&model, "y"),
+            patched(weight_y, &[(one_y.0, one_y.1, 0.5)]),
+        );
+
+        let one = model.remove_lora(Path::new("one")).unwrap();
+        assert_eq!(one.scaling, 0.5);
+        assert_eq!(
+            data(&model, "x"),
+            patched(weight_x, &[(two_x.0, two_x.1, 1.0)]),
+        );
+        assert_eq!(data(&model, "y"), weight_y);
+
+        // The original data is restored exactly, rather than by subtracting the patches.
+        model.remove_lora(Path::new("two")).unwrap();
+        assert_eq!(data(&model, "x"), weight_x);
+        assert!(model.lora_adapters().is_empty());
+
+        assert!(matches!(
+            model.remove_lora(Path::new("two")),
+            Err(LoraError::NotApplied { .. })
+        ));
+        let unknown = LoraAdapter::from_matrices("three", 1.0, 1, &[("z", &[0.0; 2], &[0.0; 2])]);
+        assert!(matches!(
+            model.apply_lora(unknown),
+            Err(LoraError::UnknownTensor { tensor_name, .. }) if tensor_name == "z"
+        ));
+    }
+
+    #[test]
+    fn failing_to_apply_a_lora_adapter_leaves_the_model_unchanged() {
+        let mut model = model(&[("x", [1.0, 2.0, 3.0, 4.0]), ("y", [-1.0, 0.5, 2.0, 0.0])]);
+        let one = LoraAdapter::from_matrices(
+            "one",
+            1.0,
+            1,
+            &[
+                ("x", &[1.0, 2.0], &[3.0, -1.0]),
+                ("y", &[0.5, 1.0], &[1.0, 1.0]),
+            ],
+        );
+        model.apply_lora(one).unwrap();
+        let (x, y) = (data(&model, "x"), data(&model, "y"));
+
+        // Whichever tensor is patched first, patching `y` fails as its `B` matrix is missing.
+        let mut broken = LoraAdapter::from_matrices(
+            "broken",
+            1.0,
+            1,
+            &[
+                ("x", &[1.0, 1.0], &[1.0, 1.0]),
+                ("y", &[1.0, 1.0], &[1.0, 1.0]),
+            ],
+        );
+        broken.tensors.remove("y.loraB");
+        assert!(model.apply_lora(broken).is_err());
+
+        assert_eq!(model.lora_adapters().len(), 1);
+        assert_eq!(data(&model, "x"), x);
+        assert_eq!(data(&model, "y"), y);
+    }
+}
@@ -1,17 +1,24 @@
 //! Implements quantization of weights.
 
 use crate::{
-    loader::FileTypeFormat, model::HyperparametersWriteError, Hyperparameters, KnownModel,
-    LoadError, LoadProgress, Loader, Tokenizer,
+    imatrix::{self, ImportanceMatrix, LegacyBlock},
+    loader::FileTypeFormat,
+    model::{HyperparametersWriteError, TensorRole},
+    tokenizer::{bytes_to_gguf_token, TOKENIZER_TENSOR_NAME},
+    Hyperparameters, KnownModel, LoadError, LoadProgress, Loader, Tokenizer,
+};
+use ggml::format::{
+    gguf::{Metadata, MetadataValue},
+    SaveContainerType, SaveError, SaveHandler, TensorLoadInfo, TensorSaveInfo,
 };
-use ggml::format::{SaveError, SaveHandler, TensorLoadInfo, TensorSaveInfo};
 use half::f16;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
     io::{BufRead, Seek, Write},
-    path::PathBuf,
-    sync::Arc,
+    ops::Range,
+    path::{Path, PathBuf},
 };
 use thiserror::Error;
 
@@ -45,9 +52,21 @@ pub enum QuantizeProgress<'a> {
         original_size: usize,
         /// The reduced size of the tensor.
         reduced_size: usize,
+        /// The type the tensor was quantized to.
+        element_type: ggml::Type,
         /// The history of the quantization.
         history: Vec<f32>,
     },
+    /// The error of a quantized tensor has been measured. Only reported if
+    /// [QuantizeParameters::measure_error] is set.
+    TensorMeasured {
+        /// Name of the tensor.
+        name: &'a str,
+        /// The type the tensor was quantized to.
+        element_type: ggml::Type,
+        /// The error of the quantized tensor compared to the original.
+        error: QuantizationErrorStats,
+    },
     /// A tensor has been skipped.
     TensorSkipped {
         /// Name of the tensor.
@@ -100,9 +119,34 @@ pub enum QuantizeError {
         invariant: String,
     },
     /// Attempted to quantize to an invalid target.
-    #[error("invalid quantization target {element_type:?}")]
+    #[error("invalid quantization target {format:?}")]
     InvalidQuantizationTarget {
         /// The quantization target.
+        format: FileTypeFormat,
+    },
+    /// A rule of the quantization policy has a type that tensors cannot be quantized to.
+    #[error("invalid quantization policy type {element_type:?}")]
+    InvalidPolicyElementType {
+        /// The type of the rule.
+        element_type: ggml::Type,
+    },
+    /// The importance matrix has a different number of columns for a tensor than the tensor.
+    #[error("the importance matrix has {actual} columns for {tensor_name:?}, expected {expected}")]
+    ImportanceMatrixMismatch {
+        /// The name of the tensor.
+        tensor_name: String,
+        /// The number of columns of the tensor.
+        expected: usize,
+        /// The number of columns in the importance matrix.
+        actual: usize,
+    },
+    /// The importance matrix has an entry for a tensor that is quantized to a type that cannot
+    /// be weighted by it.
+    #[error("the importance matrix cannot be used to quantize {tensor_name:?} to {element_type:?}; only the legacy quantization types support it")]
+    UnsupportedImportanceMatrix {
+        /// The name of the tensor.
+        tensor_name: String,
+        /// The type the tensor would be quantized to.
         element_type: ggml::Type,
     },
     /// The quantization process encountered an unsupported element type.
@@ -131,77 +175,135 @@ impl QuantizeError {
             SaveError::VocabularyScoringNotSupported => {
                 QuantizeError::VocabularyScoringNotSupported
             }
+            SaveError::UnsupportedElementType { element_type, .. } => {
+                QuantizeError::UnsupportedElementType { element_type }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+/// Optional parameters for [quantize].
+pub struct QuantizeParameters {
+    /// Rules that override the type of specific tensors. They take precedence over the
+    /// quantization type.
+    pub policy: QuantizationPolicy,
+    /// The importance of the input columns of the weights. If set, the legacy quantization types
+    /// choose the scale of each block to minimise the error weighted by this importance, instead
+    /// of rounding to the nearest value. Quantizing a tensor that has an entry in the matrix to a
+    /// k-quant type fails with [QuantizeError::UnsupportedImportanceMatrix], as they cannot be
+    /// weighted yet.
+    pub importance_matrix: Option<ImportanceMatrix>,
+    /// The number of threads to quantize each tensor with. The rows of a tensor are split
+    /// between the threads; the output is the same regardless of the number of threads.
+    pub n_threads: usize,
+    /// Whether to measure the error of each quantized tensor, and report it with
+    /// [QuantizeProgress::TensorMeasured]. This dequantizes every quantized tensor.
+    pub measure_error: bool,
+    /// Whether to save the whole Hugging Face tokenizer given to [quantize] with the model, so
+    /// that it is used when the model is loaded with
+    /// [TokenizerSource::Embedded](crate::TokenizerSource::Embedded). The vocabulary
+    /// of a Hugging Face tokenizer is saved either way.
+    pub embed_tokenizer: bool,
+}
+impl Default for QuantizeParameters {
+    fn default() -> Self {
+        Self {
+            policy: Default::default(),
+            importance_matrix: None,
+            n_threads: 8,
+            measure_error: false,
+            embed_tokenizer: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+/// The error of a quantized tensor compared to the original tensor.
+pub struct QuantizationErrorStats {
+    /// The root mean square of the difference between the elements.
+    pub rmse: f32,
+    /// The largest absolute difference between two elements.
+    pub max_abs_error: f32,
+    /// The cosine similarity of the tensors, seen as vectors. `1.0` means that they point in
+    /// the same direction.
+    pub cosine_similarity: f32,
+}
+impl QuantizationErrorStats {
+    /// Measures the error of `quantized` compared to `original`, both dequantized to F32.
+    pub fn measure(original: &[f32], quantized: &[f32]) -> Self {
+        assert_eq!(original.len(), quantized.len());
+
+        let mut sum_sq_error = 0.0f64;
+        let mut max_abs_error = 0.0f32;
+        let (mut dot, mut norm_original, mut norm_quantized) = (0.0f64, 0.0f64, 0.0f64);
+        for (&x, &y) in original.iter().zip(quantized) {
+            let error = x - y;
+            sum_sq_error += (error as f64).powi(2);
+            max_abs_error = max_abs_error.max(error.abs());
+
+            dot += x as f64 * y as f64;
+            norm_original += (x as f64).powi(2);
+            norm_quantized += (y as f64).powi(2);
+        }
+
+        let cosine_similarity = if norm_original == 0.0 || norm_quantized == 0.0 {
+            // Two zero tensors are identical; a zero tensor has no direction otherwise.
+            if norm_original == norm_quantized {
+                1.0
+            } else {
+                0.0
+            }
+        } else {
+            dot / (norm_original.sqrt() * norm_quantized.sqrt())
+        };
+
+        Self {
+            rmse: (sum_sq_error / original.len().max(1) as f64).sqrt() as f32,
+            max_abs_error,
+            cosine_similarity: cosine_similarity as f32,
         }
     }
 }
 
 /// Quantizes a model.
+///
+/// The k-quant presets of `quantization_type` (e.g. [FileTypeFormat::MostlyQ4_K_M]) quantize
+/// some tensors with more bits depending on their [TensorRole]. Tensors whose rows do not fit
+/// the blocks of a k-quant type are quantized to the closest legacy type instead.
+///
+/// The model may already be quantized, in which case its tensors are dequantized before being
+/// quantized to their new type. Use [FileTypeFormat::MostlyF16] or [FileTypeFormat::F32] to
+/// expand a quantized model.
+///
+/// Use [QuantizeParameters::default] to quantize according to `quantization_type` alone, and
+/// [quantize_dry_run] to find out what quantizing would do without doing it.
 pub fn quantize<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
     reader: &mut R,
     writer: &mut W,
     tokenizer: Tokenizer,
-    save_container_type: ggml::format::SaveContainerType,
-    quantization_type: ggml::Type,
+    save_container_type: SaveContainerType,
+    quantization_type: FileTypeFormat,
+    parameters: &QuantizeParameters,
     progress_callback: impl Fn(QuantizeProgress),
 ) -> Result<(), QuantizeError> {
-    // Sanity check
-    let quantization_target = QuantizationTarget::try_from(quantization_type).map_err(|_| {
-        QuantizeError::InvalidQuantizationTarget {
-            element_type: quantization_type,
-        }
-    })?;
-
-    // Load the model
-    let progress_callback = Arc::new(progress_callback);
-
-    let mut loader = Loader::<M::Hyperparameters, _>::new(tokenizer, {
-        let progress_callback = progress_callback.clone();
-        move |p| {
-            if let LoadProgress::HyperparametersLoaded = p {
-                progress_callback(QuantizeProgress::HyperparametersLoaded)
-            }
-        }
-    });
-    ggml::format::load(reader, &mut loader)
-        .map_err(|err| LoadError::from_format_error(err, PathBuf::default()))?;
+    let model = QuantizeModel::load::<M, _>(
+        reader,
+        tokenizer,
+        save_container_type,
+        quantization_type,
+        parameters,
+        &progress_callback,
+    )?;
 
     // Save the quantized model, quantizing as we go
-    let Loader {
-        mut hyperparameters,
-        tokenizer,
-        tensors,
-        ..
-    } = loader;
-
-    if let Some(ft) = hyperparameters.file_type_mut() {
-        ft.quantization_version = ggml::QNT_VERSION;
-        ft.format = quantization_target
-            .try_into()
-            .expect("format has no corresponding ftype");
-    }
-
-    let tokenizer = match tokenizer {
-        Tokenizer::Embedded(v) => v.iter().collect::<Vec<_>>(),
-        Tokenizer::HuggingFace(_) => vec![],
-    };
-
-    let to_quantize = M::quantize_tensors();
-    let to_skip = M::skip_quantize_tensors();
-    let mut saver = QuantizeSaver::new(
-        quantization_target,
-        &hyperparameters,
-        &tensors,
-        &to_quantize,
-        &to_skip,
-        reader,
-        |p| progress_callback(p),
-    );
+    let mut saver = model.saver(reader, parameters, &progress_callback);
     ggml::format::save(
         writer,
         &mut saver,
         save_container_type,
-        &tokenizer,
-        &tensors.keys().cloned().collect::<Vec<_>>(),
+        &model.vocabulary,
+        &model.saved_names(),
     )
     .map_err(|err| QuantizeError::from_format_error(err, PathBuf::default()))?;
 
@@ -220,13 +322,451 @@ pub fn quantize<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
     Ok(())
 }
 
+/// Predicts what [quantize] would do with the same arguments, without reading or writing
+/// the data of any tensor.
+///
+/// Only the header and the tensor table of the model are read. The type of each tensor is
+/// decided as [quantize] would, and the size of the file and the memory needed to load it
+/// are calculated from those types.
+pub fn quantize_dry_run<M: KnownModel, R: BufRead + Seek>(
+    reader: &mut R,
+    tokenizer: Tokenizer,
+    save_container_type: SaveContainerType,
+    quantization_type: FileTypeFormat,
+    parameters: &QuantizeParameters,
+) -> Result<QuantizeDryRun, QuantizeError> {
+    let model = QuantizeModel::load::<M, _>(
+        reader,
+        tokenizer,
+        save_container_type,
+        quantization_type,
+        parameters,
+        &|_| {},
+    )?;
+
+    // Decide the type of each tensor, and calculate the size of the file from the types
+    let mut saver = model.saver(reader, parameters, |_| {});
+    let mut layouts = Vec::with_capacity(model.tensor_names.len());
+    let mut tensors = vec![];
+    let (mut memory, mut memory_mmap) = (0, 0);
+    for name in &model.tensor_names {
+        let tokenizer_tensor = model
+            .tokenizer_tensor
+            .as_ref()
+            .filter(|_| name == TOKENIZER_TENSOR_NAME);
+        if let Some(tokenizer_tensor) = tokenizer_tensor {
+            layouts.push(TensorLoadInfo {
+                name: model.saved_name(name),
+                n_dims: tokenizer_tensor.n_dims,
+                dims: tokenizer_tensor.dims,
+                n_elements: tokenizer_tensor.data.len(),
+                element_type: tokenizer_tensor.element_type,
+                start_offset: 0,
+            });
+            continue;
+        }
+
+        let plan = saver.plan(name, &model.tensors[name])?;
+        let layout = TensorLoadInfo {
+            name: model.saved_name(name),
+            element_type: plan.element_type,
+            ..model.tensors[name].clone()
+        };
+        memory += layout.calc_absolute_size(false);
+        memory_mmap += layout.calc_absolute_size(true);
+        layouts.push(layout);
+        tensors.push(plan);
+    }
+    let file_size =
+        ggml::format::saved_size(&mut saver, save_container_type, &model.vocabulary, &layouts)
+            .map_err(|err| QuantizeError::from_format_error(err, PathBuf::default()))?;
+
+    Ok(QuantizeDryRun {
+        original_size: tensors.iter().map(|plan| plan.original_size).sum(),
+        reduced_size: tensors.iter().map(|plan| plan.size).sum(),
+        file_size: usize::try_from(file_size)?,
+        memory,
+        memory_mmap,
+        tensors,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The predicted outcome of [quantize], as calculated by [quantize_dry_run].
+pub struct QuantizeDryRun {
+    /// What would be done with each tensor, in the order the tensors would be saved in.
+    pub tensors: Vec<TensorQuantizePlan>,
+    /// The size (in bytes) of the tensor data of the original model.
+    pub original_size: usize,
+    /// The size (in bytes) of the tensor data of the quantized model.
+    pub reduced_size: usize,
+    /// The size (in bytes) of the quantized model file.
+    pub file_size: usize,
+    /// The memory (in bytes) needed to load the tensors of the quantized model without mmap.
+    ///
+    /// This does not include the memory of the sessions that use the model, such as their key
+    /// and value caches, as it depends on the context size they are started with.
+    pub memory: usize,
+    /// The memory (in bytes) needed to load the tensors of the quantized model with mmap. The
+    /// tensor data is not part of this, as it is read from the file as it is used. Like
+    /// [QuantizeDryRun::memory], this does not include the memory of the sessions.
+    pub memory_mmap: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// What [quantize] would do with a tensor, as calculated by [quantize_dry_run].
+pub struct TensorQuantizePlan {
+    /// Name of the tensor.
+    pub name: String,
+    /// Size of the tensor.
+    pub dims: [usize; 2],
+    /// The type of the tensor in the original model.
+    pub original_type: ggml::Type,
+    /// The type of the tensor in the quantized model. This is the original type if the tensor
+    /// would be kept as it is.
+    pub element_type: ggml::Type,
+    /// The size (in bytes) of the tensor data in the original model.
+    pub original_size: usize,
+    /// The size (in bytes) of the tensor data in the quantized model.
+    pub size: usize,
+}
+
+/// A model loaded for quantization, along with what it will be saved as.
+struct QuantizeModel<H: Hyperparameters> {
+    hyperparameters: H,
+    tensors: HashMap<String, TensorLoadInfo>,
+    vocabulary: Vec<(Vec<u8>, f32)>,
+    /// The names of the tensors to save, which may include [TOKENIZER_TENSOR_NAME].
+    tensor_names: Vec<String>,
+    save_container_type: SaveContainerType,
+    tokenizer_tensor: Option<TensorSaveInfo>,
+    targets: TensorTargets,
+    to_quantize: Vec<Regex>,
+    to_skip: Vec<Regex>,
+}
+impl<H: Hyperparameters> QuantizeModel<H> {
+    /// Loads the header and the tensor table of a model, and decides what to quantize it to.
+    fn load<M: KnownModel<Hyperparameters = H>, R: BufRead + Seek>(
+        reader: &mut R,
+        tokenizer: Tokenizer,
+        save_container_type: SaveContainerType,
+        quantization_type: FileTypeFormat,
+        parameters: &QuantizeParameters,
+        progress_callback: &dyn Fn(QuantizeProgress),
+    ) -> Result<Self, QuantizeError> {
+        let preset = QuantizationPreset::try_from(quantization_type).map_err(|_| {
+            QuantizeError::InvalidQuantizationTarget {
+                format: quantization_type,
+            }
+        })?;
+
+        // Load the model
+        let mut loader = Loader::<H, _>::new(tokenizer, |p| {
+            if let LoadProgress::HyperparametersLoaded = p {
+                progress_callback(QuantizeProgress::HyperparametersLoaded)
+            }
+        });
+        ggml::format::load(reader, &mut loader)
+            .map_err(|err| LoadError::from_format_error(err, PathBuf::default()))?;
+
+        let Loader {
+            mut hyperparameters,
+            tokenizer,
+            tensors,
+            ..
+        } = loader;
+
+        if let Some(ft) = hyperparameters.file_type_mut() {
+            ft.quantization_version = ggml::QNT_VERSION;
+            ft.format = quantization_type;
+        }
+
+        // A Hugging Face tokenizer is saved as the vocabulary, and also as a whole if requested
+        let tokenizer_tensor = match &tokenizer {
+            Tokenizer::HuggingFace(tokenizer) if parameters.embed_tokenizer => {
+                Some(tokenizer.to_tensor())
+            }
+            _ => None,
+        };
+        let mut vocabulary = tokenizer.vocabulary(hyperparameters.n_vocabulary());
+        if save_container_type == SaveContainerType::Gguf {
+            // GGUF stores the tokens the way the tokenizer of the model writes them
+            let mut metadata = Metadata::default();
+            hyperparameters
+                .write_gguf(&mut metadata)
+                .map_err(QuantizeError::HyperparametersWriteError)?;
+            let tokenizer_model = metadata
+                .get("tokenizer.ggml.model")
+                .and_then(MetadataValue::as_str);
+            for (token, _) in &mut vocabulary {
+                *token = bytes_to_gguf_token(tokenizer_model, token);
+            }
+        }
+        let mut tensor_names = tensors
+            .keys()
+            .filter(|name| tokenizer_tensor.is_none() || *name != TOKENIZER_TENSOR_NAME)
+            .cloned()
+            .collect::<Vec<_>>();
+        if tokenizer_tensor.is_some() {
+            tensor_names.push(TOKENIZER_TENSOR_NAME.to_owned());
+        }
+
+        let targets = TensorTargets::new::<M>(
+            preset,
+            &parameters.policy,
+            tensors.keys().map(String::as_str),
+        )
+        .map_err(|element_type| QuantizeError::InvalidPolicyElementType { element_type })?;
+
+        Ok(Self {
+            hyperparameters,
+            tensors,
+            vocabulary,
+            tensor_names,
+            save_container_type,
+            tokenizer_tensor,
+            targets,
+            to_quantize: M::quantize_tensors(),
+            to_skip: M::skip_quantize_tensors(),
+        })
+    }
+
+    /// The name the tensor `name` is saved under. GGUF files use architecture-independent names.
+    fn saved_name(&self, name: &str) -> String {
+        if self.save_container_type == SaveContainerType::Gguf {
+            self.hyperparameters.tensor_name_to_gguf(name)
+        } else {
+            name.to_owned()
+        }
+    }
+
+    /// The names the tensors are saved under, in the order they are saved in.
+    fn saved_names(&self) -> Vec<String> {
+        self.tensor_names
+            .iter()
+            .map(|name| self.saved_name(name))
+            .collect()
+    }
+
+    /// Returns a handler that saves this model, reading its tensors from `reader`.
+    fn saver<'a, F: Fn(QuantizeProgress), R: BufRead + Seek>(
+        &'a self,
+        reader: &'a mut R,
+        parameters: &'a QuantizeParameters,
+        progress_callback: F,
+    ) -> QuantizeSaver<'a, F, H, R> {
+        QuantizeSaver {
+            model: self,
+            parameters,
+            source_reader: reader,
+            progress_callback,
+
+            total_size_original: 0,
+            total_size_new: 0,
+            history_all: vec![0; 16],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+/// Overrides the type that tensors are quantized to, e.g. to keep the output and the first and
+/// last layers of a model at a higher precision than the rest.
+///
+/// The rules are checked in order, and the first rule that matches a tensor decides its type.
+/// Tensors that match no rule are quantized according to the file type given to [quantize].
+/// Only the tensors that the model allows to be quantized are affected.
+///
+/// Policies can be loaded from TOML or JSON files with [QuantizationPolicy::load]:
+///
+/// ```toml
+/// [[rules]]
+/// pattern = "^output\\.weight$"
+/// type = "q8_0"
+///
+/// [[rules]]
+/// layers = [0, -1]
+/// type = "q8_0"
+/// ```
+pub struct QuantizationPolicy {
+    /// The rules of the policy, in order of precedence.
+    pub rules: Vec<QuantizationRule>,
+}
+impl QuantizationPolicy {
+    /// Loads a policy from the file at `path`, which is read as TOML or JSON depending on
+    /// its extension.
+    pub fn load(path: &Path) -> Result<Self, QuantizationPolicyError> {
+        let read = || {
+            std::fs::read_to_string(path).map_err(|source| QuantizationPolicyError::ReadFailed {
+                source,
+                path: path.to_owned(),
+            })
+        };
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Self::from_toml(&read()?),
+            Some("json") => Self::from_json(&read()?),
+            _ => Err(QuantizationPolicyError::UnsupportedFormat {
+                path: path.to_owned(),
+            }),
+        }
+    }
+
+    /// Parses a policy from a TOML document.
+    pub fn from_toml(document: &str) -> Result<Self, QuantizationPolicyError> {
+        toml::from_str::<PolicyFile>(document)?.try_into()
+    }
+
+    /// Parses a policy from a JSON document.
+    pub fn from_json(document: &str) -> Result<Self, QuantizationPolicyError> {
+        serde_json::from_str::<PolicyFile>(document)?.try_into()
+    }
+
+    /// Returns the type of the first rule that matches the tensor named `name`, in a model
+    /// with `n_layers` layers.
+    pub fn element_type(&self, name: &str, n_layers: usize) -> Option<ggml::Type> {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(name, n_layers))
+            .map(|rule| rule.element_type)
+    }
+}
+
+#[derive(Debug, Clone)]
+/// A rule of a [QuantizationPolicy]. A rule matches a tensor if all of its conditions do.
+pub struct QuantizationRule {
+    /// If set, the name of the tensor must match this regex.
+    pub pattern: Option<Regex>,
+    /// If set, the tensor must be in one of these layers. The layer of a tensor is the first
+    /// number in its name; negative indices count back from the end, so `-1` is the last layer.
+    pub layers: Option<Vec<i64>>,
+    /// The type to quantize the tensor to. Besides the quantized types, this can be
+    /// [F16](ggml::Type::F16) or [F32](ggml::Type::F32) to keep the tensor unquantized.
+    pub element_type: ggml::Type,
+}
+impl QuantizationRule {
+    fn matches(&self, name: &str, n_layers: usize) -> bool {
+        if let Some(pattern) = &self.pattern {
+            if !pattern.is_match(name) {
+                return false;
+            }
+        }
+        let Some(layers) = &self.layers else {
+            return true;
+        };
+        let Some(layer) = layer_number(name) else {
+            return false;
+        };
+        layers.iter().any(|&index| {
+            let index = if index < 0 {
+                n_layers as i64 + index
+            } else {
+                index
+            };
+            index == layer as i64
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+/// Errors encountered while loading a [QuantizationPolicy].
+pub enum QuantizationPolicyError {
+    #[error("could not read policy file {path:?}")]
+    /// The policy file could not be read.
+    ReadFailed {
+        /// The original error.
+        source: std::io::Error,
+        /// The path that failed.
+        path: PathBuf,
+    },
+    #[error("unsupported policy file {path:?}; expected a .toml or .json file")]
+    /// The policy file is neither TOML nor JSON.
+    UnsupportedFormat {
+        /// The path of the file.
+        path: PathBuf,
+    },
+    #[error("invalid TOML policy")]
+    /// The TOML policy could not be parsed.
+    Toml(#[from] toml::de::Error),
+    #[error("invalid JSON policy")]
+    /// The JSON policy could not be parsed.
+    Json(#[from] serde_json::Error),
+    #[error("invalid tensor name pattern {pattern:?}")]
+    /// The tensor name pattern of a rule is not a valid regex.
+    InvalidPattern {
+        /// The original error.
+        source: regex::Error,
+        /// The pattern.
+        pattern: String,
+    },
+    #[error("unknown type {element_type:?}")]
+    /// The type of a rule is not a known type.
+    UnknownElementType {
+        /// The type.
+        element_type: String,
+    },
+}
+
+/// The file representation of a [QuantizationPolicy].
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    #[serde(default)]
+    rules: Vec<RuleFile>,
+}
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleFile {
+    pattern: Option<String>,
+    layers: Option<Vec<i64>>,
+    #[serde(rename = "type")]
+    element_type: String,
+}
+impl TryFrom<PolicyFile> for QuantizationPolicy {
+    type Error = QuantizationPolicyError;
+
+    fn try_from(file: PolicyFile) -> Result<Self, Self::Error> {
+        let rules = file
+            .rules
+            .into_iter()
+            .map(|rule| {
+                let pattern = rule
+                    .pattern
+                    .map(|pattern| {
+                        Regex::new(&pattern).map_err(|source| {
+                            QuantizationPolicyError::InvalidPattern { source, pattern }
+                        })
+                    })
+                    .transpose()?;
+                let element_type = rule.element_type.parse().map_err(|_| {
+                    QuantizationPolicyError::UnknownElementType {
+                        element_type: rule.element_type,
+                    }
+                })?;
+                Ok(QuantizationRule {
+                    pattern,
+                    layers: rule.layers,
+                    element_type,
+                })
+            })
+            .collect::<Result<_, QuantizationPolicyError>>()?;
+        Ok(Self { rules })
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-enum QuantizationTarget {
+#[allow(non_camel_case_types)]
+pub(crate) enum QuantizationTarget {
     Q4_0,
     Q4_1,
     Q5_0,
     Q5_1,
     Q8_0,
+    Q2_K,
+    Q3_K,
+    Q4_K,
+    Q5_K,
+    Q6_K,
+    F16,
+    F32,
 }
 impl TryFrom<ggml::Type> for QuantizationTarget {
     type Error = ();
@@ -238,10 +778,186 @@ impl TryFrom<ggml::Type> for QuantizationTarget {
             ggml::Type::Q5_0 => Ok(QuantizationTarget::Q5_0),
             ggml::Type::Q5_1 => Ok(QuantizationTarget::Q5_1),
             ggml::Type::Q8_0 => Ok(QuantizationTarget::Q8_0),
+            ggml::Type::Q2_K => Ok(QuantizationTarget::Q2_K),
+            ggml::Type::Q3_K => Ok(QuantizationTarget::Q3_K),
+            ggml::Type::Q4_K => Ok(QuantizationTarget::Q4_K),
+            ggml::Type::Q5_K => Ok(QuantizationTarget::Q5_K),
+            ggml::Type::Q6_K => Ok(QuantizationTarget::Q6_K),
+            ggml::Type::F16 => Ok(QuantizationTarget::F16),
+            ggml::Type::F32 => Ok(QuantizationTarget::F32),
             _ => Err(()),
         }
     }
 }
+impl QuantizationTarget {
+    /// Quantizes the `raw_data` of a tensor with `n_elements` elements, of which `n_elements_0`
+    /// are in the first dimension. Quantized data is dequantized first.
+    pub(crate) fn quantize(
+        self,
+        element_type: ggml::Type,
+        raw_data: &[u8],
+        n_elements: usize,
+        n_elements_0: usize,
+    ) -> ggml::QuantizationResult {
+        let data_f32 = ggml::dequantize(element_type, raw_data, n_elements);
+        self.quantize_f32(&data_f32, n_elements, n_elements_0)
+    }
+
+    /// Like [quantize](Self::quantize), but minimises the quantization error weighted by the
+    /// `importance` of each of the `n_elements_0` columns.
+    ///
+    /// Only the legacy quantization types support weights, and the unquantized types are
+    /// converted as usual. The k-quant types must not be weighted.
+    pub(crate) fn quantize_weighted(
+        self,
+        element_type: ggml::Type,
+        raw_data: &[u8],
+        n_elements: usize,
+        n_elements_0: usize,
+        importance: &[f32],
+    ) -> ggml::QuantizationResult {
+        let data_f32 = ggml::dequantize(element_type, raw_data, n_elements);
+        let block = match self {
+            QuantizationTarget::Q4_0 => LegacyBlock::Q4_0,
+            QuantizationTarget::Q4_1 => LegacyBlock::Q4_1,
+            QuantizationTarget::Q5_0 => LegacyBlock::Q5_0,
+            QuantizationTarget::Q5_1 => LegacyBlock::Q5_1,
+            QuantizationTarget::Q8_0 => LegacyBlock::Q8_0,
+            QuantizationTarget::F16 | QuantizationTarget::F32 => {
+                return self.quantize_f32(&data_f32, n_elements, n_elements_0)
+            }
+            _ => unreachable!("{self:?} cannot be weighted by an importance matrix"),
+        };
+        imatrix::quantize_weighted(block, &data_f32, n_elements_0, importance)
+    }
+
+    /// Quantizes the `raw_data` of a tensor made of rows of `n_elements_0` elements,
+    /// weighted by `importance` if set. The rows are split into contiguous chunks that are
+    /// quantized on up to `n_threads` threads, and whose results are joined in order.
+    pub(crate) fn quantize_rows(
+        self,
+        element_type: ggml::Type,
+        raw_data: &[u8],
+        n_elements_0: usize,
+        importance: Option<&[f32]>,
+        n_threads: usize,
+    ) -> ggml::QuantizationResult {
+        let row_size = n_elements_0 / ggml::blck_size(element_type) * ggml::type_size(element_type);
+        let n_rows = raw_data.len() / row_size;
+        let quantize_chunk = |rows: Range<usize>| {
+            let raw_data = &raw_data[rows.start * row_size..rows.end * row_size];
+            let n_elements = rows.len() * n_elements_0;
+            match importance {
+                Some(importance) => self.quantize_weighted(
+                    element_type,
+                    raw_data,
+                    n_elements,
+                    n_elements_0,
+                    importance,
+                ),
+                None => self.quantize(element_type, raw_data, n_elements, n_elements_0),
+            }
+        };
+
+        let n_threads = n_threads.clamp(1, n_rows.max(1));
+        if n_threads == 1 {
+            return quantize_chunk(0..n_rows);
+        }
+
+        let rows_per_chunk = (n_rows + n_threads - 1) / n_threads;
+        let results: Vec<_> = std::thread::scope(|scope| {
+            let quantize_chunk = &quantize_chunk;
+            let handles: Vec<_> = (0..n_rows)
+                .step_by(rows_per_chunk)
+                .map(|start| {
+                    let end = (start + rows_per_chunk).min(n_rows);
+                    scope.spawn(move || quantize_chunk(start..end))
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("quantization thread panicked"))
+                .collect()
+        });
+
+        let mut results = results.into_iter();
+        let mut result = results.next().expect("there is at least one chunk");
+        for chunk in results {
+            result.output.extend(chunk.output);
+            for (total, count) in result.history.iter_mut().zip(chunk.history) {
+                *total += count;
+            }
+        }
+        result
+    }
+
+    fn quantize_f32(
+        self,
+        data_f32: &[f32],
+        n_elements: usize,
+        n_elements_0: usize,
+    ) -> ggml::QuantizationResult {
+        match self {
+            QuantizationTarget::Q4_0 => ggml::quantize_q4_0(data_f32, n_elements, n_elements_0),
+            QuantizationTarget::Q4_1 => ggml::quantize_q4_1(data_f32, n_elements, n_elements_0),
+            QuantizationTarget::Q5_0 => ggml::quantize_q5_0(data_f32, n_elements, n_elements_0),
+            QuantizationTarget::Q5_1 => ggml::quantize_q5_1(data_f32, n_elements, n_elements_0),
+            QuantizationTarget::Q8_0 => ggml::quantize_q8_0(data_f32, n_elements, n_elements_0),
+            QuantizationTarget::Q2_K => ggml::quantize_q2_k(data_f32, n_elements, n_elements_0),
+            QuantizationTarget::Q3_K => ggml::quantize_q3_k(data_f32, n_elements, n_elements_0),
+            QuantizationTarget::Q4_K => ggml::quantize_q4_k(data_f32, n_elements, n_elements_0),
+            QuantizationTarget::Q5_K => ggml::quantize_q5_k(data_f32, n_elements, n_elements_0),
+            QuantizationTarget::Q6_K => ggml::quantize_q6_k(data_f32, n_elements, n_elements_0),
+            QuantizationTarget::F16 => ggml::QuantizationResult {
+                output: data_f32
+                    .iter()
+                    .flat_map(|x| f16::from_f32(*x).to_bits().to_le_bytes())
+                    .collect(),
+                history: vec![0; 16],
+            },
+            QuantizationTarget::F32 => ggml::QuantizationResult {
+                output: data_f32.iter().flat_map(|x| x.to_le_bytes()).collect(),
+                history: vec![0; 16],
+            },
+        }
+    }
+
+    fn is_k_quant(self) -> bool {
+        matches!(
+            self,
+            QuantizationTarget::Q2_K
+                | QuantizationTarget::Q3_K
+                | QuantizationTarget::Q4_K
+                | QuantizationTarget::Q5_K
+                | QuantizationTarget::Q6_K
+        )
+    }
+
+    /// Returns whether rows of `row_length` elements can be stored in this type, matching the
+    /// invariants checked when saving.
+    fn fits(self, row_length: usize) -> bool {
+        let block_size = match self {
+            QuantizationTarget::Q4_0 | QuantizationTarget::Q4_1 => 64,
+            target => ggml::blck_size(target.into()),
+        };
+        row_length.checked_rem(block_size) == Some(0)
+    }
+
+    /// Returns this target if rows of `row_length` elements fit its blocks. Otherwise, k-quant
+    /// targets fall back to the closest legacy type, as their blocks are much larger.
+    fn for_row_length(self, row_length: usize) -> Option<Self> {
+        let fallback = match self {
+            QuantizationTarget::Q2_K | QuantizationTarget::Q3_K => QuantizationTarget::Q4_0,
+            QuantizationTarget::Q4_K => QuantizationTarget::Q5_0,
+            QuantizationTarget::Q5_K => QuantizationTarget::Q5_1,
+            QuantizationTarget::Q6_K => QuantizationTarget::Q8_0,
+            target => return Some(target),
+        };
+        [self, fallback]
+            .into_iter()
+            .find(|target| target.fits(row_length))
+    }
+}
 impl From<QuantizationTarget> for ggml::Type {
     fn from(value: QuantizationTarget) -> Self {
         match value {
@@ -250,28 +966,182 @@ impl From<QuantizationTarget> for ggml::Type {
             QuantizationTarget::Q5_0 => ggml::Type::Q5_0,
             QuantizationTarget::Q5_1 => ggml::Type::Q5_1,
             QuantizationTarget::Q8_0 => ggml::Type::Q8_0,
+            QuantizationTarget::Q2_K => ggml::Type::Q2_K,
+            QuantizationTarget::Q3_K => ggml::Type::Q3_K,
+            QuantizationTarget::Q4_K => ggml::Type::Q4_K,
+            QuantizationTarget::Q5_K => ggml::Type::Q5_K,
+            QuantizationTarget::Q6_K => ggml::Type::Q6_K,
+            QuantizationTarget::F16 => ggml::Type::F16,
+            QuantizationTarget::F32 => ggml::Type::F32,
         }
     }
 }
-impl From<QuantizationTarget> for FileTypeFormat {
-    fn from(value: QuantizationTarget) -> Self {
-        match value {
-            QuantizationTarget::Q4_0 => FileTypeFormat::MostlyQ4_0,
-            QuantizationTarget::Q4_1 => FileTypeFormat::MostlyQ4_1,
-            QuantizationTarget::Q5_0 => FileTypeFormat::MostlyQ5_0,
-            QuantizationTarget::Q5_1 => FileTypeFormat::MostlyQ5_1,
-            QuantizationTarget::Q8_0 => FileTypeFormat::MostlyQ8_0,
+
+/// The types the tensors of a model are quantized to for a [FileTypeFormat].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct QuantizationPreset {
+    format: FileTypeFormat,
+    base: QuantizationTarget,
+}
+impl TryFrom<FileTypeFormat> for QuantizationPreset {
+    type Error = ();
+
+    fn try_from(format: FileTypeFormat) -> Result<Self, Self::Error> {
+        use FileTypeFormat as F;
+        let base = match format {
+            F::MostlyQ4_0 => QuantizationTarget::Q4_0,
+            F::MostlyQ4_1 => QuantizationTarget::Q4_1,
+            F::MostlyQ5_0 => QuantizationTarget::Q5_0,
+            F::MostlyQ5_1 => QuantizationTarget::Q5_1,
+            F::MostlyQ8_0 => QuantizationTarget::Q8_0,
+            F::MostlyQ2_K => QuantizationTarget::Q2_K,
+            F::MostlyQ3_K_S | F::MostlyQ3_K_M | F::MostlyQ3_K_L => QuantizationTarget::Q3_K,
+            F::MostlyQ4_K_S | F::MostlyQ4_K_M => QuantizationTarget::Q4_K,
+            F::MostlyQ5_K_S | F::MostlyQ5_K_M => QuantizationTarget::Q5_K,
+            F::MostlyQ6_K => QuantizationTarget::Q6_K,
+            F::MostlyF16 => QuantizationTarget::F16,
+            F::F32 => QuantizationTarget::F32,
+            F::MostlyQ4_1SomeF16 => return Err(()),
+        };
+        Ok(Self { format, base })
+    }
+}
+impl QuantizationPreset {
+    /// Returns the target for a tensor with `role` and rows of `row_length` elements, or `None`
+    /// if the tensor cannot be quantized. `layer` is the index of the tensor among the
+    /// `n_layers` tensors with the same role.
+    ///
+    /// The mixed presets follow the choices made by `llama.cpp`.
+    pub(crate) fn target(
+        self,
+        role: TensorRole,
+        layer: usize,
+        n_layers: usize,
+        row_length: usize,
+    ) -> Option<QuantizationTarget> {
+        use FileTypeFormat as F;
+        use QuantizationTarget as T;
+
+        // Use more bits for the first and last eighth of the layers, and every third layer
+        // in between.
+        let use_more_bits =
+            layer < n_layers / 8 || layer >= 7 * n_layers / 8 || (layer - n_layers / 8) % 3 == 2;
+
+        let target = match (role, self.format) {
+            (TensorRole::Output, _) if self.base.is_k_quant() => T::Q6_K,
+            (TensorRole::AttentionValue | TensorRole::FeedForwardDown, F::MostlyQ3_K_L) => T::Q5_K,
+            (
+                TensorRole::AttentionValue | TensorRole::FeedForwardDown,
+                F::MostlyQ4_K_M | F::MostlyQ5_K_M,
+            ) if use_more_bits => T::Q6_K,
+            (TensorRole::AttentionValue | TensorRole::FeedForwardDown, F::MostlyQ4_K_S)
+                if layer < n_layers / 8 =>
+            {
+                T::Q5_K
+            }
+            (TensorRole::AttentionValue, F::MostlyQ2_K | F::MostlyQ3_K_M) => T::Q4_K,
+            (TensorRole::FeedForwardDown, F::MostlyQ2_K) => T::Q3_K,
+            (TensorRole::FeedForwardDown, F::MostlyQ3_K_M) if layer < n_layers / 16 => T::Q5_K,
+            (TensorRole::FeedForwardDown, F::MostlyQ3_K_M) if use_more_bits => T::Q4_K,
+            (TensorRole::AttentionOutput, F::MostlyQ2_K) => T::Q3_K,
+            (TensorRole::AttentionOutput, F::MostlyQ3_K_M) => T::Q4_K,
+            (TensorRole::AttentionOutput, F::MostlyQ3_K_L) => T::Q5_K,
+            _ => self.base,
+        };
+        target.for_row_length(row_length)
+    }
+}
+
+/// Chooses the target of each tensor of a model from the rules of a [QuantizationPolicy], or
+/// otherwise from a [QuantizationPreset], using the [TensorRole] of each tensor and its index
+/// among the tensors with that role.
+pub(crate) struct TensorTargets {
+    preset: QuantizationPreset,
+    rules: Vec<(QuantizationRule, QuantizationTarget)>,
+    n_layers: usize,
+    roles: HashMap<String, (TensorRole, usize)>,
+    counts: HashMap<TensorRole, usize>,
+}
+impl TensorTargets {
+    /// Returns the type of the first rule of `policy` that is not a quantization target as
+    /// the error.
+    pub(crate) fn new<'a, M: KnownModel>(
+        preset: QuantizationPreset,
+        policy: &QuantizationPolicy,
+        tensor_names: impl Iterator<Item = &'a str>,
+    ) -> Result<Self, ggml::Type> {
+        let rules = policy
+            .rules
+            .iter()
+            .map(|rule| {
+                QuantizationTarget::try_from(rule.element_type)
+                    .map(|target| (rule.clone(), target))
+                    .map_err(|_| rule.element_type)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Order the tensors by the layer they are in, as the names are not in model order
+        let mut tensor_names = tensor_names
+            .map(|name| (layer_number(name), name))
+            .collect::<Vec<_>>();
+        tensor_names.sort_unstable();
+        let n_layers = tensor_names
+            .iter()
+            .filter_map(|(layer, _)| *layer)
+            .max()
+            .map_or(0, |layer| layer + 1);
+
+        let mut roles = HashMap::new();
+        let mut counts = HashMap::new();
+        for (_, name) in tensor_names {
+            let role = M::tensor_role(name);
+            let count = counts.entry(role).or_insert(0);
+            roles.insert(name.to_string(), (role, *count));
+            *count += 1;
         }
+        Ok(Self {
+            preset,
+            rules,
+            n_layers,
+            roles,
+            counts,
+        })
+    }
+
+    /// Returns the target for the tensor named `name` with rows of `row_length` elements.
+    pub(crate) fn target(&self, name: &str, row_length: usize) -> Option<QuantizationTarget> {
+        if let Some((_, target)) = self
+            .rules
+            .iter()
+            .find(|(rule, _)| rule.matches(name, self.n_layers))
+        {
+            return target.for_row_length(row_length);
+        }
+
+        let (role, layer) = self
+            .roles
+            .get(name)
+            .copied()
+            .unwrap_or((TensorRole::Other, 0));
+        let n_layers = self.counts.get(&role).copied().unwrap_or(1);
+        self.preset.target(role, layer, n_layers, row_length)
     }
 }
 
+/// Returns the first number in a tensor name, which is the index of its layer for layer tensors.
+fn layer_number(name: &str) -> Option<usize> {
+    let start = name.find(|c: char| c.is_ascii_digit())?;
+    let digits = &name[start..];
+    let end = digits
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(digits.len());
+    digits[..end].parse().ok()
+}
+
 struct QuantizeSaver<'a, F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek> {
     // Input
-    quantization_target: QuantizationTarget,
-    hyperparameters: &'a H,
-    tensors: &'a HashMap<String, TensorLoadInfo>,
-    to_quantize: &'a [Regex],
-    to_skip: &'a [Regex],
+    model: &'a QuantizeModel<H>,
+    parameters: &'a QuantizeParameters,
     source_reader: &'a mut R,
     progress_callback: F,
 
@@ -280,48 +1150,127 @@ struct QuantizeSaver<'a, F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead
     total_size_new: usize,
     history_all: Vec<i64>,
 }
-impl<'a, F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek>
-    QuantizeSaver<'a, F, H, R>
-{
-    fn new(
-        quantization_target: QuantizationTarget,
-        hyperparameters: &'a H,
-        tensors: &'a HashMap<String, TensorLoadInfo>,
-        to_quantize: &'a [Regex],
-        to_skip: &'a [Regex],
-        source_reader: &'a mut R,
-        progress_callback: F,
-    ) -> Self {
-        Self {
-            quantization_target,
-            hyperparameters,
-            tensors,
-            to_quantize,
-            to_skip,
-            source_reader,
-            progress_callback,
+impl<F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek> QuantizeSaver<'_, F, H, R> {
+    /// Returns the type to quantize the tensor to, or `None` if it is kept as it is.
+    fn target(
+        &self,
+        tensor_name: &str,
+        tensor: &TensorLoadInfo,
+    ) -> Result<Option<QuantizationTarget>, QuantizeError> {
+        // Quantize only 2D tensors
+        let quantize = tensor.n_dims == 2
+            && self
+                .model
+                .to_quantize
+                .iter()
+                .any(|re| re.is_match(tensor_name))
+            && !self.model.to_skip.iter().any(|re| re.is_match(tensor_name));
 
-            total_size_original: 0,
-            total_size_new: 0,
-            history_all: vec![0; 16],
+        // Quantized tensors are dequantized before being quantized again, but integer tensors
+        // are not weights.
+        if quantize && matches!(tensor.element_type, ggml::Type::I32 | ggml::Type::I8) {
+            return Err(QuantizeError::UnsupportedElementType {
+                element_type: tensor.element_type,
+            });
+        }
+
+        // Tensors that already have the target type are kept as they are
+        Ok(quantize
+            .then(|| self.model.targets.target(tensor_name, tensor.dims[0]))
+            .flatten()
+            .filter(|target| ggml::Type::from(*target) != tensor.element_type))
+    }
+
+    /// Returns the importance of the columns of the tensor from the importance matrix, if it
+    /// has an entry for the tensor and it can be quantized to `target` with it.
+    fn importance(
+        &self,
+        tensor_name: &str,
+        tensor: &TensorLoadInfo,
+        target: QuantizationTarget,
+    ) -> Result<Option<Vec<f32>>, QuantizeError> {
+        let Some(importance) = self
+            .parameters
+            .importance_matrix
+            .as_ref()
+            .and_then(|importance_matrix| importance_matrix.importance(tensor_name))
+        else {
+            return Ok(None);
+        };
+        if importance.len() != tensor.dims[0] {
+            return Err(QuantizeError::ImportanceMatrixMismatch {
+                tensor_name: tensor_name.to_owned(),
+                expected: tensor.dims[0],
+                actual: importance.len(),
+            });
+        }
+        if target.is_k_quant() {
+            return Err(QuantizeError::UnsupportedImportanceMatrix {
+                tensor_name: tensor_name.to_owned(),
+                element_type: target.into(),
+            });
         }
+        Ok(Some(importance))
+    }
+
+    /// Decides what would be done with the tensor, without reading its data.
+    fn plan(
+        &self,
+        tensor_name: &str,
+        tensor: &TensorLoadInfo,
+    ) -> Result<TensorQuantizePlan, QuantizeError> {
+        let target = self.target(tensor_name, tensor)?;
+        if let Some(target) = target {
+            self.importance(tensor_name, tensor, target)?;
+        }
+        let element_type = target.map_or(tensor.element_type, ggml::Type::from);
+        Ok(TensorQuantizePlan {
+            name: tensor_name.to_owned(),
+            dims: tensor.dims,
+            original_type: tensor.element_type,
+            element_type,
+            original_size: tensor.calc_size(),
+            size: TensorLoadInfo {
+                element_type,
+                ..tensor.clone()
+            }
+            .calc_size(),
+        })
     }
 }
 impl<F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek> SaveHandler<QuantizeError>
     for QuantizeSaver<'_, F, H, R>
 {
     fn write_hyperparameters(&mut self, writer: &mut dyn Write) -> Result<(), QuantizeError> {
-        self.hyperparameters
+        self.model
+            .hyperparameters
             .write_ggml(writer)
             .map_err(QuantizeError::HyperparametersWriteError)?;
         Ok(())
     }
 
-    fn tensor_data(&mut self, tensor_name: &str) -> Result<TensorSaveInfo, QuantizeError> {
-        let tensor = self.tensors.get(tensor_name).expect(
+    fn write_metadata(&mut self, metadata: &mut Metadata) -> Result<(), QuantizeError> {
+        self.model
+            .hyperparameters
+            .write_gguf(metadata)
+            .map_err(QuantizeError::HyperparametersWriteError)
+    }
+
+    fn tensor_data(&mut self, saved_name: &str) -> Result<TensorSaveInfo, QuantizeError> {
+        let tensor_name = &*if self.model.save_container_type == SaveContainerType::Gguf {
+            self.model.hyperparameters.tensor_name_from_gguf(saved_name)
+        } else {
+            saved_name.to_owned()
+        };
+        if tensor_name == TOKENIZER_TENSOR_NAME {
+            if let Some(tokenizer_tensor) = &self.model.tokenizer_tensor {
+                return Ok(tokenizer_tensor.clone());
+            }
+        }
+
+        let tensor = self.model.tensors.get(tensor_name).expect(
             "tensor not found; should be impossible due to handler being populated from loader",
         );
-
         (self.progress_callback)(QuantizeProgress::TensorLoading {
             name: tensor_name,
             dims: tensor.dims,
@@ -329,54 +1278,21 @@ impl<F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek> SaveHandler
             element_type: tensor.element_type,
         });
 
-        // Quantize only 2D tensors
-        let quantize = tensor.n_dims == 2
-            && self.to_quantize.iter().any(|re| re.is_match(tensor_name))
-            && !self.to_skip.iter().any(|re| re.is_match(tensor_name));
+        let target = self.target(tensor_name, tensor)?;
         let raw_data = tensor.read_data(self.source_reader)?;
-
-        if quantize && !matches!(tensor.element_type, ggml::Type::F32 | ggml::Type::F16) {
-            return Err(QuantizeError::UnsupportedElementType {
-                element_type: tensor.element_type,
-            });
-        }
-
         self.total_size_original += raw_data.len();
 
-        let (element_type, data) = if quantize {
+        let (element_type, data) = if let Some(target) = target {
             (self.progress_callback)(QuantizeProgress::TensorQuantizing { name: tensor_name });
 
-            let data_f32: Vec<f32> = match tensor.element_type {
-                ggml::Type::F32 => raw_data
-                    .chunks_exact(4)
-                    .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
-                    .collect(),
-                ggml::Type::F16 => raw_data
-                    .chunks_exact(2)
-                    .map(|chunk| {
-                        f16::from_bits(u16::from_le_bytes(chunk.try_into().unwrap())).to_f32()
-                    })
-                    .collect(),
-                _ => unreachable!(),
-            };
-
-            let result = match self.quantization_target {
-                QuantizationTarget::Q4_0 => {
-                    ggml::quantize_q4_0(&data_f32, tensor.n_elements, tensor.dims[0])
-                }
-                QuantizationTarget::Q4_1 => {
-                    ggml::quantize_q4_1(&data_f32, tensor.n_elements, tensor.dims[0])
-                }
-                QuantizationTarget::Q5_0 => {
-                    ggml::quantize_q5_0(&data_f32, tensor.n_elements, tensor.dims[0])
-                }
-                QuantizationTarget::Q5_1 => {
-                    ggml::quantize_q5_1(&data_f32, tensor.n_elements, tensor.dims[0])
-                }
-                QuantizationTarget::Q8_0 => {
-                    ggml::quantize_q8_0(&data_f32, tensor.n_elements, tensor.dims[0])
-                }
-            };
+            let importance = self.importance(tensor_name, tensor, target)?;
+            let result = target.quantize_rows(
+                tensor.element_type,
+                &raw_data,
+                tensor.dims[0],
+                importance.as_deref(),
+                self.parameters.n_threads,
+            );
             let new_data = result.output;
 
             let mut history_new = vec![];
@@ -389,12 +1305,23 @@ impl<F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek> SaveHandler
                 name: tensor_name,
                 original_size: raw_data.len(),
                 reduced_size: new_data.len(),
+                element_type: target.into(),
                 history: history_new,
             });
 
+            if self.parameters.measure_error {
+                let original = ggml::dequantize(tensor.element_type, &raw_data, tensor.n_elements);
+                let quantized = ggml::dequantize(target.into(), &new_data, tensor.n_elements);
+                (self.progress_callback)(QuantizeProgress::TensorMeasured {
+                    name: tensor_name,
+                    element_type: target.into(),
+                    error: QuantizationErrorStats::measure(&original, &quantized),
+                });
+            }
+
             self.total_size_new += new_data.len();
 
-            (self.quantization_target.into(), new_data)
+            (target.into(), new_data)
         } else {
             (self.progress_callback)(QuantizeProgress::TensorSkipped {
                 name: tensor_name,
@@ -412,3 +1339,254 @@ impl<F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek> SaveHandler
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_pick_targets_for_k_quant_presets() {
+        let preset = QuantizationPreset::try_from(FileTypeFormat::MostlyQ4_K_M).unwrap();
+        let target = |role, layer| preset.target(role, layer, 32, 4096);
+
+        assert_eq!(
+            target(TensorRole::Other, 10),
+            Some(QuantizationTarget::Q4_K)
+        );
+        assert_eq!(
+            target(TensorRole::Output, 0),
+            Some(QuantizationTarget::Q6_K)
+        );
+        assert_eq!(
+            target(TensorRole::FeedForwardDown, 0),
+            Some(QuantizationTarget::Q6_K)
+        );
+        assert_eq!(
+            target(TensorRole::AttentionValue, 5),
+            Some(QuantizationTarget::Q4_K)
+        );
+
+        // Rows that are not made of whole k-quant blocks fall back to a legacy type.
+        assert_eq!(
+            preset.target(TensorRole::Other, 0, 1, 4096 + 32),
+            Some(QuantizationTarget::Q5_0)
+        );
+        assert_eq!(preset.target(TensorRole::Other, 0, 1, 48), None);
+
+        assert!(QuantizationPreset::try_from(FileTypeFormat::MostlyQ4_1SomeF16).is_err());
+    }
+
+    #[test]
+    fn can_load_policies() {
+        let toml = r#"
+            [[rules]]
+            pattern = "^output\\.weight$"
+            type = "q8_0"
+
+            [[rules]]
+            layers = [0, -1]
+            pattern = "attention"
+            type = "Q8_0"
+
+            [[rules]]
+            layers = [1]
+            type = "q6_k"
+        "#;
+        let json = r#"{"rules": [
+            {"pattern": "^output\\.weight$", "type": "q8_0"},
+            {"layers": [0, -1], "pattern": "attention", "type": "Q8_0"},
+            {"layers": [1], "type": "q6_k"}
+        ]}"#;
+
+        for policy in [
+            QuantizationPolicy::from_toml(toml).unwrap(),
+            QuantizationPolicy::from_json(json).unwrap(),
+        ] {
+            let element_type = |name| policy.element_type(name, 32);
+            assert_eq!(element_type("output.weight"), Some(ggml::Type::Q8_0));
+            assert_eq!(
+                element_type("layers.0.attention.wq.weight"),
+                Some(ggml::Type::Q8_0)
+            );
+            assert_eq!(
+                element_type("layers.31.attention.wq.weight"),
+                Some(ggml::Type::Q8_0)
+            );
+            assert_eq!(element_type("layers.31.feed_forward.w1.weight"), None);
+            assert_eq!(
+                element_type("layers.1.feed_forward.w1.weight"),
+                Some(ggml::Type::Q6_K)
+            );
+            assert_eq!(element_type("layers.2.attention.wq.weight"), None);
+        }
+
+        assert!(matches!(
+            QuantizationPolicy::from_toml("[[rules]]\ntype = \"q9_0\""),
+            Err(QuantizationPolicyError::UnknownElementType { .. })
+        ));
+    }
+
+    #[test]
+    fn can_find_layer_numbers() {
+        assert_eq!(layer_number("layers.12.attention.wv.weight"), Some(12));
+        assert_eq!(layer_number("model/h3/attn/c_proj/w"), Some(3));
+        assert_eq!(layer_number("output.weight"), None);
+    }
+
+    #[test]
+    fn quantizing_rows_in_parallel_matches_quantizing_at_once() {
+        let n_elements_0 = 64;
+        let data: Vec<f32> = (0..n_elements_0 * 7)
+            .map(|i| ((i * 37) % 101) as f32 / 50.0 - 1.0)
+            .collect();
+        let raw_data: Vec<u8> = data.iter().flat_map(|x| x.to_le_bytes()).collect();
+        let importance: Vec<f32> = (0..n_elements_0).map(|i| 1.0 + i as f32).collect();
+
+        let quantize = |n_threads| {
+            QuantizationTarget::Q4_1.quantize_rows(
+                ggml::Type::F32,
+                &raw_data,
+                n_elements_0,
+                Some(&importance),
+                n_threads,
+            )
+        };
+        let at_once = quantize(1);
+        for n_threads in [2, 3, 16] {
+            let in_parallel = quantize(n_threads);
+            assert_eq!(in_parallel.output, at_once.output);
+            assert_eq!(in_parallel.history, at_once.history);
+        }
+    }
+
+    #[test]
+    fn can_expand_quantized_tensors() {
+        let preset = QuantizationPreset::try_from(FileTypeFormat::MostlyF16).unwrap();
+        assert_eq!(
+            preset.target(TensorRole::Output, 0, 1, 4096),
+            Some(QuantizationTarget::F16)
+        );
+
+        // A Q8_0 block is an F16 scale (0.25) followed by the 8-bit values.
+        let values: Vec<i8> = (0..32).collect();
+        let mut raw_data = 0x3400u16.to_le_bytes().to_vec();
+        raw_data.extend(values.iter().map(|&q| q as u8));
+
+        let result =
+            QuantizationTarget::F32.quantize_rows(ggml::Type::Q8_0, &raw_data, 32, None, 1);
+        let expected: Vec<u8> = values
+            .iter()
+            .flat_map(|&q| (q as f32 * 0.25).to_le_bytes())
+            .collect();
+        assert_eq!(result.output, expected);
+    }
+
+    #[test]
+    fn can_measure_quantization_error() {
+        let original = [1.0, -2.0, 3.0, 0.0];
+
+        let error = QuantizationErrorStats::measure(&original, &original);
+        assert_eq!(error.rmse, 0.0);
+        assert_eq!(error.max_abs_error, 0.0);
+        assert!((error.cosine_similarity - 1.0).abs() < 1e-6);
+
+        let error = QuantizationErrorStats::measure(&original, &[1.0, -2.0, 1.0, 0.0]);
+        assert_eq!(error.rmse, 1.0);
+        assert_eq!(error.max_abs_error, 2.0);
+        assert!(error.cosine_similarity < 1.0);
+
+        let error = QuantizationErrorStats::measure(&original, &[-1.0, 2.0, -3.0, 0.0]);
+        assert!((error.cosine_similarity + 1.0).abs() < 1e-6);
+        assert_eq!(
+            QuantizationErrorStats::measure(&original, &[0.0; 4]).cosine_similarity,
+            0.0
+        );
+    }
+
+    #[test]
+    fn can_measure_saved_files_without_writing_them() {
+        struct Handler;
+        impl SaveHandler<QuantizeError> for Handler {
+            fn write_hyperparameters(
+                &mut self,
+                writer: &mut dyn Write,
+            ) -> Result<(), QuantizeError> {
+                writer.write_all(&[1, 2, 3])?;
+                Ok(())
+            }
+
+            fn tensor_data(&mut self, _: &str) -> Result<TensorSaveInfo, QuantizeError> {
+                Ok(TensorSaveInfo {
+                    n_dims: 2,
+                    dims: [4, 3],
+                    element_type: ggml::Type::F32,
+                    data: vec![0; 48],
+                })
+            }
+        }
+
+        let vocabulary = vec![(b"a".to_vec(), 0.5), (b"bc".to_vec(), 0.0)];
+        let tensor_names = vec!["a".to_owned(), "bcd".to_owned()];
+
+        let mut file = std::io::Cursor::new(vec![]);
+        ggml::format::save(
+            &mut file,
+            &mut Handler,
+            SaveContainerType::GgjtV3,
+            &vocabulary,
+            &tensor_names,
+        )
+        .unwrap();
+        let layouts: Vec<_> = tensor_names
+            .iter()
+            .map(|name| TensorLoadInfo {
+                name: name.clone(),
+                n_dims: 2,
+                dims: [4, 3],
+                n_elements: 12,
+                element_type: ggml::Type::F32,
+                start_offset: 0,
+            })
+            .collect();
+        let size = ggml::format::saved_size(
+            &mut Handler,
+            SaveContainerType::GgjtV3,
+            &vocabulary,
+            &layouts,
+        )
+        .unwrap();
+
+        assert_eq!(size, file.into_inner().len() as u64);
+    }
+
+    #[test]
+    fn can_save_hugging_face_tokenizers() {
+        let tokenizer = crate::tokenizer::HuggingFaceTokenizer::new(
+            r#"{
+                "version": "1.0",
+                "model": {
+                    "type": "Unigram",
+                    "unk_id": 0,
+                    "vocab": [["<unk>", 0.0], ["▁a", -1.5], ["b", -2.0]]
+                }
+            }"#
+            .parse()
+            .unwrap(),
+        );
+
+        assert_eq!(
+            Tokenizer::from(tokenizer.clone()).vocabulary(4),
+            vec![
+                (b"<unk>".to_vec(), 0.0),
+                (b" a".to_vec(), -1.5),
+                (b"b".to_vec(), -2.0),
+                (vec![], 0.0),
+            ]
+        );
+
+        let tensor = tokenizer.to_tensor();
+        let loaded =
+            crate::tokenizer::HuggingFaceTokenizer::from_tensor_data(&tensor.data).unwrap();
+        assert_eq!(loaded.to_tensor().data, tensor.data);
+    }
+}
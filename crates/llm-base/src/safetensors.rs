@@ -0,0 +1,169 @@
+//! A minimal reader for the [safetensors](https://github.com/huggingface/safetensors) format.
+//!
+//! A safetensors file is a little-endian `u64` header length, followed by a JSON header
+//! describing each tensor, followed by the raw tensor data.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Error, ErrorKind},
+    path::Path,
+};
+
+use half::{bf16, f16};
+use memmap2::Mmap;
+use serde::Deserialize;
+
+/// The header entry for a single tensor.
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct TensorInfo {
+    /// The element type, e.g. `F16`.
+    pub dtype: String,
+    /// The shape of the tensor, outermost dimension first.
+    pub shape: Vec<usize>,
+    /// The start and end of the tensor data, relative to the end of the header.
+    pub data_offsets: [usize; 2],
+}
+impl TensorInfo {
+    /// Returns the number of elements in the tensor.
+    pub fn n_elements(&self) -> usize {
+        self.shape.iter().product()
+    }
+}
+
+/// A memory-mapped safetensors file.
+pub(crate) struct SafeTensors {
+    mmap: Mmap,
+    data_start: usize,
+    tensors: Vec<(String, TensorInfo)>,
+}
+impl SafeTensors {
+    /// Opens and memory-maps the file at `path`, and parses its header.
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let file = File::open(path)?;
+        // SAFETY: the mapping is read-only; the file is not expected to change while open.
+        let mmap = unsafe { Mmap::map(&file)? };
+        Self::from_mmap(mmap)
+    }
+
+    fn from_mmap(mmap: Mmap) -> Result<Self, Error> {
+        let (data_start, tensors) = parse_header(&mmap)?;
+        Ok(Self {
+            mmap,
+            data_start,
+            tensors,
+        })
+    }
+
+    /// Returns the tensors in the file, in the order of their data.
+    pub fn tensors(&self) -> &[(String, TensorInfo)] {
+        &self.tensors
+    }
+
+    /// Returns the raw data of `tensor`.
+    pub fn data(&self, tensor: &TensorInfo) -> &[u8] {
+        let [start, end] = tensor.data_offsets;
+        &self.mmap[self.data_start + start..self.data_start + end]
+    }
+
+    /// Reads `tensor` as `f32`s, converting from its stored element type.
+    pub fn read_f32(&self, tensor: &TensorInfo) -> Result<Vec<f32>, Error> {
+        let data = self.data(tensor);
+        let values = match tensor.dtype.as_str() {
+            "F32" => data
+                .chunks_exact(4)
+                .map(|c| f32::from_le_bytes(c.try_into().unwrap()))
+                .collect(),
+            "F16" => data
+                .chunks_exact(2)
+                .map(|c| f16::from_le_bytes(c.try_into().unwrap()).to_f32())
+                .collect(),
+            "BF16" => data
+                .chunks_exact(2)
+                .map(|c| bf16::from_le_bytes(c.try_into().unwrap()).to_f32())
+                .collect(),
+            dtype => {
+                return Err(invalid_data(format!(
+                    "unsupported element type {dtype}; only F32, F16 and BF16 are supported"
+                )))
+            }
+        };
+        Ok(values)
+    }
+}
+
+/// Parses the header at the start of `data`, returning the offset of the tensor data and the
+/// tensors sorted by their position in it.
+fn parse_header(data: &[u8]) -> Result<(usize, Vec<(String, TensorInfo)>), Error> {
+    let header_len = data
+        .get(..8)
+        .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| invalid_data("file is too small to contain a header".to_string()))?;
+    let data_start = usize::try_from(header_len)
+        .ok()
+        .and_then(|len| len.checked_add(8))
+        .filter(|start| *start <= data.len())
+        .ok_or_else(|| invalid_data(format!("header length {header_len} is out of bounds")))?;
+
+    let header: HashMap<String, serde_json::Value> =
+        serde_json::from_slice(&data[8..data_start]).map_err(Error::from)?;
+
+    let data_len = data.len() - data_start;
+    let mut tensors = header
+        .into_iter()
+        .filter(|(name, _)| name != "__metadata__")
+        .map(|(name, value)| {
+            let info: TensorInfo = serde_json::from_value(value).map_err(Error::from)?;
+            let [start, end] = info.data_offsets;
+            if start > end || end > data_len {
+                return Err(invalid_data(format!(
+                    "data of tensor {name} is out of bounds"
+                )));
+            }
+            if end - start != info.n_elements() * dtype_size(&info.dtype).unwrap_or(end - start) {
+                return Err(invalid_data(format!(
+                    "data of tensor {name} does not match its shape"
+                )));
+            }
+            Ok((name, info))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    tensors.sort_by_key(|(_, info)| info.data_offsets[0]);
+
+    Ok((data_start, tensors))
+}
+
+fn dtype_size(dtype: &str) -> Option<usize> {
+    match dtype {
+        "F64" | "I64" | "U64" => Some(8),
+        "F32" | "I32" | "U32" => Some(4),
+        "F16" | "BF16" | "I16" | "U16" => Some(2),
+        "I8" | "U8" | "BOOL" => Some(1),
+        _ => None,
+    }
+}
+
+fn invalid_data(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_parse_header() {
+        let header = br#"{"b":{"dtype":"F32","shape":[2],"data_offsets":[4,12]},"a":{"dtype":"F16","shape":[2],"data_offsets":[0,4]},"__metadata__":{"format":"pt"}}"#;
+        let mut data = (header.len() as u64).to_le_bytes().to_vec();
+        data.extend_from_slice(header);
+        data.extend_from_slice(&[0; 12]);
+
+        let (data_start, tensors) = parse_header(&data).unwrap();
+        assert_eq!(data_start, 8 + header.len());
+        let names: Vec<_> = tensors.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["a", "b"]);
+
+        // Truncating the data must be caught.
+        assert!(parse_header(&data[..data.len() - 1]).is_err());
+    }
+}
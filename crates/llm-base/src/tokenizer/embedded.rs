@@ -155,3 +155,109 @@ impl EmbeddedTokenizer {
             .map(|(token, score)| (token.clone(), *score))
     }
 }
+
+/// Converts a token stored in the metadata of a GGUF file to the bytes it represents.
+///
+/// GGUF stores tokens the way their tokenizer writes them: SentencePiece (`llama`) marks spaces
+/// with `▁` and raw bytes as `<0xNN>`, while byte-level BPE (`gpt2`) maps every byte to a printable
+/// character. Tokens from other tokenizers are returned unchanged.
+pub(crate) fn gguf_token_to_bytes(tokenizer_model: Option<&str>, token: Vec<u8>) -> Vec<u8> {
+    let Ok(text) = std::str::from_utf8(&token) else {
+        return token;
+    };
+
+    match tokenizer_model {
+        Some("llama") => {
+            let byte = text
+                .strip_prefix("<0x")
+                .and_then(|t| t.strip_suffix('>'))
+                .filter(|hex| hex.len() == 2)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            match byte {
+                Some(byte) => vec![byte],
+                None => text.replace('\u{2581}', " ").into_bytes(),
+            }
+        }
+        Some("gpt2") => text
+            .chars()
+            .map(gpt2_char_to_byte)
+            .collect::<Option<Vec<u8>>>()
+            .unwrap_or(token),
+        _ => token,
+    }
+}
+
+/// Converts the bytes of a token to the way it is stored in the metadata of a GGUF file whose
+/// tokenizer is `tokenizer_model`. This is the inverse of [gguf_token_to_bytes].
+///
+/// SentencePiece tokens that are not valid UTF-8 are only representable if they are a single
+/// byte, and are otherwise returned unchanged.
+pub(crate) fn bytes_to_gguf_token(tokenizer_model: Option<&str>, token: &[u8]) -> Vec<u8> {
+    match tokenizer_model {
+        Some("llama") => match std::str::from_utf8(token) {
+            Ok(text) => text.replace(' ', "\u{2581}").into_bytes(),
+            Err(_) if token.len() == 1 => format!("<0x{:02X}>", token[0]).into_bytes(),
+            Err(_) => token.to_vec(),
+        },
+        Some("gpt2") => token
+            .iter()
+            .map(|&b| gpt2_byte_to_char(b))
+            .collect::<String>()
+            .into_bytes(),
+        _ => token.to_vec(),
+    }
+}
+
+/// Whether GPT-2's byte-level BPE maps the byte `b` to itself. The other bytes are mapped to the
+/// characters from 256 onwards, in order.
+fn is_gpt2_printable(b: u32) -> bool {
+    matches!(b, 0x21..=0x7E | 0xA1..=0xAC | 0xAE..=0xFF)
+}
+
+/// Inverts the byte-to-character mapping used by GPT-2's byte-level BPE.
+fn gpt2_char_to_byte(c: char) -> Option<u8> {
+    let c = u32::from(c);
+    if c < 256 {
+        return is_gpt2_printable(c).then_some(c as u8);
+    }
+    (0..256u32)
+        .filter(|b| !is_gpt2_printable(*b))
+        .nth((c - 256) as usize)
+        .map(|b| b as u8)
+}
+
+/// The byte-to-character mapping used by GPT-2's byte-level BPE.
+fn gpt2_byte_to_char(b: u8) -> char {
+    let b = u32::from(b);
+    if is_gpt2_printable(b) {
+        return char::from_u32(b).unwrap();
+    }
+    let index = (0..b).filter(|b| !is_gpt2_printable(*b)).count() as u32;
+    char::from_u32(256 + index).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gguf_tokens_roundtrip() {
+        let tokens: [&[u8]; 4] = [b" the", b"a", &[0x80], "é".as_bytes()];
+        for model in ["llama", "gpt2"] {
+            for token in tokens {
+                let stored = bytes_to_gguf_token(Some(model), token);
+                assert!(std::str::from_utf8(&stored).is_ok(), "{model} {token:?}");
+                assert_eq!(gguf_token_to_bytes(Some(model), stored), token, "{model}");
+            }
+        }
+        assert_eq!(
+            bytes_to_gguf_token(Some("llama"), b" the"),
+            "▁the".as_bytes()
+        );
+        assert_eq!(bytes_to_gguf_token(Some("llama"), &[0x80]), b"<0x80>");
+        assert_eq!(
+            bytes_to_gguf_token(Some("gpt2"), b" the"),
+            "Ġthe".as_bytes()
+        );
+    }
+}
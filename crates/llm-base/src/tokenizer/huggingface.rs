@@ -1,4 +1,10 @@
-use super::{TokenId, TokenizationError};
+use ggml::format::TensorSaveInfo;
+
+use super::{gguf_token_to_bytes, TokenId, TokenizationError};
+
+/// The name of the tensor that a Hugging Face tokenizer is stored in when it is saved with a
+/// model, named after the GGUF metadata key for the tokenizer.
+pub(crate) const TOKENIZER_TENSOR_NAME: &str = "tokenizer.huggingface.json";
 
 /// A Hugging Face tokenizer.
 #[derive(Debug, Clone)]
@@ -72,4 +78,67 @@ impl HuggingFaceTokenizer {
             .as_bytes()
             .to_vec()
     }
+
+    /// Returns the first `n_vocab` tokens of this tokenizer and their scores, for a model's
+    /// embedded vocabulary. The tokens are stored as the bytes they represent, as the embedded
+    /// tokenizer expects; tokens missing from this tokenizer are left empty.
+    ///
+    /// Only Unigram models have scores. The tokens of other models have a score of 0.
+    pub(crate) fn vocabulary(&self, n_vocab: usize) -> Vec<(Vec<u8>, f32)> {
+        let tokenizer_model = match self.tokenizer.get_decoder() {
+            Some(tokenizers::DecoderWrapper::ByteLevel(_)) => "gpt2",
+            _ => "llama",
+        };
+        let scores = match self.tokenizer.get_model() {
+            tokenizers::ModelWrapper::Unigram(unigram) => unigram_scores(unigram),
+            _ => vec![],
+        };
+
+        (0..n_vocab)
+            .map(|id| {
+                let token = u32::try_from(id)
+                    .ok()
+                    .and_then(|id| self.tokenizer.id_to_token(id))
+                    .map(|t| gguf_token_to_bytes(Some(tokenizer_model), t.into()))
+                    .unwrap_or_default();
+                (token, scores.get(id).copied().unwrap_or_default())
+            })
+            .collect()
+    }
+
+    /// Returns a tensor that stores this tokenizer as JSON, to be saved with a model under
+    /// [TOKENIZER_TENSOR_NAME].
+    pub(crate) fn to_tensor(&self) -> TensorSaveInfo {
+        let data = self
+            .tokenizer
+            .to_string(false)
+            .expect("a loaded tokenizer can be serialized")
+            .into_bytes();
+        TensorSaveInfo {
+            n_dims: 1,
+            dims: [data.len(), 1],
+            element_type: ggml::Type::I8,
+            data,
+        }
+    }
+
+    /// Reads a tokenizer stored as JSON by [to_tensor](Self::to_tensor).
+    pub(crate) fn from_tensor_data(data: &[u8]) -> Result<Self, tokenizers::Error> {
+        Ok(Self::new(tokenizers::Tokenizer::from_bytes(data)?))
+    }
+}
+
+/// Returns the score of each token of a Unigram model, in token ID order.
+fn unigram_scores(unigram: &tokenizers::models::unigram::Unigram) -> Vec<f32> {
+    // The scores are not exposed by the model, but are part of its serialized form.
+    let serialized = serde_json::to_value(unigram).unwrap_or_default();
+    serialized["vocab"]
+        .as_array()
+        .map(|vocab| {
+            vocab
+                .iter()
+                .map(|entry| entry[1].as_f64().unwrap_or_default() as f32)
+                .collect()
+        })
+        .unwrap_or_default()
 }
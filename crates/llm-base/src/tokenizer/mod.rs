@@ -58,6 +58,10 @@ pub enum TokenizerSource {
     ///
     /// This is easy to use, but may not be the best choice for your use case, and is not
     /// guaranteed to be available for all models.
+    ///
+    /// If a Hugging Face tokenizer was saved with the model (see
+    /// [QuantizeParameters::embed_tokenizer](crate::QuantizeParameters::embed_tokenizer)),
+    /// it is used instead.
     Embedded,
 
     /// Read a Hugging Face tokenizer from a local Hugging Face tokenizer file.
@@ -129,6 +133,15 @@ impl Tokenizer {
     pub(crate) fn empty_embedded() -> Self {
         Self::Embedded(EmbeddedTokenizer::default())
     }
+
+    /// Returns the vocabulary to save with a model that has `n_vocab` tokens. A Hugging Face
+    /// tokenizer is converted to the vocabulary of an embedded tokenizer.
+    pub(crate) fn vocabulary(&self, n_vocab: usize) -> Vec<(Vec<u8>, f32)> {
+        match self {
+            Self::Embedded(v) => v.iter().collect(),
+            Self::HuggingFace(v) => v.vocabulary(n_vocab),
+        }
+    }
 }
 impl Tokenizer {
     /// Converts a token to the token ID it represents in this tokenizer.
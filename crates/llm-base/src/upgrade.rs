@@ -0,0 +1,220 @@
+//! Implements upgrading models in the legacy GGML-family containers to GGJT version 3.
+
+use crate::{
+    loader::quantization_version, model::HyperparametersWriteError, Hyperparameters, KnownModel,
+    LoadError, LoadProgress, Loader, Tokenizer,
+};
+use ggml::{
+    format::{SaveContainerType, SaveError, SaveHandler, TensorLoadInfo, TensorSaveInfo},
+    ContainerType,
+};
+use std::{
+    collections::HashMap,
+    io::{BufRead, Seek, Write},
+    path::PathBuf,
+    sync::Arc,
+};
+use thiserror::Error;
+
+#[derive(Clone, Debug)]
+/// Progress of upgrading a model.
+pub enum UpgradeProgress<'a> {
+    /// Hyperparameters have been loaded.
+    HyperparametersLoaded,
+    /// A tensor has been copied to the upgraded model.
+    TensorCopied {
+        /// Name of the tensor.
+        name: &'a str,
+        /// The size (in bytes) of the tensor data.
+        size: usize,
+    },
+    /// The model has been upgraded.
+    Finished {
+        /// The container type of the original model.
+        container_type: ContainerType,
+        /// The quantization version recorded in the upgraded model.
+        quantization_version: u32,
+    },
+}
+
+#[derive(Error, Debug)]
+/// Errors encountered while upgrading a model.
+pub enum UpgradeError {
+    #[error("could not load model")]
+    /// There was an error while attempting to load the model.
+    Load(#[from] LoadError),
+    #[error("non-specific I/O error")]
+    /// A non-specific IO error.
+    Io(#[from] std::io::Error),
+    #[error("invalid integer conversion")]
+    /// One of the integers encountered could not be converted to a more appropriate type.
+    InvalidIntegerConversion(#[from] std::num::TryFromIntError),
+    /// An invariant was broken.
+    #[error("invariant broken: {invariant} in {path:?}")]
+    InvariantBroken {
+        /// The path that failed.
+        path: PathBuf,
+        /// The invariant that was broken.
+        invariant: String,
+    },
+    /// The model is not in one of the GGML-family containers.
+    #[error("models in the {container_type:?} container cannot be upgraded")]
+    UnsupportedContainerType {
+        /// The container type of the model.
+        container_type: ContainerType,
+    },
+    /// The model has quantized tensors, but neither its container nor its file type records
+    /// which layout of the quantized types they are in.
+    #[error("the quantization version of the quantized {container_type:?} model is unknown")]
+    UnknownQuantizationVersion {
+        /// The container type of the model.
+        container_type: ContainerType,
+    },
+    /// An error was encountered while writing the hyperparameters.
+    #[error("an error was encountered while writing the hyperparameters")]
+    HyperparametersWriteError(#[source] HyperparametersWriteError),
+    /// An attempt was made to save a model with a container type that does not
+    /// support vocabulary scoring, despite the model having a scored vocabulary.
+    #[error("container type does not support vocabulary scoring")]
+    VocabularyScoringNotSupported,
+}
+impl UpgradeError {
+    pub(crate) fn from_format_error(value: SaveError<UpgradeError>, path: PathBuf) -> Self {
+        match value {
+            SaveError::Io(io) => UpgradeError::Io(io),
+            SaveError::InvalidIntegerConversion(e) => UpgradeError::InvalidIntegerConversion(e),
+            SaveError::ImplementationError(e) => e,
+            SaveError::InvariantBroken(invariant) => {
+                UpgradeError::InvariantBroken { path, invariant }
+            }
+            SaveError::VocabularyScoringNotSupported => UpgradeError::VocabularyScoringNotSupported,
+            err @ SaveError::UnsupportedElementType { .. } => UpgradeError::InvariantBroken {
+                path,
+                invariant: err.to_string(),
+            },
+        }
+    }
+}
+
+/// Upgrades the model read from `reader`, which may be in any of the GGML-family containers
+/// (GGML, GGMF or GGJT), and writes it to `writer` as GGJT version 3, which can be loaded with
+/// mmap.
+///
+/// The vocabulary and the data of the tensors are copied as they are. The quantization version
+/// of the model, which llama.cpp does not record in GGJT files, is recorded in its file type,
+/// so that it does not have to be inferred from the container when the model is loaded.
+///
+/// Quantized models in the GGML, GGMF and GGJT version 1 containers that do not record their
+/// quantization version in their file type cannot be upgraded, as the layout of their tensors
+/// is unknown, and are reported with [UpgradeError::UnknownQuantizationVersion].
+pub fn upgrade<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
+    reader: &mut R,
+    writer: &mut W,
+    progress_callback: impl Fn(UpgradeProgress),
+) -> Result<(), UpgradeError> {
+    // Load the model
+    let progress_callback = Arc::new(progress_callback);
+
+    let mut loader = Loader::<M::Hyperparameters, _>::new(Tokenizer::empty_embedded(), {
+        let progress_callback = progress_callback.clone();
+        move |p| {
+            if let LoadProgress::HyperparametersLoaded = p {
+                progress_callback(UpgradeProgress::HyperparametersLoaded)
+            }
+        }
+    });
+    ggml::format::load(reader, &mut loader)
+        .map_err(|err| LoadError::from_format_error(err, PathBuf::default()))?;
+
+    let Loader {
+        container_type,
+        mut hyperparameters,
+        tokenizer,
+        tensors,
+        ..
+    } = loader;
+
+    // GGUF files are newer than GGJT, and LoRA adapters are not models
+    if matches!(
+        container_type,
+        ContainerType::Gguf(_) | ContainerType::Ggla(_)
+    ) {
+        return Err(UpgradeError::UnsupportedContainerType { container_type });
+    }
+
+    let quantization_version = match hyperparameters.file_type_mut() {
+        Some(ft) => {
+            ft.quantization_version = quantization_version(container_type, ft.quantization_version);
+            ft.quantization_version
+        }
+        None => 0,
+    };
+    // Recording version 0 in GGJT version 3 would have the tensors read with the current layout
+    let quantized = tensors
+        .values()
+        .any(|tensor| tensor.element_type.is_quantized());
+    if quantized && quantization_version == 0 {
+        return Err(UpgradeError::UnknownQuantizationVersion { container_type });
+    }
+
+    // Save the upgraded model
+    let vocabulary = tokenizer.vocabulary(hyperparameters.n_vocabulary());
+    let mut saver = UpgradeSaver {
+        hyperparameters: &hyperparameters,
+        tensors: &tensors,
+        source_reader: reader,
+        progress_callback: |p| progress_callback(p),
+    };
+    ggml::format::save(
+        writer,
+        &mut saver,
+        SaveContainerType::GgjtV3,
+        &vocabulary,
+        &tensors.keys().cloned().collect::<Vec<_>>(),
+    )
+    .map_err(|err| UpgradeError::from_format_error(err, PathBuf::default()))?;
+
+    progress_callback(UpgradeProgress::Finished {
+        container_type,
+        quantization_version,
+    });
+
+    Ok(())
+}
+
+struct UpgradeSaver<'a, F: Fn(UpgradeProgress), H: Hyperparameters, R: BufRead + Seek> {
+    // Input
+    hyperparameters: &'a H,
+    tensors: &'a HashMap<String, TensorLoadInfo>,
+    source_reader: &'a mut R,
+    progress_callback: F,
+}
+impl<F: Fn(UpgradeProgress), H: Hyperparameters, R: BufRead + Seek> SaveHandler<UpgradeError>
+    for UpgradeSaver<'_, F, H, R>
+{
+    fn write_hyperparameters(&mut self, writer: &mut dyn Write) -> Result<(), UpgradeError> {
+        self.hyperparameters
+            .write_ggml(writer)
+            .map_err(UpgradeError::HyperparametersWriteError)?;
+        Ok(())
+    }
+
+    fn tensor_data(&mut self, tensor_name: &str) -> Result<TensorSaveInfo, UpgradeError> {
+        let tensor = self.tensors.get(tensor_name).expect(
+            "tensor not found; should be impossible due to handler being populated from loader",
+        );
+
+        let data = tensor.read_data(self.source_reader)?;
+        (self.progress_callback)(UpgradeProgress::TensorCopied {
+            name: tensor_name,
+            size: data.len(),
+        });
+
+        Ok(TensorSaveInfo {
+            n_dims: tensor.n_dims,
+            dims: tensor.dims,
+            element_type: tensor.element_type,
+            data,
+        })
+    }
+}
@@ -21,10 +21,11 @@ macro_rules! mulf {
     };
 }
 
+use ggml::format::gguf::{self, Metadata, MetadataValue};
 use memmap2::{Mmap, MmapAsRawDesc, MmapOptions};
 use thiserror::Error;
 
-use crate::{FileType, LoadError};
+use crate::{model::HyperparametersWriteError, FileType, FileTypeFormat, LoadError};
 
 /// Read the filetype from a reader.
 pub fn read_filetype(reader: &mut dyn BufRead) -> Result<FileType, LoadError> {
@@ -32,6 +33,156 @@ pub fn read_filetype(reader: &mut dyn BufRead) -> Result<FileType, LoadError> {
     FileType::try_from(ftype).map_err(|_| LoadError::UnsupportedFileType(ftype))
 }
 
+/// Read the filetype from the metadata of a GGUF file.
+///
+/// Files without `general.file_type` are assumed to use the default format.
+pub fn read_gguf_filetype(metadata: &Metadata) -> Result<FileType, LoadError> {
+    let format = match metadata.get_optional("general.file_type", MetadataValue::as_countable)? {
+        Some(ftype) => {
+            let ftype = i32::try_from(ftype)?;
+            FileTypeFormat::try_from(ftype as ggml::sys::llama::llama_ftype)
+                .map_err(|_| LoadError::UnsupportedFileType(ftype))?
+        }
+        None => FileTypeFormat::default(),
+    };
+    let quantization_version = metadata
+        .get_optional("general.quantization_version", MetadataValue::as_countable)?
+        .unwrap_or_default()
+        .try_into()?;
+
+    Ok(FileType {
+        format,
+        quantization_version,
+    })
+}
+
+/// Write the architecture, the tokenizer and the filetype to the metadata of a GGUF file.
+///
+/// `tokenizer_model` is the tokenizer the vocabulary is stored for (e.g. `llama` or `gpt2`).
+/// The filetype is written as [read_gguf_filetype] reads it.
+pub fn write_gguf_general(
+    metadata: &mut Metadata,
+    architecture: &str,
+    tokenizer_model: &str,
+    file_type: FileType,
+) -> Result<(), HyperparametersWriteError> {
+    metadata.insert(
+        gguf::KEY_ARCHITECTURE,
+        MetadataValue::String(architecture.to_owned()),
+    );
+    metadata.insert(
+        "tokenizer.ggml.model",
+        MetadataValue::String(tokenizer_model.to_owned()),
+    );
+    metadata.insert(
+        "general.file_type",
+        MetadataValue::UInt32(ggml::sys::llama::llama_ftype::from(file_type.format).try_into()?),
+    );
+    metadata.insert(
+        "general.quantization_version",
+        MetadataValue::UInt32(file_type.quantization_version),
+    );
+    Ok(())
+}
+
+/// Write a count (e.g. a number of layers) to the metadata of a GGUF file, as the 32-bit
+/// integer that [MetadataValue::as_countable] reads.
+pub fn write_gguf_countable(
+    metadata: &mut Metadata,
+    key: &str,
+    value: usize,
+) -> Result<(), HyperparametersWriteError> {
+    metadata.insert(key, MetadataValue::UInt32(value.try_into()?));
+    Ok(())
+}
+
+/// Read the size of the embedded vocabulary from the metadata of a GGUF file.
+pub fn read_gguf_n_vocab(metadata: &Metadata) -> Result<usize, LoadError> {
+    Ok(metadata
+        .get_with_type("tokenizer.ggml.tokens", MetadataValue::as_array)?
+        .len())
+}
+
+/// Translate the name of a tensor in a GGUF file to its name in the legacy formats.
+///
+/// `global` maps whole names. `block` maps the part of the name that follows `blk.N.`
+/// to a legacy name, in which `{}` is replaced with `N`. Names that are found in neither
+/// are returned unchanged.
+pub fn translate_gguf_tensor_name(
+    name: &str,
+    global: &[(&str, &str)],
+    block: &[(&str, &str)],
+) -> String {
+    if let Some((_, legacy)) = global.iter().find(|(gguf, _)| *gguf == name) {
+        return (*legacy).to_owned();
+    }
+
+    let block_name = name
+        .strip_prefix("blk.")
+        .and_then(|rest| rest.split_once('.'))
+        .filter(|(index, _)| index.parse::<usize>().is_ok());
+    if let Some((index, suffix)) = block_name {
+        if let Some((_, legacy)) = block.iter().find(|(gguf, _)| *gguf == suffix) {
+            return legacy.replace("{}", index);
+        }
+    }
+
+    name.to_owned()
+}
+
+/// Translate the name of a tensor in the legacy formats to its name in a GGUF file.
+///
+/// This is the inverse of [translate_gguf_tensor_name], and takes the same maps.
+pub fn translate_tensor_name_to_gguf(
+    name: &str,
+    global: &[(&str, &str)],
+    block: &[(&str, &str)],
+) -> String {
+    if let Some((gguf, _)) = global.iter().find(|(_, legacy)| *legacy == name) {
+        return (*gguf).to_owned();
+    }
+
+    for (gguf, legacy) in block {
+        let Some((prefix, suffix)) = legacy.split_once("{}") else {
+            continue;
+        };
+        let index = name
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix(suffix))
+            .filter(|index| index.parse::<usize>().is_ok());
+        if let Some(index) = index {
+            return format!("blk.{index}.{gguf}");
+        }
+    }
+
+    name.to_owned()
+}
+
+/// Translate the name of a tensor in a Hugging Face checkpoint to its name in the legacy formats.
+///
+/// This works like [translate_gguf_tensor_name], except that blocks are prefixed with
+/// `block_prefix` (e.g. `model.layers.`) and names that are found in neither map are not
+/// used by the model, and return `None`.
+pub fn translate_hf_tensor_name(
+    name: &str,
+    block_prefix: &str,
+    global: &[(&str, &str)],
+    block: &[(&str, &str)],
+) -> Option<String> {
+    if let Some((_, legacy)) = global.iter().find(|(hf, _)| *hf == name) {
+        return Some((*legacy).to_owned());
+    }
+
+    let (index, suffix) = name
+        .strip_prefix(block_prefix)
+        .and_then(|rest| rest.split_once('.'))
+        .filter(|(index, _)| index.parse::<usize>().is_ok())?;
+    block
+        .iter()
+        .find(|(hf, _)| *hf == suffix)
+        .map(|(_, legacy)| legacy.replace("{}", index))
+}
+
 /// Used to buffer incoming tokens until they produce a valid string of UTF-8 text.
 ///
 /// Tokens are *not* valid UTF-8 by themselves. However, the LLM will produce valid UTF-8
@@ -82,6 +233,12 @@ pub enum FindAllModelFilesError {
         /// The path without a parent.
         path: PathBuf,
     },
+    #[error("the model part {path:?} is missing")]
+    /// A part of a multi-part model is missing, although parts with higher numbers exist.
+    MissingPart {
+        /// The path of the missing part.
+        path: PathBuf,
+    },
     #[error("non-specific I/O error")]
     /// A non-specific IO error.
     IO(#[from] std::io::Error),
@@ -98,42 +255,45 @@ pub fn find_all_model_files(main_path: &Path) -> Result<Vec<PathBuf>, FindAllMod
     if main_path_parent.to_str() == Some("") {
         main_path_parent = Path::new(".");
     }
-    Ok(collect_related_paths(
+    collect_related_paths(
         main_path,
         std::fs::read_dir(main_path_parent)?
             .filter_map(Result::ok)
             .map(|de| de.path()),
-    ))
+    )
 }
 
 fn collect_related_paths(
     main_path: &Path,
     directory_paths: impl Iterator<Item = PathBuf>,
-) -> Vec<PathBuf> {
+) -> Result<Vec<PathBuf>, FindAllModelFilesError> {
     let main_filename = main_path.file_name().and_then(|p| p.to_str());
 
-    let mut paths: Vec<PathBuf> = directory_paths
-        .filter(|p| {
-            p.file_name()
-                .and_then(|p| p.to_str())
-                .zip(main_filename)
-                .map(|(part_filename, main_filename)| {
-                    match part_filename.strip_prefix(main_filename) {
-                        Some(suffix) => {
-                            suffix.is_empty()
-                                || (suffix
-                                    .strip_prefix('.')
-                                    .map(|s| s.parse::<usize>().is_ok())
-                                    .unwrap_or(false))
-                        }
-                        None => false,
-                    }
-                })
-                .unwrap_or(false)
+    // Parts are numbered after the main file, e.g. `model.bin`, `model.bin.1`, `model.bin.2`.
+    let mut paths: Vec<(Option<usize>, PathBuf)> = directory_paths
+        .filter_map(|p| {
+            let suffix = p.file_name()?.to_str()?.strip_prefix(main_filename?)?;
+            let part_number = if suffix.is_empty() {
+                None
+            } else {
+                Some(suffix.strip_prefix('.')?.parse::<usize>().ok()?)
+            };
+            Some((part_number, p))
         })
         .collect();
+    // Sort by part number rather than by name, so that `.10` comes after `.9`.
     paths.sort();
-    paths
+
+    // The parts must be numbered consecutively, or the tensors would be joined incorrectly.
+    for (index, (part_number, _)) in paths.iter().enumerate().skip(1) {
+        if *part_number != Some(index) {
+            let mut path = main_path.as_os_str().to_owned();
+            path.push(format!(".{index}"));
+            return Err(FindAllModelFilesError::MissingPart { path: path.into() });
+        }
+    }
+
+    Ok(paths.into_iter().map(|(_, p)| p).collect())
 }
 
 /// mmap with MAP_POPULATE
@@ -173,10 +333,80 @@ mod tests {
         ]
         .map(PathBuf::from);
 
-        let output_paths = collect_related_paths(&main_path, directory_paths.into_iter());
+        let output_paths = collect_related_paths(&main_path, directory_paths.into_iter()).unwrap();
         assert_eq!(expected_paths.as_slice(), output_paths);
     }
 
+    #[test]
+    fn test_collect_related_paths_orders_parts() {
+        let main_path = PathBuf::from("/models/llama.bin");
+        // Directories are not listed in any particular order.
+        let directory_paths = [7, 0, 11, 2, 10, 1, 9, 3, 5, 4, 8, 6].map(|part| match part {
+            0 => PathBuf::from("/models/llama.bin"),
+            part => PathBuf::from(format!("/models/llama.bin.{part}")),
+        });
+
+        let output_paths = collect_related_paths(&main_path, directory_paths.into_iter()).unwrap();
+        let expected_paths: Vec<_> = std::iter::once(main_path.clone())
+            .chain((1..=11).map(|part| PathBuf::from(format!("/models/llama.bin.{part}"))))
+            .collect();
+        assert_eq!(expected_paths, output_paths);
+    }
+
+    #[test]
+    fn test_collect_related_paths_missing_part() {
+        let main_path = PathBuf::from("/models/llama.bin");
+        let directory_paths = [
+            "/models/llama.bin",
+            "/models/llama.bin.1",
+            "/models/llama.bin.3",
+        ]
+        .map(PathBuf::from);
+
+        match collect_related_paths(&main_path, directory_paths.into_iter()) {
+            Err(FindAllModelFilesError::MissingPart { path }) => {
+                assert_eq!(path, PathBuf::from("/models/llama.bin.2"))
+            }
+            result => panic!("expected a missing part, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn test_translate_gguf_tensor_name() {
+        let global = [("token_embd.weight", "tok_embeddings.weight")];
+        let block = [("attn_q.weight", "layers.{}.attention.wq.weight")];
+
+        let translate = |name| translate_gguf_tensor_name(name, &global, &block);
+        assert_eq!(translate("token_embd.weight"), "tok_embeddings.weight");
+        assert_eq!(
+            translate("blk.12.attn_q.weight"),
+            "layers.12.attention.wq.weight"
+        );
+        assert_eq!(translate("blk.12.attn_k.weight"), "blk.12.attn_k.weight");
+        assert_eq!(translate("blk.x.attn_q.weight"), "blk.x.attn_q.weight");
+    }
+
+    #[test]
+    fn test_translate_tensor_name_to_gguf() {
+        let global = [("token_embd.weight", "tok_embeddings.weight")];
+        let block = [("attn_q.weight", "layers.{}.attention.wq.weight")];
+
+        let translate = |name| translate_tensor_name_to_gguf(name, &global, &block);
+        assert_eq!(translate("tok_embeddings.weight"), "token_embd.weight");
+        assert_eq!(
+            translate("layers.12.attention.wq.weight"),
+            "blk.12.attn_q.weight"
+        );
+        assert_eq!(
+            translate("layers.12.attention.wk.weight"),
+            "layers.12.attention.wk.weight"
+        );
+        assert_eq!(
+            translate("layers.x.attention.wq.weight"),
+            "layers.x.attention.wq.weight"
+        );
+    }
+
     #[test]
     fn test_valid_utf8() {
         let mut buffer = TokenUtf8Buffer::new();
@@ -70,6 +70,7 @@
 use std::{
     error::Error,
     fmt::{Debug, Display},
+    io::{Seek, Write},
     path::Path,
     str::FromStr,
 };
@@ -77,17 +78,23 @@ use std::{
 // Try not to expose too many GGML details here.
 // This is the "user-facing" API, and GGML may not always be our backend.
 pub use llm_base::{
-    conversation_inference_callback, feed_prompt_callback,
+    beam_search, conversation_inference_callback, convert, feed_prompt_callback,
     ggml::accelerator::get_accelerator as ggml_get_accelerator,
     ggml::accelerator::Accelerator as GgmlAccelerator, ggml::format as ggml_format,
-    ggml::RoPEOverrides, load, load_progress_callback_stdout, quantize, samplers, ElementType,
-    FileType, FileTypeFormat, FormatMagic, Hyperparameters, InferenceError, InferenceFeedback,
-    InferenceParameters, InferenceRequest, InferenceResponse, InferenceSession,
-    InferenceSessionConfig, InferenceSnapshot, InferenceSnapshotRef, InferenceStats,
-    InvalidTokenBias, KnownModel, LoadError, LoadProgress, Loader, Model, ModelKVMemoryType,
-    ModelParameters, OutputRequest, Prompt, QuantizeError, QuantizeProgress, RewindError,
-    SnapshotError, TokenBias, TokenId, TokenUtf8Buffer, TokenizationError, Tokenizer,
-    TokenizerSource,
+    ggml::RoPEOverrides, load, load_from_bytes, load_from_reader, load_progress_callback_stdout,
+    merge_lora, quantize, quantize_dry_run, samplers, upgrade, BatchedSequence,
+    BeamSearchHypothesis, BeamSearchParameters, ContextOverflowPolicy, ConvertError,
+    ConvertProgress, ElementType, FileType, FileTypeFormat, FormatMagic, HfConfig, Hyperparameters,
+    ImportanceMatrix, InferenceError, InferenceFeedback, InferenceParameters, InferenceRequest,
+    InferenceResponse, InferenceSession, InferenceSessionConfig, InferenceSnapshot,
+    InferenceSnapshotRef, InferenceStats, InvalidTokenBias, KnownModel, LoadError, LoadProgress,
+    Loader, LoraAdapter, LoraError, MemoryLayout, MergeLoraAdapter, MergeLoraError,
+    MergeLoraProgress, Model, ModelContext, ModelKVMemoryType, ModelParameters, OutputRequest,
+    PositionEncoding, Prompt, QuantizationErrorStats, QuantizationPolicy, QuantizationPolicyError,
+    QuantizationRule, QuantizeDryRun, QuantizeError, QuantizeParameters, QuantizeProgress,
+    RewindError, SessionLoraAdapter, SnapshotError, TensorQuantizePlan, TensorRole,
+    TensorTransform, TokenBias, TokenId, TokenUtf8Buffer, TokenizationError, Tokenizer,
+    TokenizerSource, UpgradeError, UpgradeProgress,
 };
 
 use serde::Serialize;
@@ -205,7 +212,7 @@ impl Debug for UnsupportedModelArchitecture {
 
 /// A helper function that loads the specified model from disk using an architecture
 /// specified at runtime. If no architecture is specified, it will try to infer it
-/// from the model's metadata.
+/// from the model file using [guess_model_architecture].
 ///
 /// This method returns a [`Box`], which means that the model will have single ownership.
 /// If you'd like to share ownership (i.e. to use the model in multiple threads), we
@@ -232,9 +239,10 @@ pub fn load_dynamic(
         )?))
     }
 
-    let architecture = architecture.ok_or_else(|| LoadError::MissingModelArchitecture {
-        path: path.to_owned(),
-    })?;
+    let architecture = match architecture {
+        Some(architecture) => architecture,
+        None => guess_model_architecture(path)?,
+    };
 
     struct LoadVisitor<'a, F: FnMut(LoadProgress)> {
         path: &'a Path,
@@ -263,17 +271,91 @@ pub fn load_dynamic(
     })
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Converts the Hugging Face checkpoint in `directory` to a GGJT model written to `writer`, using
+/// an architecture specified at runtime. If no architecture is specified, it is taken from the
+/// `model_type` in the checkpoint's `config.json`.
+///
+/// See [llm_base::convert] for the expected layout of the checkpoint.
+pub fn convert_dynamic<W: Write + Seek>(
+    architecture: Option<ModelArchitecture>,
+    directory: &Path,
+    writer: &mut W,
+    element_type: ElementType,
+    progress_callback: impl Fn(ConvertProgress),
+) -> Result<(), ConvertError> {
+    let architecture = match architecture {
+        Some(architecture) => architecture,
+        None => HfConfig::load(&directory.join("config.json"))?
+            .get_optional_str("model_type")?
+            .and_then(|model_type| model_type.parse().ok())
+            .ok_or_else(|| ConvertError::InvalidConfigValue {
+                key: "model_type".to_string(),
+            })?,
+    };
 
-    #[test]
-    fn test_model_architecture_from_str() {
-        for arch in ModelArchitecture::ALL {
-            assert_eq!(
-                arch,
-                &arch.to_string().parse::<ModelArchitecture>().unwrap()
-            );
+    struct ConvertVisitor<'a, W: Write + Seek, F: Fn(ConvertProgress)> {
+        directory: &'a Path,
+        writer: &'a mut W,
+        element_type: ElementType,
+        progress_callback: F,
+    }
+    impl<'a, W: Write + Seek, F: Fn(ConvertProgress)>
+        ModelArchitectureVisitor<Result<(), ConvertError>> for ConvertVisitor<'a, W, F>
+    {
+        fn visit<M: KnownModel + 'static>(&mut self) -> Result<(), ConvertError> {
+            convert::<M, _>(
+                self.directory,
+                self.writer,
+                self.element_type,
+                &self.progress_callback,
+            )
         }
     }
+
+    architecture.visit(&mut ConvertVisitor {
+        directory,
+        writer,
+        element_type,
+        progress_callback,
+    })
 }
+
+/// Guesses the architecture of the model at `path` by checking its hyperparameters and
+/// tensor names against each of the [ModelArchitecture::ALL] architectures (see [llm_base::probe]).
+///
+/// Returns [LoadError::MissingModelArchitecture] if no architecture, or more than one
+/// architecture, matches the model.
+pub fn guess_model_architecture(path: &Path) -> Result<ModelArchitecture, LoadError> {
+    struct ProbeVisitor<'a>(&'a Path);
+    impl ModelArchitectureVisitor<Result<bool, LoadError>> for ProbeVisitor<'_> {
+        fn visit<M: KnownModel + 'static>(&mut self) -> Result<bool, LoadError> {
+            llm_base::probe::<M>(self.0)
+        }
+    }
+
+    if !path.exists() {
+        return Err(LoadError::FileDoesNotExist {
+            path: path.to_owned(),
+        });
+    }
+
+    let mut matches = vec![];
+    for architecture in ModelArchitecture::ALL {
+        if architecture.visit(&mut ProbeVisitor(path))? {
+            matches.push(*architecture);
+        }
+    }
+
+    match matches.as_slice() {
+        [architecture] => Ok(*architecture),
+        _ => {
+            tracing::debug!("Architectures matching {path:?}: {matches:?}");
+            Err(LoadError::MissingModelArchitecture {
+                path: path.to_owned(),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,252 @@
+use super::*;
+
+#[test]
+fn test_remove_range_shifts_keys() {
+    let model = load_llama(&tiny_llama());
+    let config = InferenceSessionConfig {
+        memory_k_type: ModelKVMemoryType::Float32,
+        memory_v_type: ModelKVMemoryType::Float32,
+        ..Default::default()
+    };
+    let mut session = model.start_session(config);
+    feed_tokens(model.as_ref(), &mut session, &[1, 5, 2, 7, 3]);
+    let removed = session.remove_range(model.as_ref(), 1..3).unwrap();
+    assert_eq!(removed, vec![5, 2]);
+
+    // The keys after the removed tokens are rotated back by two positions, so the keys of
+    // the first layer, which only depend on the token and its position, match the keys of
+    // evaluating the remaining tokens. Later layers attended to the removed tokens.
+    let mut expected = model.start_session(config);
+    feed_tokens(model.as_ref(), &mut expected, &[1, 7, 3]);
+    let keys = |session: &mut InferenceSession| {
+        let snapshot = unsafe { session.get_snapshot() };
+        assert_eq!(snapshot.npast, 3);
+        snapshot.memory_k[..3 * TINY_LLAMA.n_embd * 4]
+            .chunks_exact(4)
+            .map(|b| f32::from_ne_bytes(b.try_into().unwrap()))
+            .collect::<Vec<_>>()
+    };
+    assert_close(&keys(&mut session), &keys(&mut expected));
+}
+
+#[test]
+fn test_remove_range() {
+    let model = load_llama(&tiny_llama());
+    let mut session = model.start_session(Default::default());
+    feed_tokens(model.as_ref(), &mut session, &[1, 5, 2, 7, 3]);
+    let logits = session.last_logits.clone();
+
+    let removed = session.remove_range(model.as_ref(), 1..3).unwrap();
+    assert_eq!(removed, vec![5, 2]);
+    assert_eq!(session.tokens(), &[1, 7, 3]);
+    assert_eq!(session.decoded_tokens(), b"bhd");
+    assert_eq!(session.n_past, 3);
+    assert_eq!(session.last_logits, logits);
+
+    assert!(matches!(
+        session.remove_range(model.as_ref(), 2..4),
+        Err(RewindError::InvalidRange { n_tokens: 3, .. })
+    ));
+}
+
+#[test]
+fn test_replace_range() {
+    let model = load_llama(&tiny_llama());
+    let mut session = model.start_session(Default::default());
+    feed_tokens(model.as_ref(), &mut session, &[1, 5, 2, 7, 3]);
+
+    // Replacing the first tokens evaluates the new ones where they were, as if the
+    // session had started with them.
+    let mut output_request = OutputRequest {
+        all_logits: Some(vec![]),
+        embeddings: None,
+    };
+    let replaced = session
+        .replace_range(model.as_ref(), 0..2, &[4, 6, 0], &mut output_request)
+        .unwrap();
+    assert_eq!(replaced, vec![1, 5]);
+    assert_eq!(session.tokens(), &[4, 6, 0, 2, 7, 3]);
+    assert_eq!(session.decoded_tokens(), b"egachd");
+    assert_eq!(session.n_past, 6);
+    let mut expected = model.start_session(Default::default());
+    assert_close(
+        &output_request.all_logits.unwrap(),
+        &evaluate_all(model.as_ref(), &mut expected, &[4, 6, 0]),
+    );
+
+    // Replacing the last token replaces the logits with its own.
+    let logits = session.last_logits.clone();
+    let mut output_request = OutputRequest {
+        all_logits: Some(vec![]),
+        embeddings: None,
+    };
+    session
+        .replace_range(model.as_ref(), 5..6, &[1], &mut output_request)
+        .unwrap();
+    assert_eq!(session.decoded_tokens(), b"egachb");
+    assert_eq!(
+        Some(&session.last_logits),
+        output_request.all_logits.as_ref()
+    );
+    assert_ne!(session.last_logits, logits);
+}
+
+#[test]
+fn test_splice_halted_prompt() {
+    let model = load_llama(&tiny_llama());
+    let mut session = model.start_session(Default::default());
+
+    // Halting the prompt after its first token leaves the rest of the batch evaluated,
+    // but not recorded, so only the recorded tokens can be removed.
+    session
+        .feed_prompt(
+            model.as_ref(),
+            &[1, 5, 2][..],
+            &mut OutputRequest::default(),
+            |_| Ok::<_, std::convert::Infallible>(InferenceFeedback::Halt),
+        )
+        .unwrap();
+    assert_eq!(session.n_past, 3);
+    assert_eq!(session.tokens(), &[] as &[TokenId]);
+    assert!(matches!(
+        session.remove_range(model.as_ref(), 0..1),
+        Err(RewindError::InvalidRange { n_tokens: 0, .. })
+    ));
+}
+
+#[test]
+fn test_splice_hugging_face_tokens() {
+    // The Metaspace decoder drops the space of the first token, so `▁b` decodes to "b"
+    // on its own, but to " b" after another token.
+    let tokenizer = serde_json::json!({
+        "version": "1.0",
+        "decoder": { "type": "Metaspace", "replacement": "\u{2581}", "add_prefix_space": true },
+        "model": {
+            "type": "BPE",
+            "vocab": {
+                "\u{2581}a": 0, "\u{2581}b": 1, "\u{2581}c": 2, "\u{2581}d": 3,
+                "a": 4, "b": 5, "c": 6, "d": 7,
+            },
+            "merges": [],
+        },
+    });
+    let model: Box<dyn Model> = Box::new(
+        load_from_reader::<models::Llama>(
+            std::io::Cursor::new(tiny_llama()),
+            TokenizerSource::HuggingFaceTokenizerString(tokenizer.to_string()),
+            ModelParameters::default(),
+            |_| {},
+        )
+        .unwrap(),
+    );
+    let model = model.as_ref();
+    let mut session = model.start_session(Default::default());
+    feed_tokens(model, &mut session, &[0, 1, 6, 2, 3]);
+    assert_eq!(session.decoded_tokens(), b"a bc c d");
+
+    session.remove_range(model, 1..3).unwrap();
+    assert_eq!(session.decoded_tokens(), b"a c d");
+    session
+        .replace_range(model, 0..1, &[5, 7], &mut OutputRequest::default())
+        .unwrap();
+    assert_eq!(session.decoded_tokens(), b"bd c d");
+
+    // Decoding continues from the rebuilt text.
+    feed_tokens(model, &mut session, &[4]);
+    assert_eq!(session.decoded_tokens(), b"bd c da");
+}
+
+#[test]
+fn test_fork() {
+    let model = load_llama(&tiny_llama());
+    let model = model.as_ref();
+    let continuation = |prefix: &[TokenId], tokens: &[TokenId]| {
+        let mut session = model.start_session(Default::default());
+        feed_tokens(model, &mut session, prefix);
+        evaluate_all(model, &mut session, tokens)
+    };
+
+    let mut parent = model.start_session(Default::default());
+    feed_tokens(model, &mut parent, &[1, 5, 2]);
+    let mut fork = parent.fork(model);
+    assert_eq!(fork.tokens(), parent.tokens());
+    assert_eq!(fork.last_logits, parent.last_logits);
+
+    // Both sessions attend to the shared prefix, but not to each other's tokens.
+    assert_close(
+        &evaluate_all(model, &mut parent, &[7, 3]),
+        &continuation(&[1, 5, 2], &[7, 3]),
+    );
+    assert_close(
+        &evaluate_all(model, &mut fork, &[4]),
+        &continuation(&[1, 5, 2], &[4]),
+    );
+
+    // The fork keeps the shared prefix alive after the parent is dropped.
+    drop(parent);
+    assert_close(
+        &evaluate_all(model, &mut fork, &[6, 0]),
+        &continuation(&[1, 5, 2, 4], &[6, 0]),
+    );
+}
+
+#[test]
+fn test_evaluate_batch() {
+    let model = load_llama(&tiny_llama());
+    let model = model.as_ref();
+    let prefixes: [&[TokenId]; 3] = [&[1, 5], &[], &[3, 3, 6]];
+    let inputs: [&[TokenId]; 3] = [&[2, 7], &[4], &[0, 1, 5]];
+
+    let mut sequential = vec![];
+    for (prefix, input) in prefixes.iter().zip(inputs) {
+        let mut session = model.start_session(Default::default());
+        feed_tokens(model, &mut session, prefix);
+        sequential.push(evaluate_all(model, &mut session, input));
+    }
+
+    // A fork shares the memory of its prefix with its parent in the batch.
+    let mut sessions: Vec<_> = prefixes
+        .iter()
+        .map(|prefix| {
+            let mut session = model.start_session(Default::default());
+            feed_tokens(model, &mut session, prefix);
+            session
+        })
+        .collect();
+    let fork = sessions[0].fork(model);
+    sessions.push(fork);
+    let inputs = [inputs[0], inputs[1], inputs[2], &[6]];
+    let mut output_requests: Vec<_> = (0..sessions.len())
+        .map(|_| OutputRequest {
+            all_logits: Some(vec![]),
+            embeddings: None,
+        })
+        .collect();
+    let mut sequences: Vec<_> = sessions
+        .iter_mut()
+        .zip(inputs)
+        .zip(&mut output_requests)
+        .map(
+            |((session, input_tokens), output_request)| BatchedSequence {
+                session,
+                input_tokens,
+                output_request,
+            },
+        )
+        .collect();
+    model.evaluate_batch(&mut sequences);
+
+    let mut expected = model.start_session(Default::default());
+    feed_tokens(model, &mut expected, prefixes[0]);
+    sequential.push(evaluate_all(model, &mut expected, &[6]));
+    for ((session, output_request), expected) in
+        sessions.iter().zip(&output_requests).zip(&sequential)
+    {
+        let logits = output_request.all_logits.as_ref().unwrap();
+        assert_close(logits, expected);
+        assert_eq!(
+            session.last_logits,
+            logits[logits.len() - TINY_LLAMA.n_vocab..]
+        );
+    }
+}
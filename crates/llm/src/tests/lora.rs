@@ -0,0 +1,127 @@
+use super::*;
+
+#[test]
+fn test_session_lora_adapters() {
+    let directory = std::env::temp_dir().join("llm-test-session-lora-adapters");
+    let peft_directory = directory.join("peft");
+    std::fs::create_dir_all(&peft_directory).unwrap();
+
+    let (n_embd, n_ff, r) = (TINY_LLAMA.n_embd, 24, 2);
+    let values = |n: usize, seed: usize| -> Vec<f32> {
+        (0..n)
+            .map(|i| ((i * 7 + seed) % 11) as f32 / 11.0 - 0.5)
+            .collect()
+    };
+
+    // The GGLA adapter patches a square and a non-square weight of the first layer.
+    let ggla_path = directory.join("adapter.bin");
+    write_ggla(
+        &ggla_path,
+        &llm_base::LoraParameters { r: 2, alpha: 2 },
+        &[
+            tensor(
+                "layers.0.attention.wq.weight.loraA",
+                &[r, n_embd],
+                &values(r * n_embd, 1),
+            ),
+            tensor(
+                "layers.0.attention.wq.weight.loraB",
+                &[r, n_embd],
+                &values(r * n_embd, 2),
+            ),
+            tensor(
+                "layers.0.feed_forward.w2.weight.loraA",
+                &[r, n_ff],
+                &values(r * n_ff, 3),
+            ),
+            tensor(
+                "layers.0.feed_forward.w2.weight.loraB",
+                &[r, n_embd],
+                &values(r * n_embd, 4),
+            ),
+        ],
+    );
+
+    // The PEFT adapter patches the second layer, including a weight with rotated rows.
+    let config = serde_json::json!({ "r": r, "lora_alpha": 4 });
+    std::fs::write(
+        peft_directory.join("adapter_config.json"),
+        config.to_string(),
+    )
+    .unwrap();
+    let mut peft = vec![];
+    for (i, module) in ["self_attn.q_proj", "self_attn.v_proj"]
+        .into_iter()
+        .enumerate()
+    {
+        let prefix = format!("base_model.model.model.layers.1.{module}");
+        peft.push((
+            format!("{prefix}.lora_A.weight"),
+            vec![r, n_embd],
+            values(r * n_embd, 5 + 2 * i),
+        ));
+        peft.push((
+            format!("{prefix}.lora_B.weight"),
+            vec![n_embd, r],
+            values(r * n_embd, 6 + 2 * i),
+        ));
+    }
+    write_safetensors(&peft_directory.join("adapter_model.safetensors"), peft);
+
+    let model_bytes = tiny_llama();
+    let mut merged = std::io::Cursor::new(vec![]);
+    let merge_result = merge_lora::<models::Llama, _, _>(
+        &mut std::io::Cursor::new(model_bytes.clone()),
+        &mut merged,
+        TokenizerSource::Embedded.retrieve(&directory).unwrap(),
+        &[
+            MergeLoraAdapter {
+                path: ggla_path.clone(),
+                scaling: None,
+            },
+            MergeLoraAdapter {
+                path: peft_directory.clone(),
+                scaling: Some(0.5),
+            },
+        ],
+        None,
+        |_| {},
+    );
+    let adapters = (|| -> Result<Vec<_>, LoadError> {
+        let ggla = LoraAdapter::load(&ggla_path)?;
+        let mut peft = LoraAdapter::load_peft(&peft_directory, &TINY_LLAMA, |_| {})?;
+        peft.scaling = 0.5;
+        Ok(vec![
+            std::sync::Arc::new(SessionLoraAdapter::from_adapter(ggla)?),
+            std::sync::Arc::new(SessionLoraAdapter::from_adapter(peft)?),
+        ])
+    })();
+    std::fs::remove_dir_all(&directory).unwrap();
+    merge_result.unwrap();
+
+    // Evaluating the adapters alongside the model computes `W·x + B·(A·x)·s`, which must
+    // match multiplying by the merged weights `W + B·A·s`.
+    let tokens = [1, 5, 2, 7];
+    let model = load_llama(&model_bytes);
+    let base = evaluate_all(
+        model.as_ref(),
+        &mut model.start_session(Default::default()),
+        &tokens,
+    );
+    let mut session = model.start_session(Default::default());
+    session.set_lora_adapters(adapters.unwrap());
+    let adapted = evaluate_all(model.as_ref(), &mut session, &tokens);
+
+    let merged = load_llama(&merged.into_inner());
+    let expected = evaluate_all(
+        merged.as_ref(),
+        &mut merged.start_session(Default::default()),
+        &tokens,
+    );
+
+    assert_close(&adapted, &expected);
+    assert!(base
+        .iter()
+        .zip(&expected)
+        .any(|(b, e)| (b - e).abs() > 1e-3));
+}
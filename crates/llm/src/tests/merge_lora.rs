@@ -0,0 +1,148 @@
+use super::*;
+
+#[test]
+fn test_merge_lora() {
+    let (n_embd, r) = (4, 2);
+    let directory = std::env::temp_dir().join("llm-test-merge-lora");
+    let peft_directory = directory.join("peft");
+    std::fs::create_dir_all(&peft_directory).unwrap();
+
+    let values =
+        |n: usize, offset: f32| -> Vec<f32> { (0..n).map(|i| i as f32 * 0.25 - offset).collect() };
+    let (wq, wk, wv) = (values(16, 1.0), values(16, 0.5), values(16, 2.0));
+    let mut model = std::io::Cursor::new(vec![]);
+    save_ggjt(
+        &mut model,
+        &models::llama::Hyperparameters {
+            n_embd,
+            n_head: 1,
+            n_layer: 1,
+            ..Default::default()
+        },
+        &[],
+        &[
+            tensor("layers.0.attention.wq.weight", &[n_embd, n_embd], &wq),
+            tensor("layers.0.attention.wk.weight", &[n_embd, n_embd], &wk),
+            tensor("layers.0.attention.wv.weight", &[n_embd, n_embd], &wv),
+        ],
+    );
+
+    // GGLA stores `A` as `[r, n_in]` and `B` as `[r, n_out]`, innermost first. Its scaling
+    // is `alpha / r`.
+    let (ggla_a, ggla_b) = (values(8, 0.5), values(8, 1.5));
+    let ggla_path = directory.join("adapter.bin");
+    write_ggla(
+        &ggla_path,
+        &llm_base::LoraParameters { r: 2, alpha: 4 },
+        &[
+            tensor("layers.0.attention.wq.weight.loraA", &[r, n_embd], &ggla_a),
+            tensor("layers.0.attention.wq.weight.loraB", &[r, n_embd], &ggla_b),
+        ],
+    );
+
+    // PEFT stores `A` as `[r, n_in]` and `B` as `[n_out, r]`, outermost first.
+    let (peft_a, peft_b) = (values(8, 0.75), values(8, 0.25));
+    let config = serde_json::json!({ "r": r, "lora_alpha": 2 });
+    std::fs::write(
+        peft_directory.join("adapter_config.json"),
+        config.to_string(),
+    )
+    .unwrap();
+    let prefix = "base_model.model.model.layers.0.self_attn.v_proj";
+    write_safetensors(
+        &peft_directory.join("adapter_model.safetensors"),
+        [
+            (
+                format!("{prefix}.lora_A.weight"),
+                vec![r, n_embd],
+                peft_a.clone(),
+            ),
+            (
+                format!("{prefix}.lora_B.weight"),
+                vec![n_embd, r],
+                peft_b.clone(),
+            ),
+        ],
+    );
+
+    let mut output = std::io::Cursor::new(vec![]);
+    let n_patched = std::cell::Cell::new(0);
+    model.set_position(0);
+    let result = merge_lora::<models::Llama, _, _>(
+        &mut model,
+        &mut output,
+        TokenizerSource::Embedded.retrieve(&directory).unwrap(),
+        &[
+            MergeLoraAdapter {
+                path: ggla_path,
+                scaling: None,
+            },
+            MergeLoraAdapter {
+                path: peft_directory,
+                scaling: Some(0.5),
+            },
+        ],
+        None,
+        |progress| {
+            if let MergeLoraProgress::Finished { n_patched: n } = progress {
+                n_patched.set(n);
+            }
+        },
+    );
+    std::fs::remove_dir_all(&directory).unwrap();
+    result.unwrap();
+    assert_eq!(n_patched.get(), 2);
+
+    let mut loader = Loader::<models::llama::Hyperparameters, _>::new(
+        TokenizerSource::Embedded.retrieve(&directory).unwrap(),
+        |_| {},
+    );
+    output.set_position(0);
+    ggml_format::load(&mut output, &mut loader).unwrap();
+    let mut merged = |name: &str| -> Vec<f32> {
+        loader.tensors[name]
+            .read_data(&mut output)
+            .unwrap()
+            .chunks_exact(4)
+            .map(|c| f32::from_le_bytes(c.try_into().unwrap()))
+            .collect()
+    };
+
+    // W' = W + B·A·s, with W stored as `[n_in, n_out]`, innermost first.
+    let patched =
+        |w: &[f32], a: &dyn Fn(usize, usize) -> f32, b: &dyn Fn(usize, usize) -> f32, s| {
+            (0..n_embd * n_embd)
+                .map(|i| {
+                    let (row, column) = (i / n_embd, i % n_embd);
+                    w[i] + s * (0..r).map(|k| b(row, k) * a(k, column)).sum::<f32>()
+                })
+                .collect::<Vec<_>>()
+        };
+    let expected_wq = patched(
+        &wq,
+        &|k, column| ggla_a[column * r + k],
+        &|row, k| ggla_b[row * r + k],
+        2.0,
+    );
+    let expected_wv = patched(
+        &wv,
+        &|k, column| peft_a[k * n_embd + column],
+        &|row, k| peft_b[row * r + k],
+        0.5,
+    );
+
+    for (name, expected) in [
+        ("layers.0.attention.wq.weight", expected_wq),
+        ("layers.0.attention.wk.weight", wk),
+        ("layers.0.attention.wv.weight", expected_wv),
+    ] {
+        let actual = merged(name);
+        assert!(
+            actual
+                .iter()
+                .zip(&expected)
+                .all(|(a, e)| (a - e).abs() < 1e-5),
+            "{name}: {actual:?} != {expected:?}"
+        );
+    }
+}
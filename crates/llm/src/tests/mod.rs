@@ -0,0 +1,457 @@
+use super::*;
+
+// The modules of `llm-base` that need a model to be tested are tested here with a tiny LLaMA
+// model, in a module named after each of them.
+#[cfg(feature = "llama")]
+mod inference_session;
+#[cfg(feature = "llama")]
+mod lora;
+#[cfg(feature = "llama")]
+mod merge_lora;
+#[cfg(feature = "llama")]
+mod quantize;
+#[cfg(feature = "llama")]
+mod upgrade;
+
+#[test]
+fn test_model_architecture_from_str() {
+    for arch in ModelArchitecture::ALL {
+        assert_eq!(
+            arch,
+            &arch.to_string().parse::<ModelArchitecture>().unwrap()
+        );
+    }
+}
+
+#[cfg(feature = "llama")]
+#[test]
+fn test_guess_model_architecture() {
+    struct SaveHandler(models::llama::Hyperparameters);
+    impl ggml_format::SaveHandler<std::io::Error> for SaveHandler {
+        fn write_hyperparameters(
+            &mut self,
+            writer: &mut dyn std::io::Write,
+        ) -> Result<(), std::io::Error> {
+            self.0.write_ggml(writer).unwrap();
+            Ok(())
+        }
+
+        fn tensor_data(
+            &mut self,
+            _tensor_name: &str,
+        ) -> Result<ggml_format::TensorSaveInfo, std::io::Error> {
+            Ok(ggml_format::TensorSaveInfo {
+                n_dims: 1,
+                dims: [1, 1],
+                element_type: ElementType::F32,
+                data: vec![0; 4],
+            })
+        }
+    }
+
+    // BLOOM and LLaMA have compatible headers, so only the tensor names tell them apart.
+    let path = std::env::temp_dir().join("llm-test-guess-model-architecture.bin");
+    let mut file = std::fs::File::create(&path).unwrap();
+    ggml_format::save(
+        &mut file,
+        &mut SaveHandler(models::llama::Hyperparameters {
+            n_layer: 1,
+            ..Default::default()
+        }),
+        ggml_format::SaveContainerType::GgjtV3,
+        &[],
+        &[
+            "tok_embeddings.weight".to_owned(),
+            "layers.0.attention.wq.weight".to_owned(),
+        ],
+    )
+    .unwrap();
+    drop(file);
+
+    let guessed = guess_model_architecture(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(guessed.unwrap(), ModelArchitecture::Llama);
+}
+
+#[cfg(feature = "gpt2")]
+#[test]
+fn test_convert_gpt2() {
+    let (n_vocab, n_ctx, n_embd) = (4, 2, 2);
+    let directory = std::env::temp_dir().join("llm-test-convert-gpt2");
+    std::fs::create_dir_all(&directory).unwrap();
+
+    let config = serde_json::json!({
+        "model_type": "gpt2",
+        "vocab_size": n_vocab,
+        "n_positions": n_ctx,
+        "n_embd": n_embd,
+        "n_head": 1,
+        "n_layer": 1,
+    });
+    std::fs::write(directory.join("config.json"), config.to_string()).unwrap();
+
+    // `Ġ` is how byte-level BPE stores a space.
+    let tokenizer = serde_json::json!({
+        "version": "1.0",
+        "decoder": { "type": "ByteLevel", "add_prefix_space": true, "trim_offsets": true },
+        "model": {
+            "type": "BPE",
+            "vocab": { "a": 0, "b": 1, "\u{120}a": 2 },
+            "merges": [],
+        },
+    });
+    std::fs::write(directory.join("tokenizer.json"), tokenizer.to_string()).unwrap();
+
+    // Each tensor counts up from 0, so the converted data shows how it was rearranged.
+    let mut tensors = vec![
+        ("wte.weight".to_owned(), vec![n_vocab, n_embd]),
+        ("wpe.weight".to_owned(), vec![n_ctx, n_embd]),
+        ("ln_f.weight".to_owned(), vec![n_embd]),
+        ("ln_f.bias".to_owned(), vec![n_embd]),
+        ("h.0.attn.bias".to_owned(), vec![1, 1, n_ctx, n_ctx]),
+    ];
+    for (name, shape) in [
+        ("ln_1.weight", vec![n_embd]),
+        ("ln_1.bias", vec![n_embd]),
+        ("ln_2.weight", vec![n_embd]),
+        ("ln_2.bias", vec![n_embd]),
+        ("attn.c_attn.weight", vec![n_embd, 3 * n_embd]),
+        ("attn.c_attn.bias", vec![3 * n_embd]),
+        ("attn.c_proj.weight", vec![n_embd, n_embd]),
+        ("attn.c_proj.bias", vec![n_embd]),
+        ("mlp.c_fc.weight", vec![n_embd, 4 * n_embd]),
+        ("mlp.c_fc.bias", vec![4 * n_embd]),
+        ("mlp.c_proj.weight", vec![4 * n_embd, n_embd]),
+        ("mlp.c_proj.bias", vec![n_embd]),
+    ] {
+        tensors.push((format!("h.0.{name}"), shape));
+    }
+    let tensors = tensors.into_iter().map(|(name, shape)| {
+        let n_elements: usize = shape.iter().product();
+        (name, shape, (0..n_elements).map(|i| i as f32).collect())
+    });
+    write_safetensors(&directory.join("model.safetensors"), tensors);
+
+    let mut output = std::io::Cursor::new(vec![]);
+    let result = convert_dynamic(None, &directory, &mut output, ElementType::F32, |_| {});
+    std::fs::remove_dir_all(&directory).unwrap();
+    result.unwrap();
+
+    let mut loader = Loader::<models::gpt2::Hyperparameters, _>::new(
+        TokenizerSource::Embedded.retrieve(&directory).unwrap(),
+        |_| {},
+    );
+    output.set_position(0);
+    ggml_format::load(&mut output, &mut loader).unwrap();
+
+    assert_eq!(loader.hyperparameters.n_vocabulary(), n_vocab);
+    assert_eq!(loader.tokenizer.token(2), b" a");
+    // The vocabulary is padded to the size of the model's embeddings.
+    assert_eq!(loader.tokenizer.token(3), b"");
+    assert_eq!(loader.tensors.len(), 16);
+    assert!(!loader.tensors.contains_key("model/h0/attn/bias"));
+
+    // `Conv1D` weights are transposed.
+    let c_proj = &loader.tensors["model/h0/attn/c_proj/w"];
+    assert_eq!(c_proj.dims, [n_embd, n_embd]);
+    let c_proj_data = c_proj.read_data(&mut output).unwrap();
+    let c_proj_data: Vec<f32> = c_proj_data
+        .chunks_exact(4)
+        .map(|c| f32::from_le_bytes(c.try_into().unwrap()))
+        .collect();
+    assert_eq!(c_proj_data, [0.0, 2.0, 1.0, 3.0]);
+}
+
+#[cfg(feature = "llama")]
+#[test]
+fn test_convert_llama_tied_embeddings() {
+    let directory = std::env::temp_dir().join("llm-test-convert-llama-tied-embeddings");
+    std::fs::create_dir_all(&directory).unwrap();
+
+    let config = serde_json::json!({
+        "model_type": "llama",
+        "vocab_size": 2,
+        "hidden_size": 2,
+        "num_attention_heads": 1,
+        "intermediate_size": 8,
+        "num_hidden_layers": 1,
+        "tie_word_embeddings": true,
+    });
+    std::fs::write(directory.join("config.json"), config.to_string()).unwrap();
+    let tokenizer = serde_json::json!({
+        "version": "1.0",
+        "model": { "type": "BPE", "vocab": { "a": 0, "b": 1 }, "merges": [] },
+    });
+    std::fs::write(directory.join("tokenizer.json"), tokenizer.to_string()).unwrap();
+
+    // The norm is stored in both files, but must only be written once.
+    let embedding = vec![1.0, 2.0, 3.0, 4.0];
+    let norm = || ("model.norm.weight".to_owned(), vec![2], vec![1.0, 1.0]);
+    write_safetensors(
+        &directory.join("model-00001-of-00002.safetensors"),
+        [
+            (
+                "model.embed_tokens.weight".to_owned(),
+                vec![2, 2],
+                embedding.clone(),
+            ),
+            norm(),
+        ],
+    );
+    let second_path = directory.join("model-00002-of-00002.safetensors");
+    write_safetensors(&second_path, [norm()]);
+
+    let convert = || {
+        let mut output = std::io::Cursor::new(vec![]);
+        convert_dynamic(None, &directory, &mut output, ElementType::F32, |_| {}).map(|_| output)
+    };
+    let result = convert();
+
+    // Copies of a tensor that differ cannot be converted.
+    let (name, shape, _) = norm();
+    write_safetensors(&second_path, [(name, shape, vec![2.0, 2.0])]);
+    let conflicting = convert();
+
+    std::fs::remove_dir_all(&directory).unwrap();
+    let mut output = result.unwrap();
+    assert!(matches!(
+        conflicting,
+        Err(ConvertError::UnsupportedTensor { name, .. }) if name == "model.norm.weight"
+    ));
+
+    let mut loader = Loader::<models::llama::Hyperparameters, _>::new(
+        TokenizerSource::Embedded.retrieve(&directory).unwrap(),
+        |_| {},
+    );
+    output.set_position(0);
+    ggml_format::load(&mut output, &mut loader).unwrap();
+
+    let mut names: Vec<_> = loader.tensors.keys().cloned().collect();
+    names.sort();
+    assert_eq!(
+        names,
+        ["norm.weight", "output.weight", "tok_embeddings.weight"]
+    );
+
+    // The output projection is the embedding.
+    let output_data = loader.tensors["output.weight"]
+        .read_data(&mut output)
+        .unwrap();
+    let output_data: Vec<f32> = output_data
+        .chunks_exact(4)
+        .map(|c| f32::from_le_bytes(c.try_into().unwrap()))
+        .collect();
+    assert_eq!(output_data, embedding);
+}
+
+/// A F32 tensor to save with [save_ggjt], with its name and shape, innermost first.
+#[cfg(feature = "llama")]
+fn tensor(name: &str, shape: &[usize], values: &[f32]) -> (String, Vec<usize>, Vec<f32>) {
+    (name.to_owned(), shape.to_vec(), values.to_vec())
+}
+
+/// Saves the `hyperparameters`, the `vocabulary` and the F32 `tensors` to a GGJT file.
+#[cfg(feature = "llama")]
+fn save_ggjt(
+    writer: &mut (impl Write + Seek),
+    hyperparameters: &impl Hyperparameters,
+    vocabulary: &[(Vec<u8>, f32)],
+    tensors: &[(String, Vec<usize>, Vec<f32>)],
+) {
+    struct Saver<'a, H>(&'a H, &'a [(String, Vec<usize>, Vec<f32>)]);
+    impl<H: Hyperparameters> ggml_format::SaveHandler<std::io::Error> for Saver<'_, H> {
+        fn write_hyperparameters(
+            &mut self,
+            writer: &mut dyn std::io::Write,
+        ) -> Result<(), std::io::Error> {
+            self.0.write_ggml(writer).unwrap();
+            Ok(())
+        }
+
+        fn tensor_data(
+            &mut self,
+            tensor_name: &str,
+        ) -> Result<ggml_format::TensorSaveInfo, std::io::Error> {
+            let (_, shape, values) = self.1.iter().find(|(n, ..)| n == tensor_name).unwrap();
+            Ok(ggml_format::TensorSaveInfo {
+                n_dims: shape.len(),
+                dims: [shape[0], shape.get(1).copied().unwrap_or(1)],
+                element_type: ElementType::F32,
+                data: values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            })
+        }
+    }
+
+    let names: Vec<_> = tensors.iter().map(|(name, ..)| name.clone()).collect();
+    ggml_format::save(
+        writer,
+        &mut Saver(hyperparameters, tensors),
+        ggml_format::SaveContainerType::GgjtV3,
+        vocabulary,
+        &names,
+    )
+    .unwrap();
+}
+
+/// Writes a GGLA adapter with the `parameters` and the F32 `tensors` to `path`.
+#[cfg(feature = "llama")]
+fn write_ggla(
+    path: &Path,
+    parameters: &llm_base::LoraParameters,
+    tensors: &[(String, Vec<usize>, Vec<f32>)],
+) {
+    // GGLA files are laid out like GGJT files without a vocabulary.
+    let mut ggla = std::io::Cursor::new(vec![]);
+    save_ggjt(&mut ggla, parameters, &[], tensors);
+    let mut ggla = ggla.into_inner();
+    ggla[..4].copy_from_slice(&llm_base::ggml::FILE_MAGIC_GGLA.to_le_bytes());
+    ggla[4..8].copy_from_slice(&1u32.to_le_bytes());
+    std::fs::write(path, ggla).unwrap();
+}
+
+/// The hyperparameters of [tiny_llama].
+#[cfg(feature = "llama")]
+const TINY_LLAMA: models::llama::Hyperparameters = models::llama::Hyperparameters {
+    n_vocab: 8,
+    n_embd: 8,
+    n_mult: 4,
+    n_ff: None,
+    n_head: 2,
+    n_layer: 2,
+    n_rot: 4,
+    file_type: FileType {
+        format: FileTypeFormat::F32,
+        quantization_version: 0,
+    },
+    rope_freq_base: None,
+};
+
+/// Builds a GGJT LLaMA model with [TINY_LLAMA] hyperparameters and random weights, so that
+/// inference can be tested without downloading a model.
+#[cfg(feature = "llama")]
+fn tiny_llama() -> Vec<u8> {
+    use rand::{Rng, SeedableRng};
+
+    let models::llama::Hyperparameters {
+        n_vocab,
+        n_embd,
+        n_layer,
+        ..
+    } = TINY_LLAMA;
+    let n_ff = 24;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    let mut random = |shape: &[usize], mean: f32| -> Vec<f32> {
+        let n_elements = shape.iter().product();
+        (0..n_elements)
+            .map(|_| mean + rng.gen_range(-0.5..0.5))
+            .collect()
+    };
+
+    let mut tensors = vec![
+        tensor(
+            "tok_embeddings.weight",
+            &[n_embd, n_vocab],
+            &random(&[n_embd, n_vocab], 0.0),
+        ),
+        tensor("norm.weight", &[n_embd], &random(&[n_embd], 1.0)),
+        tensor(
+            "output.weight",
+            &[n_embd, n_vocab],
+            &random(&[n_embd, n_vocab], 0.0),
+        ),
+    ];
+    for i in 0..n_layer {
+        for (name, shape, mean) in [
+            ("attention_norm", vec![n_embd], 1.0),
+            ("attention.wq", vec![n_embd, n_embd], 0.0),
+            ("attention.wk", vec![n_embd, n_embd], 0.0),
+            ("attention.wv", vec![n_embd, n_embd], 0.0),
+            ("attention.wo", vec![n_embd, n_embd], 0.0),
+            ("ffn_norm", vec![n_embd], 1.0),
+            ("feed_forward.w1", vec![n_embd, n_ff], 0.0),
+            ("feed_forward.w2", vec![n_ff, n_embd], 0.0),
+            ("feed_forward.w3", vec![n_embd, n_ff], 0.0),
+        ] {
+            let values = random(&shape, mean);
+            tensors.push(tensor(
+                &format!("layers.{i}.{name}.weight"),
+                &shape,
+                &values,
+            ));
+        }
+    }
+
+    let vocabulary: Vec<_> = (0..n_vocab).map(|i| (vec![b'a' + i as u8], 0.0)).collect();
+    let mut model = std::io::Cursor::new(vec![]);
+    save_ggjt(&mut model, &TINY_LLAMA, &vocabulary, &tensors);
+    model.into_inner()
+}
+
+/// Loads a LLaMA model from its GGJT `bytes`.
+#[cfg(feature = "llama")]
+fn load_llama(bytes: &[u8]) -> Box<dyn Model> {
+    let model: models::Llama = load_from_reader(
+        std::io::Cursor::new(bytes.to_vec()),
+        TokenizerSource::Embedded,
+        ModelParameters::default(),
+        |_| {},
+    )
+    .unwrap();
+    Box::new(model)
+}
+
+/// Evaluates `tokens` in the `session`, and returns the logits of all of them.
+#[cfg(feature = "llama")]
+fn evaluate_all(model: &dyn Model, session: &mut InferenceSession, tokens: &[TokenId]) -> Vec<f32> {
+    let mut output_request = OutputRequest {
+        all_logits: Some(vec![]),
+        embeddings: None,
+    };
+    model.evaluate(session, tokens, &mut output_request);
+    output_request.all_logits.unwrap()
+}
+
+/// Feeds `tokens` to the `session` as a prompt, so that they are recorded in it.
+#[cfg(feature = "llama")]
+fn feed_tokens(model: &dyn Model, session: &mut InferenceSession, tokens: &[TokenId]) {
+    session
+        .feed_prompt(model, tokens, &mut OutputRequest::default(), |_| {
+            Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue)
+        })
+        .unwrap();
+}
+
+#[cfg(feature = "llama")]
+fn assert_close(actual: &[f32], expected: &[f32]) {
+    assert_eq!(actual.len(), expected.len());
+    assert!(
+        actual
+            .iter()
+            .zip(expected)
+            .all(|(a, e)| (a - e).abs() < 1e-4),
+        "{actual:?} != {expected:?}"
+    );
+}
+
+/// Writes the F32 `tensors`, each with its name and shape, to a safetensors file.
+#[cfg(any(feature = "gpt2", feature = "llama"))]
+fn write_safetensors(
+    path: &Path,
+    tensors: impl IntoIterator<Item = (String, Vec<usize>, Vec<f32>)>,
+) {
+    let mut header = serde_json::Map::new();
+    let mut data = vec![];
+    for (name, shape, values) in tensors {
+        let start = data.len();
+        data.extend(values.iter().flat_map(|v| v.to_le_bytes()));
+        header.insert(
+            name,
+            serde_json::json!({ "dtype": "F32", "shape": shape, "data_offsets": [start, data.len()] }),
+        );
+    }
+    let header = serde_json::Value::Object(header).to_string();
+    let mut safetensors = (header.len() as u64).to_le_bytes().to_vec();
+    safetensors.extend_from_slice(header.as_bytes());
+    safetensors.extend_from_slice(&data);
+    std::fs::write(path, safetensors).unwrap();
+}
@@ -0,0 +1,93 @@
+use super::*;
+
+#[test]
+fn test_quantize_importance_matrix() {
+    let n_embd = 256;
+    let mut model = std::io::Cursor::new(vec![]);
+    save_ggjt(
+        &mut model,
+        &models::llama::Hyperparameters {
+            n_embd,
+            n_layer: 1,
+            ..Default::default()
+        },
+        &[],
+        &[
+            tensor("layers.0.attention.wq.weight", &[n_embd, 2], &[0.5; 512]),
+            tensor("layers.0.attention.wk.weight", &[n_embd, 2], &[0.5; 512]),
+        ],
+    );
+    let mut importance_matrix = ImportanceMatrix::default();
+    importance_matrix.accumulate("layers.0.attention.wq.weight", n_embd, &[1.0; 256]);
+    let parameters = QuantizeParameters {
+        importance_matrix: Some(importance_matrix),
+        ..Default::default()
+    };
+
+    let mut dry_run = |format| {
+        model.set_position(0);
+        quantize_dry_run::<models::Llama, _>(
+            &mut model,
+            TokenizerSource::Embedded.retrieve(Path::new("")).unwrap(),
+            ggml_format::SaveContainerType::GgjtV3,
+            format,
+            &parameters,
+        )
+    };
+
+    // The legacy types are weighted by the importance matrix.
+    dry_run(FileTypeFormat::MostlyQ4_0).unwrap();
+    // The k-quants cannot be, which must not be ignored.
+    assert!(matches!(
+        dry_run(FileTypeFormat::MostlyQ4_K_S),
+        Err(QuantizeError::UnsupportedImportanceMatrix { tensor_name, element_type: ElementType::Q4_K })
+            if tensor_name == "layers.0.attention.wq.weight"
+    ));
+}
+
+#[test]
+fn test_quantize_to_every_container() {
+    let original = tiny_llama();
+    let tokens = [1, 4, 2, 7];
+    let expected = {
+        let model = load_llama(&original);
+        let mut session = model.start_session(Default::default());
+        evaluate_all(model.as_ref(), &mut session, &tokens)
+    };
+
+    for container_type in [
+        ggml_format::SaveContainerType::Ggmf,
+        ggml_format::SaveContainerType::GgjtV1,
+        ggml_format::SaveContainerType::GgjtV2,
+        ggml_format::SaveContainerType::GgjtV3,
+        ggml_format::SaveContainerType::Gguf,
+    ] {
+        let mut saved = std::io::Cursor::new(vec![]);
+        quantize::<models::Llama, _, _>(
+            &mut std::io::Cursor::new(&original),
+            &mut saved,
+            TokenizerSource::Embedded.retrieve(Path::new("")).unwrap(),
+            container_type,
+            FileTypeFormat::F32,
+            &QuantizeParameters::default(),
+            |_| {},
+        )
+        .unwrap();
+        let saved = saved.into_inner();
+
+        // GGUF files name their tensors independently of the architecture.
+        let has_gguf_names = saved
+            .windows(b"blk.0.attn_q.weight".len())
+            .any(|name| name == b"blk.0.attn_q.weight");
+        assert_eq!(
+            has_gguf_names,
+            container_type == ggml_format::SaveContainerType::Gguf,
+            "{container_type:?}"
+        );
+
+        let model = load_llama(&saved);
+        let mut session = model.start_session(Default::default());
+        let actual = evaluate_all(model.as_ref(), &mut session, &tokens);
+        assert_close(&actual, &expected);
+    }
+}
@@ -0,0 +1,134 @@
+use super::*;
+
+#[test]
+fn test_upgrade_unquantized() {
+    use llm_base::ggml::ContainerType;
+
+    let original = tiny_llama();
+    let mut ggmf = std::io::Cursor::new(vec![]);
+    quantize::<models::Llama, _, _>(
+        &mut std::io::Cursor::new(&original),
+        &mut ggmf,
+        TokenizerSource::Embedded.retrieve(Path::new("")).unwrap(),
+        ggml_format::SaveContainerType::Ggmf,
+        FileTypeFormat::F32,
+        &QuantizeParameters::default(),
+        |_| {},
+    )
+    .unwrap();
+
+    let mut upgraded = std::io::Cursor::new(vec![]);
+    let source_container_type = std::cell::Cell::new(None);
+    upgrade::<models::Llama, _, _>(
+        &mut std::io::Cursor::new(ggmf.into_inner()),
+        &mut upgraded,
+        |progress| {
+            if let UpgradeProgress::Finished { container_type, .. } = progress {
+                source_container_type.set(Some(container_type));
+            }
+        },
+    )
+    .unwrap();
+    assert_eq!(source_container_type.get(), Some(ContainerType::Ggmf(1)));
+
+    // The upgraded model is GGJT v3, and evaluates like the original.
+    let upgraded = upgraded.into_inner();
+    assert_eq!(
+        ContainerType::read::<std::io::Error>(&mut std::io::Cursor::new(&upgraded)).unwrap(),
+        ContainerType::Ggjt(3)
+    );
+    let tokens = [1, 4, 2, 7];
+    let evaluate = |bytes: &[u8]| {
+        let model = load_llama(bytes);
+        let mut session = model.start_session(Default::default());
+        evaluate_all(model.as_ref(), &mut session, &tokens)
+    };
+    assert_close(&evaluate(&upgraded), &evaluate(&original));
+}
+
+#[test]
+fn test_upgrade_quantized() {
+    use llm_base::ggml::ContainerType;
+
+    /// Saves a GGML model with a single Q8_0 tensor, and the quantization version
+    /// `quantization_version` recorded in its file type.
+    fn quantized_ggml(quantization_version: u32) -> Vec<u8> {
+        struct Saver(models::llama::Hyperparameters);
+        impl ggml_format::SaveHandler<std::io::Error> for Saver {
+            fn write_hyperparameters(
+                &mut self,
+                writer: &mut dyn std::io::Write,
+            ) -> Result<(), std::io::Error> {
+                self.0.write_ggml(writer).unwrap();
+                Ok(())
+            }
+
+            fn tensor_data(
+                &mut self,
+                _: &str,
+            ) -> Result<ggml_format::TensorSaveInfo, std::io::Error> {
+                Ok(ggml_format::TensorSaveInfo {
+                    n_dims: 2,
+                    dims: [32, 2],
+                    element_type: ElementType::Q8_0,
+                    data: vec![0; 2 * 34],
+                })
+            }
+        }
+
+        let hyperparameters = models::llama::Hyperparameters {
+            file_type: FileType {
+                format: FileTypeFormat::MostlyQ8_0,
+                quantization_version,
+            },
+            ..TINY_LLAMA
+        };
+        let mut model = std::io::Cursor::new(vec![]);
+        ggml_format::save(
+            &mut model,
+            &mut Saver(hyperparameters),
+            ggml_format::SaveContainerType::Ggml,
+            &(0..TINY_LLAMA.n_vocab)
+                .map(|token| (vec![b'a' + token as u8], 0.0))
+                .collect::<Vec<_>>(),
+            &["layers.0.attention.wq.weight".to_owned()],
+        )
+        .unwrap();
+        model.into_inner()
+    }
+
+    /// Upgrades `model`, and returns the upgraded model and the quantization version
+    /// recorded in it.
+    fn upgrade_model(model: Vec<u8>) -> Result<(Vec<u8>, u32), UpgradeError> {
+        let mut upgraded = std::io::Cursor::new(vec![]);
+        let recorded_version = std::cell::Cell::new(None);
+        upgrade::<models::Llama, _, _>(
+            &mut std::io::Cursor::new(model),
+            &mut upgraded,
+            |progress| {
+                if let UpgradeProgress::Finished {
+                    quantization_version,
+                    ..
+                } = progress
+                {
+                    recorded_version.set(Some(quantization_version));
+                }
+            },
+        )?;
+        Ok((upgraded.into_inner(), recorded_version.get().unwrap()))
+    }
+
+    // The layout of the tensors is unknown, so they cannot be upgraded.
+    assert!(matches!(
+        upgrade_model(quantized_ggml(0)),
+        Err(UpgradeError::UnknownQuantizationVersion {
+            container_type: ContainerType::Ggml
+        })
+    ));
+
+    // A recorded version is kept, and read back from the upgraded model.
+    let (upgraded, version) = upgrade_model(quantized_ggml(1)).unwrap();
+    assert_eq!(version, 1);
+    let (_, version) = upgrade_model(upgraded).unwrap();
+    assert_eq!(version, 1);
+}
@@ -2,13 +2,15 @@
 //! for the `llm` ecosystem.
 #![deny(missing_docs)]
 
-use std::sync::Arc;
-
 use llm_base::{
-    ggml,
+    ggml::{
+        self,
+        format::gguf::{Metadata, MetadataValue},
+    },
     model::{common, HyperparametersWriteError},
     util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel,
-    ModelParameters, OutputRequest, Regex, TokenId, Tokenizer,
+    MemoryLayout, ModelContext, ModelParameters, OutputRequest, PositionEncoding, Regex,
+    TensorRole, TokenId, Tokenizer,
 };
 
 /// The BLOOM model. Ref: [Introducing BLOOM](https://bigscience.huggingface.co/blog/bloom)
@@ -37,7 +39,7 @@ pub struct Bloom {
     layers: Vec<Layer>,
 
     // must be kept alive for the model
-    context: Arc<ggml::Context>,
+    context: ModelContext,
 }
 
 unsafe impl Send for Bloom {}
@@ -101,7 +103,7 @@ impl KnownModel for Bloom {
             output_norm_bias,
             output,
             layers,
-            context: Arc::new(context),
+            context,
         })
     }
 
@@ -123,7 +125,7 @@ impl KnownModel for Bloom {
     ) {
         let input_len = input_tokens.len();
         let session_len = session.n_past;
-        let ctx_size = self.params.context_size;
+        let layout = self.layout();
 
         let Hyperparameters {
             n_vocab,
@@ -134,12 +136,8 @@ impl KnownModel for Bloom {
             file_type: _,
         } = self.hyperparameters;
 
-        let outputs = session.compute(self.context.clone(), input_tokens, |builder| {
+        let outputs = session.compute(&self.context, input_tokens, |builder| {
             let ctx0 = builder.ctx0.borrow();
-            let (memory_k_size, memory_v_size) = (
-                builder.memory_k.element_size(),
-                builder.memory_v.element_size(),
-            );
             let embd = &builder.embd;
             let mut input_layer = ctx0.op_get_rows(&self.wte, embd);
 
@@ -167,7 +165,7 @@ impl KnownModel for Bloom {
                 );
 
                 //attention
-                current = ctx0.op_mul_mat(&self.layers[il].query_key_value, &current);
+                current = builder.lora_mul_mat(&ctx0, &self.layers[il].query_key_value, &current);
                 current = ctx0.op_add(
                     &ctx0.op_repeat(&self.layers[il].query_key_value_b, &current),
                     &current,
@@ -197,20 +195,7 @@ impl KnownModel for Bloom {
 
                 // store key and value to memory
                 if input_len >= 1 {
-                    let k = ctx0.op_view_1d(
-                        builder.memory_k,
-                        input_len * n_embd,
-                        (memory_k_size * n_embd) * (il * ctx_size + session_len),
-                    );
-
-                    let v = ctx0.op_view_1d(
-                        builder.memory_v,
-                        input_len * n_embd,
-                        (memory_v_size * n_embd) * (il * ctx_size + session_len),
-                    );
-
-                    gf.build_forward_expand(&ctx0.op_cpy(&k_current, &k));
-                    gf.build_forward_expand(&ctx0.op_cpy(&v_current, &v));
+                    builder.store_memory(&ctx0, &mut gf, &layout, il, &k_current, &v_current);
                 }
 
                 // Q = Qcur.contiguous().view(n_embd/n_head, n_head, N).permute(0, 2, 1, 3)
@@ -222,23 +207,8 @@ impl KnownModel for Bloom {
                     (0, 2, 1, 3),
                 );
 
-                // K = Kmem.view(n_embd/n_head, n_head, n_past + N).permute(0, 2, 1, 3)
-                let big_k = ctx0.op_permute(
-                    &ctx0.op_reshape_3d(
-                        &ctx0.op_view_1d(
-                            builder.memory_k,
-                            (session_len + input_len) * n_embd,
-                            il * ctx_size * memory_k_size * n_embd,
-                        ),
-                        n_embd / n_head,
-                        n_head,
-                        session_len + input_len,
-                    ),
-                    (0, 2, 1, 3),
-                );
-
-                // K * Q
-                let k_q = ctx0.op_mul_mat(&big_k, &big_q);
+                // K * Q, with K = Kmem.view(n_embd/n_head, n_head, n_past + N).permute(0, 2, 1, 3)
+                let k_q = builder.memory_keys_mul_mat(&ctx0, &mut gf, il, n_head, &big_q);
 
                 // KQ_scaled = KQ / sqrt(n_embd/n_head)
                 let k_q_scaled = ctx0.op_scale(
@@ -256,31 +226,8 @@ impl KnownModel for Bloom {
                 // KQ = soft_max(KQ_masked)
                 let k_q_soft_max = ctx0.op_soft_max(&k_q_masked);
 
-                let memv_elsize = memory_v_size;
-
-                let v_trans = ctx0.op_cpy(
-                    &ctx0.op_permute(
-                        &ctx0.op_reshape_3d(
-                            &ctx0.op_view_1d(
-                                builder.memory_v,
-                                (session_len + input_len) * n_embd,
-                                il * ctx_size * memv_elsize * n_embd,
-                            ),
-                            n_embd / n_head,
-                            n_head,
-                            session_len + input_len,
-                        ),
-                        (1, 2, 0, 3),
-                    ),
-                    &ctx0.new_tensor_3d(
-                        builder.memory_v.get_type(),
-                        session_len + input_len,
-                        n_embd / n_head,
-                        n_head,
-                    ),
-                );
-
-                let k_q_v = ctx0.op_mul_mat(&v_trans, &k_q_soft_max);
+                let k_q_v =
+                    builder.memory_values_mul_mat(&ctx0, &layout, il, n_head, &k_q_soft_max);
 
                 // KQV_merged = KQV.permute(0, 2, 1, 3)
                 let k_q_v_merged = ctx0.op_permute(&k_q_v, (0, 2, 1, 3));
@@ -292,7 +239,7 @@ impl KnownModel for Bloom {
                 );
 
                 // projection
-                current = ctx0.op_mul_mat(&self.layers[il].wo, &current);
+                current = builder.lora_mul_mat(&ctx0, &self.layers[il].wo, &current);
                 current = ctx0.op_add(&ctx0.op_repeat(&self.layers[il].wo_b, &current), &current);
 
                 let input_feed_forward = ctx0.op_add(&current, &input_self_attention);
@@ -312,7 +259,7 @@ impl KnownModel for Bloom {
                     &current,
                 );
 
-                current = ctx0.op_mul_mat(&self.layers[il].w1, &current);
+                current = builder.lora_mul_mat(&ctx0, &self.layers[il].w1, &current);
 
                 current = ctx0.op_add(&ctx0.op_repeat(&self.layers[il].w1_b, &current), &current);
 
@@ -320,7 +267,7 @@ impl KnownModel for Bloom {
 
                 current = ctx0.op_gelu(&current);
 
-                current = ctx0.op_mul_mat(&self.layers[il].w2, &current);
+                current = builder.lora_mul_mat(&ctx0, &self.layers[il].w2, &current);
 
                 current = ctx0.op_add(&ctx0.op_repeat(&self.layers[il].w2_b, &current), &current);
 
@@ -347,7 +294,7 @@ impl KnownModel for Bloom {
             let embeddings_tensor: ggml::Tensor = input_layer.share();
 
             // lm_head
-            input_layer = ctx0.op_mul_mat(&self.output, &input_layer);
+            input_layer = builder.lora_mul_mat(&ctx0, &self.output, &input_layer);
 
             (
                 gf,
@@ -368,6 +315,14 @@ impl KnownModel for Bloom {
         &self.hyperparameters
     }
 
+    fn context(&self) -> &ModelContext {
+        &self.context
+    }
+
+    fn context_mut(&mut self) -> &mut ModelContext {
+        &mut self.context
+    }
+
     fn tokenizer(&self) -> &Tokenizer {
         &self.tokenizer
     }
@@ -392,9 +347,37 @@ impl KnownModel for Bloom {
         vec![]
     }
 
+    fn tensor_role(name: &str) -> TensorRole {
+        match name {
+            "output.weight" => TensorRole::Output,
+            _ if name.ends_with(".attention.query_key_value.weight") => TensorRole::AttentionValue,
+            _ if name.ends_with(".attention.wo.weight") => TensorRole::AttentionOutput,
+            _ if name.ends_with(".feed_forward.w2.weight") => TensorRole::FeedForwardDown,
+            _ => TensorRole::Other,
+        }
+    }
+
     fn supports_rewind(&self) -> bool {
         true
     }
+
+    fn memory_layout(&self) -> Option<MemoryLayout> {
+        Some(self.layout())
+    }
+
+    fn matches_tensors(has_tensor: &dyn Fn(&str) -> bool) -> bool {
+        has_tensor("tok_embeddings.weight")
+            && has_tensor("layers.0.attention.query_key_value.weight")
+    }
+}
+
+impl Bloom {
+    fn layout(&self) -> MemoryLayout {
+        MemoryLayout {
+            position_encoding: PositionEncoding::Alibi,
+            transposed_values: false,
+        }
+    }
 }
 
 /// BLOOM [hyperparameters](https://en.wikipedia.org/wiki/Hyperparameter_(machine_learning))
@@ -414,6 +397,35 @@ pub struct Hyperparameters {
     pub file_type: FileType,
 }
 
+/// The names of the tensors of the model in GGUF files, and the names it loads them by.
+const GGUF_TENSOR_NAMES: &[(&str, &str)] = &[
+    ("token_embd.weight", "tok_embeddings.weight"),
+    ("token_embd_norm.weight", "norm.weight"),
+    ("token_embd_norm.bias", "norm.bias"),
+    ("output_norm.weight", "output_norm.weight"),
+    ("output_norm.bias", "output_norm.bias"),
+    ("output.weight", "output.weight"),
+];
+/// Like [GGUF_TENSOR_NAMES], for the tensors of each block. `{}` stands for the index of
+/// the block.
+const GGUF_BLOCK_TENSOR_NAMES: &[(&str, &str)] = &[
+    ("attn_norm.weight", "layers.{}.attention_norm.weight"),
+    ("attn_norm.bias", "layers.{}.attention_norm.bias"),
+    (
+        "attn_qkv.weight",
+        "layers.{}.attention.query_key_value.weight",
+    ),
+    ("attn_qkv.bias", "layers.{}.attention.query_key_value.bias"),
+    ("attn_output.weight", "layers.{}.attention.wo.weight"),
+    ("attn_output.bias", "layers.{}.attention.wo.bias"),
+    ("ffn_norm.weight", "layers.{}.ffn_norm.weight"),
+    ("ffn_norm.bias", "layers.{}.ffn_norm.bias"),
+    ("ffn_up.weight", "layers.{}.feed_forward.w1.weight"),
+    ("ffn_up.bias", "layers.{}.feed_forward.w1.bias"),
+    ("ffn_down.weight", "layers.{}.feed_forward.w2.weight"),
+    ("ffn_down.bias", "layers.{}.feed_forward.w2.bias"),
+];
+
 impl llm_base::Hyperparameters for Hyperparameters {
     fn read_ggml(reader: &mut dyn std::io::BufRead) -> Result<Self, llm_base::LoadError> {
         Ok(Hyperparameters {
@@ -436,6 +448,36 @@ impl llm_base::Hyperparameters for Hyperparameters {
         Ok(())
     }
 
+    fn read_gguf(metadata: &Metadata) -> Result<Self, llm_base::LoadError> {
+        Ok(Hyperparameters {
+            n_vocab: util::read_gguf_n_vocab(metadata)?,
+            n_embd: metadata
+                .get_with_type("bloom.embedding_length", MetadataValue::as_countable)?,
+            // Not stored in GGUF; BLOOM conversions have always used 1.
+            n_mult: 1,
+            n_head: metadata
+                .get_with_type("bloom.attention.head_count", MetadataValue::as_countable)?,
+            n_layer: metadata.get_with_type("bloom.block_count", MetadataValue::as_countable)?,
+            file_type: util::read_gguf_filetype(metadata)?,
+        })
+    }
+
+    fn write_gguf(&self, metadata: &mut Metadata) -> Result<(), HyperparametersWriteError> {
+        util::write_gguf_general(metadata, "bloom", "gpt2", self.file_type)?;
+        util::write_gguf_countable(metadata, "bloom.embedding_length", self.n_embd)?;
+        util::write_gguf_countable(metadata, "bloom.attention.head_count", self.n_head)?;
+        util::write_gguf_countable(metadata, "bloom.block_count", self.n_layer)?;
+        Ok(())
+    }
+
+    fn tensor_name_from_gguf(&self, name: &str) -> String {
+        util::translate_gguf_tensor_name(name, GGUF_TENSOR_NAMES, GGUF_BLOCK_TENSOR_NAMES)
+    }
+
+    fn tensor_name_to_gguf(&self, name: &str) -> String {
+        util::translate_tensor_name_to_gguf(name, GGUF_TENSOR_NAMES, GGUF_BLOCK_TENSOR_NAMES)
+    }
+
     fn n_vocabulary(&self) -> usize {
         self.n_vocab
     }
@@ -7,14 +7,15 @@
 //! supported. It is currently only available as a preview.
 #![deny(missing_docs)]
 
-use std::sync::Arc;
-
 use ggml::Tensor;
 use llm_base::{
-    ggml,
+    ggml::{
+        self,
+        format::gguf::{Metadata, MetadataValue},
+    },
     model::{common, HyperparametersWriteError},
     util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel, LoadError,
-    ModelParameters, OutputRequest, Regex, TokenId, Tokenizer,
+    ModelContext, ModelParameters, OutputRequest, Regex, TensorRole, TokenId, Tokenizer,
 };
 
 /// The Falcon model. Ref: [Technology Innovation Institute](https://huggingface.co/tiiuae)
@@ -39,7 +40,7 @@ pub struct Falcon {
     layers: Vec<Layer>,
 
     // must be kept alive for the model
-    context: Arc<ggml::Context>,
+    context: ModelContext,
 }
 
 unsafe impl Send for Falcon {}
@@ -110,7 +111,7 @@ impl KnownModel for Falcon {
             output_norm_b,
             lm_head,
             layers,
-            context: Arc::new(context),
+            context,
         })
     }
 
@@ -146,7 +147,7 @@ impl KnownModel for Falcon {
         let head_dim = n_embd / n_head;
         let n = input_len;
 
-        let outputs = session.compute(self.context.clone(), input_tokens, |builder| {
+        let outputs = session.compute(&self.context, input_tokens, |builder| {
             let ctx0 = builder.ctx0.borrow();
             let embd = builder.embd;
             let mut input_layer = ctx0.op_get_rows(&self.tok_embeddings, embd);
@@ -200,7 +201,7 @@ impl KnownModel for Falcon {
                 }
 
                 // compute QKV
-                current = ctx0.op_mul_mat(&self.layers[il].query_key_value, &current);
+                current = builder.lora_mul_mat(&ctx0, &self.layers[il].query_key_value, &current);
 
                 let fused_qkv_row_nb = head_dim * (n_head + 2 * n_head_kv) * f32_size;
 
@@ -302,7 +303,7 @@ impl KnownModel for Falcon {
                 );
 
                 // projection
-                current = ctx0.op_mul_mat(&self.layers[il].wo, &current);
+                current = builder.lora_mul_mat(&ctx0, &self.layers[il].wo, &current);
 
                 // feed forward uses second scratch buffer
                 ctx0.use_scratch(builder.get_scratch(1));
@@ -311,9 +312,9 @@ impl KnownModel for Falcon {
                 let attn_out =
                     ctx0.op_cpy(&current, &ctx0.new_tensor_2d(ggml::Type::F32, n_embd, n));
 
-                current = ctx0.op_mul_mat(&self.layers[il].ffn_up, &inp_ff);
+                current = builder.lora_mul_mat(&ctx0, &self.layers[il].ffn_up, &inp_ff);
                 current = ctx0.op_gelu(&current);
-                current = ctx0.op_mul_mat(&self.layers[il].ffn_down, &current);
+                current = builder.lora_mul_mat(&ctx0, &self.layers[il].ffn_down, &current);
 
                 current = ctx0.op_add(&current, &attn_out);
                 current = ctx0.op_add(&current, &input_layer);
@@ -339,7 +340,7 @@ impl KnownModel for Falcon {
             ctx0.use_scratch(None);
 
             // lm_head
-            input_layer = ctx0.op_mul_mat(&self.lm_head, &input_layer);
+            input_layer = builder.lora_mul_mat(&ctx0, &self.lm_head, &input_layer);
 
             (
                 gf,
@@ -360,6 +361,14 @@ impl KnownModel for Falcon {
         &self.hyperparameters
     }
 
+    fn context(&self) -> &ModelContext {
+        &self.context
+    }
+
+    fn context_mut(&mut self) -> &mut ModelContext {
+        &mut self.context
+    }
+
     fn tokenizer(&self) -> &Tokenizer {
         &self.tokenizer
     }
@@ -383,6 +392,23 @@ impl KnownModel for Falcon {
     fn skip_quantize_tensors() -> Vec<Regex> {
         vec![]
     }
+
+    fn tensor_role(name: &str) -> TensorRole {
+        match name {
+            "lm_head.weight" => TensorRole::Output,
+            _ if name.ends_with(".self_attention.query_key_value.weight") => {
+                TensorRole::AttentionValue
+            }
+            _ if name.ends_with(".self_attention.dense.weight") => TensorRole::AttentionOutput,
+            _ if name.ends_with(".mlp.dense_4h_to_h.weight") => TensorRole::FeedForwardDown,
+            _ => TensorRole::Other,
+        }
+    }
+
+    fn matches_tensors(has_tensor: &dyn Fn(&str) -> bool) -> bool {
+        has_tensor("transformer.word_embeddings.weight")
+            && has_tensor("transformer.h.0.self_attention.query_key_value.weight")
+    }
 }
 
 /// Falcon [hyperparameters](https://en.wikipedia.org/wiki/Hyperparameter_(machine_learning))
@@ -402,6 +428,54 @@ pub struct Hyperparameters {
     file_type: FileType,
 }
 
+/// The names of the tensors of the model in GGUF files, and the names it loads them by.
+const GGUF_TENSOR_NAMES: &[(&str, &str)] = &[
+    ("token_embd.weight", "transformer.word_embeddings.weight"),
+    ("output_norm.weight", "transformer.ln_f.weight"),
+    ("output_norm.bias", "transformer.ln_f.bias"),
+    ("output.weight", "lm_head.weight"),
+];
+
+impl Hyperparameters {
+    /// Like [GGUF_TENSOR_NAMES], for the tensors of each block. `{}` stands for the index of
+    /// the block.
+    fn gguf_block_tensor_names(&self) -> Vec<(&'static str, &'static str)> {
+        // The first norm of a block is `input_layernorm` in Falcon 7B, and `ln_mlp` in Falcon 40B.
+        let attn_norm = if self.n_head_kv == 1 {
+            [
+                (
+                    "attn_norm.weight",
+                    "transformer.h.{}.input_layernorm.weight",
+                ),
+                ("attn_norm.bias", "transformer.h.{}.input_layernorm.bias"),
+            ]
+        } else {
+            [
+                ("attn_norm.weight", "transformer.h.{}.ln_mlp.weight"),
+                ("attn_norm.bias", "transformer.h.{}.ln_mlp.bias"),
+            ]
+        };
+        let block = [
+            ("attn_norm_2.weight", "transformer.h.{}.ln_attn.weight"),
+            ("attn_norm_2.bias", "transformer.h.{}.ln_attn.bias"),
+            (
+                "attn_qkv.weight",
+                "transformer.h.{}.self_attention.query_key_value.weight",
+            ),
+            (
+                "attn_output.weight",
+                "transformer.h.{}.self_attention.dense.weight",
+            ),
+            ("ffn_up.weight", "transformer.h.{}.mlp.dense_h_to_4h.weight"),
+            (
+                "ffn_down.weight",
+                "transformer.h.{}.mlp.dense_4h_to_h.weight",
+            ),
+        ];
+        [&attn_norm[..], &block[..]].concat()
+    }
+}
+
 impl llm_base::Hyperparameters for Hyperparameters {
     fn read_ggml(reader: &mut dyn std::io::BufRead) -> Result<Self, LoadError> {
         let hyperparameters = Hyperparameters {
@@ -426,6 +500,45 @@ impl llm_base::Hyperparameters for Hyperparameters {
         Ok(())
     }
 
+    fn read_gguf(metadata: &Metadata) -> Result<Self, LoadError> {
+        Ok(Hyperparameters {
+            n_vocab: util::read_gguf_n_vocab(metadata)?,
+            n_embd: metadata
+                .get_with_type("falcon.embedding_length", MetadataValue::as_countable)?,
+            n_head: metadata
+                .get_with_type("falcon.attention.head_count", MetadataValue::as_countable)?,
+            n_head_kv: metadata
+                .get_optional(
+                    "falcon.attention.head_count_kv",
+                    MetadataValue::as_countable,
+                )?
+                .unwrap_or(1),
+            n_layer: metadata.get_with_type("falcon.block_count", MetadataValue::as_countable)?,
+            file_type: util::read_gguf_filetype(metadata)?,
+        })
+    }
+
+    fn write_gguf(&self, metadata: &mut Metadata) -> Result<(), HyperparametersWriteError> {
+        util::write_gguf_general(metadata, "falcon", "gpt2", self.file_type)?;
+        util::write_gguf_countable(metadata, "falcon.embedding_length", self.n_embd)?;
+        util::write_gguf_countable(metadata, "falcon.attention.head_count", self.n_head)?;
+        util::write_gguf_countable(metadata, "falcon.attention.head_count_kv", self.n_head_kv)?;
+        util::write_gguf_countable(metadata, "falcon.block_count", self.n_layer)?;
+        Ok(())
+    }
+
+    fn tensor_name_from_gguf(&self, name: &str) -> String {
+        util::translate_gguf_tensor_name(name, GGUF_TENSOR_NAMES, &self.gguf_block_tensor_names())
+    }
+
+    fn tensor_name_to_gguf(&self, name: &str) -> String {
+        util::translate_tensor_name_to_gguf(
+            name,
+            GGUF_TENSOR_NAMES,
+            &self.gguf_block_tensor_names(),
+        )
+    }
+
     fn n_vocabulary(&self) -> usize {
         self.n_vocab
     }
@@ -1,14 +1,16 @@
 //! An implementation of [GPT-2](https://huggingface.co/docs/transformers/model_doc/gpt2) for the `llm` ecosystem.
 #![deny(missing_docs)]
 
-use std::sync::Arc;
-
 use ggml::Tensor;
 use llm_base::{
-    ggml,
+    ggml::{
+        self,
+        format::gguf::{Metadata, MetadataValue},
+    },
     model::{common, HyperparametersWriteError},
-    util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel, LoadError,
-    ModelParameters, OutputRequest, Regex, TokenId, Tokenizer,
+    util, ConvertError, FileType, GraphOutputs, HfConfig, InferenceSession, InferenceSessionConfig,
+    KnownModel, LoadError, ModelContext, ModelParameters, OutputRequest, Regex, TensorRole,
+    TensorTransform, TokenId, Tokenizer,
 };
 
 /// The GPT-2 model. Ref: [The Illustrated GPT-2](https://jalammar.github.io/illustrated-gpt2/)
@@ -38,7 +40,7 @@ pub struct Gpt2 {
     layers: Vec<Layer>,
 
     // must be kept alive for the model
-    context: Arc<ggml::Context>,
+    context: ModelContext,
 }
 
 unsafe impl Send for Gpt2 {}
@@ -97,7 +99,7 @@ impl KnownModel for Gpt2 {
             wte,
             wpe,
             lm_head,
-            context: Arc::new(context),
+            context,
         })
     }
 
@@ -129,7 +131,7 @@ impl KnownModel for Gpt2 {
             ..
         } = self.hyperparameters;
 
-        let outputs = session.compute(self.context.clone(), input_tokens, |builder| {
+        let outputs = session.compute(&self.context, input_tokens, |builder| {
             let ctx0 = builder.ctx0.borrow();
             let (memory_k_size, memory_v_size) = (
                 builder.memory_k.element_size(),
@@ -159,7 +161,7 @@ impl KnownModel for Gpt2 {
                 );
 
                 // attn
-                current = ctx0.op_mul_mat(&self.layers[il].c_attn_attn_w, &current);
+                current = builder.lora_mul_mat(&ctx0, &self.layers[il].c_attn_attn_w, &current);
                 current = ctx0.op_add(
                     &ctx0.op_repeat(&self.layers[il].c_attn_attn_b, &current),
                     &current,
@@ -251,7 +253,7 @@ impl KnownModel for Gpt2 {
                 );
 
                 // projection
-                current = ctx0.op_mul_mat(&self.layers[il].c_attn_proj_w, &current);
+                current = builder.lora_mul_mat(&ctx0, &self.layers[il].c_attn_proj_w, &current);
                 current = ctx0.op_add(
                     &ctx0.op_repeat(&self.layers[il].c_attn_proj_b, &current),
                     &current,
@@ -273,7 +275,7 @@ impl KnownModel for Gpt2 {
                 );
 
                 // feed-forward fully connected
-                current = ctx0.op_mul_mat(&self.layers[il].c_mlp_fc_w, &current);
+                current = builder.lora_mul_mat(&ctx0, &self.layers[il].c_mlp_fc_w, &current);
                 current = ctx0.op_add(
                     &ctx0.op_repeat(&self.layers[il].c_mlp_fc_b, &current),
                     &current,
@@ -283,7 +285,7 @@ impl KnownModel for Gpt2 {
                 current = ctx0.op_gelu(&current);
 
                 // feed-forward projection
-                current = ctx0.op_mul_mat(&self.layers[il].c_mlp_proj_w, &current);
+                current = builder.lora_mul_mat(&ctx0, &self.layers[il].c_mlp_proj_w, &current);
                 current = ctx0.op_add(
                     &ctx0.op_repeat(&self.layers[il].c_mlp_proj_b, &current),
                     &current,
@@ -307,7 +309,7 @@ impl KnownModel for Gpt2 {
             let embeddings_tensor: ggml::Tensor = input_layer.share();
 
             let head = self.lm_head.as_ref().unwrap_or(&self.wte);
-            input_layer = ctx0.op_mul_mat(head, &input_layer);
+            input_layer = builder.lora_mul_mat(&ctx0, head, &input_layer);
 
             (
                 gf,
@@ -328,6 +330,14 @@ impl KnownModel for Gpt2 {
         &self.hyperparameters
     }
 
+    fn context(&self) -> &ModelContext {
+        &self.context
+    }
+
+    fn context_mut(&mut self) -> &mut ModelContext {
+        &mut self.context
+    }
+
     fn tokenizer(&self) -> &Tokenizer {
         &self.tokenizer
     }
@@ -361,6 +371,20 @@ impl KnownModel for Gpt2 {
     fn skip_quantize_tensors() -> Vec<Regex> {
         vec![]
     }
+
+    fn tensor_role(name: &str) -> TensorRole {
+        match name {
+            "model/lm_head" => TensorRole::Output,
+            _ if name.ends_with("/attn/c_attn/w") => TensorRole::AttentionValue,
+            _ if name.ends_with("/attn/c_proj/w") => TensorRole::AttentionOutput,
+            _ if name.ends_with("/mlp/c_proj/w") => TensorRole::FeedForwardDown,
+            _ => TensorRole::Other,
+        }
+    }
+
+    fn matches_tensors(has_tensor: &dyn Fn(&str) -> bool) -> bool {
+        has_tensor("model/wte") && has_tensor("model/h0/attn/c_attn/w")
+    }
 }
 
 /// GPT-2 [hyperparameters](https://en.wikipedia.org/wiki/Hyperparameter_(machine_learning))
@@ -380,6 +404,31 @@ pub struct Hyperparameters {
     file_type: FileType,
 }
 
+/// The names of the tensors of the model in GGUF files, and the names it loads them by.
+const GGUF_TENSOR_NAMES: &[(&str, &str)] = &[
+    ("token_embd.weight", "model/wte"),
+    ("position_embd.weight", "model/wpe"),
+    ("output_norm.weight", "model/ln_f/g"),
+    ("output_norm.bias", "model/ln_f/b"),
+    ("output.weight", "model/lm_head"),
+];
+/// Like [GGUF_TENSOR_NAMES], for the tensors of each block. `{}` stands for the index of
+/// the block.
+const GGUF_BLOCK_TENSOR_NAMES: &[(&str, &str)] = &[
+    ("attn_norm.weight", "model/h{}/ln_1/g"),
+    ("attn_norm.bias", "model/h{}/ln_1/b"),
+    ("attn_qkv.weight", "model/h{}/attn/c_attn/w"),
+    ("attn_qkv.bias", "model/h{}/attn/c_attn/b"),
+    ("attn_output.weight", "model/h{}/attn/c_proj/w"),
+    ("attn_output.bias", "model/h{}/attn/c_proj/b"),
+    ("ffn_norm.weight", "model/h{}/ln_2/g"),
+    ("ffn_norm.bias", "model/h{}/ln_2/b"),
+    ("ffn_up.weight", "model/h{}/mlp/c_fc/w"),
+    ("ffn_up.bias", "model/h{}/mlp/c_fc/b"),
+    ("ffn_down.weight", "model/h{}/mlp/c_proj/w"),
+    ("ffn_down.bias", "model/h{}/mlp/c_proj/b"),
+];
+
 impl llm_base::Hyperparameters for Hyperparameters {
     fn read_ggml(reader: &mut dyn std::io::BufRead) -> Result<Self, LoadError> {
         let hyperparameters = Hyperparameters {
@@ -417,6 +466,82 @@ impl llm_base::Hyperparameters for Hyperparameters {
         Ok(())
     }
 
+    fn read_gguf(metadata: &Metadata) -> Result<Self, LoadError> {
+        Ok(Hyperparameters {
+            n_vocab: util::read_gguf_n_vocab(metadata)?,
+            n_ctx: metadata.get_with_type("gpt2.context_length", MetadataValue::as_countable)?,
+            n_embd: metadata.get_with_type("gpt2.embedding_length", MetadataValue::as_countable)?,
+            n_head: metadata
+                .get_with_type("gpt2.attention.head_count", MetadataValue::as_countable)?,
+            n_layer: metadata.get_with_type("gpt2.block_count", MetadataValue::as_countable)?,
+            file_type: util::read_gguf_filetype(metadata)?,
+        })
+    }
+
+    fn write_gguf(&self, metadata: &mut Metadata) -> Result<(), HyperparametersWriteError> {
+        util::write_gguf_general(metadata, "gpt2", "gpt2", self.file_type)?;
+        util::write_gguf_countable(metadata, "gpt2.context_length", self.n_ctx)?;
+        util::write_gguf_countable(metadata, "gpt2.embedding_length", self.n_embd)?;
+        util::write_gguf_countable(metadata, "gpt2.attention.head_count", self.n_head)?;
+        util::write_gguf_countable(metadata, "gpt2.block_count", self.n_layer)?;
+        Ok(())
+    }
+
+    fn tensor_name_from_gguf(&self, name: &str) -> String {
+        util::translate_gguf_tensor_name(name, GGUF_TENSOR_NAMES, GGUF_BLOCK_TENSOR_NAMES)
+    }
+
+    fn tensor_name_to_gguf(&self, name: &str) -> String {
+        util::translate_tensor_name_to_gguf(name, GGUF_TENSOR_NAMES, GGUF_BLOCK_TENSOR_NAMES)
+    }
+
+    fn read_hf_config(config: &HfConfig) -> Result<Self, ConvertError> {
+        Ok(Hyperparameters {
+            n_vocab: config.get_usize("vocab_size")?,
+            n_ctx: config.get_usize("n_positions")?,
+            n_embd: config.get_usize("n_embd")?,
+            n_head: config.get_usize("n_head")?,
+            n_layer: config.get_usize("n_layer")?,
+            file_type: FileType::default(),
+        })
+    }
+
+    fn tensor_from_hf(&self, name: &str) -> Option<(String, TensorTransform)> {
+        let name = util::translate_hf_tensor_name(
+            name.strip_prefix("transformer.").unwrap_or(name),
+            "h.",
+            &[
+                ("wte.weight", "model/wte"),
+                ("wpe.weight", "model/wpe"),
+                ("ln_f.weight", "model/ln_f/g"),
+                ("ln_f.bias", "model/ln_f/b"),
+                ("lm_head.weight", "model/lm_head"),
+            ],
+            &[
+                ("ln_1.weight", "model/h{}/ln_1/g"),
+                ("ln_1.bias", "model/h{}/ln_1/b"),
+                ("ln_2.weight", "model/h{}/ln_2/g"),
+                ("ln_2.bias", "model/h{}/ln_2/b"),
+                ("attn.c_attn.weight", "model/h{}/attn/c_attn/w"),
+                ("attn.c_attn.bias", "model/h{}/attn/c_attn/b"),
+                ("attn.c_proj.weight", "model/h{}/attn/c_proj/w"),
+                ("attn.c_proj.bias", "model/h{}/attn/c_proj/b"),
+                ("mlp.c_fc.weight", "model/h{}/mlp/c_fc/w"),
+                ("mlp.c_fc.bias", "model/h{}/mlp/c_fc/b"),
+                ("mlp.c_proj.weight", "model/h{}/mlp/c_proj/w"),
+                ("mlp.c_proj.bias", "model/h{}/mlp/c_proj/b"),
+            ],
+        )?;
+
+        // GPT-2 stores its projections as `Conv1D`, which is the transpose of a linear layer.
+        let transform = if name.starts_with("model/h") && name.ends_with("/w") {
+            TensorTransform::Transpose
+        } else {
+            TensorTransform::None
+        };
+        Some((name, transform))
+    }
+
     fn n_vocabulary(&self) -> usize {
         self.n_vocab
     }
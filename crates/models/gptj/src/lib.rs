@@ -1,14 +1,18 @@
 //! An implementation of [GPT-J](https://huggingface.co/docs/transformers/model_doc/gptj) for the `llm` ecosystem.
 #![deny(missing_docs)]
 
-use std::{error::Error, sync::Arc};
+use std::error::Error;
 
 use ggml::Tensor;
 use llm_base::{
-    ggml,
+    ggml::{
+        self,
+        format::gguf::{Metadata, MetadataValue},
+    },
     model::{common, HyperparametersWriteError},
     util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel, LoadError,
-    ModelParameters, OutputRequest, Regex, TensorLoader, TokenId, Tokenizer,
+    MemoryLayout, ModelContext, ModelParameters, OutputRequest, PositionEncoding, Regex,
+    TensorLoader, TensorRole, TokenId, Tokenizer,
 };
 
 /// The GPT-J model. Ref: [GitHub](https://github.com/kingoflolz/mesh-transformer-jax/#gpt-j-6b)
@@ -35,7 +39,7 @@ pub struct GptJ {
     layers: Vec<Layer>,
 
     // must be kept alive for the model
-    context: Arc<ggml::Context>,
+    context: ModelContext,
 }
 
 unsafe impl Send for GptJ {}
@@ -92,7 +96,7 @@ impl KnownModel for GptJ {
             lmh_g,
             lmh_b,
             layers,
-            context: Arc::new(context),
+            context,
         })
     }
 
@@ -114,7 +118,7 @@ impl KnownModel for GptJ {
     ) {
         let input_len = input_tokens.len();
         let session_len = session.n_past;
-        let ctx_size = self.params.context_size;
+        let layout = self.layout();
 
         let Hyperparameters {
             n_embd,
@@ -125,12 +129,8 @@ impl KnownModel for GptJ {
             ..
         } = self.hyperparameters;
 
-        let outputs = session.compute(self.context.clone(), input_tokens, |builder| {
+        let outputs = session.compute(&self.context, input_tokens, |builder| {
             let ctx0 = builder.ctx0.borrow();
-            let (memory_k_size, memory_v_size) = (
-                builder.memory_k.element_size(),
-                builder.memory_v.element_size(),
-            );
             let embd = builder.embd;
 
             let mut input_layer = ctx0.op_get_rows(&self.wte, embd);
@@ -150,7 +150,7 @@ impl KnownModel for GptJ {
                 let overrides = self.params.rope_overrides.as_ref();
                 let qcur = ctx0.op_rope_inplace(
                     &ctx0.op_reshape_3d(
-                        &ctx0.op_mul_mat(&self.layers[il].c_attn_q_proj_w, &current),
+                        &builder.lora_mul_mat(&ctx0, &self.layers[il].c_attn_q_proj_w, &current),
                         n_embd / n_head,
                         n_head,
                         input_len,
@@ -162,7 +162,7 @@ impl KnownModel for GptJ {
                 );
                 let kcur = ctx0.op_rope_inplace(
                     &ctx0.op_reshape_3d(
-                        &ctx0.op_mul_mat(&self.layers[il].c_attn_k_proj_w, &current),
+                        &builder.lora_mul_mat(&ctx0, &self.layers[il].c_attn_k_proj_w, &current),
                         n_embd / n_head,
                         n_head,
                         input_len,
@@ -174,40 +174,12 @@ impl KnownModel for GptJ {
                 );
 
                 // self-attention store key and value to memory
-                let vcur =
-                    ctx0.op_transpose(&ctx0.op_mul_mat(&self.layers[il].c_attn_v_proj_w, &current));
+                let vcur = builder.lora_mul_mat(&ctx0, &self.layers[il].c_attn_v_proj_w, &current);
 
-                let k = ctx0.op_view_1d(
-                    builder.memory_k,
-                    input_len * n_embd,
-                    (memory_k_size * n_embd) * (il * ctx_size + session_len),
-                );
-                let v = ctx0.op_view_2d(
-                    builder.memory_v,
-                    (input_len, n_embd),
-                    ctx_size * memory_v_size,
-                    (il * ctx_size) * memory_v_size * n_embd + session_len * memory_v_size,
-                );
-
-                gf.build_forward_expand(&ctx0.op_cpy(&kcur, &k));
-                gf.build_forward_expand(&ctx0.op_cpy(&vcur, &v));
+                builder.store_memory(&ctx0, &mut gf, &layout, il, &kcur, &vcur);
 
                 let q = ctx0.op_permute(&qcur, (0, 2, 1, 3));
-                let big_k = ctx0.op_permute(
-                    &ctx0.op_reshape_3d(
-                        &ctx0.op_view_1d(
-                            builder.memory_k,
-                            (session_len + input_len) * n_embd,
-                            il * ctx_size * memory_k_size * n_embd,
-                        ),
-                        n_embd / n_head,
-                        n_head,
-                        session_len + input_len,
-                    ),
-                    (0, 2, 1, 3),
-                );
-
-                let kq = ctx0.op_mul_mat(&big_k, &q);
+                let kq = builder.memory_keys_mul_mat(&ctx0, &mut gf, il, n_head, &q);
                 let kq_scaled = ctx0.op_scale_inplace(
                     &kq,
                     &ctx0.new_f32(1f32 / f32::sqrt(n_embd as f32 / n_head as f32)),
@@ -216,17 +188,7 @@ impl KnownModel for GptJ {
                 let kq_masked = ctx0.op_diag_mask_inf_inplace(&kq_scaled, session_len);
                 let kq_softmax = ctx0.op_soft_max_inplace(&kq_masked);
 
-                let big_v = ctx0.op_view_3d(
-                    builder.memory_v,
-                    (session_len + input_len, n_embd / n_head, n_head),
-                    (
-                        ctx_size * memory_v_size,
-                        ctx_size * memory_v_size * n_embd / n_head,
-                    ),
-                    il * ctx_size * memory_v_size * n_embd,
-                );
-
-                let kqv = ctx0.op_mul_mat(&big_v, &kq_softmax);
+                let kqv = builder.memory_values_mul_mat(&ctx0, &layout, il, n_head, &kq_softmax);
                 let kqv_merged = ctx0.op_permute(&kqv, (0, 2, 1, 3));
 
                 current = ctx0.op_cpy(
@@ -235,12 +197,12 @@ impl KnownModel for GptJ {
                 );
 
                 // self-attention projection
-                current = ctx0.op_mul_mat(&self.layers[il].c_attn_proj_w, &current);
+                current = builder.lora_mul_mat(&ctx0, &self.layers[il].c_attn_proj_w, &current);
 
                 // feed-forward
                 let ff_in = current.share();
 
-                current = ctx0.op_mul_mat(&self.layers[il].c_mlp_fc_w, &input_sa);
+                current = builder.lora_mul_mat(&ctx0, &self.layers[il].c_mlp_fc_w, &input_sa);
                 current = ctx0.op_add(
                     &ctx0.op_repeat(&self.layers[il].c_mlp_fc_b, &current),
                     &current,
@@ -249,7 +211,7 @@ impl KnownModel for GptJ {
                 current = ctx0.op_gelu(&current);
 
                 // feed-forward projection
-                current = ctx0.op_mul_mat(&self.layers[il].c_mlp_proj_w, &current);
+                current = builder.lora_mul_mat(&ctx0, &self.layers[il].c_mlp_proj_w, &current);
                 current = ctx0.op_add(
                     &ctx0.op_repeat(&self.layers[il].c_mlp_proj_b, &current),
                     &current,
@@ -271,7 +233,7 @@ impl KnownModel for GptJ {
             let embeddings_tensor: ggml::Tensor = input_layer.share();
 
             // lm_head
-            input_layer = ctx0.op_mul_mat(&self.lmh_g, &input_layer);
+            input_layer = builder.lora_mul_mat(&ctx0, &self.lmh_g, &input_layer);
             input_layer = ctx0.op_add(&ctx0.op_repeat(&self.lmh_b, &input_layer), &input_layer);
 
             (
@@ -293,6 +255,14 @@ impl KnownModel for GptJ {
         &self.hyperparameters
     }
 
+    fn context(&self) -> &ModelContext {
+        &self.context
+    }
+
+    fn context_mut(&mut self) -> &mut ModelContext {
+        &mut self.context
+    }
+
     fn tokenizer(&self) -> &Tokenizer {
         &self.tokenizer
     }
@@ -317,9 +287,41 @@ impl KnownModel for GptJ {
         vec![]
     }
 
+    fn tensor_role(name: &str) -> TensorRole {
+        match name {
+            "lm_head.weight" => TensorRole::Output,
+            _ if name.ends_with(".attn.v_proj.weight") => TensorRole::AttentionValue,
+            _ if name.ends_with(".attn.out_proj.weight") => TensorRole::AttentionOutput,
+            _ if name.ends_with(".mlp.fc_out.weight") => TensorRole::FeedForwardDown,
+            _ => TensorRole::Other,
+        }
+    }
+
     fn supports_rewind(&self) -> bool {
         true
     }
+
+    fn memory_layout(&self) -> Option<MemoryLayout> {
+        Some(self.layout())
+    }
+
+    fn matches_tensors(has_tensor: &dyn Fn(&str) -> bool) -> bool {
+        has_tensor("transformer.wte.weight") && has_tensor("transformer.h.0.attn.q_proj.weight")
+    }
+}
+
+impl GptJ {
+    fn layout(&self) -> MemoryLayout {
+        MemoryLayout {
+            position_encoding: PositionEncoding::Rotary {
+                n_embd_head: self.hyperparameters.n_embd / self.hyperparameters.n_head,
+                n_rot: self.hyperparameters.n_rot,
+                mode: 0,
+                overrides: self.params.rope_overrides.clone(),
+            },
+            transposed_values: true,
+        }
+    }
 }
 
 /// GPT-J [hyperparameters](https://en.wikipedia.org/wiki/Hyperparameter_(machine_learning))
@@ -341,6 +343,32 @@ pub struct Hyperparameters {
     pub file_type: FileType,
 }
 
+/// The names of the tensors of the model in GGUF files, and the names it loads them by.
+const GGUF_TENSOR_NAMES: &[(&str, &str)] = &[
+    ("token_embd.weight", "transformer.wte.weight"),
+    ("output_norm.weight", "transformer.ln_f.weight"),
+    ("output_norm.bias", "transformer.ln_f.bias"),
+    ("output.weight", "lm_head.weight"),
+    ("output.bias", "lm_head.bias"),
+];
+/// Like [GGUF_TENSOR_NAMES], for the tensors of each block. `{}` stands for the index of
+/// the block.
+const GGUF_BLOCK_TENSOR_NAMES: &[(&str, &str)] = &[
+    ("attn_norm.weight", "transformer.h.{}.ln_1.weight"),
+    ("attn_norm.bias", "transformer.h.{}.ln_1.bias"),
+    ("attn_q.weight", "transformer.h.{}.attn.q_proj.weight"),
+    ("attn_k.weight", "transformer.h.{}.attn.k_proj.weight"),
+    ("attn_v.weight", "transformer.h.{}.attn.v_proj.weight"),
+    (
+        "attn_output.weight",
+        "transformer.h.{}.attn.out_proj.weight",
+    ),
+    ("ffn_up.weight", "transformer.h.{}.mlp.fc_in.weight"),
+    ("ffn_up.bias", "transformer.h.{}.mlp.fc_in.bias"),
+    ("ffn_down.weight", "transformer.h.{}.mlp.fc_out.weight"),
+    ("ffn_down.bias", "transformer.h.{}.mlp.fc_out.bias"),
+];
+
 impl llm_base::Hyperparameters for Hyperparameters {
     fn read_ggml(reader: &mut dyn std::io::BufRead) -> Result<Self, LoadError> {
         let hyperparameters = Hyperparameters {
@@ -379,6 +407,38 @@ impl llm_base::Hyperparameters for Hyperparameters {
         Ok(())
     }
 
+    fn read_gguf(metadata: &Metadata) -> Result<Self, LoadError> {
+        Ok(Hyperparameters {
+            n_vocab: util::read_gguf_n_vocab(metadata)?,
+            n_ctx: metadata.get_with_type("gptj.context_length", MetadataValue::as_countable)?,
+            n_embd: metadata.get_with_type("gptj.embedding_length", MetadataValue::as_countable)?,
+            n_head: metadata
+                .get_with_type("gptj.attention.head_count", MetadataValue::as_countable)?,
+            n_layer: metadata.get_with_type("gptj.block_count", MetadataValue::as_countable)?,
+            n_rot: metadata
+                .get_with_type("gptj.rope.dimension_count", MetadataValue::as_countable)?,
+            file_type: util::read_gguf_filetype(metadata)?,
+        })
+    }
+
+    fn write_gguf(&self, metadata: &mut Metadata) -> Result<(), HyperparametersWriteError> {
+        util::write_gguf_general(metadata, "gptj", "gpt2", self.file_type)?;
+        util::write_gguf_countable(metadata, "gptj.context_length", self.n_ctx)?;
+        util::write_gguf_countable(metadata, "gptj.embedding_length", self.n_embd)?;
+        util::write_gguf_countable(metadata, "gptj.attention.head_count", self.n_head)?;
+        util::write_gguf_countable(metadata, "gptj.block_count", self.n_layer)?;
+        util::write_gguf_countable(metadata, "gptj.rope.dimension_count", self.n_rot)?;
+        Ok(())
+    }
+
+    fn tensor_name_from_gguf(&self, name: &str) -> String {
+        util::translate_gguf_tensor_name(name, GGUF_TENSOR_NAMES, GGUF_BLOCK_TENSOR_NAMES)
+    }
+
+    fn tensor_name_to_gguf(&self, name: &str) -> String {
+        util::translate_tensor_name_to_gguf(name, GGUF_TENSOR_NAMES, GGUF_BLOCK_TENSOR_NAMES)
+    }
+
     fn n_vocabulary(&self) -> usize {
         self.n_vocab
     }
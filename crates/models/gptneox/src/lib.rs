@@ -2,14 +2,18 @@
 //! This crate also supports the [RedPajama](https://www.together.xyz/blog/redpajama) GPT-NeoX model.
 #![deny(missing_docs)]
 
-use std::{error::Error, sync::Arc};
+use std::error::Error;
 
 use ggml::Tensor;
 use llm_base::{
-    ggml,
+    ggml::{
+        self,
+        format::gguf::{Metadata, MetadataValue},
+    },
     model::{common, HyperparametersWriteError},
-    util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel, LoadError,
-    ModelParameters, OutputRequest, Regex, TensorLoader, TokenId, Tokenizer,
+    util, ConvertError, FileType, GraphOutputs, HfConfig, InferenceSession, InferenceSessionConfig,
+    KnownModel, LoadError, MemoryLayout, ModelContext, ModelParameters, OutputRequest,
+    PositionEncoding, Regex, TensorLoader, TensorRole, TensorTransform, TokenId, Tokenizer,
 };
 
 /// The GPT-NeoX model. Ref: [GitHub](https://github.com/EleutherAI/gpt-neox)
@@ -35,7 +39,7 @@ pub struct GptNeoX {
     layers: Vec<Layer>,
 
     // must be kept alive for the model
-    context: Arc<ggml::Context>,
+    context: ModelContext,
 }
 
 unsafe impl Send for GptNeoX {}
@@ -105,7 +109,7 @@ impl KnownModel for GptNeoX {
             wte,
             lmh_g,
             layers,
-            context: Arc::new(context),
+            context,
         })
     }
 
@@ -129,7 +133,7 @@ impl KnownModel for GptNeoX {
     ) {
         let n = input_tokens.len();
         let n_past = session.n_past;
-        let n_ctx = self.params.context_size;
+        let layout = self.layout();
 
         let Hyperparameters {
             n_embd,
@@ -141,14 +145,10 @@ impl KnownModel for GptNeoX {
             ..
         } = self.hyperparameters;
 
-        let outputs = session.compute(self.context.clone(), input_tokens, |builder| {
+        let outputs = session.compute(&self.context, input_tokens, |builder| {
             let ctx0 = builder.ctx0.borrow();
             let embd = builder.embd;
             let mut input_layer = ctx0.op_get_rows(&self.wte, embd);
-            let (memory_k_size, memory_v_size) = (
-                builder.memory_k.element_size(),
-                builder.memory_v.element_size(),
-            );
 
             let mut gf = ggml::ComputationGraph::new();
 
@@ -164,7 +164,7 @@ impl KnownModel for GptNeoX {
                 );
 
                 // self-attention compute QKV
-                current = ctx0.op_mul_mat(&self.layers[il].c_attn_attn_w, &current);
+                current = builder.lora_mul_mat(&ctx0, &self.layers[il].c_attn_attn_w, &current);
                 current = ctx0.op_add(
                     &ctx0.op_repeat(&self.layers[il].c_attn_attn_b, &current),
                     &current,
@@ -185,7 +185,7 @@ impl KnownModel for GptNeoX {
                     (nb / n_head, nb),
                     f32_size * n_embd / n_head,
                 ));
-                let mut vcur = ctx0.op_cont(&ctx0.op_view_3d(
+                let vcur = ctx0.op_cont(&ctx0.op_view_3d(
                     &current,
                     (n_embd / n_head, n_head, n),
                     (nb / n_head, nb),
@@ -198,43 +198,14 @@ impl KnownModel for GptNeoX {
                 kcur = ctx0.op_rope_inplace(&kcur, n_past, n_rot, 2, overrides);
 
                 // store key and value to memory
-                vcur = ctx0.op_transpose(&ctx0.op_reshape_2d(&vcur, n_embd, n));
-
-                let k = ctx0.op_view_1d(
-                    builder.memory_k,
-                    n * n_embd,
-                    (memory_k_size * n_embd) * (il * n_ctx + n_past),
-                );
-
-                let v = ctx0.op_view_2d(
-                    builder.memory_v,
-                    (n, n_embd),
-                    n_ctx * memory_v_size,
-                    (il * n_ctx) * memory_v_size * n_embd + n_past * memory_v_size,
-                );
-
-                gf.build_forward_expand(&ctx0.op_cpy(&kcur, &k));
-                gf.build_forward_expand(&ctx0.op_cpy(&vcur, &v));
+                let vcur = ctx0.op_reshape_2d(&vcur, n_embd, n);
+                builder.store_memory(&ctx0, &mut gf, &layout, il, &kcur, &vcur);
 
                 // Q = Qcur.contiguous().view(n_embd/n_head, n_head, N).permute(0, 2, 1, 3)
                 let Q = ctx0.op_permute(&qcur, (0, 2, 1, 3));
-                // K = Kmem.view(n_embd/n_head, n_head, n_past + N).permute(0, 2, 1, 3)
-                let K = ctx0.op_permute(
-                    &ctx0.op_reshape_3d(
-                        &ctx0.op_view_1d(
-                            builder.memory_k,
-                            (n_past + n) * n_embd,
-                            il * n_ctx * memory_k_size * n_embd,
-                        ),
-                        n_embd / n_head,
-                        n_head,
-                        n_past + n,
-                    ),
-                    (0, 2, 1, 3),
-                );
 
-                // K * Q
-                let KQ = ctx0.op_mul_mat(&K, &Q);
+                // K * Q, with K = Kmem.view(n_embd/n_head, n_head, n_past + N).permute(0, 2, 1, 3)
+                let KQ = builder.memory_keys_mul_mat(&ctx0, &mut gf, il, n_head, &Q);
 
                 // KQ_scaled = KQ / sqrt(n_embd/n_head)
                 let KQ_scaled = ctx0.op_scale_inplace(
@@ -248,19 +219,9 @@ impl KnownModel for GptNeoX {
                 // KQ = soft_max(KQ_masked)
                 let KQ_softmax = ctx0.op_soft_max_inplace(&KQ_masked);
 
-                // V_trans = Vmem.view(n_embd/n_head, n_head, n_past + N).permute(1, 2, 0, 3).contiguous()
-                let V = ctx0.op_view_3d(
-                    builder.memory_v,
-                    (n_past + n, n_embd / n_head, n_head),
-                    (
-                        n_ctx * memory_v_size,
-                        n_ctx * memory_v_size * n_embd / n_head,
-                    ),
-                    il * n_ctx * memory_v_size * n_embd,
-                );
-
-                // KQV = transpose(V) * KQ_soft_max
-                let KQV = ctx0.op_mul_mat(&V, &KQ_softmax);
+                // KQV = transpose(V) * KQ_soft_max,
+                // with V_trans = Vmem.view(n_embd/n_head, n_head, n_past + N).permute(1, 2, 0, 3).contiguous()
+                let KQV = builder.memory_values_mul_mat(&ctx0, &layout, il, n_head, &KQ_softmax);
                 // KQV_merged = KQV.permute(0, 2, 1, 3)
                 let KQV_merged = ctx0.op_permute(&KQV, (0, 2, 1, 3));
 
@@ -268,7 +229,7 @@ impl KnownModel for GptNeoX {
                 current = ctx0.op_cpy(&KQV_merged, &ctx0.new_tensor_2d(ggml::Type::F32, n_embd, n));
 
                 // self-attention projection
-                current = ctx0.op_mul_mat(&self.layers[il].c_attn_proj_w, &current);
+                current = builder.lora_mul_mat(&ctx0, &self.layers[il].c_attn_proj_w, &current);
                 current = ctx0.op_add(
                     &ctx0.op_repeat(&self.layers[il].c_attn_proj_b, &current),
                     &current,
@@ -280,7 +241,12 @@ impl KnownModel for GptNeoX {
                 let feedforward_input: Tensor;
                 if !use_parallel_residual {
                     feedforward_input = ctx0.op_add(&current, &input_layer);
-                    current = feed_forward_network(&ctx0, &self.layers[il], &feedforward_input);
+                    current = feed_forward_network(
+                        &ctx0,
+                        &self.layers[il],
+                        &feedforward_input,
+                        |w, x| builder.lora_mul_mat(&ctx0, w, x),
+                    );
                     // input for next layer
                     input_layer = ctx0.op_add(&current, &feedforward_input);
                 } else {
@@ -289,7 +255,10 @@ impl KnownModel for GptNeoX {
 
                     // this is independent of the self-attention result, so it could be done in parallel to the self-attention
                     // note here we pass inpL instead of cur
-                    current = feed_forward_network(&ctx0, &self.layers[il], &input_layer);
+                    current =
+                        feed_forward_network(&ctx0, &self.layers[il], &input_layer, |w, x| {
+                            builder.lora_mul_mat(&ctx0, w, x)
+                        });
 
                     // layer input + FF
                     current = ctx0.op_add(&current, &feedforward_input);
@@ -316,7 +285,7 @@ impl KnownModel for GptNeoX {
             ctx0.use_scratch(None);
 
             // apply language model head
-            input_layer = ctx0.op_mul_mat(&self.lmh_g, &input_layer);
+            input_layer = builder.lora_mul_mat(&ctx0, &self.lmh_g, &input_layer);
 
             (
                 gf,
@@ -337,6 +306,14 @@ impl KnownModel for GptNeoX {
         &self.hyperparameters
     }
 
+    fn context(&self) -> &ModelContext {
+        &self.context
+    }
+
+    fn context_mut(&mut self) -> &mut ModelContext {
+        &mut self.context
+    }
+
     fn tokenizer(&self) -> &Tokenizer {
         &self.tokenizer
     }
@@ -361,9 +338,41 @@ impl KnownModel for GptNeoX {
         vec![]
     }
 
+    fn tensor_role(name: &str) -> TensorRole {
+        match name {
+            "embed_out.weight" => TensorRole::Output,
+            _ if name.ends_with(".attention.query_key_value.weight") => TensorRole::AttentionValue,
+            _ if name.ends_with(".attention.dense.weight") => TensorRole::AttentionOutput,
+            _ if name.ends_with(".mlp.dense_4h_to_h.weight") => TensorRole::FeedForwardDown,
+            _ => TensorRole::Other,
+        }
+    }
+
     fn supports_rewind(&self) -> bool {
         true
     }
+
+    fn memory_layout(&self) -> Option<MemoryLayout> {
+        Some(self.layout())
+    }
+
+    fn matches_tensors(has_tensor: &dyn Fn(&str) -> bool) -> bool {
+        has_tensor("gpt_neox.embed_in.weight")
+    }
+}
+
+impl GptNeoX {
+    fn layout(&self) -> MemoryLayout {
+        MemoryLayout {
+            position_encoding: PositionEncoding::Rotary {
+                n_embd_head: self.hyperparameters.n_embd / self.hyperparameters.n_head,
+                n_rot: self.hyperparameters.n_rot,
+                mode: 2,
+                overrides: self.params.rope_overrides.clone(),
+            },
+            transposed_values: true,
+        }
+    }
 }
 
 /// GPT-NeoX [hyperparameters](https://en.wikipedia.org/wiki/Hyperparameter_(machine_learning))
@@ -403,6 +412,57 @@ impl Default for Hyperparameters {
     }
 }
 
+/// The names of the tensors of the model in GGUF files, and the names it loads them by.
+const GGUF_TENSOR_NAMES: &[(&str, &str)] = &[
+    ("token_embd.weight", "gpt_neox.embed_in.weight"),
+    ("output_norm.weight", "gpt_neox.final_layer_norm.weight"),
+    ("output_norm.bias", "gpt_neox.final_layer_norm.bias"),
+    ("output.weight", "embed_out.weight"),
+];
+/// Like [GGUF_TENSOR_NAMES], for the tensors of each block. `{}` stands for the index of
+/// the block.
+const GGUF_BLOCK_TENSOR_NAMES: &[(&str, &str)] = &[
+    (
+        "attn_norm.weight",
+        "gpt_neox.layers.{}.input_layernorm.weight",
+    ),
+    ("attn_norm.bias", "gpt_neox.layers.{}.input_layernorm.bias"),
+    (
+        "attn_qkv.weight",
+        "gpt_neox.layers.{}.attention.query_key_value.weight",
+    ),
+    (
+        "attn_qkv.bias",
+        "gpt_neox.layers.{}.attention.query_key_value.bias",
+    ),
+    (
+        "attn_output.weight",
+        "gpt_neox.layers.{}.attention.dense.weight",
+    ),
+    (
+        "attn_output.bias",
+        "gpt_neox.layers.{}.attention.dense.bias",
+    ),
+    (
+        "ffn_norm.weight",
+        "gpt_neox.layers.{}.post_attention_layernorm.weight",
+    ),
+    (
+        "ffn_norm.bias",
+        "gpt_neox.layers.{}.post_attention_layernorm.bias",
+    ),
+    (
+        "ffn_up.weight",
+        "gpt_neox.layers.{}.mlp.dense_h_to_4h.weight",
+    ),
+    ("ffn_up.bias", "gpt_neox.layers.{}.mlp.dense_h_to_4h.bias"),
+    (
+        "ffn_down.weight",
+        "gpt_neox.layers.{}.mlp.dense_4h_to_h.weight",
+    ),
+    ("ffn_down.bias", "gpt_neox.layers.{}.mlp.dense_4h_to_h.bias"),
+];
+
 impl llm_base::Hyperparameters for Hyperparameters {
     fn read_ggml(reader: &mut dyn std::io::BufRead) -> Result<Self, LoadError> {
         Ok(Hyperparameters {
@@ -429,6 +489,77 @@ impl llm_base::Hyperparameters for Hyperparameters {
         Ok(())
     }
 
+    fn read_gguf(metadata: &Metadata) -> Result<Self, LoadError> {
+        Ok(Hyperparameters {
+            n_vocab: util::read_gguf_n_vocab(metadata)?,
+            n_ctx: metadata.get_with_type("gptneox.context_length", MetadataValue::as_countable)?,
+            n_embd: metadata
+                .get_with_type("gptneox.embedding_length", MetadataValue::as_countable)?,
+            n_head: metadata
+                .get_with_type("gptneox.attention.head_count", MetadataValue::as_countable)?,
+            n_layer: metadata.get_with_type("gptneox.block_count", MetadataValue::as_countable)?,
+            n_rot: metadata
+                .get_with_type("gptneox.rope.dimension_count", MetadataValue::as_countable)?,
+            use_parallel_residual: metadata
+                .get_optional("gptneox.use_parallel_residual", MetadataValue::as_bool)?
+                .unwrap_or(true),
+            file_type: util::read_gguf_filetype(metadata)?,
+        })
+    }
+
+    fn write_gguf(&self, metadata: &mut Metadata) -> Result<(), HyperparametersWriteError> {
+        util::write_gguf_general(metadata, "gptneox", "gpt2", self.file_type)?;
+        util::write_gguf_countable(metadata, "gptneox.context_length", self.n_ctx)?;
+        util::write_gguf_countable(metadata, "gptneox.embedding_length", self.n_embd)?;
+        util::write_gguf_countable(metadata, "gptneox.attention.head_count", self.n_head)?;
+        util::write_gguf_countable(metadata, "gptneox.block_count", self.n_layer)?;
+        util::write_gguf_countable(metadata, "gptneox.rope.dimension_count", self.n_rot)?;
+        metadata.insert(
+            "gptneox.use_parallel_residual",
+            MetadataValue::Bool(self.use_parallel_residual),
+        );
+        Ok(())
+    }
+
+    fn tensor_name_from_gguf(&self, name: &str) -> String {
+        util::translate_gguf_tensor_name(name, GGUF_TENSOR_NAMES, GGUF_BLOCK_TENSOR_NAMES)
+    }
+
+    fn tensor_name_to_gguf(&self, name: &str) -> String {
+        util::translate_tensor_name_to_gguf(name, GGUF_TENSOR_NAMES, GGUF_BLOCK_TENSOR_NAMES)
+    }
+
+    fn read_hf_config(config: &HfConfig) -> Result<Self, ConvertError> {
+        let n_embd = config.get_usize("hidden_size")?;
+        let n_head = config.get_usize("num_attention_heads")?;
+        let rotary_pct = config.get_optional_f64("rotary_pct")?.unwrap_or(1.0);
+
+        Ok(Hyperparameters {
+            n_vocab: config.get_usize("vocab_size")?,
+            n_ctx: config.get_usize("max_position_embeddings")?,
+            n_embd,
+            n_head,
+            n_layer: config.get_usize("num_hidden_layers")?,
+            n_rot: (rotary_pct * (n_embd / n_head) as f64) as usize,
+            use_parallel_residual: config
+                .get_optional_bool("use_parallel_residual")?
+                .unwrap_or(true),
+            file_type: FileType::default(),
+        })
+    }
+
+    fn tensor_from_hf(&self, name: &str) -> Option<(String, TensorTransform)> {
+        // The Hugging Face names are the ones this model uses; only the buffers are dropped.
+        let is_buffer = name.ends_with(".attention.bias")
+            || name.ends_with(".attention.masked_bias")
+            || name.ends_with(".rotary_emb.inv_freq");
+        (!is_buffer).then(|| (name.to_owned(), TensorTransform::None))
+    }
+
+    fn tied_hf_tensors(&self) -> Option<(&'static str, &'static str)> {
+        Some(("gpt_neox.embed_in.weight", "embed_out.weight"))
+    }
+
     fn n_vocabulary(&self) -> usize {
         self.n_vocab
     }
@@ -466,7 +597,13 @@ struct Layer {
     c_mlp_proj_b: Tensor,
 }
 
-fn feed_forward_network(context: &ggml::Context, layer: &Layer, input: &Tensor) -> Tensor {
+/// `mul_mat` multiplies a weight of the layer by its input.
+fn feed_forward_network(
+    context: &ggml::Context,
+    layer: &Layer,
+    input: &Tensor,
+    mul_mat: impl Fn(&Tensor, &Tensor) -> Tensor,
+) -> Tensor {
     let mut current = context.op_norm(input);
 
     //gain and bias
@@ -476,7 +613,7 @@ fn feed_forward_network(context: &ggml::Context, layer: &Layer, input: &Tensor)
     );
 
     // apply weights
-    current = context.op_mul_mat(&layer.c_mlp_fc_w, &current);
+    current = mul_mat(&layer.c_mlp_fc_w, &current);
 
     // apply bias
     current = context.op_add(&context.op_repeat(&layer.c_mlp_fc_b, &current), &current);
@@ -486,7 +623,7 @@ fn feed_forward_network(context: &ggml::Context, layer: &Layer, input: &Tensor)
 
     // projection
     // cur = proj_w*cur + proj_b
-    current = context.op_mul_mat(&layer.c_mlp_proj_w, &current);
+    current = mul_mat(&layer.c_mlp_proj_w, &current);
 
     current = context.op_add(&context.op_repeat(&layer.c_mlp_proj_b, &current), &current);
 
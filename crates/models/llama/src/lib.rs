@@ -1,13 +1,18 @@
 //! An implementation of [LLaMA](https://huggingface.co/docs/transformers/model_doc/llama) for the `llm` ecosystem.
 #![deny(missing_docs)]
 
-use std::{error::Error, sync::Arc};
+use std::error::Error;
 
 use llm_base::{
-    ggml::{self},
+    ggml::{
+        self,
+        format::gguf::{Metadata, MetadataError, MetadataValue},
+    },
     model::{common, HyperparametersWriteError},
-    util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel, LoadError,
-    ModelParameters, OutputRequest, Regex, TensorLoader, TokenId, Tokenizer,
+    util, BatchedSequence, ConvertError, FileType, GraphOutputs, HfConfig, InferenceSession,
+    InferenceSessionConfig, KnownModel, LoadError, MemoryLayout, ModelContext, ModelParameters,
+    OutputRequest, PositionEncoding, Regex, TensorLoader, TensorRole, TensorSplit, TensorTransform,
+    TokenId, Tokenizer,
 };
 
 /// The LLaMA model. Ref: [Introducing LLaMA](https://ai.facebook.com/blog/large-language-model-llama-meta-ai/)
@@ -31,7 +36,7 @@ pub struct Llama {
     layers: Vec<Layer>,
 
     // must be kept alive for the model
-    context: Arc<ggml::Context>,
+    context: ModelContext,
 }
 
 unsafe impl Send for Llama {}
@@ -48,6 +53,17 @@ impl KnownModel for Llama {
     ) -> Result<Self, E> {
         let mut tl = tensor_loader;
 
+        // Use the RoPE frequency base the model was trained with, unless it was overridden.
+        let mut params = params;
+        if let (None, Some(frequency_base)) =
+            (&params.rope_overrides, hyperparameters.rope_freq_base)
+        {
+            params.rope_overrides = Some(ggml::RoPEOverrides {
+                frequency_base,
+                ..Default::default()
+            });
+        }
+
         // model-global weights
         let wte = tl.load("tok_embeddings.weight")?;
 
@@ -102,7 +118,7 @@ impl KnownModel for Llama {
             norm,
             output,
             layers,
-            context: Arc::new(context),
+            context,
         })
     }
 
@@ -126,19 +142,21 @@ impl KnownModel for Llama {
     ) {
         let input_len = input_tokens.len();
         let session_len = session.n_past;
-        let ctx_size = self.params.context_size;
+        let layout = self.layout();
 
         let Hyperparameters {
             n_vocab,
             n_embd,
             n_mult: _,
+            n_ff: _,
             n_head,
             n_layer,
             n_rot,
             file_type: _,
+            rope_freq_base: _,
         } = self.hyperparameters;
 
-        let outputs = session.compute(self.context.clone(), input_tokens, |builder| {
+        let outputs = session.compute(&self.context, input_tokens, |builder| {
             let mut ctx0 = builder.ctx0.borrow_mut();
             let embd = builder.embd;
 
@@ -166,7 +184,7 @@ impl KnownModel for Llama {
                 let q_current = ctx0
                     .op_rope_inplace(
                         &ctx0.op_reshape_3d(
-                            &ctx0.op_mul_mat(&self.layers[il].wq, &current),
+                            &builder.lora_mul_mat(&ctx0, &self.layers[il].wq, &current),
                             n_embd / n_head,
                             n_head,
                             input_len,
@@ -180,7 +198,7 @@ impl KnownModel for Llama {
                 let k_current = ctx0
                     .op_rope_inplace(
                         &ctx0.op_reshape_3d(
-                            &ctx0.op_mul_mat(&self.layers[il].wk, &current),
+                            &builder.lora_mul_mat(&ctx0, &self.layers[il].wk, &current),
                             n_embd / n_head,
                             n_head,
                             input_len,
@@ -193,51 +211,22 @@ impl KnownModel for Llama {
                     .set_name("Kcur");
 
                 // store key and value to memory
-                // compute the transposed [N, n_embd] V matrix
-                let v_current = ctx0.op_transpose(&ctx0.op_reshape_2d(
-                    &ctx0.op_mul_mat(&self.layers[il].wv, &current),
+                // the [n_embd, N] V matrix is stored transposed
+                let v_current = ctx0.op_reshape_2d(
+                    &builder.lora_mul_mat(&ctx0, &self.layers[il].wv, &current),
                     n_embd,
                     input_len,
-                ));
-
-                let k = ctx0.op_view_1d(
-                    builder.memory_k,
-                    input_len * n_embd,
-                    (builder.memory_k.element_size() * n_embd) * (il * ctx_size + session_len),
-                );
-
-                let v = ctx0.op_view_2d(
-                    builder.memory_v,
-                    (input_len, n_embd),
-                    ctx_size * builder.memory_v.element_size(),
-                    (il * ctx_size) * builder.memory_v.element_size() * n_embd
-                        + session_len * builder.memory_v.element_size(),
                 );
 
                 // important: storing RoPE-ed version of K in the KV cache!
-                gf.build_forward_expand(&ctx0.op_cpy(&k_current, &k));
-                gf.build_forward_expand(&ctx0.op_cpy(&v_current, &v));
+                builder.store_memory(&ctx0, &mut gf, &layout, il, &k_current, &v_current);
 
                 let q = ctx0.op_permute(&q_current, (0, 2, 1, 3)).set_name("Q");
 
-                let k = ctx0
-                    .op_permute(
-                        &ctx0.op_reshape_3d(
-                            &ctx0.op_view_1d(
-                                builder.memory_k,
-                                (session_len + input_len) * n_embd,
-                                il * ctx_size * builder.memory_k.element_size() * n_embd,
-                            ),
-                            n_embd / n_head,
-                            n_head,
-                            session_len + input_len,
-                        ),
-                        (0, 2, 1, 3),
-                    )
-                    .set_name("K");
-
                 // K * Q
-                let k_q = ctx0.op_mul_mat(&k, &q).set_name("KQ");
+                let k_q = builder
+                    .memory_keys_mul_mat(&ctx0, &mut gf, il, n_head, &q)
+                    .set_name("KQ");
 
                 // KQ_scaled = KQ / sqrt(n_embd/n_head)
                 let kq_scale = ctx0
@@ -256,19 +245,9 @@ impl KnownModel for Llama {
                     .set_name("KQ_soft_max");
 
                 // split cached V into n_head heads
-                let v = ctx0
-                    .op_view_3d(
-                        builder.memory_v,
-                        (session_len + input_len, n_embd / n_head, n_head),
-                        (
-                            ctx_size * builder.memory_v.element_size(),
-                            ctx_size * builder.memory_v.element_size() * n_embd / n_head,
-                        ),
-                        il * ctx_size * builder.memory_v.element_size() * n_embd,
-                    )
-                    .set_name("V");
-
-                let k_q_v = ctx0.op_mul_mat(&v, &k_q_soft_max).set_name("KQV");
+                let k_q_v = builder
+                    .memory_values_mul_mat(&ctx0, &layout, il, n_head, &k_q_soft_max)
+                    .set_name("KQV");
 
                 // KQV_merged = KQV.permute(0, 2, 1, 3)
                 let k_q_v_merged = ctx0.op_permute(&k_q_v, (0, 2, 1, 3)).set_name("KQV_merged");
@@ -282,7 +261,7 @@ impl KnownModel for Llama {
                     .set_name("KQV_merged_contiguous");
 
                 // projection (no bias)
-                current = ctx0.op_mul_mat(&self.layers[il].wo, &current);
+                current = builder.lora_mul_mat(&ctx0, &self.layers[il].wo, &current);
 
                 ctx0.use_scratch(builder.get_scratch(1));
 
@@ -295,16 +274,16 @@ impl KnownModel for Llama {
                 // cur = cur*ffn_norm(broadcasted)
                 current = ctx0.op_mul(&current, &self.layers[il].ffn_norm);
 
-                let tmp = ctx0.op_mul_mat(&self.layers[il].w3, &current);
+                let tmp = builder.lora_mul_mat(&ctx0, &self.layers[il].w3, &current);
 
-                current = ctx0.op_mul_mat(&self.layers[il].w1, &current);
+                current = builder.lora_mul_mat(&ctx0, &self.layers[il].w1, &current);
 
                 // SILU activation
                 current = ctx0.op_silu(&current);
 
                 current = ctx0.op_mul(&current, &tmp);
 
-                current = ctx0.op_mul_mat(&self.layers[il].w2, &current);
+                current = builder.lora_mul_mat(&ctx0, &self.layers[il].w2, &current);
 
                 current = ctx0.op_add(&current, &input_feed_forward);
 
@@ -324,7 +303,7 @@ impl KnownModel for Llama {
 
             ctx0.set_offloading(false);
             // lm_head
-            input_layer = ctx0.op_mul_mat(&self.output, &input_layer);
+            input_layer = builder.lora_mul_mat(&ctx0, &self.output, &input_layer);
 
             ctx0.use_scratch(None);
             (
@@ -342,10 +321,221 @@ impl KnownModel for Llama {
         common::extract_embeddings(output_request, &outputs.embedding_result, n_embd, input_len);
     }
 
+    fn evaluate_batch(&self, sequences: &mut [BatchedSequence]) {
+        let Hyperparameters {
+            n_vocab,
+            n_embd,
+            n_mult: _,
+            n_ff: _,
+            n_head,
+            n_layer,
+            n_rot,
+            file_type: _,
+            rope_freq_base: _,
+        } = self.hyperparameters;
+        let layout = self.layout();
+
+        let outputs = InferenceSession::compute_batch(sequences, |builder| {
+            let mut ctx0 = builder.ctx0.borrow_mut();
+            let embd = builder.embd;
+            let input_len = embd.nelements();
+
+            let mut input_layer = ctx0.op_get_rows(&self.wte, embd);
+
+            let mut gf = ggml::ComputationGraph::new();
+
+            for il in 0..n_layer {
+                ctx0.set_offloading(self.params.should_offload(il));
+
+                let input_self_attention = input_layer.share();
+                let mut current: ggml::Tensor;
+
+                ctx0.use_scratch(builder.get_scratch(0));
+
+                // norm
+                current = ctx0.op_rms_norm(&input_layer);
+
+                // cur = attention_norm * cur
+                current = ctx0.op_mul(&current, &self.layers[il].attention_norm);
+
+                // compute Q, K and V for the tokens of all of the sequences
+                let q_all = ctx0.op_reshape_3d(
+                    &ctx0.op_mul_mat(&self.layers[il].wq, &current),
+                    n_embd / n_head,
+                    n_head,
+                    input_len,
+                );
+                let k_all = ctx0.op_reshape_3d(
+                    &ctx0.op_mul_mat(&self.layers[il].wk, &current),
+                    n_embd / n_head,
+                    n_head,
+                    input_len,
+                );
+                let v_all = ctx0.op_mul_mat(&self.layers[il].wv, &current);
+
+                // self-attention, with the memory and the positions of each sequence
+                let attention = ctx0.new_tensor_2d(ggml::Type::F32, n_embd, input_len);
+                for memory in &builder.sequences {
+                    let (n, offset, n_past) = (memory.n_tokens(), memory.offset(), memory.n_past());
+                    let tokens_3d = |t: &ggml::Tensor| {
+                        let nb = t.get_nb();
+                        ctx0.op_view_3d(
+                            t,
+                            (n_embd / n_head, n_head, n),
+                            (nb[1], nb[2]),
+                            offset * nb[2],
+                        )
+                    };
+                    let tokens_2d = |t: &ggml::Tensor| {
+                        let nb1 = t.get_nb()[1];
+                        ctx0.op_view_2d(t, (n_embd, n), nb1, offset * nb1)
+                    };
+
+                    // RoPE Q and K at the positions of the sequence
+                    let overrides = self.params.rope_overrides.as_ref();
+                    let q_current =
+                        ctx0.op_rope_inplace(&tokens_3d(&q_all), n_past, n_rot, 0, overrides);
+                    let k_current =
+                        ctx0.op_rope_inplace(&tokens_3d(&k_all), n_past, n_rot, 0, overrides);
+
+                    // important: storing RoPE-ed version of K in the KV cache!
+                    memory.store(&ctx0, &mut gf, &layout, il, &k_current, &tokens_2d(&v_all));
+
+                    let q = ctx0.op_permute(&q_current, (0, 2, 1, 3));
+
+                    // K * Q
+                    let k_q = memory.keys_mul_mat(&ctx0, &mut gf, il, n_head, &q);
+
+                    // KQ_scaled = KQ / sqrt(n_embd/n_head)
+                    let kq_scale = ctx0.new_f32(1.0 / ((n_embd as f32 / n_head as f32).sqrt()));
+                    let k_q_scaled = ctx0.op_scale_inplace(&k_q, &kq_scale);
+
+                    // KQ_masked = mask_past(KQ_scaled)
+                    let k_q_masked = ctx0.op_diag_mask_inf_inplace(&k_q_scaled, n_past);
+
+                    // KQ = soft_max(KQ_masked)
+                    let k_q_soft_max = ctx0.op_soft_max_inplace(&k_q_masked);
+
+                    let k_q_v = memory.values_mul_mat(&ctx0, &layout, il, n_head, &k_q_soft_max);
+
+                    // KQV_merged = KQV.permute(0, 2, 1, 3), written to the columns of the sequence
+                    let k_q_v_merged = ctx0.op_permute(&k_q_v, (0, 2, 1, 3));
+                    gf.build_forward_expand(&ctx0.op_cpy(&k_q_v_merged, &tokens_2d(&attention)));
+                }
+
+                // projection (no bias)
+                current = ctx0.op_mul_mat(&self.layers[il].wo, &attention);
+
+                ctx0.use_scratch(builder.get_scratch(1));
+
+                let input_feed_forward = ctx0.op_add(&current, &input_self_attention);
+
+                // feed-forward network
+                // norm
+                current = ctx0.op_rms_norm(&input_feed_forward);
+
+                // cur = cur*ffn_norm(broadcasted)
+                current = ctx0.op_mul(&current, &self.layers[il].ffn_norm);
+
+                let tmp = ctx0.op_mul_mat(&self.layers[il].w3, &current);
+
+                current = ctx0.op_mul_mat(&self.layers[il].w1, &current);
+
+                // SILU activation
+                current = ctx0.op_silu(&current);
+
+                current = ctx0.op_mul(&current, &tmp);
+
+                current = ctx0.op_mul_mat(&self.layers[il].w2, &current);
+
+                current = ctx0.op_add(&current, &input_feed_forward);
+
+                // input for next layer
+                input_layer = current;
+            }
+
+            ctx0.use_scratch(builder.get_scratch(0));
+
+            // norm
+            input_layer = ctx0.op_rms_norm(&input_layer);
+
+            // inpL = inpL*norm(broadcasted)
+            input_layer = ctx0.op_mul(&input_layer, &self.norm);
+
+            let embedding_result: ggml::Tensor = input_layer.share();
+
+            ctx0.set_offloading(false);
+            // lm_head
+            input_layer = ctx0.op_mul_mat(&self.output, &input_layer);
+
+            ctx0.use_scratch(None);
+
+            // split the outputs between the sequences
+            let outputs = builder
+                .sequences
+                .iter()
+                .map(|memory| {
+                    let (n, offset) = (memory.n_tokens(), memory.offset());
+                    let result_nb1 = input_layer.get_nb()[1];
+                    let embedding_nb1 = embedding_result.get_nb()[1];
+                    GraphOutputs {
+                        result: ctx0.op_view_2d(
+                            &input_layer,
+                            (n_vocab, n),
+                            result_nb1,
+                            offset * result_nb1,
+                        ),
+                        embedding_result: ctx0.op_view_2d(
+                            &embedding_result,
+                            (n_embd, n),
+                            embedding_nb1,
+                            offset * embedding_nb1,
+                        ),
+                    }
+                })
+                .collect();
+            (gf, outputs)
+        });
+
+        let Some(outputs) = outputs else {
+            // The sequences cannot be evaluated together, so evaluate them one by one.
+            for sequence in sequences {
+                self.evaluate(
+                    sequence.session,
+                    sequence.input_tokens,
+                    sequence.output_request,
+                );
+            }
+            return;
+        };
+
+        // finish evaluation
+        for (sequence, outputs) in sequences.iter_mut().zip(outputs) {
+            let input_len = sequence.input_tokens.len();
+            let output_request = &mut *sequence.output_request;
+            common::read_last_token(sequence.session, &outputs.result, n_vocab, input_len);
+            common::extract_logits(output_request, &outputs.result, n_vocab, input_len);
+            common::extract_embeddings(
+                output_request,
+                &outputs.embedding_result,
+                n_embd,
+                input_len,
+            );
+        }
+    }
+
     fn hyperparameters(&self) -> &Self::Hyperparameters {
         &self.hyperparameters
     }
 
+    fn context(&self) -> &ModelContext {
+        &self.context
+    }
+
+    fn context_mut(&mut self) -> &mut ModelContext {
+        &mut self.context
+    }
+
     fn tokenizer(&self) -> &Tokenizer {
         &self.tokenizer
     }
@@ -370,9 +560,53 @@ impl KnownModel for Llama {
         vec![]
     }
 
+    fn tensor_role(name: &str) -> TensorRole {
+        match name {
+            "output.weight" => TensorRole::Output,
+            _ if name.ends_with(".attention.wv.weight") => TensorRole::AttentionValue,
+            _ if name.ends_with(".attention.wo.weight") => TensorRole::AttentionOutput,
+            _ if name.ends_with(".feed_forward.w2.weight") => TensorRole::FeedForwardDown,
+            _ => TensorRole::Other,
+        }
+    }
+
     fn supports_rewind(&self) -> bool {
         true
     }
+
+    fn memory_layout(&self) -> Option<MemoryLayout> {
+        Some(self.layout())
+    }
+
+    fn tensor_split(name: &str) -> TensorSplit {
+        // The original checkpoints shard these tensors along their first dimension.
+        if name == "tok_embeddings.weight"
+            || name.ends_with(".attention.wo.weight")
+            || name.ends_with(".feed_forward.w2.weight")
+        {
+            TensorSplit::ByColumns
+        } else {
+            TensorSplit::ByRows
+        }
+    }
+
+    fn matches_tensors(has_tensor: &dyn Fn(&str) -> bool) -> bool {
+        has_tensor("tok_embeddings.weight") && has_tensor("layers.0.attention.wq.weight")
+    }
+}
+
+impl Llama {
+    fn layout(&self) -> MemoryLayout {
+        MemoryLayout {
+            position_encoding: PositionEncoding::Rotary {
+                n_embd_head: self.hyperparameters.n_embd / self.hyperparameters.n_head,
+                n_rot: self.hyperparameters.n_rot,
+                mode: 0,
+                overrides: self.params.rope_overrides.clone(),
+            },
+            transposed_values: true,
+        }
+    }
 }
 
 /// LLaMA [hyperparameters](https://en.wikipedia.org/wiki/Hyperparameter_(machine_learning))
@@ -382,8 +616,12 @@ pub struct Hyperparameters {
     pub n_vocab: usize,
     /// Size of the model's embedding layer
     pub n_embd: usize,
-    /// n_mult
+    /// n_mult. For formats that store `n_ff` instead, an `n_mult` that reproduces it, or 0 if
+    /// none does.
     pub n_mult: usize,
+    /// The size of the feed-forward layers, if the format stores it directly (GGUF and
+    /// Hugging Face configs). Otherwise, it is derived from `n_mult`.
+    pub n_ff: Option<usize>,
     /// n_head
     pub n_head: usize,
     /// Number of layers in the model
@@ -392,22 +630,57 @@ pub struct Hyperparameters {
     pub n_rot: usize,
     /// file_type
     pub file_type: FileType,
+    /// The base frequency of the rotary embeddings, if the model specifies one. Only GGUF
+    /// models can specify it.
+    pub rope_freq_base: Option<usize>,
 }
 
+/// The names of the tensors of the model in GGUF files, and the names it loads them by.
+const GGUF_TENSOR_NAMES: &[(&str, &str)] = &[
+    ("token_embd.weight", "tok_embeddings.weight"),
+    ("output_norm.weight", "norm.weight"),
+    ("output.weight", "output.weight"),
+];
+/// Like [GGUF_TENSOR_NAMES], for the tensors of each block. `{}` stands for the index of
+/// the block.
+const GGUF_BLOCK_TENSOR_NAMES: &[(&str, &str)] = &[
+    ("attn_norm.weight", "layers.{}.attention_norm.weight"),
+    ("attn_q.weight", "layers.{}.attention.wq.weight"),
+    ("attn_k.weight", "layers.{}.attention.wk.weight"),
+    ("attn_v.weight", "layers.{}.attention.wv.weight"),
+    ("attn_output.weight", "layers.{}.attention.wo.weight"),
+    ("ffn_norm.weight", "layers.{}.ffn_norm.weight"),
+    ("ffn_gate.weight", "layers.{}.feed_forward.w1.weight"),
+    ("ffn_down.weight", "layers.{}.feed_forward.w2.weight"),
+    ("ffn_up.weight", "layers.{}.feed_forward.w3.weight"),
+];
+
 impl llm_base::Hyperparameters for Hyperparameters {
     fn read_ggml(reader: &mut dyn std::io::BufRead) -> Result<Self, LoadError> {
         Ok(Hyperparameters {
             n_vocab: util::read_i32(reader)?.try_into()?,
             n_embd: util::read_i32(reader)?.try_into()?,
             n_mult: util::read_i32(reader)?.try_into()?,
+            n_ff: None,
             n_head: util::read_i32(reader)?.try_into()?,
             n_layer: util::read_i32(reader)?.try_into()?,
             n_rot: util::read_i32(reader)?.try_into()?,
             file_type: util::read_filetype(reader)?,
+            rope_freq_base: None,
         })
     }
 
     fn write_ggml(&self, writer: &mut dyn std::io::Write) -> Result<(), HyperparametersWriteError> {
+        // The legacy formats can only store feed-forward sizes that `n_mult` reproduces.
+        if let Some(n_ff) = self.n_ff {
+            if self.n_mult == 0 || n_ff_for_n_mult(self.n_embd, self.n_mult) != n_ff {
+                return Err(HyperparametersWriteError::UnrepresentableValue {
+                    name: "feed-forward size",
+                    value: n_ff,
+                });
+            }
+        }
+
         util::write_i32(writer, self.n_vocab.try_into()?)?;
         util::write_i32(writer, self.n_embd.try_into()?)?;
         util::write_i32(writer, self.n_mult.try_into()?)?;
@@ -418,6 +691,136 @@ impl llm_base::Hyperparameters for Hyperparameters {
         Ok(())
     }
 
+    fn read_gguf(metadata: &Metadata) -> Result<Self, LoadError> {
+        let n_embd =
+            metadata.get_with_type("llama.embedding_length", MetadataValue::as_countable)?;
+        let n_head =
+            metadata.get_with_type("llama.attention.head_count", MetadataValue::as_countable)?;
+        // Grouped-query attention is not supported by this implementation.
+        if metadata
+            .get_optional("llama.attention.head_count_kv", MetadataValue::as_countable)?
+            .unwrap_or(n_head)
+            != n_head
+        {
+            return Err(MetadataError::UnsupportedValue {
+                key: "llama.attention.head_count_kv".to_string(),
+            }
+            .into());
+        }
+        // GGUF stores the feed-forward size directly; recover an `n_mult` that reproduces it.
+        let n_ff =
+            metadata.get_with_type("llama.feed_forward_length", MetadataValue::as_countable)?;
+
+        Ok(Hyperparameters {
+            n_vocab: util::read_gguf_n_vocab(metadata)?,
+            n_embd,
+            n_mult: n_mult_for_n_ff(n_embd, n_ff).unwrap_or(0),
+            n_ff: Some(n_ff),
+            n_head,
+            n_layer: metadata.get_with_type("llama.block_count", MetadataValue::as_countable)?,
+            n_rot: metadata
+                .get_optional("llama.rope.dimension_count", MetadataValue::as_countable)?
+                .unwrap_or(n_embd / n_head),
+            file_type: util::read_gguf_filetype(metadata)?,
+            rope_freq_base: metadata
+                .get_optional("llama.rope.freq_base", MetadataValue::as_f32)?
+                .map(|freq_base| freq_base.round() as usize),
+        })
+    }
+
+    fn write_gguf(&self, metadata: &mut Metadata) -> Result<(), HyperparametersWriteError> {
+        util::write_gguf_general(metadata, "llama", "llama", self.file_type)?;
+        util::write_gguf_countable(metadata, "llama.embedding_length", self.n_embd)?;
+        util::write_gguf_countable(metadata, "llama.feed_forward_length", self.n_ff())?;
+        util::write_gguf_countable(metadata, "llama.attention.head_count", self.n_head)?;
+        util::write_gguf_countable(metadata, "llama.block_count", self.n_layer)?;
+        util::write_gguf_countable(metadata, "llama.rope.dimension_count", self.n_rot)?;
+        if let Some(freq_base) = self.rope_freq_base {
+            metadata.insert(
+                "llama.rope.freq_base",
+                MetadataValue::Float32(freq_base as f32),
+            );
+        }
+        Ok(())
+    }
+
+    fn tensor_name_from_gguf(&self, name: &str) -> String {
+        util::translate_gguf_tensor_name(name, GGUF_TENSOR_NAMES, GGUF_BLOCK_TENSOR_NAMES)
+    }
+
+    fn tensor_name_to_gguf(&self, name: &str) -> String {
+        util::translate_tensor_name_to_gguf(name, GGUF_TENSOR_NAMES, GGUF_BLOCK_TENSOR_NAMES)
+    }
+
+    fn read_hf_config(config: &HfConfig) -> Result<Self, ConvertError> {
+        let n_embd = config.get_usize("hidden_size")?;
+        let n_head = config.get_usize("num_attention_heads")?;
+        let n_ff = config.get_usize("intermediate_size")?;
+        // Grouped-query attention is not supported by this implementation.
+        if config
+            .get_optional_usize("num_key_value_heads")?
+            .unwrap_or(n_head)
+            != n_head
+        {
+            return Err(ConvertError::InvalidConfigValue {
+                key: "num_key_value_heads".to_string(),
+            });
+        }
+
+        Ok(Hyperparameters {
+            n_vocab: config.get_usize("vocab_size")?,
+            n_embd,
+            n_mult: n_mult_for_n_ff(n_embd, n_ff).unwrap_or(0),
+            n_ff: Some(n_ff),
+            n_head,
+            n_layer: config.get_usize("num_hidden_layers")?,
+            n_rot: n_embd / n_head,
+            file_type: FileType::default(),
+            rope_freq_base: None,
+        })
+    }
+
+    fn tensor_from_hf(&self, name: &str) -> Option<(String, TensorTransform)> {
+        let name = util::translate_hf_tensor_name(
+            name,
+            "model.layers.",
+            &[
+                ("model.embed_tokens.weight", "tok_embeddings.weight"),
+                ("model.norm.weight", "norm.weight"),
+                ("lm_head.weight", "output.weight"),
+            ],
+            &[
+                ("input_layernorm.weight", "layers.{}.attention_norm.weight"),
+                ("self_attn.q_proj.weight", "layers.{}.attention.wq.weight"),
+                ("self_attn.k_proj.weight", "layers.{}.attention.wk.weight"),
+                ("self_attn.v_proj.weight", "layers.{}.attention.wv.weight"),
+                ("self_attn.o_proj.weight", "layers.{}.attention.wo.weight"),
+                (
+                    "post_attention_layernorm.weight",
+                    "layers.{}.ffn_norm.weight",
+                ),
+                ("mlp.gate_proj.weight", "layers.{}.feed_forward.w1.weight"),
+                ("mlp.down_proj.weight", "layers.{}.feed_forward.w2.weight"),
+                ("mlp.up_proj.weight", "layers.{}.feed_forward.w3.weight"),
+            ],
+        )?;
+
+        // The Hugging Face weights permute the query and key rows for their rotary embeddings.
+        let transform =
+            if name.ends_with(".attention.wq.weight") || name.ends_with(".attention.wk.weight") {
+                TensorTransform::UnpermuteRotary {
+                    n_head: self.n_head,
+                }
+            } else {
+                TensorTransform::None
+            };
+        Some((name, transform))
+    }
+
+    fn tied_hf_tensors(&self) -> Option<(&'static str, &'static str)> {
+        Some(("model.embed_tokens.weight", "lm_head.weight"))
+    }
+
     fn n_vocabulary(&self) -> usize {
         self.n_vocab
     }
@@ -447,3 +850,88 @@ struct Layer {
     w2: ggml::Tensor,
     w3: ggml::Tensor,
 }
+
+impl Hyperparameters {
+    /// The size of the feed-forward layers, which the legacy formats store as `n_mult`.
+    fn n_ff(&self) -> usize {
+        self.n_ff
+            .unwrap_or_else(|| n_ff_for_n_mult(self.n_embd, self.n_mult))
+    }
+}
+
+/// The feed-forward size that the legacy formats derive from `n_mult`.
+fn n_ff_for_n_mult(n_embd: usize, n_mult: usize) -> usize {
+    // Rounded up to a multiple of `n_mult`.
+    let n_ff = 2 * (4 * n_embd) / 3;
+    n_ff + (n_mult - n_ff % n_mult) % n_mult
+}
+
+/// Recovers an `n_mult` that reproduces the feed-forward size `n_ff`, for formats that store
+/// the size directly. Returns `None` if no `n_mult` does, e.g. if `n_ff` is smaller than the
+/// size the legacy formats derive.
+fn n_mult_for_n_ff(n_embd: usize, n_ff: usize) -> Option<usize> {
+    (2..=8192)
+        .rev()
+        .find(|n_mult| n_ff_for_n_mult(n_embd, *n_mult) == n_ff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use llm_base::{ggml::format::gguf::MetadataValueType, Hyperparameters as _};
+
+    #[test]
+    fn test_read_gguf() {
+        let mut metadata = Metadata::default();
+        for (key, value) in [
+            ("llama.embedding_length", 8),
+            ("llama.attention.head_count", 2),
+            ("llama.feed_forward_length", 32),
+            ("llama.block_count", 1),
+        ] {
+            metadata.insert(key, MetadataValue::UInt32(value));
+        }
+        metadata.insert(
+            "tokenizer.ggml.tokens",
+            MetadataValue::Array {
+                element_type: MetadataValueType::String,
+                values: vec![MetadataValue::String("a".to_owned()); 4],
+            },
+        );
+        metadata.insert("llama.rope.freq_base", MetadataValue::Float32(1_000_000.0));
+
+        let hyperparameters = Hyperparameters::read_gguf(&metadata).unwrap();
+        assert_eq!(hyperparameters.n_vocab, 4);
+        assert_eq!(hyperparameters.n_rot, 4);
+        assert_eq!(hyperparameters.rope_freq_base, Some(1_000_000));
+        assert_eq!(hyperparameters.n_ff, Some(32));
+        hyperparameters.write_ggml(&mut vec![]).unwrap();
+
+        // A feed-forward size below the one LLaMA derives from `n_mult` can be loaded and
+        // saved as GGUF, but not in the legacy formats.
+        metadata.insert("llama.feed_forward_length", MetadataValue::UInt32(16));
+        let hyperparameters = Hyperparameters::read_gguf(&metadata).unwrap();
+        assert_eq!(hyperparameters.n_ff, Some(16));
+        assert!(matches!(
+            hyperparameters.write_ggml(&mut vec![]),
+            Err(HyperparametersWriteError::UnrepresentableValue { value: 16, .. })
+        ));
+        let mut written = Metadata::default();
+        hyperparameters.write_gguf(&mut written).unwrap();
+        assert_eq!(
+            written
+                .get_with_type("llama.feed_forward_length", MetadataValue::as_countable)
+                .unwrap(),
+            16
+        );
+
+        // Grouped-query attention is rejected rather than loaded incorrectly.
+        metadata.insert("llama.attention.head_count_kv", MetadataValue::UInt32(1));
+        assert!(matches!(
+            Hyperparameters::read_gguf(&metadata),
+            Err(LoadError::InvalidMetadata(
+                MetadataError::UnsupportedValue { .. }
+            ))
+        ));
+    }
+}
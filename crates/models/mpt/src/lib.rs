@@ -1,14 +1,16 @@
 //! An implementation of [MPT](https://huggingface.co/mosaicml) for the `llm` ecosystem.
 #![deny(missing_docs)]
 
-use std::sync::Arc;
-
 use ggml::Tensor;
 use llm_base::{
-    ggml::{self},
+    ggml::{
+        self,
+        format::gguf::{Metadata, MetadataValue},
+    },
     model::{common, HyperparametersWriteError},
     util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel, LoadError,
-    ModelParameters, OutputRequest, Regex, TokenId, Tokenizer,
+    MemoryLayout, ModelContext, ModelParameters, OutputRequest, PositionEncoding, Regex,
+    TensorRole, TokenId, Tokenizer,
 };
 
 /// The MosaicML Pretrained Transformer (MPT) model. Ref: [Mosaic ML](https://www.mosaicml.com/blog/mpt-7b)
@@ -31,7 +33,7 @@ pub struct Mpt {
     layers: Vec<Layer>,
 
     // must be kept alive for the model
-    context: Arc<ggml::Context>,
+    context: ModelContext,
 }
 
 unsafe impl Send for Mpt {}
@@ -78,7 +80,7 @@ impl KnownModel for Mpt {
             wte,
             norm,
             layers,
-            context: Arc::new(context),
+            context,
         })
     }
 
@@ -100,7 +102,7 @@ impl KnownModel for Mpt {
     ) {
         let n = input_tokens.len();
         let session_len = session.n_past;
-        let ctx_size = self.params.context_size;
+        let layout = self.layout();
 
         let Hyperparameters {
             n_embd,
@@ -111,12 +113,8 @@ impl KnownModel for Mpt {
             ..
         } = self.hyperparameters;
 
-        let outputs = session.compute(self.context.clone(), input_tokens, |builder| {
+        let outputs = session.compute(&self.context, input_tokens, |builder| {
             let ctx0 = builder.ctx0.borrow();
-            let (memory_k_size, memory_v_size) = (
-                builder.memory_k.element_size(),
-                builder.memory_v.element_size(),
-            );
             let embd = builder.embd;
 
             let mut input_layer = ctx0.op_get_rows(&self.wte, embd);
@@ -134,26 +132,15 @@ impl KnownModel for Mpt {
                     &current,
                 );
 
-                current = ctx0.op_mul_mat(&self.layers[il].c_attn_wqkv_weight, &current);
+                current =
+                    builder.lora_mul_mat(&ctx0, &self.layers[il].c_attn_wqkv_weight, &current);
 
                 let nb = current.get_nb()[1];
                 let qcur = ctx0.op_view_2d(&current, (n_embd, n), nb, 0);
                 let kcur = ctx0.op_view_2d(&current, (n_embd, n), nb, f32_size * n_embd);
                 let vcur = ctx0.op_view_2d(&current, (n_embd, n), nb, f32_size * n_embd * 2);
 
-                let k = ctx0.op_view_1d(
-                    builder.memory_k,
-                    n * n_embd,
-                    (memory_k_size * n_embd) * (il * ctx_size + session_len),
-                );
-                let v = ctx0.op_view_1d(
-                    builder.memory_v,
-                    n * n_embd,
-                    (memory_v_size * n_embd) * (il * ctx_size + session_len),
-                );
-
-                gf.build_forward_expand(&ctx0.op_cpy(&kcur, &k));
-                gf.build_forward_expand(&ctx0.op_cpy(&vcur, &v));
+                builder.store_memory(&ctx0, &mut gf, &layout, il, &kcur, &vcur);
 
                 let q = ctx0.op_permute(
                     &ctx0.op_cpy(
@@ -163,21 +150,7 @@ impl KnownModel for Mpt {
                     (0, 2, 1, 3),
                 );
 
-                let bigk = ctx0.op_permute(
-                    &ctx0.op_reshape_3d(
-                        &ctx0.op_view_1d(
-                            builder.memory_k,
-                            (session_len + n) * n_embd,
-                            il * ctx_size * memory_k_size * n_embd,
-                        ),
-                        n_embd / n_head,
-                        n_head,
-                        session_len + n,
-                    ),
-                    (0, 2, 1, 3),
-                );
-
-                let kq = ctx0.op_mul_mat(&bigk, &q);
+                let kq = builder.memory_keys_mul_mat(&ctx0, &mut gf, il, n_head, &q);
                 let kq_scaled = ctx0.op_scale(
                     &kq,
                     &ctx0.new_f32(1f32 / f32::sqrt(n_embd as f32 / n_head as f32)),
@@ -187,34 +160,13 @@ impl KnownModel for Mpt {
                 let kq_masked = ctx0.op_diag_mask_inf(&kq_scaled_alibi, session_len);
                 let kq_softmax = ctx0.op_soft_max(&kq_masked);
 
-                let v_trans = ctx0.op_cpy(
-                    &ctx0.op_permute(
-                        &ctx0.op_reshape_3d(
-                            &ctx0.op_view_1d(
-                                builder.memory_v,
-                                (session_len + n) * n_embd,
-                                il * ctx_size * memory_v_size * n_embd,
-                            ),
-                            n_embd / n_head,
-                            n_head,
-                            session_len + n,
-                        ),
-                        (1, 2, 0, 3),
-                    ),
-                    &ctx0.new_tensor_3d(
-                        builder.memory_v.get_type(),
-                        session_len + n,
-                        n_embd / n_head,
-                        n_head,
-                    ),
-                );
-
-                let kqv = ctx0.op_mul_mat(&v_trans, &kq_softmax);
+                let kqv = builder.memory_values_mul_mat(&ctx0, &layout, il, n_head, &kq_softmax);
                 let kqv_merged = ctx0.op_permute(&kqv, (0, 2, 1, 3));
 
                 current = ctx0.op_cpy(&kqv_merged, &ctx0.new_tensor_2d(ggml::Type::F32, n_embd, n));
                 // projection
-                current = ctx0.op_mul_mat(&self.layers[il].c_attn_out_proj_weight, &current);
+                current =
+                    builder.lora_mul_mat(&ctx0, &self.layers[il].c_attn_out_proj_weight, &current);
 
                 input_layer = ctx0.op_add(&input_layer, &current);
 
@@ -227,12 +179,12 @@ impl KnownModel for Mpt {
                     &current,
                 );
 
-                current = ctx0.op_mul_mat(&self.layers[il].ffn_up_proj, &current);
+                current = builder.lora_mul_mat(&ctx0, &self.layers[il].ffn_up_proj, &current);
 
                 current = ctx0.op_gelu(&current);
 
                 // projection
-                current = ctx0.op_mul_mat(&self.layers[il].ffn_down_proj, &current);
+                current = builder.lora_mul_mat(&ctx0, &self.layers[il].ffn_down_proj, &current);
 
                 input_layer = ctx0.op_add(&input_layer, &current);
             }
@@ -249,7 +201,7 @@ impl KnownModel for Mpt {
             // disable scratch buffer for last layer
             ctx0.use_scratch(None);
             // output embedding weight tied to input embedding
-            input_layer = ctx0.op_mul_mat(&self.wte, &input_layer);
+            input_layer = builder.lora_mul_mat(&ctx0, &self.wte, &input_layer);
 
             (
                 gf,
@@ -270,6 +222,14 @@ impl KnownModel for Mpt {
         &self.hyperparameters
     }
 
+    fn context(&self) -> &ModelContext {
+        &self.context
+    }
+
+    fn context_mut(&mut self) -> &mut ModelContext {
+        &mut self.context
+    }
+
     fn tokenizer(&self) -> &Tokenizer {
         &self.tokenizer
     }
@@ -294,9 +254,35 @@ impl KnownModel for Mpt {
         vec![]
     }
 
+    fn tensor_role(name: &str) -> TensorRole {
+        match name {
+            _ if name.ends_with(".attn.Wqkv.weight") => TensorRole::AttentionValue,
+            _ if name.ends_with(".attn.out_proj.weight") => TensorRole::AttentionOutput,
+            _ if name.ends_with(".ffn.down_proj.weight") => TensorRole::FeedForwardDown,
+            _ => TensorRole::Other,
+        }
+    }
+
     fn supports_rewind(&self) -> bool {
         true
     }
+
+    fn memory_layout(&self) -> Option<MemoryLayout> {
+        Some(self.layout())
+    }
+
+    fn matches_tensors(has_tensor: &dyn Fn(&str) -> bool) -> bool {
+        has_tensor("transformer.wte.weight") && has_tensor("transformer.blocks.0.attn.Wqkv.weight")
+    }
+}
+
+impl Mpt {
+    fn layout(&self) -> MemoryLayout {
+        MemoryLayout {
+            position_encoding: PositionEncoding::Alibi,
+            transposed_values: false,
+        }
+    }
 }
 
 /// MPT [hyperparameters](https://en.wikipedia.org/wiki/Hyperparameter_(machine_learning))
@@ -321,6 +307,28 @@ pub struct Hyperparameters {
 }
 impl Eq for Hyperparameters {}
 
+/// The names of the tensors of the model in GGUF files, and the names it loads them by.
+const GGUF_TENSOR_NAMES: &[(&str, &str)] = &[
+    ("token_embd.weight", "transformer.wte.weight"),
+    ("output_norm.weight", "transformer.norm_f.weight"),
+];
+/// Like [GGUF_TENSOR_NAMES], for the tensors of each block. `{}` stands for the index of
+/// the block.
+const GGUF_BLOCK_TENSOR_NAMES: &[(&str, &str)] = &[
+    ("attn_norm.weight", "transformer.blocks.{}.norm_1.weight"),
+    ("attn_qkv.weight", "transformer.blocks.{}.attn.Wqkv.weight"),
+    (
+        "attn_output.weight",
+        "transformer.blocks.{}.attn.out_proj.weight",
+    ),
+    ("ffn_norm.weight", "transformer.blocks.{}.norm_2.weight"),
+    ("ffn_up.weight", "transformer.blocks.{}.ffn.up_proj.weight"),
+    (
+        "ffn_down.weight",
+        "transformer.blocks.{}.ffn.down_proj.weight",
+    ),
+];
+
 impl llm_base::Hyperparameters for Hyperparameters {
     fn read_ggml(reader: &mut dyn std::io::BufRead) -> Result<Self, LoadError> {
         let hyperparameters = Hyperparameters {
@@ -349,6 +357,49 @@ impl llm_base::Hyperparameters for Hyperparameters {
         Ok(())
     }
 
+    fn read_gguf(metadata: &Metadata) -> Result<Self, LoadError> {
+        Ok(Hyperparameters {
+            n_embd: metadata.get_with_type("mpt.embedding_length", MetadataValue::as_countable)?,
+            max_seq_len: metadata
+                .get_with_type("mpt.context_length", MetadataValue::as_countable)?,
+            n_head: metadata
+                .get_with_type("mpt.attention.head_count", MetadataValue::as_countable)?,
+            n_layer: metadata.get_with_type("mpt.block_count", MetadataValue::as_countable)?,
+            n_vocab: util::read_gguf_n_vocab(metadata)?,
+            alibi_bias_max: metadata
+                .get_with_type("mpt.attention.max_alibi_bias", MetadataValue::as_f32)?,
+            clip_kqv: metadata
+                .get_optional("mpt.attention.clamp_kqv", MetadataValue::as_f32)?
+                .unwrap_or_default(),
+            file_type: util::read_gguf_filetype(metadata)?,
+        })
+    }
+
+    fn write_gguf(&self, metadata: &mut Metadata) -> Result<(), HyperparametersWriteError> {
+        util::write_gguf_general(metadata, "mpt", "gpt2", self.file_type)?;
+        util::write_gguf_countable(metadata, "mpt.embedding_length", self.n_embd)?;
+        util::write_gguf_countable(metadata, "mpt.context_length", self.max_seq_len)?;
+        util::write_gguf_countable(metadata, "mpt.attention.head_count", self.n_head)?;
+        util::write_gguf_countable(metadata, "mpt.block_count", self.n_layer)?;
+        metadata.insert(
+            "mpt.attention.max_alibi_bias",
+            MetadataValue::Float32(self.alibi_bias_max),
+        );
+        metadata.insert(
+            "mpt.attention.clamp_kqv",
+            MetadataValue::Float32(self.clip_kqv),
+        );
+        Ok(())
+    }
+
+    fn tensor_name_from_gguf(&self, name: &str) -> String {
+        util::translate_gguf_tensor_name(name, GGUF_TENSOR_NAMES, GGUF_BLOCK_TENSOR_NAMES)
+    }
+
+    fn tensor_name_to_gguf(&self, name: &str) -> String {
+        util::translate_tensor_name_to_gguf(name, GGUF_TENSOR_NAMES, GGUF_BLOCK_TENSOR_NAMES)
+    }
+
     fn n_vocabulary(&self) -> usize {
         self.n_vocab
     }